@@ -0,0 +1,234 @@
+//! A plain membership Bloom filter with a simple binary format that's easy to read from other
+//! languages.
+//!
+//! # Format
+//!
+//! All integers are little-endian.
+//!
+//! | Field           | Type         | Description                                          |
+//! |-----------------|--------------|------------------------------------------------------|
+//! | magic           | 8 bytes      | `b"WIMBDBLM"`                                        |
+//! | version         | u32          | Currently 1                                          |
+//! | unit            | u8           | 0 for ngrams, 1 for paragraphs                       |
+//! | ngram           | u32          | The ngram size, 0 for paragraphs                     |
+//! | tokenizer       | u32 + bytes  | Length-prefixed UTF-8 name of the tokenizer          |
+//! | num_hashes      | u32          | The number of hash functions                         |
+//! | seed            | u64          | The seed for the hash functions                      |
+//! | num_bits        | u64          | The number of bits, always a multiple of 64          |
+//! | bits            | u64 * n      | `num_bits / 64` words, bit `i` is `words[i / 64] >> (i % 64) & 1` |
+//!
+//! The key of an ngram is the UTF-8 bytes of each token followed by a 0 byte. The key of a
+//! paragraph is its UTF-8 bytes with surrounding whitespace trimmed. The `i`-th bit for a key is
+//! `(h1 + i * h2) % num_bits` using wrapping u64 arithmetic, where `h1 = xxh3_64(key, seed)`
+//! and `h2 = xxh3_64(key, seed + 1) | 1`. For example, in Python with the `xxhash` package:
+//!
+//! ```python
+//! h1 = xxhash.xxh3_64_intdigest(key, seed)
+//! h2 = xxhash.xxh3_64_intdigest(key, seed + 1) | 1
+//! bits = [(h1 + i * h2) % 2**64 % num_bits for i in range(num_hashes)]
+//! ```
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{bail, Result};
+use xxhash_rust::xxh3::xxh3_64_with_seed;
+
+const MAGIC: &[u8; 8] = b"WIMBDBLM";
+const VERSION: u32 = 1;
+
+/// What the items in a Bloom filter are.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BloomUnit {
+    Ngram,
+    Paragraph,
+}
+
+/// A thread-safe membership Bloom filter.
+pub struct BloomFilter {
+    pub unit: BloomUnit,
+    /// The ngram size, 0 for paragraphs.
+    pub ngram: usize,
+    pub tokenizer: String,
+    num_hashes: usize,
+    seed: u64,
+    bits: Vec<AtomicU64>,
+}
+
+impl BloomFilter {
+    /// Create an empty filter that takes up about `size` bytes.
+    pub fn new(
+        unit: BloomUnit,
+        ngram: usize,
+        tokenizer: &str,
+        size: usize,
+        num_hashes: usize,
+        seed: u64,
+    ) -> Result<Self> {
+        let num_words = size / 8;
+        if num_words == 0 {
+            bail!("Bloom filter size must be at least 8 bytes");
+        }
+        let mut bits = Vec::new();
+        bits.try_reserve_exact(num_words)?;
+        bits.extend((0..num_words).map(|_| AtomicU64::new(0)));
+        Ok(Self {
+            unit,
+            ngram,
+            tokenizer: tokenizer.into(),
+            num_hashes,
+            seed,
+            bits,
+        })
+    }
+
+    fn num_bits(&self) -> u64 {
+        self.bits.len() as u64 * 64
+    }
+
+    fn bit_indices(&self, key: &[u8]) -> impl Iterator<Item = u64> {
+        let h1 = xxh3_64_with_seed(key, self.seed);
+        let h2 = xxh3_64_with_seed(key, self.seed.wrapping_add(1)) | 1;
+        let num_bits = self.num_bits();
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    /// Add a key to the filter.
+    pub fn insert(&self, key: &[u8]) {
+        for i in self.bit_indices(key) {
+            self.bits[(i / 64) as usize].fetch_or(1 << (i % 64), Ordering::Relaxed);
+        }
+    }
+
+    /// Check if a key might be in the filter.
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.bit_indices(key)
+            .all(|i| self.bits[(i / 64) as usize].load(Ordering::Relaxed) & (1 << (i % 64)) != 0)
+    }
+
+    /// Write the filter to a file in the format described in the module docs.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&[match self.unit {
+            BloomUnit::Ngram => 0,
+            BloomUnit::Paragraph => 1,
+        }])?;
+        writer.write_all(&(self.ngram as u32).to_le_bytes())?;
+        writer.write_all(&(self.tokenizer.len() as u32).to_le_bytes())?;
+        writer.write_all(self.tokenizer.as_bytes())?;
+        writer.write_all(&(self.num_hashes as u32).to_le_bytes())?;
+        writer.write_all(&self.seed.to_le_bytes())?;
+        writer.write_all(&self.num_bits().to_le_bytes())?;
+        for word in &self.bits {
+            writer.write_all(&word.load(Ordering::Relaxed).to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read a filter written with [`BloomFilter::write()`].
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            bail!("{:?} is not a wimbd Bloom filter", path);
+        }
+        let version = read_u32(&mut reader)?;
+        if version != VERSION {
+            bail!("Unsupported Bloom filter version {}", version);
+        }
+        let mut unit = [0u8; 1];
+        reader.read_exact(&mut unit)?;
+        let unit = match unit[0] {
+            0 => BloomUnit::Ngram,
+            1 => BloomUnit::Paragraph,
+            other => bail!("Unknown Bloom filter unit {}", other),
+        };
+        let ngram = read_u32(&mut reader)? as usize;
+        let mut tokenizer = vec![0u8; read_u32(&mut reader)? as usize];
+        reader.read_exact(&mut tokenizer)?;
+        let tokenizer = String::from_utf8(tokenizer)?;
+        let num_hashes = read_u32(&mut reader)? as usize;
+        let seed = read_u64(&mut reader)?;
+        let num_bits = read_u64(&mut reader)?;
+        if num_bits == 0 || num_bits % 64 != 0 {
+            bail!("Invalid number of bits {} in Bloom filter", num_bits);
+        }
+
+        let filter = Self::new(
+            unit,
+            ngram,
+            &tokenizer,
+            (num_bits / 8) as usize,
+            num_hashes,
+            seed,
+        )?;
+        for word in &filter.bits {
+            word.store(read_u64(&mut reader)?, Ordering::Relaxed);
+        }
+        Ok(filter)
+    }
+}
+
+/// The key for an ngram.
+pub fn ngram_key<T: AsRef<str>>(tokens: &[T]) -> Vec<u8> {
+    let mut key = Vec::new();
+    for token in tokens {
+        key.extend_from_slice(token.as_ref().as_bytes());
+        key.push(0);
+    }
+    key
+}
+
+/// The key for a paragraph.
+pub fn paragraph_key(paragraph: &str) -> &[u8] {
+    paragraph.trim().as_bytes()
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_contains() {
+        let filter = BloomFilter::new(BloomUnit::Ngram, 2, "unicode", 1024, 4, 0).unwrap();
+        filter.insert(&ngram_key(&["hi", "there"]));
+        assert!(filter.contains(&ngram_key(&["hi", "there"])));
+        assert!(!filter.contains(&ngram_key(&["bye", "there"])));
+        // Token boundaries are part of the key.
+        assert!(!filter.contains(&ngram_key(&["hit", "here"])));
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let path = std::env::temp_dir().join(format!("wimbd-bloom-test-{}", std::process::id()));
+        let filter = BloomFilter::new(BloomUnit::Paragraph, 0, "gpt2", 1024, 3, 42).unwrap();
+        filter.insert(paragraph_key("  Hello, World!\n"));
+        filter.write(&path).unwrap();
+
+        let loaded = BloomFilter::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unit, BloomUnit::Paragraph);
+        assert_eq!(loaded.tokenizer, "gpt2");
+        assert!(loaded.contains(paragraph_key("Hello, World!")));
+        assert!(!loaded.contains(paragraph_key("Hello, World")));
+    }
+}
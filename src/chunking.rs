@@ -0,0 +1,161 @@
+//! Content-defined chunking with FastCDC.
+//!
+//! Data is split into chunks at positions chosen by a rolling "gear" hash of the bytes right
+//! before them, instead of at fixed offsets. An insertion or deletion only changes the chunks
+//! around it, so texts that share long passages share most of their chunks even if the passages
+//! are at different offsets.
+//!
+//! Chunk sizes are normalized as described in the FastCDC paper: cut points are harder to hit
+//! before the average size and easier after it, which keeps most chunks close to the average.
+
+/// The random value of every byte for the gear hash.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // Fixed SplitMix64 output, so that chunk boundaries are the same across runs and versions.
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// A mask of the top `bits` bits. The gear hash shifts left, so the top bits depend on the most
+/// bytes.
+fn mask(bits: u32) -> u64 {
+    !0u64 << (64 - bits.clamp(1, 63))
+}
+
+/// Splits data into content-defined chunks.
+#[derive(Debug, Clone, Copy)]
+pub struct Chunker {
+    min_size: usize,
+    avg_size: usize,
+    max_size: usize,
+    /// The mask used before the average size, with more bits than the average size needs.
+    mask_small: u64,
+    /// The mask used after the average size, with fewer bits.
+    mask_large: u64,
+}
+
+impl Chunker {
+    /// Create a chunker for chunks of about `avg_size` bytes, which is rounded down to a power
+    /// of 2. Chunks are between a quarter and 8 times the average size, except for the last
+    /// chunk, which can be smaller.
+    pub fn new(avg_size: usize) -> Self {
+        let bits = usize::BITS - 1 - avg_size.max(4).leading_zeros();
+        let avg_size = 1 << bits;
+        Self {
+            min_size: avg_size / 4,
+            avg_size,
+            max_size: avg_size * 8,
+            mask_small: mask(bits + 2),
+            mask_large: mask(bits.saturating_sub(2)),
+        }
+    }
+
+    pub fn avg_size(&self) -> usize {
+        self.avg_size
+    }
+
+    /// The length of the first chunk of the data.
+    pub fn cut_point(&self, data: &[u8]) -> usize {
+        if data.len() <= self.min_size {
+            return data.len();
+        }
+        let end = std::cmp::min(data.len(), self.max_size);
+        let normal = std::cmp::min(end, self.avg_size);
+        let mut hash: u64 = 0;
+        let mut i = self.min_size;
+        while i < normal {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_small == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        while i < end {
+            hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+            if hash & self.mask_large == 0 {
+                return i + 1;
+            }
+            i += 1;
+        }
+        end
+    }
+
+    /// Iterate over the chunks of the data, in order. Empty data has no chunks.
+    pub fn chunks<'a>(&self, mut data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> {
+        let chunker = *self;
+        std::iter::from_fn(move || {
+            if data.is_empty() {
+                return None;
+            }
+            let (chunk, rest) = data.split_at(chunker.cut_point(data));
+            data = rest;
+            Some(chunk)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic pseudo-random text.
+    fn text(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                b"abcdefghijklmnopqrstuvwxyz     "[(state >> 59) as usize % 31]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chunks() {
+        let chunker = Chunker::new(256);
+        assert_eq!(chunker.avg_size(), 256);
+        assert_eq!(Chunker::new(300).avg_size(), 256);
+        assert_eq!(chunker.chunks(b"").count(), 0);
+        assert_eq!(
+            chunker.chunks(b"short").collect::<Vec<_>>(),
+            vec![&b"short"[..]]
+        );
+
+        let data = text(100_000, 1);
+        let chunks: Vec<&[u8]> = chunker.chunks(&data).collect();
+        assert_eq!(chunks.concat(), data);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() >= 64 && chunk.len() <= 2048);
+        }
+        let mean = data.len() / chunks.len();
+        assert!(mean > 128 && mean < 512, "mean chunk size {mean}");
+    }
+
+    #[test]
+    fn test_chunks_after_insertion() {
+        let chunker = Chunker::new(256);
+        let data = text(20_000, 2);
+        let mut edited = b"an inserted prefix".to_vec();
+        edited.extend_from_slice(&data);
+
+        let chunks: std::collections::HashSet<&[u8]> = chunker.chunks(&data).collect();
+        let edited_chunks: Vec<&[u8]> = chunker.chunks(&edited).collect();
+        let shared = edited_chunks
+            .iter()
+            .filter(|chunk| chunks.contains(*chunk))
+            .count();
+        assert!(shared + 3 >= edited_chunks.len());
+    }
+}
@@ -0,0 +1,272 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU32;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{parse_size_default_to_gb, DataInstance};
+use crate::io::LineReader;
+use crate::ngrams::NgramCounter;
+use crate::tokens::load_tokenizer;
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a gzip-compressed JSON lines file to use as the sample.
+    #[structopt(parse(from_os_str))]
+    path: PathBuf,
+
+    /// Limit the number of JSON lines to benchmark with. All lines are held in memory.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// The numbers of threads/workers to benchmark with, e.g. "1,8,32".
+    #[structopt(
+        short = "j",
+        long = "workers",
+        use_delimiter = true,
+        default_value = "1,2,4,8"
+    )]
+    workers: Vec<usize>,
+
+    /// Ngram size for the counting stage.
+    #[structopt(short = "n", long = "ngram", default_value = "3")]
+    ngram: usize,
+
+    /// Specify the size budget for the ngram counter hash table in the counting stage,
+    /// e.g. "1GiB".
+    #[structopt(long = "size", default_value = "1GiB", parse(try_from_str = parse_size_default_to_gb))]
+    size: u64,
+
+    /// Specify the number of hash functions to use in the counting stage.
+    #[structopt(short = "h", long = "hashes", default_value = "5")]
+    hashes: u8,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e.
+    /// each line will be a JSON object with the results for one stage and number of workers.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct StageResult {
+    stage: &'static str,
+    workers: usize,
+    seconds: f64,
+    mb_per_sec: f64,
+    lines_per_sec: f64,
+}
+
+impl StageResult {
+    fn new(
+        stage: &'static str,
+        workers: usize,
+        elapsed: Duration,
+        bytes: usize,
+        lines: usize,
+    ) -> Self {
+        let seconds = elapsed.as_secs_f64().max(1e-9);
+        Self {
+            stage,
+            workers,
+            seconds,
+            mb_per_sec: bytes as f64 / 1_000_000.0 / seconds,
+            lines_per_sec: lines as f64 / seconds,
+        }
+    }
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    if !opt.path.is_file() {
+        bail!("File {:?} does not exist", opt.path);
+    }
+    if opt.workers.is_empty() || opt.workers.contains(&0) {
+        bail!("-j/--workers must be a list of numbers greater than 0");
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    if opt.size == 0 {
+        bail!("--size must be greater than 0");
+    }
+    if opt.hashes == 0 {
+        bail!("-h/--hashes must be greater than 0");
+    }
+
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    // Load the sample into memory so that the later stages only measure what they do.
+    log::info!("Loading sample from {:?}...", opt.path);
+    let lines = read_lines(&opt.path, opt.limit)?;
+    if lines.is_empty() {
+        bail!("File {:?} is empty", opt.path);
+    }
+    let num_lines = lines.len();
+    let num_bytes: usize = lines.iter().map(|line| line.len()).sum();
+    let texts: Vec<String> = lines
+        .iter()
+        .map(|line| -> Result<String> {
+            let data: DataInstance = serde_json::from_str(line)?;
+            Ok(data.text.unwrap_or_default())
+        })
+        .collect::<Result<_>>()?;
+    let tokens: Vec<Vec<String>> = texts
+        .iter()
+        .map(|text| Ok(tokenizer.tokenize(text)?))
+        .collect::<Result<_>>()?;
+    log::info!(
+        "Loaded {} lines ({} bytes)",
+        num_lines.separate_with_commas(),
+        num_bytes.separate_with_commas()
+    );
+
+    let mut results = Vec::new();
+    for &workers in &opt.workers {
+        log::info!("Benchmarking with {} worker(s)...", workers);
+
+        // Decompression: every worker reads the whole file, as if each had its own file.
+        let start = Instant::now();
+        std::thread::scope(|scope| -> Result<()> {
+            let handles: Vec<_> = (0..workers)
+                .map(|_| scope.spawn(|| read_lines(&opt.path, opt.limit).map(|_| ())))
+                .collect();
+            for handle in handles {
+                handle.join().map_err(|_| anyhow!("Worker panicked"))??;
+            }
+            Ok(())
+        })?;
+        results.push(StageResult::new(
+            "decompress",
+            workers,
+            start.elapsed(),
+            workers * num_bytes,
+            workers * num_lines,
+        ));
+
+        // JSON parsing.
+        let elapsed = run_chunked(&lines, workers, |line| -> Result<()> {
+            let _: DataInstance = serde_json::from_str(line)?;
+            Ok(())
+        })?;
+        results.push(StageResult::new(
+            "parse", workers, elapsed, num_bytes, num_lines,
+        ));
+
+        // Tokenization.
+        let elapsed = run_chunked(&texts, workers, |text| -> Result<()> {
+            tokenizer.tokenize(text)?;
+            Ok(())
+        })?;
+        results.push(StageResult::new(
+            "tokenize", workers, elapsed, num_bytes, num_lines,
+        ));
+
+        // Ngram counting. The counter is allocated outside of the timed section.
+        let ngram_counts =
+            NgramCounter::<AtomicU32>::new((opt.size / 4) as usize, opt.hashes as usize, None, 0)?;
+        let elapsed = run_chunked(&tokens, workers, |tokens| -> Result<()> {
+            for ngram in tokens.windows(opt.ngram) {
+                ngram_counts.increment(ngram, 1);
+            }
+            Ok(())
+        })?;
+        results.push(StageResult::new(
+            "count", workers, elapsed, num_bytes, num_lines,
+        ));
+    }
+
+    for result in &results {
+        let json_out = serde_json::to_string(result)?;
+        if opt.json {
+            println!("{json_out}");
+        } else {
+            println!(
+                "{:<10} workers={:<3} {:>10.1} MB/s {:>14} lines/s",
+                style(result.stage).cyan(),
+                result.workers,
+                result.mb_per_sec,
+                (result.lines_per_sec as usize).separate_with_commas(),
+            );
+        }
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn read_lines(path: &Path, limit: Option<usize>) -> Result<Vec<String>> {
+    let mut lines = Vec::new();
+    for line in LineReader::open(path)?.take(limit.unwrap_or(usize::MAX)) {
+        lines.push(line?);
+    }
+    Ok(lines)
+}
+
+/// Split the items into even chunks and apply the function to every item using one thread
+/// per chunk, returning the total wall time.
+fn run_chunked<T, F>(items: &[T], workers: usize, func: F) -> Result<Duration>
+where
+    T: Sync,
+    F: Fn(&T) -> Result<()> + Sync,
+{
+    let chunk_size = items.len().div_ceil(workers).max(1);
+    let func = &func;
+    let start = Instant::now();
+    std::thread::scope(|scope| -> Result<()> {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().try_for_each(func)))
+            .collect();
+        for handle in handles {
+            handle.join().map_err(|_| anyhow!("Worker panicked"))??;
+        }
+        Ok(())
+    })?;
+    Ok(start.elapsed())
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
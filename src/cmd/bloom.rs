@@ -0,0 +1,189 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use structopt::StructOpt;
+
+use super::util::{expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance};
+use crate::bloom::{ngram_key, paragraph_key, BloomFilter, BloomUnit};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer, UnicodeTokenizer};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) enum Opt {
+    /// Build a Bloom filter over the ngrams or paragraphs of a dataset.
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Build(BuildOpt),
+}
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct BuildOpt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// What to add to the filter: "ngram" for ngrams of size '-n/--ngram', or "paragraph"
+    /// for the non-empty lines of each document.
+    #[structopt(long = "unit", default_value = "ngram", possible_values = &["ngram", "paragraph"])]
+    unit: String,
+
+    /// Ngram size.
+    #[structopt(short = "n", long = "ngram", default_value = "13")]
+    ngram: usize,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Specify the size of the filter, e.g. "8GiB".
+    #[structopt(long = "size", default_value = "1GiB", parse(try_from_str = parse_size_default_to_gb))]
+    size: u64,
+
+    /// Specify the number of hash functions to use.
+    #[structopt(short = "h", long = "hashes", default_value = "5")]
+    hashes: u8,
+
+    /// Set the seed for the hashing functions. It's saved with the filter.
+    #[structopt(long = "seed", default_value = "0")]
+    seed: u64,
+
+    /// A path to write the filter to, e.g. "corpus.bloom".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: PathBuf,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace. Only used for ngrams.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    match opt {
+        Opt::Build(opt) => build(opt),
+    }
+}
+
+fn build(mut opt: BuildOpt) -> Result<()> {
+    // Validate arguments.
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.size < 8 {
+        bail!("--size must be at least 8 bytes");
+    }
+    if opt.hashes == 0 {
+        bail!("-h/--hashes must be greater than 0");
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    if opt.out.is_dir() {
+        bail!("-o/--out must be a valid file name, not a directory");
+    }
+    if opt.out.is_file() && !opt.force {
+        bail!(
+            "Output file {:?} already exists, use --force to overwrite",
+            opt.out
+        );
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let unit = if &opt.unit == "paragraph" {
+        BloomUnit::Paragraph
+    } else {
+        BloomUnit::Ngram
+    };
+    provenance::record_tokenizer(&opt.tokenizer);
+    provenance::record_seed(opt.seed);
+    // Paragraphs aren't tokenized.
+    let tokenizer: Arc<dyn Tokenizer> = if unit == BloomUnit::Paragraph {
+        Arc::new(UnicodeTokenizer)
+    } else {
+        load_tokenizer(&opt.tokenizer)?
+    };
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    log::info!("Initializing Bloom filter...");
+    let filter = Arc::new(BloomFilter::new(
+        unit,
+        if unit == BloomUnit::Ngram {
+            opt.ngram
+        } else {
+            0
+        },
+        &opt.tokenizer,
+        opt.size as usize,
+        opt.hashes as usize,
+        opt.seed,
+    )?);
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Building", opt.quiet)?;
+
+    for path in &opt.path {
+        let add_document = {
+            let filter = filter.clone();
+            let tokenizer = tokenizer.clone();
+
+            move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    match unit {
+                        BloomUnit::Ngram => {
+                            let tokens = tokenizer.tokenize(&text)?;
+                            for ngram in tokens.windows(opt.ngram) {
+                                filter.insert(&ngram_key(ngram));
+                            }
+                        }
+                        BloomUnit::Paragraph => {
+                            for paragraph in text.split('\n').filter(|p| !p.trim().is_empty()) {
+                                filter.insert(paragraph_key(paragraph));
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute(path, add_document)?;
+    }
+
+    executor.join()?;
+
+    filter.write(&opt.out)?;
+    provenance::record_output(&opt.out);
+    log::info!("Bloom filter written to {:?}", opt.out);
+
+    Ok(())
+}
@@ -1,28 +1,36 @@
-use std::collections::VecDeque;
-use std::fs::File;
+use std::collections::HashSet;
 use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
 use std::sync::mpsc::sync_channel;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use atomic_traits::Atomic;
 use console::style;
 use num_traits::{NumCast, One};
-use rand::{random, rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rand::seq::SliceRandom;
 use serde_json::json;
 use structopt::StructOpt;
+use thousands::Separable;
 
-use super::util::{parse_size_default_to_gb, DataExecutor, DataInstance};
-use crate::ngrams::{NgramCounter, TopKNgrams};
-use crate::tokens::{tokenize, PretrainedTokenizer};
-use crate::util;
+use super::util::{
+    derive_rng, expand_paths, parse_size_default_to_gb, uniform_hash, DataExecutor, DataInstance,
+};
+use crate::ngrams::{NgramCounter, NgramWindows, TopKNgrams};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util::{self, OutputFile};
+
+/// The number of rare ngrams each worker buffers with '--single-pass' before adding them to the
+/// shared candidates.
+const CANDIDATE_BUFFER_SIZE: usize = 100_000;
 
 #[derive(Debug, StructOpt, Clone)]
 pub(crate) struct Opt {
-    /// Path to a gzip-compressed JSON lines file.
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
     #[structopt(parse(from_os_str))]
     path: Vec<PathBuf>,
 
@@ -56,7 +64,8 @@ pub(crate) struct Opt {
     #[structopt(short = "h", long = "hashes", default_value = "5")]
     hashes: u8,
 
-    /// Set the seed for the hashing functions. By default the seed is chosen at random.
+    /// Set the seed for the hashing functions, the order of the files, and '--p-keep'.
+    /// By default the seed is chosen at random.
     #[structopt(long = "seed")]
     seed: Option<u64>,
 
@@ -88,6 +97,14 @@ pub(crate) struct Opt {
     #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
     tokenizer: String,
 
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+
     /// Set a maximum count threshold for ngrams to be considered for the bottom-k.
     /// Setting a lower threshold can improve speed, but be careful not to set a threshold
     /// lower than what you expect the maximum count in the bottom-k to be.
@@ -95,9 +112,20 @@ pub(crate) struct Opt {
     threshold: u32,
 
     /// Add more randomness to the results by specifying a probably of keeping each rare ngram
-    /// encountered.
+    /// encountered. Which ngrams are kept only depends on '--seed'.
     #[structopt(long = "--p-keep")]
     p_keep: Option<f32>,
+
+    /// Find rare ngrams in a single pass through the data instead of two. Ngrams are kept as
+    /// candidates while their count is at most '--threshold', which has to be set, and
+    /// dropped once it goes over. This halves the IO, but once there are '--max-candidates'
+    /// rare ngrams new ones are missed, so the results aren't guaranteed to be the rarest.
+    #[structopt(long = "single-pass")]
+    single_pass: bool,
+
+    /// The max number of candidate ngrams to keep with '--single-pass'.
+    #[structopt(long = "max-candidates", default_value = "1000000")]
+    max_candidates: usize,
 }
 
 pub(crate) fn main(mut opt: Opt) -> Result<()> {
@@ -117,20 +145,27 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
     if opt.ngram == 0 {
         bail!("-n/--ngram must be greater than 0");
     }
+    if opt.single_pass && opt.threshold == u32::MAX {
+        bail!("--single-pass requires --threshold");
+    }
+    if opt.single_pass && opt.max_candidates == 0 {
+        bail!("--max-candidates must be greater than 0");
+    }
+    opt.path = expand_paths(&opt.path)?;
     if let Some(file_limit) = opt.file_limit {
         opt.path.truncate(file_limit);
     }
+    // Pick a seed up front so that it's recorded in the run metadata.
+    provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
     if let Some(p_keep) = opt.p_keep {
         if p_keep <= 0.0 || p_keep > 1.0 {
             bail!("--p-keep must be between in the interval (0, 1]");
         }
     }
 
-    let tokenizer: Option<PretrainedTokenizer> = if &opt.tokenizer == "unicode" {
-        None
-    } else {
-        Some(PretrainedTokenizer::new(&opt.tokenizer)?)
-    };
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
 
     let (mut out_file, out_path) = match get_output_file(&opt)? {
         Some(out) => (Some(out.0), Some(out.1)),
@@ -138,12 +173,8 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
     };
 
     // Shuffle paths.
-    let mut rng = if let Some(seed) = opt.seed {
-        StdRng::seed_from_u64(seed)
-    } else {
-        StdRng::from_entropy()
-    };
-    opt.path.shuffle(&mut rng);
+    let seed = opt.seed.unwrap();
+    opt.path.shuffle(&mut derive_rng(seed, "paths", 0));
 
     log::info!("Initializing ngram counter...");
     // We're storing an array of u32s, so each u32 is 32 bits of memory, or 4 bytes.
@@ -156,6 +187,76 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
         u32::MAX,
     )?);
 
+    let mut topk: TopKNgrams<String, AtomicU32> = TopKNgrams::new(opt.k);
+    if opt.single_pass {
+        collect_single_pass(
+            opt.clone(),
+            &tokenizer,
+            preprocessor,
+            &ngram_counts,
+            &mut topk,
+        )?;
+    } else {
+        collect_two_pass(
+            opt.clone(),
+            &tokenizer,
+            preprocessor,
+            &ngram_counts,
+            &mut topk,
+        )?;
+    }
+
+    let bottom_k_final = topk.drain();
+    for (i, (ngram, inverse_count)) in bottom_k_final.iter().enumerate() {
+        let count = u32::MAX - inverse_count;
+        let ngram_str = tokenizer.decode(ngram)?;
+        let json_out = &json!({
+            "tokens": **ngram,
+            "string": ngram_str,
+            "count": count,
+            "rank": i + 1,
+        })
+        .to_string();
+
+        // Display output.
+        if opt.json {
+            println!("{json_out}");
+        } else if opt.out.is_none() {
+            println!(
+                "[{}/{}] {:?} (count {} {})",
+                i + 1,
+                bottom_k_final.len(),
+                style(ngram_str).cyan(),
+                if count > 1 { "≤" } else { "=" },
+                count,
+            );
+        }
+
+        // Write ngram and count to file.
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Count ngrams in a first pass through the data and collect the rarest ones in a second pass.
+fn collect_two_pass(
+    opt: Opt,
+    tokenizer: &Arc<dyn Tokenizer>,
+    preprocessor: Preprocessor,
+    ngram_counts: &Arc<NgramCounter<AtomicU32>>,
+    topk: &mut TopKNgrams<String, AtomicU32>,
+) -> Result<()> {
+    let seed = opt.seed.unwrap();
     let executor = DataExecutor::new(
         &opt.path,
         opt.workers,
@@ -174,25 +275,12 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
 
             move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
                 if let Some(text) = data.text {
-                    let tokens: Box<dyn Iterator<Item = String>> =
-                        if let Some(tokenizer) = &tokenizer {
-                            Box::new(tokenizer.tokenize(&text)?.into_iter())
-                        } else {
-                            Box::new(tokenize(&text).map(|s| s.to_string()))
-                        };
-
-                    let mut ngram_deque: VecDeque<String> = VecDeque::with_capacity(opt.ngram);
-                    for token in tokens {
-                        if ngram_deque.len() == opt.ngram {
-                            ngram_deque.pop_front();
-                        }
+                    let text = preprocessor.apply(&text);
+                    let tokens = tokenizer.tokens(&text)?;
+                    let tokens = tokens.iter().map(|token| token.as_ref());
 
-                        ngram_deque.push_back(token);
-
-                        if ngram_deque.len() == opt.ngram {
-                            ngram_counts
-                                .decrement(&ngram_deque, <AtomicU32 as Atomic>::Type::one());
-                        }
+                    for ngram in NgramWindows::new(tokens, opt.ngram) {
+                        ngram_counts.decrement(&ngram[..], <AtomicU32 as Atomic>::Type::one());
                     }
                 }
 
@@ -205,14 +293,14 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
 
     executor.join()?;
 
-    let executor = DataExecutor::new(
+    let mut executor = DataExecutor::new(
         &opt.path,
         opt.workers,
         opt.limit,
         "Collecting ngrams",
         opt.quiet,
     )?;
-    let mut topk: TopKNgrams<String, AtomicU32> = TopKNgrams::new(opt.k);
+    executor.set_shared_state();
     let (tx, rx) = sync_channel(512_000);
 
     // Second pass through the data: collect ngrams and add to the top-k (bottom-k)
@@ -224,40 +312,30 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
             let min_count = topk.min_count();
             let threshold = u32::MAX - opt.threshold;
             move |data: DataInstance,
-                  _: &Path,
-                  _: usize,
+                  path: &Path,
+                  line_num: usize,
                   local_topk: &mut TopKNgrams<String, AtomicU32>|
                   -> Result<()> {
                 if let Some(text) = data.text {
-                    let tokens: Box<dyn Iterator<Item = String>> =
-                        if let Some(tokenizer) = &tokenizer {
-                            Box::new(tokenizer.tokenize(&text)?.into_iter())
-                        } else {
-                            Box::new(tokenize(&text).map(|s| s.to_string()))
-                        };
-
-                    let mut ngram_deque: VecDeque<String> = VecDeque::with_capacity(opt.ngram);
-                    for token in tokens {
-                        if ngram_deque.len() == opt.ngram {
-                            ngram_deque.pop_front();
-                        }
-
-                        ngram_deque.push_back(token);
-
-                        if ngram_deque.len() == opt.ngram {
-                            let inverse_count = ngram_counts.max_count(&ngram_deque);
-                            if inverse_count > threshold
-                                && inverse_count >= local_topk.min_count
-                                && inverse_count >= min_count.load(Ordering::Relaxed)
-                            {
-                                if let Some(p_keep) = opt.p_keep {
-                                    if random::<f32>() > p_keep {
-                                        continue;
-                                    }
+                    let text = preprocessor.apply(&text);
+                    let tokens = tokenizer.tokens(&text)?;
+                    let tokens = tokens.iter().map(|token| token.as_ref());
+
+                    for (i, ngram) in NgramWindows::new(tokens, opt.ngram).enumerate() {
+                        let inverse_count = ngram_counts.max_count(&ngram[..]);
+                        if inverse_count > threshold
+                            && inverse_count >= local_topk.min_count
+                            && inverse_count >= min_count.load(Ordering::Relaxed)
+                        {
+                            if let Some(p_keep) = opt.p_keep {
+                                // The position of the ngram's last token.
+                                let position = i + opt.ngram - 1;
+                                if uniform_hash(seed, path, line_num, position) >= p_keep as f64 {
+                                    continue;
                                 }
-                                let ngram: Vec<String> = ngram_deque.iter().cloned().collect();
-                                local_topk.insert(ngram, inverse_count);
                             }
+                            let ngram: Vec<String> = ngram.iter().map(|s| s.to_string()).collect();
+                            local_topk.insert(ngram, inverse_count);
                         }
                     }
                 }
@@ -311,52 +389,173 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
         }
     }
 
-    executor.join()?;
+    executor.join()
+}
 
-    let bottom_k_final = topk.drain();
-    for (i, (ngram, inverse_count)) in bottom_k_final.iter().enumerate() {
-        let count = u32::MAX - inverse_count;
-        let ngram_str = if let Some(ref tokenizer) = tokenizer {
-            tokenizer.decode(ngram)?
-        } else {
-            ngram.join(" ")
+/// Count ngrams and collect candidates for the rarest ones in a single pass through the data.
+///
+/// Workers buffer the ngrams whose count is at most '--threshold' so far and add them to a
+/// shared set of candidates every so often. When the set is full, candidates whose count has
+/// gone over the threshold since are evicted. The bottom-k is picked from the candidates by
+/// their final counts at the end.
+fn collect_single_pass(
+    opt: Opt,
+    tokenizer: &Arc<dyn Tokenizer>,
+    preprocessor: Preprocessor,
+    ngram_counts: &Arc<NgramCounter<AtomicU32>>,
+    topk: &mut TopKNgrams<String, AtomicU32>,
+) -> Result<()> {
+    let seed = opt.seed.unwrap();
+    let threshold = u32::MAX - opt.threshold;
+    let candidates: Arc<Mutex<HashSet<Vec<String>>>> = Arc::new(Mutex::new(HashSet::new()));
+    let missed = Arc::new(AtomicUsize::new(0));
+
+    let mut executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting ngrams",
+        opt.quiet,
+    )?;
+    executor.set_shared_state();
+
+    for path in &opt.path {
+        let collect_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let ngram_counts = ngram_counts.clone();
+            let candidates = candidates.clone();
+            let missed = missed.clone();
+
+            move |data: DataInstance,
+                  path: &Path,
+                  line_num: usize,
+                  buffer: &mut Vec<Vec<String>>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    let tokens = tokenizer.tokens(&text)?;
+                    let tokens = tokens.iter().map(|token| token.as_ref());
+
+                    for (i, ngram) in NgramWindows::new(tokens, opt.ngram).enumerate() {
+                        let inverse_count =
+                            ngram_counts.decrement(&ngram[..], <AtomicU32 as Atomic>::Type::one());
+                        if inverse_count <= threshold {
+                            continue;
+                        }
+                        if let Some(p_keep) = opt.p_keep {
+                            // The position of the ngram's last token.
+                            let position = i + opt.ngram - 1;
+                            if uniform_hash(seed, path, line_num, position) >= p_keep as f64 {
+                                continue;
+                            }
+                        }
+                        buffer.push(ngram.iter().map(|s| s.to_string()).collect());
+                    }
+
+                    if buffer.len() >= CANDIDATE_BUFFER_SIZE {
+                        let mut candidates = candidates
+                            .lock()
+                            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                        let num_missed = add_candidates(
+                            &mut candidates,
+                            buffer.drain(..),
+                            &ngram_counts,
+                            threshold,
+                            opt.max_candidates,
+                        );
+                        missed.fetch_add(num_missed, Ordering::Relaxed);
+                    }
+                }
+
+                Ok(())
+            }
         };
-        let json_out = &json!({
-            "tokens": **ngram,
-            "string": ngram_str,
-            "count": count,
-            "rank": i + 1,
-        })
-        .to_string();
 
-        // Display output.
-        if opt.json {
-            println!("{json_out}");
-        } else if opt.out.is_none() {
-            println!(
-                "[{}/{}] {:?} (count {} {})",
-                i + 1,
-                bottom_k_final.len(),
-                style(ngram_str).cyan(),
-                if count > 1 { "≤" } else { "=" },
-                count,
-            );
-        }
+        // Add whatever is left in the buffer at the end of a file.
+        let flush_buffer = {
+            let ngram_counts = ngram_counts.clone();
+            let candidates = candidates.clone();
+            let missed = missed.clone();
+
+            move |buffer: Vec<Vec<String>>| -> Result<()> {
+                let mut candidates = candidates
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                let num_missed = add_candidates(
+                    &mut candidates,
+                    buffer,
+                    &ngram_counts,
+                    threshold,
+                    opt.max_candidates,
+                );
+                missed.fetch_add(num_missed, Ordering::Relaxed);
+                Ok(())
+            }
+        };
 
-        // Write ngram and count to file.
-        if let Some(ref mut file) = out_file {
-            writeln!(file, "{json_out}")?;
-        }
+        executor.execute_with_callback(
+            path,
+            collect_ngrams,
+            || -> Result<Vec<Vec<String>>> { Ok(Vec::new()) },
+            flush_buffer,
+        )?;
     }
 
-    if let Some(path) = out_path {
-        log::info!("Output written to {:?}", path);
+    executor.join()?;
+
+    let missed = missed.load(Ordering::Relaxed);
+    if missed > 0 {
+        log::warn!(
+            "Missed {} rare ngram(s) because the candidates were full, consider increasing --max-candidates",
+            missed.separate_with_commas()
+        );
+    }
+
+    let candidates = std::mem::take(
+        &mut *candidates
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+    );
+    for ngram in candidates {
+        let inverse_count = ngram_counts.max_count(&ngram[..]);
+        if inverse_count > threshold {
+            topk.insert(ngram, inverse_count);
+        }
     }
 
     Ok(())
 }
 
-fn get_output_file(opt: &Opt) -> Result<Option<(File, PathBuf)>> {
+/// Add ngrams that are still rare to the candidates, without going over `max_candidates`. If
+/// the candidates are full, those that aren't rare anymore are evicted first. Returns the
+/// number of rare ngrams that didn't fit.
+fn add_candidates(
+    candidates: &mut HashSet<Vec<String>>,
+    ngrams: impl IntoIterator<Item = Vec<String>>,
+    ngram_counts: &NgramCounter<AtomicU32>,
+    threshold: u32,
+    max_candidates: usize,
+) -> usize {
+    let mut evicted = false;
+    let mut missed = 0;
+    for ngram in ngrams {
+        if ngram_counts.max_count(&ngram[..]) <= threshold {
+            continue;
+        }
+        if candidates.len() >= max_candidates && !evicted {
+            candidates.retain(|candidate| ngram_counts.max_count(&candidate[..]) > threshold);
+            evicted = true;
+        }
+        if candidates.len() < max_candidates || candidates.contains(&ngram) {
+            candidates.insert(ngram);
+        } else {
+            missed += 1;
+        }
+    }
+    missed
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
     if let Some(path) = &opt.out {
         if path.is_dir() || path.extension().is_none() {
             let mut parts = vec![format!("n{}-k{}-h{}", opt.ngram, opt.k, opt.hashes)];
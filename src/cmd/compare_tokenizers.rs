@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::json;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{
+    is_byte_fallback_token, is_unknown_token, load_tokenizer, tokenize, Tokenizer,
+};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// A tokenizer to compare. This can be the name of a pretrained tokenizer from HuggingFace
+    /// or "unicode". Give this option once for every tokenizer.
+    #[structopt(short = "t", long = "tokenizer", number_of_values = 1)]
+    tokenizer: Vec<String>,
+
+    /// Limit the number of JSON lines per file to process. Since every document is tokenized
+    /// by every tokenizer, a sample is usually enough.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+/// The counts for a single tokenizer.
+#[derive(Debug, Clone, Default)]
+struct TokenizerCounts {
+    tokens: usize,
+    unknown: usize,
+    byte_fallback: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct LocalCounts {
+    documents: usize,
+    words: usize,
+    bytes: usize,
+    tokenizers: Vec<TokenizerCounts>,
+}
+
+impl LocalCounts {
+    fn new(num_tokenizers: usize) -> Self {
+        Self {
+            tokenizers: vec![TokenizerCounts::default(); num_tokenizers],
+            ..Default::default()
+        }
+    }
+
+    fn merge(&mut self, other: &LocalCounts) {
+        self.documents += other.documents;
+        self.words += other.words;
+        self.bytes += other.bytes;
+        for (counts, other_counts) in self.tokenizers.iter_mut().zip(&other.tokenizers) {
+            counts.tokens += other_counts.tokens;
+            counts.unknown += other_counts.unknown;
+            counts.byte_fallback += other_counts.byte_fallback;
+        }
+    }
+}
+
+/// The comparison of a single tokenizer.
+#[derive(Debug, Serialize)]
+struct TokenizerReport<'a> {
+    tokenizer: &'a str,
+    tokens: usize,
+    tokens_per_word: f64,
+    tokens_per_byte: f64,
+    unknown_rate: f64,
+    byte_fallback_rate: f64,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.tokenizer.is_empty() {
+        bail!("at least one -t/--tokenizer is required");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let mut tokenizers: Vec<Arc<dyn Tokenizer>> = Vec::with_capacity(opt.tokenizer.len());
+    for name in &opt.tokenizer {
+        provenance::record_tokenizer(name);
+        tokenizers.push(load_tokenizer(name)?);
+    }
+    let tokenizers = Arc::new(tokenizers);
+    let totals = Arc::new(Mutex::new(LocalCounts::new(tokenizers.len())));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Tokenizing", opt.quiet)?;
+
+    let preprocessor = Preprocessor::defaults();
+
+    for path in &opt.path {
+        let count_tokens = {
+            let tokenizers = tokenizers.clone();
+
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_counts: &mut LocalCounts|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    local_counts.documents += 1;
+                    local_counts.words += tokenize(&text).count();
+                    local_counts.bytes += text.len();
+                    for (tokenizer, counts) in
+                        tokenizers.iter().zip(local_counts.tokenizers.iter_mut())
+                    {
+                        let tokens = tokenizer.tokenize(&text)?;
+                        counts.tokens += tokens.len();
+                        for token in &tokens {
+                            if is_unknown_token(token) {
+                                counts.unknown += 1;
+                            } else if is_byte_fallback_token(token) {
+                                counts.byte_fallback += 1;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        // Counts are only added once the whole file is done so that retries don't count any
+        // document twice.
+        let sync_counts_callback = {
+            let totals = totals.clone();
+
+            move |local_counts: LocalCounts| -> Result<()> {
+                totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .merge(&local_counts);
+                Ok(())
+            }
+        };
+
+        let local_counts_factory = {
+            let num_tokenizers = tokenizers.len();
+            move || -> Result<LocalCounts> { Ok(LocalCounts::new(num_tokenizers)) }
+        };
+
+        executor.execute_with_callback(
+            path,
+            count_tokens,
+            local_counts_factory,
+            sync_counts_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let ratio = |count: usize, total: usize| count as f64 / total.max(1) as f64;
+    let reports: Vec<TokenizerReport> = opt
+        .tokenizer
+        .iter()
+        .zip(&totals.tokenizers)
+        .map(|(name, counts)| TokenizerReport {
+            tokenizer: name,
+            tokens: counts.tokens,
+            tokens_per_word: ratio(counts.tokens, totals.words),
+            tokens_per_byte: ratio(counts.tokens, totals.bytes),
+            unknown_rate: ratio(counts.unknown, counts.tokens),
+            byte_fallback_rate: ratio(counts.byte_fallback, counts.tokens),
+        })
+        .collect();
+
+    if opt.json {
+        println!(
+            "{}",
+            json!({
+                "documents": totals.documents,
+                "words": totals.words,
+                "bytes": totals.bytes,
+                "tokenizers": reports,
+            })
+        );
+    } else if !opt.quiet {
+        println!(
+            "{} documents, {} words, {} bytes",
+            totals.documents.separate_with_commas(),
+            totals.words.separate_with_commas(),
+            totals.bytes.separate_with_commas()
+        );
+        for report in &reports {
+            println!("{}:", style(report.tokenizer).cyan());
+            println!(
+                "  {}: {}",
+                style("tokens").cyan(),
+                report.tokens.separate_with_commas()
+            );
+            println!(
+                "  {}: {:.3}",
+                style("tokens per word").cyan(),
+                report.tokens_per_word
+            );
+            println!(
+                "  {}: {:.3}",
+                style("tokens per byte").cyan(),
+                report.tokens_per_byte
+            );
+            println!(
+                "  {}: {:.4}%",
+                style("unknown tokens").cyan(),
+                100.0 * report.unknown_rate
+            );
+            println!(
+                "  {}: {:.4}%",
+                style("byte fallback tokens").cyan(),
+                100.0 * report.byte_fallback_rate
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,263 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde_json::Value;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, DataExecutor};
+use crate::code::code_score;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::load_tokenizer;
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// A top-level field to group the results by, e.g. "source". By default results are
+    /// grouped by file.
+    #[structopt(long = "group-by")]
+    group_by: Option<String>,
+
+    /// The minimum code score (between 0 and 1) for a document to be counted as source code.
+    #[structopt(long = "threshold", default_value = "0.5")]
+    threshold: f64,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e.
+    /// each line will be a JSON object with the counts for one group.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars. Additionally, if an output file is specified nothing will be written to stdout.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+struct GroupCounts {
+    documents: usize,
+    code_documents: usize,
+    tokens: usize,
+    code_tokens: usize,
+}
+
+impl GroupCounts {
+    fn add(&mut self, other: &GroupCounts) {
+        self.documents += other.documents;
+        self.code_documents += other.code_documents;
+        self.tokens += other.tokens;
+        self.code_tokens += other.code_tokens;
+    }
+
+    fn code_document_fraction(&self) -> f64 {
+        if self.documents == 0 {
+            0.0
+        } else {
+            self.code_documents as f64 / self.documents as f64
+        }
+    }
+
+    fn code_token_fraction(&self) -> f64 {
+        if self.tokens == 0 {
+            0.0
+        } else {
+            self.code_tokens as f64 / self.tokens as f64
+        }
+    }
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if !(0.0..=1.0).contains(&opt.threshold) {
+        bail!("--threshold must be between 0 and 1");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let groups: Arc<Mutex<BTreeMap<String, GroupCounts>>> = Arc::new(Mutex::new(BTreeMap::new()));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Classifying", opt.quiet)?;
+
+    for path in &opt.path {
+        let classify = {
+            let tokenizer = tokenizer.clone();
+            let group_by = opt.group_by.clone();
+            let threshold = opt.threshold;
+
+            move |data: Value,
+                  path: &Path,
+                  _: usize,
+                  local_groups: &mut HashMap<String, GroupCounts>|
+                  -> Result<()> {
+                let text = match data.get("text") {
+                    Some(Value::String(text)) => text,
+                    _ => return Ok(()),
+                };
+                let text = preprocessor.apply(text);
+                let group = match &group_by {
+                    Some(field) => match data.get(field) {
+                        Some(Value::String(s)) => s.clone(),
+                        Some(other) => other.to_string(),
+                        None => "null".to_string(),
+                    },
+                    None => path.to_string_lossy().to_string(),
+                };
+
+                let num_tokens = tokenizer.count_tokens(&text)?;
+                let is_code = code_score(&text) >= threshold;
+
+                let counts = local_groups.entry(group).or_default();
+                counts.documents += 1;
+                counts.tokens += num_tokens;
+                if is_code {
+                    counts.code_documents += 1;
+                    counts.code_tokens += num_tokens;
+                }
+                Ok(())
+            }
+        };
+
+        let sync_groups_callback = {
+            let groups = groups.clone();
+            move |local_groups: HashMap<String, GroupCounts>| -> Result<()> {
+                let mut groups = groups
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                for (group, counts) in local_groups {
+                    groups.entry(group).or_default().add(&counts);
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            classify,
+            || -> Result<HashMap<String, GroupCounts>> { Ok(HashMap::new()) },
+            sync_groups_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let groups = groups
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let mut total = GroupCounts::default();
+    for counts in groups.values() {
+        total.add(counts);
+    }
+
+    for (group, counts) in groups
+        .iter()
+        .chain(std::iter::once((&"total".to_string(), &total)))
+    {
+        let json_out = serde_json::json!({
+            "group": group,
+            "documents": counts.documents,
+            "code_documents": counts.code_documents,
+            "code_document_fraction": counts.code_document_fraction(),
+            "tokens": counts.tokens,
+            "code_tokens": counts.code_tokens,
+            "code_token_fraction": counts.code_token_fraction(),
+        })
+        .to_string();
+
+        if opt.json {
+            println!("{json_out}");
+        } else if !(opt.quiet && out_file.is_some()) {
+            println!(
+                "{}: {:.2}% of documents ({} / {}), {:.2}% of tokens ({} / {}) are code",
+                style(group).cyan(),
+                100.0 * counts.code_document_fraction(),
+                counts.code_documents.separate_with_commas(),
+                counts.documents.separate_with_commas(),
+                100.0 * counts.code_token_fraction(),
+                counts.code_tokens.separate_with_commas(),
+                counts.tokens.separate_with_commas(),
+            );
+        }
+
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
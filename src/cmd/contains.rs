@@ -0,0 +1,364 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use structopt::StructOpt;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::bloom::{ngram_key, paragraph_key, BloomFilter, BloomUnit};
+use crate::index::NgramIndex;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer, UnicodeTokenizer};
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to the sketch of the reference corpus, i.e. an index directory written by
+    /// 'wimbd topk --save-index' or a Bloom filter file written by 'wimbd bloom build'.
+    /// The ngram size and tokenizer are taken from the sketch.
+    ///
+    /// With a Bloom filter over paragraphs, paragraphs are looked up as a whole and a document
+    /// counts as contained if enough of its paragraphs are found. No ngram stats are reported.
+    #[structopt(long = "sketch", parse(from_os_str))]
+    sketch: PathBuf,
+
+    /// The fraction of a document's or paragraph's ngrams that have to be found in the sketch
+    /// for the document or paragraph to count as contained.
+    #[structopt(long = "threshold", default_value = "0.8")]
+    threshold: f64,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the JSON output to.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars. Additionally, if an output file is specified nothing will be written to stdout.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+/// A sketch of the reference corpus.
+enum Sketch {
+    Index(NgramIndex),
+    Bloom(BloomFilter),
+}
+
+impl Sketch {
+    fn load(path: &Path) -> Result<Self> {
+        if path.is_dir() {
+            Ok(Sketch::Index(NgramIndex::load(path)?))
+        } else {
+            Ok(Sketch::Bloom(BloomFilter::read(path)?))
+        }
+    }
+
+    fn tokenizer(&self) -> &str {
+        match self {
+            Sketch::Index(index) => &index.metadata.tokenizer,
+            Sketch::Bloom(filter) => &filter.tokenizer,
+        }
+    }
+
+    /// The ngram size, or `None` if the sketch is over paragraphs.
+    fn ngram(&self) -> Option<usize> {
+        match self {
+            Sketch::Index(index) => Some(index.metadata.ngram),
+            Sketch::Bloom(filter) => match filter.unit {
+                BloomUnit::Ngram => Some(filter.ngram),
+                BloomUnit::Paragraph => None,
+            },
+        }
+    }
+
+    fn contains_ngram(&self, ngram: &[String]) -> bool {
+        match self {
+            Sketch::Index(index) => index.counter.count(ngram) > 0,
+            Sketch::Bloom(filter) => filter.contains(&ngram_key(ngram)),
+        }
+    }
+
+    fn contains_paragraph(&self, paragraph: &str) -> bool {
+        match self {
+            Sketch::Index(_) => false,
+            Sketch::Bloom(filter) => filter.contains(paragraph_key(paragraph)),
+        }
+    }
+}
+
+/// How many units (ngrams, documents, or paragraphs) were seen and how many were found.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+struct Containment {
+    total: usize,
+    found: usize,
+    fraction: f64,
+}
+
+impl Containment {
+    fn add(&mut self, found: bool) {
+        self.total += 1;
+        if found {
+            self.found += 1;
+        }
+    }
+
+    fn merge(&mut self, other: &Containment) {
+        self.total += other.total;
+        self.found += other.found;
+    }
+
+    fn finish(&mut self) {
+        if self.total > 0 {
+            self.fraction = self.found as f64 / self.total as f64;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+struct ContainmentReport {
+    ngrams: Containment,
+    documents: Containment,
+    paragraphs: Containment,
+    /// Documents and paragraphs with fewer tokens than the ngram size, which are left out.
+    too_short: usize,
+}
+
+impl ContainmentReport {
+    fn merge(&mut self, other: &ContainmentReport) {
+        self.ngrams.merge(&other.ngrams);
+        self.documents.merge(&other.documents);
+        self.paragraphs.merge(&other.paragraphs);
+        self.too_short += other.too_short;
+    }
+
+    /// Record a document or paragraph. Returns the number of its ngrams that were found and
+    /// the total number of ngrams, or `None` if it's too short.
+    fn check(
+        &mut self,
+        text: &str,
+        n: usize,
+        sketch: &Sketch,
+        tokenizer: &Arc<dyn Tokenizer>,
+    ) -> Result<Option<(usize, usize)>> {
+        let tokens = tokenizer.tokenize(text)?;
+        if tokens.len() < n {
+            self.too_short += 1;
+            return Ok(None);
+        }
+        let mut found = 0;
+        let mut total = 0;
+        for ngram in tokens.windows(n) {
+            total += 1;
+            if sketch.contains_ngram(ngram) {
+                found += 1;
+            }
+        }
+        Ok(Some((found, total)))
+    }
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if !(0.0..=1.0).contains(&opt.threshold) {
+        bail!("--threshold must be between 0 and 1");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    log::info!("Loading sketch from {:?}...", opt.sketch);
+    let sketch = Arc::new(Sketch::load(&opt.sketch)?);
+    provenance::record_tokenizer(sketch.tokenizer());
+    // Paragraphs aren't tokenized.
+    let tokenizer: Arc<dyn Tokenizer> = if sketch.ngram().is_none() {
+        Arc::new(UnicodeTokenizer)
+    } else {
+        load_tokenizer(sketch.tokenizer())?
+    };
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let report = Arc::new(Mutex::new(ContainmentReport::default()));
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Checking", opt.quiet)?;
+
+    for path in &opt.path {
+        let check_document = {
+            let sketch = sketch.clone();
+            let tokenizer = tokenizer.clone();
+
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_report: &mut ContainmentReport|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    let paragraphs = text.split('\n').filter(|p| !p.trim().is_empty());
+
+                    if let Some(n) = sketch.ngram() {
+                        // Documents and ngrams.
+                        if let Some((found, total)) =
+                            local_report.check(&text, n, &sketch, &tokenizer)?
+                        {
+                            local_report.ngrams.total += total;
+                            local_report.ngrams.found += found;
+                            local_report
+                                .documents
+                                .add(found as f64 >= opt.threshold * total as f64);
+                        }
+
+                        // Paragraphs.
+                        for paragraph in paragraphs {
+                            if let Some((found, total)) =
+                                local_report.check(paragraph, n, &sketch, &tokenizer)?
+                            {
+                                local_report
+                                    .paragraphs
+                                    .add(found as f64 >= opt.threshold * total as f64);
+                            }
+                        }
+                    } else {
+                        // Paragraphs are looked up as a whole.
+                        let mut found = 0;
+                        let mut total = 0;
+                        for paragraph in paragraphs {
+                            let contained = sketch.contains_paragraph(paragraph);
+                            local_report.paragraphs.add(contained);
+                            total += 1;
+                            if contained {
+                                found += 1;
+                            }
+                        }
+                        if total > 0 {
+                            local_report
+                                .documents
+                                .add(found as f64 >= opt.threshold * total as f64);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let sync_report_callback = {
+            let report = report.clone();
+            move |local_report: ContainmentReport| -> Result<()> {
+                report
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .merge(&local_report);
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            check_document,
+            || -> Result<ContainmentReport> { Ok(ContainmentReport::default()) },
+            sync_report_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let mut report = report
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?
+        .clone();
+    report.ngrams.finish();
+    report.documents.finish();
+    report.paragraphs.finish();
+
+    let json_out = serde_json::to_string(&report)?;
+    if opt.json {
+        println!("{json_out}");
+    } else if !opt.quiet {
+        for (name, containment) in [
+            ("ngrams", &report.ngrams),
+            ("documents", &report.documents),
+            ("paragraphs", &report.paragraphs),
+        ] {
+            println!(
+                "{}: {}/{} found ({:.2}%)",
+                style(name).cyan(),
+                containment.found,
+                containment.total,
+                100.0 * containment.fraction
+            );
+        }
+        println!(
+            "{}: {}",
+            style("too short to check").cyan(),
+            report.too_short
+        );
+    }
+
+    if let Some(ref mut file) = out_file {
+        writeln!(file, "{json_out}")?;
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
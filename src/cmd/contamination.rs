@@ -0,0 +1,407 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use thousands::Separable;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::io::LineReader;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util::{self, OutputFile};
+
+/// The benchmark fields that are checked if no '--field' is given.
+const DEFAULT_FIELDS: &[&str] = &["prompt", "target"];
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file of the corpus, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to the benchmark, a JSON lines file (optionally compressed) with one instance
+    /// per line.
+    #[structopt(long = "benchmark", parse(from_os_str))]
+    benchmark: PathBuf,
+
+    /// A field of the benchmark instances to check, like "question" or "answer". Give this
+    /// option once for every field. The fields of an instance are checked separately, so
+    /// ngrams don't span fields. Defaults to "prompt" and "target".
+    #[structopt(long = "field", number_of_values = 1)]
+    field: Vec<String>,
+
+    /// The field that identifies a benchmark instance in the output. Instances without it are
+    /// identified by their line number.
+    #[structopt(long = "id-field", default_value = "id")]
+    id_field: String,
+
+    /// Ngram size.
+    #[structopt(short = "n", long = "ngram", default_value = "13")]
+    ngram: usize,
+
+    /// The fraction of an instance's ngrams that have to be found in the corpus for the
+    /// instance to count as contaminated. With 0 any overlap counts.
+    #[structopt(long = "threshold", default_value = "0.8")]
+    threshold: f64,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the results for every benchmark instance to, as JSON lines with the
+    /// keys "line", "id", "ngrams", "found", "fraction", and "contaminated".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars. Additionally, if an output file is specified nothing will be written to stdout.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the summary as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing the corpus.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing the corpus.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+/// A benchmark instance and the ids of its distinct ngrams.
+struct Instance {
+    line: usize,
+    id: Value,
+    ngrams: Vec<usize>,
+}
+
+/// The ngrams of all benchmark instances.
+struct Benchmark {
+    instances: Vec<Instance>,
+    /// The id of every distinct ngram by its hash.
+    ngram_ids: HashMap<u64, usize>,
+    /// Instances with fewer tokens than the ngram size in all their fields, which are left out.
+    too_short: usize,
+}
+
+impl Benchmark {
+    fn read(opt: &Opt, fields: &[String], tokenizer: &Arc<dyn Tokenizer>) -> Result<Self> {
+        let mut benchmark = Self {
+            instances: Vec::new(),
+            ngram_ids: HashMap::new(),
+            too_short: 0,
+        };
+        let mut key = Vec::new();
+        for (i, line) in read_lines(&opt.benchmark)?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Value = serde_json::from_str(&line)
+                .with_context(|| format!("failed to parse line {} of the benchmark", i + 1))?;
+
+            let mut ngrams = Vec::new();
+            let mut has_field = false;
+            for field in fields {
+                let text = match lookup(&record, field) {
+                    Some(Value::String(text)) => text,
+                    _ => continue,
+                };
+                has_field = true;
+                let tokens = tokenizer.tokenize(text)?;
+                for ngram in tokens.windows(opt.ngram) {
+                    let next_id = benchmark.ngram_ids.len();
+                    let id = *benchmark
+                        .ngram_ids
+                        .entry(ngram_hash(ngram, &mut key))
+                        .or_insert(next_id);
+                    ngrams.push(id);
+                }
+            }
+            if !has_field {
+                bail!(
+                    "line {} of the benchmark has none of the fields {:?}",
+                    i + 1,
+                    fields
+                );
+            }
+            if ngrams.is_empty() {
+                benchmark.too_short += 1;
+                continue;
+            }
+            ngrams.sort_unstable();
+            ngrams.dedup();
+            benchmark.instances.push(Instance {
+                line: i + 1,
+                id: lookup(&record, &opt.id_field)
+                    .cloned()
+                    .unwrap_or(Value::Null),
+                ngrams,
+            });
+        }
+        Ok(benchmark)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct CorpusCounts {
+    documents: usize,
+    overlapping: usize,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if !opt.benchmark.is_file() {
+        bail!("Benchmark file {:?} does not exist", opt.benchmark);
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    if !(0.0..=1.0).contains(&opt.threshold) {
+        bail!("--threshold must be between 0 and 1");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let fields: Vec<String> = if opt.field.is_empty() {
+        DEFAULT_FIELDS
+            .iter()
+            .map(|field| field.to_string())
+            .collect()
+    } else {
+        opt.field.clone()
+    };
+    log::info!("Reading benchmark {:?}...", opt.benchmark);
+    let benchmark = Benchmark::read(&opt, &fields, &tokenizer)?;
+    if benchmark.too_short > 0 {
+        log::warn!(
+            "{} benchmark instances have fewer than {} tokens and are left out",
+            benchmark.too_short.separate_with_commas(),
+            opt.ngram
+        );
+    }
+    log::info!(
+        "Checking {} benchmark instances with {} distinct {}-grams",
+        benchmark.instances.len().separate_with_commas(),
+        benchmark.ngram_ids.len().separate_with_commas(),
+        opt.ngram
+    );
+
+    // The number of times every benchmark ngram occurs in the corpus.
+    let ngram_counts: Arc<Vec<AtomicU64>> = Arc::new(
+        (0..benchmark.ngram_ids.len())
+            .map(|_| AtomicU64::new(0))
+            .collect(),
+    );
+    let ngram_ids = Arc::new(benchmark.ngram_ids);
+    let totals = Arc::new(Mutex::new(CorpusCounts::default()));
+
+    let mut executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Checking", opt.quiet)?;
+    executor.enable_threads_per_file();
+    executor.set_shared_state();
+
+    for path in &opt.path {
+        let check_document = {
+            let tokenizer = tokenizer.clone();
+            let ngram_ids = ngram_ids.clone();
+            let ngram_counts = ngram_counts.clone();
+            let n = opt.ngram;
+            let mut key = Vec::new();
+
+            move |data: DataInstance, _: &Path, _: usize, local: &mut CorpusCounts| -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    let tokens = tokenizer.tokenize(&text)?;
+                    let mut overlapping = false;
+                    for ngram in tokens.windows(n) {
+                        if let Some(&id) = ngram_ids.get(&ngram_hash(ngram, &mut key)) {
+                            ngram_counts[id].fetch_add(1, Ordering::Relaxed);
+                            overlapping = true;
+                        }
+                    }
+                    local.documents += 1;
+                    if overlapping {
+                        local.overlapping += 1;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let sync_counts_callback = {
+            let totals = totals.clone();
+            move |local: CorpusCounts| -> Result<()> {
+                let mut totals = totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                totals.documents += local.documents;
+                totals.overlapping += local.overlapping;
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            check_document,
+            || -> Result<CorpusCounts> { Ok(CorpusCounts::default()) },
+            sync_counts_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let mut contaminated = Vec::new();
+    for instance in &benchmark.instances {
+        let found = instance
+            .ngrams
+            .iter()
+            .filter(|&&id| ngram_counts[id].load(Ordering::Relaxed) > 0)
+            .count();
+        let fraction = found as f64 / instance.ngrams.len() as f64;
+        let is_contaminated = found > 0 && fraction >= opt.threshold;
+        if is_contaminated {
+            contaminated.push((instance, fraction));
+        }
+        if let Some(ref mut file) = out_file {
+            writeln!(
+                file,
+                "{}",
+                json!({
+                    "line": instance.line,
+                    "id": instance.id,
+                    "ngrams": instance.ngrams.len(),
+                    "found": found,
+                    "fraction": fraction,
+                    "contaminated": is_contaminated,
+                })
+            )?;
+        }
+    }
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let checked = benchmark.instances.len();
+    if opt.json {
+        println!(
+            "{}",
+            json!({
+                "instances": checked + benchmark.too_short,
+                "checked": checked,
+                "too_short": benchmark.too_short,
+                "contaminated": contaminated.len(),
+                "fraction_contaminated": contaminated.len() as f64 / checked.max(1) as f64,
+                "documents": totals.documents,
+                "overlapping_documents": totals.overlapping,
+            })
+        );
+    } else if !(opt.quiet && out_file.is_some()) {
+        println!(
+            "{}: {}/{} ({:.2}%)",
+            style("contaminated instances").cyan(),
+            contaminated.len().separate_with_commas(),
+            checked.separate_with_commas(),
+            100.0 * contaminated.len() as f64 / checked.max(1) as f64
+        );
+        println!(
+            "{}: {}/{}",
+            style("documents with overlap").cyan(),
+            totals.overlapping.separate_with_commas(),
+            totals.documents.separate_with_commas()
+        );
+        if out_file.is_none() {
+            for (instance, fraction) in &contaminated {
+                let id = match &instance.id {
+                    Value::Null => format!("line {}", instance.line),
+                    id => id.to_string(),
+                };
+                println!("  - {} ({:.2}% of ngrams found)", id, 100.0 * fraction);
+            }
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Hash an ngram, using `key` as a buffer.
+fn ngram_hash(ngram: &[String], key: &mut Vec<u8>) -> u64 {
+    key.clear();
+    for token in ngram {
+        key.extend_from_slice(token.as_bytes());
+        key.push(0);
+    }
+    xxh3_64(key)
+}
+
+/// Look up a field of a record, where nested fields are separated by dots.
+fn lookup<'a>(record: &'a Value, field: &str) -> Option<&'a Value> {
+    field
+        .split('.')
+        .try_fold(record, |value, key| value.get(key))
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
+
+fn read_lines(path: &Path) -> Result<impl Iterator<Item = Result<String>>> {
+    Ok(LineReader::open(path)?.map(|line| -> Result<String> { Ok(line?) }))
+}
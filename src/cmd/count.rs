@@ -1,23 +1,30 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Write;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
-use ahash::RandomState;
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use console::style;
+use memchr::memmem::Finder;
 use serde_json::json;
 use structopt::StructOpt;
 
-use super::util::{DataExecutor, DataInstance};
-use crate::tokens::{tokenize, PretrainedTokenizer};
-use crate::util;
+use super::util::{expand_paths, DataExecutor, DataInstance, LengthBand};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::table::{ColumnType, OutFormat, TableWriter};
+use crate::tokens::{load_tokenizer, word_boundaries};
+
+/// The columns of CSV and Parquet output files.
+const COLUMNS: &[(&str, ColumnType)] = &[
+    ("tokens", ColumnType::StringList),
+    ("string", ColumnType::String),
+    ("count", ColumnType::Int),
+];
 
 #[derive(Debug, StructOpt, Clone)]
 pub(crate) struct Opt {
-    /// Path to a gzip-compressed JSON lines file.
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
     #[structopt(parse(from_os_str))]
     path: Vec<PathBuf>,
 
@@ -25,6 +32,13 @@ pub(crate) struct Opt {
     #[structopt(short = "s", long = "search", number_of_values = 1)]
     search: Vec<String>,
 
+    /// A sequence of token IDs to search for, separated by commas, e.g. "464,2159,318". This
+    /// requires a pretrained '-t/--tokenizer', and is meant for tracing runs of IDs from a
+    /// training pipeline back to documents. Documents are tokenized on their own, so IDs that
+    /// only come up at document boundaries in packed training sequences won't match.
+    #[structopt(long = "token-ids", number_of_values = 1)]
+    token_ids: Vec<String>,
+
     /// Limit the number of JSON lines per file to process.
     #[structopt(short = "l", long = "limit")]
     limit: Option<usize>,
@@ -37,13 +51,21 @@ pub(crate) struct Opt {
     #[structopt(short = "j", long = "workers")]
     workers: Option<usize>,
 
-    /// A path to write the output to. Output will be written as JSON lines, i.e.
-    /// each line will be a JSON object with the keys "search" and "count".
+    /// A path to write the output to. Output will be written as JSON lines by default, i.e.
+    /// each line will be a JSON object with the keys "tokens", "string", and "count".
+    /// See '--out-format' for other formats.
     ///
     /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
     #[structopt(short = "o", long = "out")]
     out: Option<PathBuf>,
 
+    /// The format of the '-o/--out' file: "jsonl", "csv", "parquet", or "sqlite". CSV and
+    /// Parquet files have the columns "tokens", "string", and "count". In CSV files the tokens
+    /// are written as a JSON array. SQLite databases get the rows appended to the "counts"
+    /// table, so they're never overwritten.
+    #[structopt(long = "out-format", default_value = "jsonl", possible_values = &["jsonl", "csv", "parquet", "sqlite"])]
+    out_format: OutFormat,
+
     /// Don't show progress bars. Additionally, if an output file is specified nothing will be written to stdout.
     /// This doesn't affect logging.
     #[structopt(short = "q", long = "quiet")]
@@ -61,12 +83,65 @@ pub(crate) struct Opt {
     /// from HuggingFace.
     #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
     tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+
+    /// Only include documents with at least this many tokens.
+    #[structopt(long = "min-doc-tokens")]
+    min_doc_tokens: Option<usize>,
+
+    /// Only include documents with at most this many tokens.
+    #[structopt(long = "max-doc-tokens")]
+    max_doc_tokens: Option<usize>,
+
+    /// Only count non-overlapping occurrences, like 'grep -o' does. For example, "ha ha" occurs
+    /// twice in "ha ha ha" by default but only once with this option. Occurrences are matched
+    /// from left to right.
+    #[structopt(long = "non-overlapping")]
+    non_overlapping: bool,
+
+    /// Count exact occurrences of each '-s/--search' string in the raw bytes of the text
+    /// instead of in the tokens, so that counts match 'grep -o "..." | wc -l' on the same
+    /// text. Case and whitespace have to match exactly and occurrences can start and end
+    /// anywhere, even inside of words. '--non-overlapping' still applies, but tokenizer options
+    /// don't.
+    #[structopt(long = "raw")]
+    raw: bool,
+
+    /// Only count occurrences that start and end on Unicode word boundaries in the original
+    /// text. Pretrained subword tokenizers can otherwise match fragments of longer words, e.g.
+    /// "cat" in "concatenate". The number of rejected matches is logged at the end. This has
+    /// no effect with the unicode tokenizer, whose tokens are always whole words.
+    #[structopt(long = "whole-words")]
+    whole_words: bool,
+
+    /// Print the counts of every search for every file as soon as the file is done, as JSON
+    /// lines with the keys "path", "tokens", "string", and "count". This gives usable partial
+    /// results during long runs. The totals are still printed and written to '-o/--out' at
+    /// the end.
+    #[structopt(long = "stream-results")]
+    stream_results: bool,
+
+    /// Count the number of documents that contain each search instead of the number of times
+    /// it occurs, so that every document counts at most once. This also applies with '--raw'.
+    #[structopt(long = "doc-freq")]
+    doc_freq: bool,
 }
 
+/// The tokens and the display string of every search, in the order they were given.
+type Searches = Arc<Vec<(Vec<String>, String)>>;
+
 pub(crate) fn main(mut opt: Opt) -> Result<()> {
-    if opt.search.is_empty() {
-        bail!("At least one -s/--search term is required");
+    if opt.search.is_empty() && opt.token_ids.is_empty() {
+        bail!("At least one -s/--search term or --token-ids sequence is required");
     }
+    opt.path = expand_paths(&opt.path)?;
     if let Some(file_limit) = opt.file_limit {
         if file_limit == 0 {
             bail!("File limit cannot be 0");
@@ -76,79 +151,275 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
     if opt.path.is_empty() {
         bail!("at least one path is required");
     }
+    if opt.raw {
+        if !opt.token_ids.is_empty() {
+            bail!("--token-ids can't be used with --raw");
+        }
+        if opt.whole_words || opt.min_doc_tokens.is_some() || opt.max_doc_tokens.is_some() {
+            bail!("--whole-words, --min-doc-tokens, and --max-doc-tokens can't be used with --raw");
+        }
+        return count_raw(opt);
+    }
 
-    let tokenizer: Option<PretrainedTokenizer> = if &opt.tokenizer == "unicode" {
-        None
-    } else {
-        Some(PretrainedTokenizer::new(&opt.tokenizer)?)
-    };
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+    let length_band = LengthBand::new(opt.min_doc_tokens, opt.max_doc_tokens)?;
+    // Unicode tokens always start and end on word boundaries.
+    let whole_words = opt.whole_words && opt.tokenizer != "unicode";
 
-    let mut counts: HashMap<Vec<String>, Arc<AtomicUsize>, RandomState> =
-        HashMap::with_capacity_and_hasher(opt.search.len(), RandomState::new());
-    let mut min_search_length = usize::MAX;
+    let mut searches: Vec<Vec<String>> = Vec::with_capacity(opt.search.len());
     for search in &opt.search {
-        let search_tokens: Vec<String> = if let Some(ref tokenizer) = tokenizer {
-            tokenizer.tokenize(search)?
-        } else {
-            tokenize(search).map(|t| t.into()).collect()
-        };
-        min_search_length = std::cmp::min(min_search_length, search_tokens.len());
-        counts.insert(search_tokens, Arc::new(AtomicUsize::new(0)));
+        let search_tokens = tokenizer.tokenize(search)?;
+        if !searches.contains(&search_tokens) {
+            searches.push(search_tokens);
+        }
+    }
+    for token_ids in &opt.token_ids {
+        if opt.tokenizer == "unicode" {
+            bail!("--token-ids requires a pretrained -t/--tokenizer");
+        }
+        let search_tokens = tokenizer.ids_to_tokens(&parse_token_ids(token_ids)?)?;
+        if !searches.contains(&search_tokens) {
+            searches.push(search_tokens);
+        }
+    }
+    let min_search_length = searches.iter().map(Vec::len).min().unwrap_or(0);
+    let mut labeled_searches = Vec::with_capacity(searches.len());
+    for search in searches {
+        let search_str = tokenizer.decode(&search)?;
+        labeled_searches.push((search, search_str));
     }
+    let searches: Searches = Arc::new(labeled_searches);
+    let totals = Arc::new(Mutex::new(vec![0usize; searches.len()]));
 
-    let (mut out_file, out_path) = match get_output_file(&opt)? {
+    let (out_file, out_path) = match get_output_file(&opt)? {
         Some(out) => (Some(out.0), Some(out.1)),
         None => (None, None),
     };
 
-    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Searching", opt.quiet)?;
+    let mut executor =
+        DataExecutor::new(&opt.path, opt.workers, opt.limit, "Searching", opt.quiet)?;
+    // Streamed results are per file.
+    if !opt.stream_results {
+        executor.enable_threads_per_file();
+    }
+    executor.set_shared_state();
+    let rejected = Arc::new(AtomicUsize::new(0));
 
     for path in &opt.path {
-        let counts = counts.clone();
+        let sync_counts = sync_counts_callback(path, &searches, &totals, opt.stream_results);
+        let num_searches = searches.len();
+        let local_counts_factory = move || -> Result<Vec<usize>> { Ok(vec![0; num_searches]) };
 
-        if let Some(ref tokenizer) = tokenizer {
-            let tokenizer = (*tokenizer).clone();
+        let tokenizer = tokenizer.clone();
+        let rejected = rejected.clone();
+        let searches = searches.clone();
 
-            executor.execute(
-                path,
-                move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
-                    if let Some(text) = data.text {
-                        let tokens = tokenizer.tokenize(&text)?;
-                        count_occurences(min_search_length, tokens, &counts);
-                    };
-                    Ok(())
-                },
-            )?;
-        } else {
-            executor.execute(
-                path,
-                move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
-                    if let Some(text) = data.text {
-                        let tokens: Vec<&str> = tokenize(&text).collect();
-                        count_occurences(min_search_length, tokens, &counts);
-                    };
-                    Ok(())
-                },
-            )?;
+        executor.execute_with_callback(
+            path,
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_counts: &mut Vec<usize>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    if !whole_words {
+                        let tokens = tokenizer.tokens(&text)?;
+                        if !length_band.contains(tokens.len()) {
+                            return Ok(());
+                        }
+                        count_occurences(
+                            min_search_length,
+                            tokens,
+                            &searches,
+                            local_counts,
+                            opt.non_overlapping,
+                            opt.doc_freq,
+                            |_, _| true,
+                        );
+                        return Ok(());
+                    }
+
+                    let (tokens, spans): (Vec<String>, Vec<(usize, usize)>) =
+                        tokenizer.tokenize_with_offsets(&text)?.into_iter().unzip();
+                    if !length_band.contains(tokens.len()) {
+                        return Ok(());
+                    }
+                    // Only computed for documents with candidate matches.
+                    let mut boundaries: Option<Vec<usize>> = None;
+                    let n_rejected = count_occurences(
+                        min_search_length,
+                        tokens,
+                        &searches,
+                        local_counts,
+                        opt.non_overlapping,
+                        opt.doc_freq,
+                        |start, end| {
+                            let boundaries =
+                                boundaries.get_or_insert_with(|| word_boundaries(&text));
+                            boundaries.binary_search(&spans[start].0).is_ok()
+                                && boundaries.binary_search(&spans[end - 1].1).is_ok()
+                        },
+                    );
+                    rejected.fetch_add(n_rejected, Ordering::Relaxed);
+                };
+                Ok(())
+            },
+            local_counts_factory,
+            sync_counts,
+        )?;
+    }
+
+    executor.join()?;
+
+    if whole_words {
+        log::info!(
+            "Rejected {} match(es) that didn't start and end on word boundaries",
+            rejected.load(Ordering::Relaxed)
+        );
+    }
+
+    let results = collect_results(&searches, &totals)?;
+    write_results(&opt, results, out_file, out_path)
+}
+
+/// Like [`main()`] but for '--raw', where searches are matched against the bytes of the text.
+fn count_raw(opt: Opt) -> Result<()> {
+    let mut finders: Vec<Finder<'static>> = Vec::with_capacity(opt.search.len());
+    for search in &opt.search {
+        if search.is_empty() {
+            bail!("-s/--search can't be empty with --raw");
         }
+        finders.push(Finder::new(search.as_bytes()).into_owned());
+    }
+    let finders = Arc::new(finders);
+    let searches: Searches = Arc::new(
+        opt.search
+            .iter()
+            .map(|search| (vec![search.clone()], search.clone()))
+            .collect(),
+    );
+    let totals = Arc::new(Mutex::new(vec![0usize; searches.len()]));
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let mut executor =
+        DataExecutor::new(&opt.path, opt.workers, opt.limit, "Searching", opt.quiet)?;
+    // Streamed results are per file.
+    if !opt.stream_results {
+        executor.enable_threads_per_file();
+    }
+
+    for path in &opt.path {
+        let finders = finders.clone();
+        let num_searches = searches.len();
+
+        executor.execute_with_callback(
+            path,
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_counts: &mut Vec<usize>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    for (finder, count) in finders.iter().zip(local_counts.iter_mut()) {
+                        *count += if opt.doc_freq {
+                            finder.find(text.as_bytes()).is_some() as usize
+                        } else {
+                            count_substrings(text.as_bytes(), finder, opt.non_overlapping)
+                        };
+                    }
+                }
+                Ok(())
+            },
+            move || -> Result<Vec<usize>> { Ok(vec![0; num_searches]) },
+            sync_counts_callback(path, &searches, &totals, opt.stream_results),
+        )?;
     }
 
     executor.join()?;
 
-    for (i, (search, count)) in counts.iter().enumerate() {
-        let count = count.load(Ordering::Relaxed);
+    let results = collect_results(&searches, &totals)?;
+    write_results(&opt, results, out_file, out_path)
+}
 
-        let search_str = if let Some(ref tokenizer) = tokenizer {
-            tokenizer.decode(search)?
-        } else {
-            search.join(" ")
-        };
-        let json_out = &json!({
+/// The callback that adds the counts of a file to the totals once the file is done, and
+/// prints them right away with '--stream-results'. Counts are only added once the whole file
+/// is done so that retries don't count any document twice.
+fn sync_counts_callback(
+    path: &Path,
+    searches: &Searches,
+    totals: &Arc<Mutex<Vec<usize>>>,
+    stream_results: bool,
+) -> impl FnMut(Vec<usize>) -> Result<()> + Send + Clone + 'static {
+    let path = path.to_path_buf();
+    let searches = searches.clone();
+    let totals = totals.clone();
+
+    move |local_counts: Vec<usize>| -> Result<()> {
+        {
+            let mut totals = totals
+                .lock()
+                .map_err(|_| anyhow!("Failed to acquire lock"))?;
+            for (total, count) in totals.iter_mut().zip(&local_counts) {
+                *total += count;
+            }
+        }
+        if stream_results {
+            // Hold the lock on stdout so that the results of a file stay together.
+            let mut stdout = io::stdout().lock();
+            for ((search, search_str), count) in searches.iter().zip(&local_counts) {
+                let row = json!({
+                    "path": path,
+                    "tokens": search,
+                    "string": search_str,
+                    "count": count,
+                });
+                writeln!(stdout, "{row}")?;
+            }
+            stdout.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The tokens, string, and total count of every search.
+fn collect_results(
+    searches: &Searches,
+    totals: &Mutex<Vec<usize>>,
+) -> Result<Vec<(Vec<String>, String, usize)>> {
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    Ok(searches
+        .iter()
+        .zip(totals.iter())
+        .map(|((search, search_str), count)| (search.clone(), search_str.clone(), *count))
+        .collect())
+}
+
+/// Print the tokens, string, and count of every search and write them to the output file.
+fn write_results(
+    opt: &Opt,
+    results: Vec<(Vec<String>, String, usize)>,
+    mut out_file: Option<TableWriter>,
+    out_path: Option<PathBuf>,
+) -> Result<()> {
+    let num_results = results.len();
+    for (i, (search, search_str, count)) in results.into_iter().enumerate() {
+        let row = json!({
             "tokens": search,
             "string": search_str,
             "count": count,
-        })
-        .to_string();
+        });
+        let json_out = &row.to_string();
 
         if opt.json {
             println!("{json_out}");
@@ -156,17 +427,21 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
             println!(
                 "[{}/{}] {:?} (count = {})",
                 i + 1,
-                counts.len(),
+                num_results,
                 style(search_str).cyan(),
                 count
             );
         }
 
-        if let Some(ref mut file) = out_file {
-            writeln!(file, "{json_out}")?;
+        if let Some(ref mut writer) = out_file {
+            writer.write_row(&row)?;
         }
     }
 
+    if let Some(writer) = out_file {
+        writer.finish()?;
+    }
+
     if let Some(path) = out_path {
         log::info!("Output written to {:?}", path);
     }
@@ -174,33 +449,92 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
     Ok(())
 }
 
-fn get_output_file(opt: &Opt) -> Result<Option<(File, PathBuf)>> {
+/// Count the occurrences of the needle of `finder` in `haystack`.
+fn count_substrings(haystack: &[u8], finder: &Finder, non_overlapping: bool) -> usize {
+    if non_overlapping {
+        return finder.find_iter(haystack).count();
+    }
+    let mut count = 0;
+    let mut start = 0;
+    while let Some(i) = finder.find(&haystack[start..]) {
+        count += 1;
+        start += i + 1;
+    }
+    count
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(TableWriter, PathBuf)>> {
     if let Some(path) = &opt.out {
         if path.is_dir() {
             bail!("-o/--out must be a valid file name, not a directory");
         } else {
-            Ok(Some(util::get_output_file(path, opt.force)?))
+            Ok(Some(TableWriter::create(
+                path,
+                opt.force,
+                opt.out_format,
+                "counts",
+                COLUMNS,
+            )?))
         }
     } else {
         Ok(None)
     }
 }
 
-fn count_occurences<T>(
+/// Parse a comma-separated sequence of token IDs.
+fn parse_token_ids(s: &str) -> Result<Vec<u32>> {
+    s.split(',')
+        .map(|id| {
+            id.trim()
+                .parse::<u32>()
+                .map_err(|_| anyhow!("invalid token ID {:?} in --token-ids {:?}", id, s))
+        })
+        .collect()
+}
+
+/// Count the occurrences of each search in `tokens`, adding them to `counts`. Matches are only
+/// counted if `is_whole(start, end)` is true for the range of tokens they span. With
+/// `doc_freq`, only the first match of each search counts. Returns the number of matches that
+/// were rejected.
+fn count_occurences<T, W>(
     min_search_length: usize,
     tokens: Vec<T>,
-    counts: &HashMap<Vec<String>, Arc<AtomicUsize>, RandomState>,
-) where
+    searches: &[(Vec<String>, String)],
+    counts: &mut [usize],
+    non_overlapping: bool,
+    doc_freq: bool,
+    mut is_whole: W,
+) -> usize
+where
     T: std::cmp::PartialEq<String>,
+    W: FnMut(usize, usize) -> bool,
 {
+    let mut rejected = 0;
+    // The end of the last occurrence of each search, for skipping overlapping occurrences. This
+    // is only 0 if there hasn't been an occurrence yet, since searches aren't empty.
+    let mut last_ends = vec![0; searches.len()];
     for index in min_search_length..(tokens.len() + 1) {
-        for (search, count) in counts.iter() {
+        for (((search, _), count), last_end) in searches
+            .iter()
+            .zip(counts.iter_mut())
+            .zip(last_ends.iter_mut())
+        {
             if search.len() <= index {
-                let slice = &tokens[(index - search.len())..index];
+                let start = index - search.len();
+                if (non_overlapping && start < *last_end) || (doc_freq && *last_end > 0) {
+                    continue;
+                }
+                let slice = &tokens[start..index];
                 if slice == &search[..] {
-                    count.fetch_add(1, Ordering::Relaxed);
+                    if !is_whole(start, index) {
+                        rejected += 1;
+                        continue;
+                    }
+                    *count += 1;
+                    *last_end = index;
                 }
             }
         }
     }
+    rejected
 }
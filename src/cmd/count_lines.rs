@@ -0,0 +1,229 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use humantime::format_duration;
+use serde::Serialize;
+use structopt::StructOpt;
+use thousands::Separable;
+use threadpool::ThreadPool;
+
+use super::util::expand_paths;
+use crate::io::open_decompressed;
+use crate::progress::get_file_progress_bar;
+use crate::provenance;
+use crate::util::{self, OutputFile};
+
+/// The size of the chunks that decompressed data is scanned in.
+const CHUNK_SIZE: usize = 1 << 20;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the counts for every file to. Output will be written as JSON lines,
+    /// i.e. each line will be a JSON object with the keys "path", "lines", "documents",
+    /// "compressed_bytes", and "uncompressed_bytes", in the same order as the input files.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars. Additionally, if an output file is specified nothing will be written to stdout.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the totals as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// The counts for a single file, or the totals over all files.
+#[derive(Debug, Clone, Default, Serialize)]
+struct LineCounts {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    lines: usize,
+    /// Lines that aren't empty or only whitespace.
+    documents: usize,
+    compressed_bytes: u64,
+    uncompressed_bytes: u64,
+}
+
+impl LineCounts {
+    fn add(&mut self, other: &LineCounts) {
+        self.lines += other.lines;
+        self.documents += other.documents;
+        self.compressed_bytes += other.compressed_bytes;
+        self.uncompressed_bytes += other.uncompressed_bytes;
+    }
+
+    fn display(&self) {
+        for (name, value) in [
+            ("total lines", self.lines as u64),
+            ("total documents", self.documents as u64),
+            ("total compressed bytes", self.compressed_bytes),
+            ("total uncompressed bytes", self.uncompressed_bytes),
+        ] {
+            println!("{}: {}", style(name).cyan(), value.separate_with_commas());
+        }
+    }
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+    for path in &opt.path {
+        if !path.is_file() {
+            bail!("File {:?} does not exist", path);
+        }
+    }
+    provenance::record_files(&opt.path);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let start = std::time::Instant::now();
+    let workers = std::cmp::max(
+        1,
+        std::cmp::min(
+            opt.workers
+                .unwrap_or_else(|| std::cmp::min(64, num_cpus::get())),
+            opt.path.len(),
+        ),
+    );
+    let pool = ThreadPool::with_name("wimbd-worker".to_string(), workers);
+    let progress = get_file_progress_bar("Counting lines", opt.path.len(), opt.quiet)?;
+    let (tx, rx) = channel::<(usize, Result<LineCounts>)>();
+
+    for (i, path) in opt.path.iter().enumerate() {
+        let path = path.clone();
+        let tx = tx.clone();
+        pool.execute(move || {
+            let result = count_lines(&path)
+                .map_err(|err| anyhow!("{err:?} encounted while processing {path:?}"));
+            tx.send((i, result)).ok();
+        });
+    }
+    drop(tx);
+
+    let mut counts: Vec<Option<LineCounts>> = vec![None; opt.path.len()];
+    for (i, result) in rx {
+        counts[i] = Some(result?);
+        progress.inc(1);
+    }
+    pool.join();
+    progress.finish();
+
+    let mut totals = LineCounts::default();
+    for file_counts in counts.iter().flatten() {
+        totals.add(file_counts);
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{}", serde_json::to_string(file_counts)?)?;
+        }
+    }
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+
+    log::info!(
+        "Counted {} lines in {}",
+        totals.lines.separate_with_commas(),
+        format_duration(std::time::Duration::from_secs(start.elapsed().as_secs()))
+    );
+
+    if opt.json {
+        println!("{}", serde_json::to_string(&totals)?);
+    } else if !(opt.quiet && out_path.is_some()) {
+        totals.display();
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Count the lines and bytes in a file by scanning the decompressed data for newlines, without
+/// decoding or parsing any lines.
+fn count_lines(path: &Path) -> Result<LineCounts> {
+    let mut counts = LineCounts {
+        path: Some(path.into()),
+        compressed_bytes: fs::metadata(path)?.len(),
+        ..Default::default()
+    };
+    let mut reader = open_decompressed(path)?;
+    let mut buf = vec![0; CHUNK_SIZE];
+    // Whether the current line has anything other than whitespace so far.
+    let mut non_blank = false;
+    // Whether the data so far ends with a newline, or is empty.
+    let mut at_line_start = true;
+
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        counts.uncompressed_bytes += n as u64;
+        for &byte in &buf[..n] {
+            if byte == b'\n' {
+                counts.lines += 1;
+                if non_blank {
+                    counts.documents += 1;
+                }
+                non_blank = false;
+            } else if !byte.is_ascii_whitespace() {
+                non_blank = true;
+            }
+        }
+        at_line_start = buf[n - 1] == b'\n';
+    }
+
+    // The last line might not end with a newline.
+    if !at_line_start {
+        counts.lines += 1;
+        if non_blank {
+            counts.documents += 1;
+        }
+    }
+
+    Ok(counts)
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,633 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU8;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::util::{expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance};
+use crate::bloom::ngram_key;
+use crate::io::LineReader;
+use crate::ngrams::{ngrams, NgramCounter};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util::{self, OutputFile};
+
+/// Number of equal-width bins used for the coverage histogram.
+const NUM_BINS: usize = 10;
+
+/// The number of leading tokens of each eval example that are looked up first with '--exact'.
+const ANCHOR_TOKENS: usize = 8;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file from the corpus, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to the evaluation set, a JSON lines file (optionally compressed).
+    #[structopt(long = "eval", parse(from_os_str))]
+    eval: PathBuf,
+
+    /// The field(s) of each evaluation example to check. If multiple fields are given,
+    /// e.g. '--eval-field prompt --eval-field answer', they are joined with a space.
+    #[structopt(long = "eval-field", number_of_values = 1, default_value = "text")]
+    eval_field: Vec<String>,
+
+    /// Instead of ngram coverage, check which corpus documents contain a whole eval example
+    /// verbatim. Examples and documents are normalized by lowercasing and tokenizing them, so
+    /// differences in whitespace and, with the default tokenizer, punctuation are ignored.
+    /// This is cheaper than ngram coverage and has high precision, but misses examples that
+    /// are only partly contained.
+    ///
+    /// With this option '-o/--out' gets the documents that contain an example instead.
+    #[structopt(long = "exact")]
+    exact: bool,
+
+    /// Ngram size.
+    #[structopt(short = "n", long = "ngram", default_value = "13")]
+    ngram: usize,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Specify the size budget for the internal ngram Bloom filter, e.g. "8GiB".
+    /// In general it's best to choose the largest size that will fit in memory
+    /// on your machine.
+    #[structopt(long = "size", default_value = "4GiB", parse(try_from_str = parse_size_default_to_gb))]
+    size: u64,
+
+    /// Specify the number of hash functions to use.
+    #[structopt(short = "h", long = "hashes", default_value = "5")]
+    hashes: u8,
+
+    /// Set the seed for the hashing functions. By default the seed is chosen at random.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// A path to write the per-example output to. Output will be written as JSON lines, i.e.
+    /// each line will be a JSON object with the keys "index", "num_ngrams", "num_found",
+    /// and "coverage".
+    ///
+    /// With '--exact', each line will instead be a JSON object with the keys "path", "line",
+    /// "id", and "examples" for a corpus document that contains at least one example, where
+    /// "examples" are the indices of those examples. Documents are grouped by file but files
+    /// are in no particular order.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct ExampleCoverage {
+    index: usize,
+    num_ngrams: usize,
+    num_found: usize,
+    coverage: Option<f64>,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    // Validate arguments.
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.size == 0 {
+        bail!("--size must be greater than 0");
+    }
+    if opt.hashes == 0 {
+        bail!("-h/--hashes must be greater than 0");
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    if !opt.eval.is_file() {
+        bail!("Eval file {:?} does not exist", opt.eval);
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+    // Pick a seed up front so that it's recorded in the run metadata.
+    provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    if opt.exact {
+        return check_exact(&opt, tokenizer, preprocessor);
+    }
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    log::info!("Initializing ngram Bloom filter...");
+    // We're storing an array of u8s, so the size (in bytes) is also the length.
+    let ngram_counts = Arc::new(NgramCounter::<AtomicU8>::new(
+        opt.size as usize,
+        opt.hashes as usize,
+        opt.seed,
+        0,
+    )?);
+
+    log::info!("Collecting corpus ngrams...");
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Collecting ngrams",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let collect_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let ngram_counts = ngram_counts.clone();
+
+            move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    for ngram in ngrams(&text, opt.ngram, tokenizer.as_ref())? {
+                        ngram_counts.increment(&ngram[..], 1);
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute(path, collect_ngrams)?;
+    }
+
+    executor.join()?;
+
+    log::info!("Checking eval examples...");
+    let mut histogram = [0usize; NUM_BINS];
+    let mut num_examples: usize = 0;
+    let mut num_too_short: usize = 0;
+    let mut num_fully_covered: usize = 0;
+    let mut coverage_sum: f64 = 0.0;
+
+    for (index, line) in read_eval_lines(&opt.eval)?.enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let text = get_eval_text(&line, &opt.eval_field)
+            .with_context(|| format!("failed to parse eval example {}", index + 1))?;
+        let text = preprocessor.apply(&text);
+
+        let mut num_ngrams: usize = 0;
+        let mut num_found: usize = 0;
+        for ngram in ngrams(&text, opt.ngram, tokenizer.as_ref())? {
+            num_ngrams += 1;
+            if ngram_counts.count(&ngram[..]) > 0 {
+                num_found += 1;
+            }
+        }
+
+        num_examples += 1;
+        let coverage = if num_ngrams > 0 {
+            let coverage = num_found as f64 / num_ngrams as f64;
+            coverage_sum += coverage;
+            histogram[std::cmp::min((coverage * NUM_BINS as f64) as usize, NUM_BINS - 1)] += 1;
+            if num_found == num_ngrams {
+                num_fully_covered += 1;
+            }
+            Some(coverage)
+        } else {
+            num_too_short += 1;
+            None
+        };
+
+        if let Some(ref mut file) = out_file {
+            let example = ExampleCoverage {
+                index,
+                num_ngrams,
+                num_found,
+                coverage,
+            };
+            writeln!(file, "{}", serde_json::to_string(&example)?)?;
+        }
+    }
+
+    let num_scored = num_examples - num_too_short;
+    let mean_coverage = if num_scored > 0 {
+        coverage_sum / num_scored as f64
+    } else {
+        0.0
+    };
+    let bins: Vec<_> = histogram
+        .iter()
+        .enumerate()
+        .map(|(i, count)| {
+            json!({
+                "min": i as f64 / NUM_BINS as f64,
+                "max": (i + 1) as f64 / NUM_BINS as f64,
+                "count": count,
+            })
+        })
+        .collect();
+
+    if opt.json {
+        let json_out = json!({
+            "num_examples": num_examples,
+            "num_too_short": num_too_short,
+            "num_fully_covered": num_fully_covered,
+            "mean_coverage": mean_coverage,
+            "histogram": bins,
+        });
+        println!("{json_out}");
+    } else if !opt.quiet {
+        println!("{}: {}", style("examples").cyan(), num_examples);
+        println!(
+            "{}: {}",
+            style(format!("examples shorter than {} tokens", opt.ngram)).cyan(),
+            num_too_short
+        );
+        println!("{}: {}", style("fully covered").cyan(), num_fully_covered);
+        println!("{}: {:.4}", style("mean coverage").cyan(), mean_coverage);
+        println!("{}:", style("coverage distribution").cyan());
+        for (i, count) in histogram.iter().enumerate() {
+            println!(
+                "  [{:.1}, {:.1}{} {}",
+                i as f64 / NUM_BINS as f64,
+                (i + 1) as f64 / NUM_BINS as f64,
+                if i == NUM_BINS - 1 { "]" } else { ")" },
+                count
+            );
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// The eval examples, normalized and hashed, for finding them in documents with '--exact'.
+struct EvalSet {
+    /// The number of tokens, the hash of all tokens, and the index of every example by the
+    /// hash of its first (up to) `ANCHOR_TOKENS` tokens.
+    anchors: HashMap<u64, Vec<(usize, u64, usize)>>,
+    /// The distinct anchor sizes in tokens.
+    anchor_sizes: Vec<usize>,
+    num_examples: usize,
+    /// Examples without any tokens, which are left out.
+    num_empty: usize,
+}
+
+impl EvalSet {
+    fn read(
+        path: &Path,
+        fields: &[String],
+        tokenizer: &Arc<dyn Tokenizer>,
+        preprocessor: Preprocessor,
+    ) -> Result<Self> {
+        let mut anchors: HashMap<u64, Vec<(usize, u64, usize)>> = HashMap::new();
+        let mut num_examples = 0;
+        let mut num_empty = 0;
+        for (index, line) in read_eval_lines(path)?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let text = get_eval_text(&line, fields)
+                .with_context(|| format!("failed to parse eval example {}", index + 1))?;
+            let tokens = normalized_tokens(&preprocessor.apply(&text), tokenizer)?;
+            num_examples += 1;
+            if tokens.is_empty() {
+                num_empty += 1;
+                continue;
+            }
+            let anchor = &tokens[..std::cmp::min(tokens.len(), ANCHOR_TOKENS)];
+            anchors.entry(hash_tokens(anchor)).or_default().push((
+                tokens.len(),
+                hash_tokens(&tokens),
+                index,
+            ));
+        }
+
+        let mut anchor_sizes: Vec<usize> = anchors
+            .values()
+            .flatten()
+            .map(|&(len, _, _)| std::cmp::min(len, ANCHOR_TOKENS))
+            .collect();
+        anchor_sizes.sort_unstable();
+        anchor_sizes.dedup();
+
+        Ok(Self {
+            anchors,
+            anchor_sizes,
+            num_examples,
+            num_empty,
+        })
+    }
+
+    /// The indices of the examples that the tokens contain, sorted.
+    fn find(&self, tokens: &[String]) -> Vec<usize> {
+        let mut found = Vec::new();
+        for start in 0..tokens.len() {
+            for &size in &self.anchor_sizes {
+                if start + size > tokens.len() {
+                    break;
+                }
+                if let Some(candidates) =
+                    self.anchors.get(&hash_tokens(&tokens[start..start + size]))
+                {
+                    for &(len, hash, index) in candidates {
+                        if start + len <= tokens.len()
+                            && hash_tokens(&tokens[start..start + len]) == hash
+                        {
+                            found.push(index);
+                        }
+                    }
+                }
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct ContainingDocument {
+    path: PathBuf,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    examples: Vec<usize>,
+}
+
+#[derive(Default)]
+struct LocalMatches {
+    num_documents: usize,
+    num_containing: usize,
+    /// The number of documents that contain each example found.
+    examples: HashMap<usize, usize>,
+    documents: Vec<ContainingDocument>,
+}
+
+/// Find the corpus documents that contain a whole eval example, see '--exact'.
+fn check_exact(opt: &Opt, tokenizer: Arc<dyn Tokenizer>, preprocessor: Preprocessor) -> Result<()> {
+    log::info!("Reading eval examples...");
+    let eval_set = Arc::new(EvalSet::read(
+        &opt.eval,
+        &opt.eval_field,
+        &tokenizer,
+        preprocessor,
+    )?);
+    if eval_set.anchors.is_empty() {
+        bail!("no eval examples with any tokens found in {:?}", opt.eval);
+    }
+
+    let (writer, out_path) = match get_output_file(opt)? {
+        Some((file, path)) => (Some(Arc::new(Mutex::new(Some(file)))), Some(path)),
+        None => (None, None),
+    };
+    let totals = Arc::new(Mutex::new(LocalMatches::default()));
+
+    log::info!("Checking corpus documents...");
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Checking", opt.quiet)?;
+
+    for path in &opt.path {
+        let check_document = {
+            let eval_set = eval_set.clone();
+            let tokenizer = tokenizer.clone();
+
+            move |data: DataInstance,
+                  path: &Path,
+                  line_num: usize,
+                  local_matches: &mut LocalMatches|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    local_matches.num_documents += 1;
+                    let tokens = normalized_tokens(&preprocessor.apply(&text), &tokenizer)?;
+                    let examples = eval_set.find(&tokens);
+                    if !examples.is_empty() {
+                        local_matches.num_containing += 1;
+                        for &index in &examples {
+                            *local_matches.examples.entry(index).or_insert(0) += 1;
+                        }
+                        local_matches.documents.push(ContainingDocument {
+                            path: path.into(),
+                            line: line_num,
+                            id: data.id,
+                            examples,
+                        });
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        // Matches are only added once the whole file is done so that retries don't count or
+        // write any document twice.
+        let sync_matches_callback = {
+            let writer = writer.clone();
+            let totals = totals.clone();
+
+            move |local_matches: LocalMatches| -> Result<()> {
+                if let Some(writer) = &writer {
+                    write_documents(writer, &local_matches.documents)?;
+                }
+                let mut totals = totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                totals.num_documents += local_matches.num_documents;
+                totals.num_containing += local_matches.num_containing;
+                for (index, count) in local_matches.examples {
+                    *totals.examples.entry(index).or_insert(0) += count;
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            check_document,
+            || -> Result<LocalMatches> { Ok(LocalMatches::default()) },
+            sync_matches_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    if let Some(writer) = writer {
+        let writer = writer
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .take();
+        if let Some(writer) = writer {
+            writer.finish()?;
+        }
+    }
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let num_containing = totals.num_containing;
+    // The examples contained in the most documents first.
+    let mut examples: Vec<(usize, usize)> = totals
+        .examples
+        .iter()
+        .map(|(&index, &count)| (index, count))
+        .collect();
+    examples.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+    if opt.json {
+        let examples: BTreeMap<usize, usize> = examples.iter().copied().collect();
+        println!(
+            "{}",
+            json!({
+                "num_examples": eval_set.num_examples,
+                "num_empty": eval_set.num_empty,
+                "num_examples_found": examples.len(),
+                "num_documents": totals.num_documents,
+                "num_containing_documents": num_containing,
+                "examples": examples,
+            })
+        );
+    } else if !opt.quiet {
+        println!("{}: {}", style("examples").cyan(), eval_set.num_examples);
+        println!(
+            "{}: {}",
+            style("examples without tokens").cyan(),
+            eval_set.num_empty
+        );
+        println!(
+            "{}: {}/{} ({:.2}%)",
+            style("examples found").cyan(),
+            examples.len(),
+            eval_set.num_examples,
+            100.0 * examples.len() as f64 / eval_set.num_examples.max(1) as f64
+        );
+        println!(
+            "{}: {}/{} ({:.2}%)",
+            style("documents containing an example").cyan(),
+            num_containing,
+            totals.num_documents,
+            100.0 * num_containing as f64 / totals.num_documents.max(1) as f64
+        );
+        if !examples.is_empty() {
+            println!("{}:", style("most contained examples").cyan());
+            for (index, count) in examples.iter().take(10) {
+                println!("  example {}: {} documents", index, count);
+            }
+        }
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Lowercase and tokenize text for matching eval examples verbatim.
+fn normalized_tokens(text: &str, tokenizer: &Arc<dyn Tokenizer>) -> Result<Vec<String>> {
+    let text = text.to_lowercase();
+    Ok(tokenizer.tokenize(&text)?)
+}
+
+fn hash_tokens(tokens: &[String]) -> u64 {
+    xxh3_64(&ngram_key(tokens))
+}
+
+fn write_documents(
+    writer: &Mutex<Option<OutputFile>>,
+    documents: &[ContainingDocument],
+) -> Result<()> {
+    let mut writer = writer
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let writer = writer
+        .as_mut()
+        .ok_or_else(|| anyhow!("Output is already closed"))?;
+    for document in documents {
+        serde_json::to_writer(&mut *writer, document)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
+
+/// Read the lines of the eval file, which may be compressed.
+fn read_eval_lines(path: &Path) -> Result<impl Iterator<Item = Result<String>>> {
+    Ok(LineReader::open(path)?.map(|line| -> Result<String> { Ok(line?) }))
+}
+
+fn get_eval_text(line: &str, fields: &[String]) -> Result<String> {
+    let example: serde_json::Value = serde_json::from_str(line)?;
+    let mut parts = Vec::with_capacity(fields.len());
+    for field in fields {
+        let value = example
+            .get(field)
+            .ok_or_else(|| anyhow!("missing field '{}'", field))?;
+        match value {
+            serde_json::Value::String(s) => parts.push(s.clone()),
+            other => parts.push(other.to_string()),
+        }
+    }
+    Ok(parts.join(" "))
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,258 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use structopt::StructOpt;
+use thousands::Separable;
+use threadpool::ThreadPool;
+
+use super::util::expand_paths;
+use crate::io::NpyReader;
+use crate::progress::get_file_progress_bar;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util;
+
+/// The number of token IDs to read at a time when splitting a shard at '--eos-id'.
+const CHUNK_SIZE: u64 = 1 << 20;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to an ".npy" shard of token IDs, like the ones written by the 'tokenize' command.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The name of the pretrained tokenizer from HuggingFace that the shards were tokenized
+    /// with.
+    #[structopt(short = "t", long = "tokenizer")]
+    tokenizer: String,
+
+    /// The directory to write the documents to. Every shard "<name>.npy" is written to
+    /// "<name>.json.gz" as JSON lines with the keys "text" and, if the shard has an index,
+    /// "id", "path", and "line" of the original document.
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: PathBuf,
+
+    /// The ID of the token that ends every document, for splitting shards into documents.
+    /// This is only used for shards without an index "<name>.index.jsonl" next to them.
+    #[structopt(long = "eos-id")]
+    eos_id: Option<u32>,
+
+    /// Limit the number of documents per shard to write.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output files if they already exist.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// A line of a shard's index, see the 'tokenize' command.
+#[derive(Debug, Deserialize)]
+struct IndexEntry {
+    path: Option<PathBuf>,
+    line: Option<usize>,
+    id: Option<Value>,
+    start: u64,
+    length: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct Document {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    text: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    line: Option<usize>,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    opt.path = expand_paths(&opt.path)?;
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+
+    if opt.tokenizer == "unicode" {
+        bail!("-t/--tokenizer must be a pretrained tokenizer");
+    }
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+
+    let mut jobs = Vec::with_capacity(opt.path.len());
+    for path in &opt.path {
+        if !path.is_file() {
+            bail!("File {:?} does not exist", path);
+        }
+        let name = shard_name(path)?;
+        let index_path = path.with_file_name(format!("{name}.index.jsonl"));
+        let index_path = if index_path.is_file() {
+            Some(index_path)
+        } else if opt.eos_id.is_some() {
+            None
+        } else {
+            bail!(
+                "{:?} doesn't have an index {:?}, use --eos-id to split it into documents",
+                path,
+                index_path
+            );
+        };
+        let out_path = opt.out.join(format!("{name}.json.gz"));
+        if out_path.is_file() && !opt.force {
+            bail!(
+                "Output file {:?} already exists, use --force to overwrite",
+                out_path
+            );
+        }
+        jobs.push((path.clone(), index_path, out_path));
+    }
+
+    let workers = std::cmp::max(
+        1,
+        std::cmp::min(
+            opt.workers
+                .unwrap_or_else(|| std::cmp::min(64, num_cpus::get())),
+            jobs.len(),
+        ),
+    );
+    let pool = ThreadPool::with_name("wimbd-worker".to_string(), workers);
+    let progress = get_file_progress_bar("Detokenizing", jobs.len(), opt.quiet)?;
+    let (tx, rx) = channel::<Result<usize>>();
+
+    for (path, index_path, out_path) in jobs {
+        let tokenizer = tokenizer.clone();
+        let tx = tx.clone();
+        let (eos_id, limit, force) = (opt.eos_id, opt.limit, opt.force);
+        pool.execute(move || {
+            let result = detokenize_shard(
+                &path,
+                index_path.as_deref(),
+                &out_path,
+                tokenizer,
+                eos_id,
+                limit,
+                force,
+            )
+            .map_err(|err| anyhow!("{err:?} encounted while detokenizing {path:?}"));
+            tx.send(result).ok();
+        });
+    }
+    drop(tx);
+
+    let mut total_documents: usize = 0;
+    for result in rx {
+        total_documents += result?;
+        progress.inc(1);
+    }
+    pool.join();
+    progress.finish();
+
+    log::info!("Wrote {} documents", total_documents.separate_with_commas());
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
+
+/// The file name of a shard without the ".npy" extension.
+fn shard_name(path: &Path) -> Result<&str> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_suffix(".npy"))
+        .ok_or_else(|| anyhow!("{:?} is not an .npy file", path))
+}
+
+/// Decode the documents of a shard, either at the positions in its index or split at
+/// `eos_id`, and write them out. Returns the number of documents written.
+fn detokenize_shard(
+    path: &Path,
+    index_path: Option<&Path>,
+    out_path: &Path,
+    tokenizer: Arc<dyn Tokenizer>,
+    eos_id: Option<u32>,
+    limit: Option<usize>,
+    force: bool,
+) -> Result<usize> {
+    let mut reader = NpyReader::open(path)?;
+    let (mut writer, _) = util::get_output_file(out_path, force)?;
+    let limit = limit.unwrap_or(usize::MAX);
+    let mut num_documents = 0;
+
+    let mut write_document = |document: Document| -> Result<()> {
+        serde_json::to_writer(&mut writer, &document)?;
+        writer.write_all(b"\n")?;
+        Ok(())
+    };
+
+    if let Some(index_path) = index_path {
+        for line in BufReader::new(File::open(index_path)?).lines() {
+            if num_documents >= limit {
+                break;
+            }
+            let entry: IndexEntry = serde_json::from_str(&line?)?;
+            reader.seek(entry.start)?;
+            let ids = reader.read_values(entry.length)?;
+            write_document(Document {
+                id: entry.id,
+                text: tokenizer.decode_ids(&ids)?,
+                path: entry.path,
+                line: entry.line,
+            })?;
+            num_documents += 1;
+        }
+    } else if let Some(eos_id) = eos_id {
+        let mut ids = Vec::new();
+        let mut remaining = reader.len();
+        while remaining > 0 && num_documents < limit {
+            let chunk = reader.read_values(std::cmp::min(remaining, CHUNK_SIZE))?;
+            remaining -= chunk.len() as u64;
+            for id in chunk {
+                if id != eos_id {
+                    ids.push(id);
+                    continue;
+                }
+                write_document(Document {
+                    id: None,
+                    text: tokenizer.decode_ids(&ids)?,
+                    path: None,
+                    line: None,
+                })?;
+                ids.clear();
+                num_documents += 1;
+                if num_documents >= limit {
+                    break;
+                }
+            }
+        }
+        // The last document may not end with the EOS token.
+        if !ids.is_empty() && num_documents < limit {
+            write_document(Document {
+                id: None,
+                text: tokenizer.decode_ids(&ids)?,
+                path: None,
+                line: None,
+            })?;
+            num_documents += 1;
+        }
+    }
+
+    writer.finish()?;
+    Ok(num_documents)
+}
@@ -0,0 +1,859 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicU8, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::json;
+use structopt::StructOpt;
+use thousands::Separable;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::util::{expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance};
+use crate::chunking::Chunker;
+use crate::ngrams::{NgramCounter, TopKNgrams};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::simhash;
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The number of most duplicated documents to return.
+    #[structopt(short = "k", long = "topk", default_value = "20")]
+    topk: usize,
+
+    /// The max number of example documents to point to for each duplicated document.
+    #[structopt(long = "examples", default_value = "5")]
+    examples: usize,
+
+    /// The max number of characters of each document's text to include in the output.
+    #[structopt(long = "snippet-chars", default_value = "200")]
+    snippet_chars: usize,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Specify the size budget for the internal document counter hash table, e.g. "8GiB".
+    #[structopt(long = "size", default_value = "4GiB", parse(try_from_str = parse_size_default_to_gb))]
+    size: u64,
+
+    /// Specify the number of hash functions to use.
+    #[structopt(short = "h", long = "hashes", default_value = "5")]
+    hashes: u8,
+
+    /// Set the seed for the hashing functions. By default the seed is chosen at random.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Find near-duplicates instead of exact duplicates by comparing 64-bit SimHash
+    /// fingerprints of each document's word shingles. Documents whose fingerprints differ in
+    /// at most '--max-distance' bits, directly or through other documents, count as copies of
+    /// each other.
+    ///
+    /// This keeps a fingerprint and a pointer for every document in memory and clusters them
+    /// all at once, which takes around 100 bytes per document at its peak, so a billion
+    /// documents need about 100GB of memory. It ignores '--size' and '-h/--hashes'.
+    #[structopt(long = "simhash")]
+    simhash: bool,
+
+    /// The max number of bits that SimHash fingerprints of near-duplicates can differ in.
+    #[structopt(long = "max-distance", default_value = "3")]
+    max_distance: u32,
+
+    /// The number of words in each shingle for SimHash fingerprints.
+    #[structopt(long = "shingle-size", default_value = "3")]
+    shingle_size: usize,
+
+    /// Estimate the fraction of duplicated bytes in the corpus instead of finding duplicated
+    /// documents. The text of every document is split into content-defined chunks (FastCDC)
+    /// and every chunk that's already been seen counts as duplicated. This doesn't depend on
+    /// a tokenizer and also catches documents that are only partly duplicated.
+    ///
+    /// Chunks are counted in the same kind of counter as whole documents, sized by '--size',
+    /// and hash collisions make the estimate slightly too high.
+    #[structopt(long = "chunks", conflicts_with = "simhash")]
+    chunks: bool,
+
+    /// The average chunk size in bytes for '--chunks', rounded down to a power of 2. Smaller
+    /// chunks catch shorter duplicated passages but take more space in the counter.
+    #[structopt(long = "chunk-size", default_value = "512")]
+    chunk_size: usize,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e.
+    /// each line will be a JSON object with the keys "xxh3", "count", "snippet", and "examples".
+    /// With '--simhash', the "xxh3" key is replaced by "simhash", the fingerprint of the first
+    /// copy. With '--chunks', the summary is written as a single JSON object instead.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Also write a pointer to every copy of every duplicated document to this file as JSON
+    /// lines, not only the '--examples' of the top-k, i.e. each line will be a JSON object with
+    /// the keys "xxh3" (or "simhash"), "count", and "members", a list of objects with the keys
+    /// "path" and "line". The largest clusters come first. The file is compressed if its name
+    /// ends in ".gz" or ".zst".
+    ///
+    /// For exact duplicates, this keeps a pointer to every document that was counted more than
+    /// once in memory until all files are done.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(long = "clusters", parse(from_os_str), conflicts_with = "chunks")]
+    clusters: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DocumentPointer {
+    path: PathBuf,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct Duplicate {
+    count: usize,
+    snippet: String,
+    examples: Vec<DocumentPointer>,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    // Validate arguments.
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.topk == 0 {
+        bail!("-k/--topk must be greater than 0");
+    }
+    if opt.size == 0 {
+        bail!("--size must be greater than 0");
+    }
+    if opt.hashes == 0 {
+        bail!("-h/--hashes must be greater than 0");
+    }
+    if opt.simhash && opt.max_distance >= 64 {
+        bail!("--max-distance must be less than 64");
+    }
+    if opt.simhash && opt.shingle_size == 0 {
+        bail!("--shingle-size must be greater than 0");
+    }
+    if opt.chunks && opt.chunk_size < 64 {
+        bail!("--chunk-size must be at least 64");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+    // Pick a seed up front so that it's recorded in the run metadata.
+    provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+    let mut clusters_file = match &opt.clusters {
+        Some(path) => Some(util::get_output_file(path, opt.force)?.0),
+        None => None,
+    };
+
+    if opt.chunks {
+        let summary = duplicated_chunks(&opt)?;
+        let json_out = summary.to_json()?.to_string();
+        if opt.json {
+            println!("{json_out}");
+        } else if !opt.quiet {
+            summary.print();
+        }
+        if let Some(mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+            file.finish()?;
+        }
+        if let Some(path) = out_path {
+            log::info!("Output written to {:?}", path);
+        }
+        return Ok(());
+    }
+
+    let duplicates = if opt.simhash {
+        near_duplicates(&opt, clusters_file.as_mut())?
+    } else {
+        exact_duplicates(&opt, clusters_file.as_mut())?
+    };
+    let hash_key = if opt.simhash { "simhash" } else { "xxh3" };
+
+    for (i, (hash, duplicate)) in duplicates.iter().enumerate() {
+        let json_out = &json!({
+            hash_key: format!("{hash:016x}"),
+            "count": duplicate.count,
+            "snippet": duplicate.snippet,
+            "examples": duplicate.examples,
+            "rank": i + 1,
+        })
+        .to_string();
+
+        // Display output.
+        if opt.json {
+            println!("{json_out}");
+        } else if opt.out.is_none() {
+            let example = &duplicate.examples[0];
+            println!(
+                "[{}/{}] {} copies (e.g. {:?} line {}): {:?}",
+                i + 1,
+                duplicates.len(),
+                duplicate.count,
+                example.path,
+                example.line,
+                style(&duplicate.snippet).cyan(),
+            );
+        }
+
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+        }
+    }
+
+    if duplicates.is_empty() {
+        log::warn!("No documents occurred more than once");
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    if let Some(file) = clusters_file {
+        file.finish()?;
+    }
+
+    if let Some(path) = &opt.clusters {
+        log::info!("Clusters written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Write every cluster of copies for '--clusters', the largest clusters first.
+fn write_clusters(
+    file: &mut OutputFile,
+    hash_key: &str,
+    mut clusters: Vec<(u64, Vec<DocumentPointer>)>,
+) -> Result<()> {
+    clusters.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(&b.0)));
+    for (hash, mut members) in clusters {
+        members.sort_by(|a, b| a.path.cmp(&b.path).then(a.line.cmp(&b.line)));
+        let json_out = json!({
+            hash_key: format!("{hash:016x}"),
+            "count": members.len(),
+            "members": members,
+        });
+        writeln!(file, "{json_out}")?;
+    }
+    Ok(())
+}
+
+/// Find the documents with the most exact copies, sorted by the number of copies. If `clusters`
+/// is set, every document with copies is written to it.
+fn exact_duplicates(opt: &Opt, clusters: Option<&mut OutputFile>) -> Result<Vec<(u64, Duplicate)>> {
+    log::info!("Initializing document counter...");
+    // We're storing an array of u32s, each of which is 4 bytes.
+    let doc_counts = Arc::new(NgramCounter::<AtomicU32>::new(
+        (opt.size / 4) as usize,
+        opt.hashes as usize,
+        opt.seed,
+        0,
+    )?);
+
+    // First pass: count the hashes of all documents and find the top-k candidates, like 'topk'
+    // does for ngrams with a single "token" per document.
+    log::info!("Counting documents...");
+    let mut topk: TopKNgrams<u64, AtomicU32> = TopKNgrams::new(opt.topk);
+    // Documents are compared after the global preprocessing steps, if any.
+    let preprocessor = Preprocessor::defaults();
+    let (tx, rx) = sync_channel::<(u64, u32)>(512_000);
+    let mut executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting documents",
+        opt.quiet,
+    )?;
+    executor.set_shared_state();
+
+    for path in &opt.path {
+        let count_documents = {
+            let doc_counts = doc_counts.clone();
+            let min_count = topk.min_count();
+
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_topk: &mut TopKNgrams<u64, AtomicU32>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let hash = [xxh3_64(preprocessor.apply(&text).as_bytes())];
+                    let count = doc_counts.increment(&hash[..], 1);
+                    if count > 1
+                        && count >= local_topk.min_count
+                        && count >= min_count.load(Ordering::Relaxed)
+                    {
+                        local_topk.insert(hash.to_vec(), count);
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let sync_local_topk_callback = {
+            let min_count = topk.min_count();
+            let tx = tx.clone();
+
+            move |mut local_topk: TopKNgrams<u64, AtomicU32>| -> Result<()> {
+                for (hash, count) in local_topk.drain() {
+                    if count >= min_count.load(Ordering::Relaxed) {
+                        tx.send((hash[0], count))?;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let topk_size = opt.topk;
+        executor.execute_with_callback(
+            path,
+            count_documents,
+            move || -> Result<TopKNgrams<u64, AtomicU32>> { Ok(TopKNgrams::new(topk_size)) },
+            sync_local_topk_callback,
+        )?;
+    }
+
+    drop(tx);
+
+    while !executor.done() {
+        while let Ok((hash, count)) = rx.recv_timeout(Duration::from_secs(1)) {
+            topk.insert(vec![hash], count);
+            if executor.has_errors() {
+                break;
+            }
+        }
+    }
+
+    executor.join()?;
+
+    for (hash, count) in rx.try_iter() {
+        topk.insert(vec![hash], count);
+    }
+
+    // Second pass: get exact counts, snippets, and examples for the candidates. This also rules
+    // out candidates that only made it into the top-k through hash collisions.
+    log::info!("Collecting examples...");
+    let duplicates: Arc<Mutex<HashMap<u64, Duplicate>>> = Arc::new(Mutex::new(
+        topk.drain()
+            .into_iter()
+            .map(|(hash, _)| (hash[0], Duplicate::default()))
+            .collect(),
+    ));
+    let candidates: Arc<HashSet<u64>> = Arc::new(
+        duplicates
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .keys()
+            .copied()
+            .collect(),
+    );
+    // Every document that was counted more than once, by hash, for '--clusters'. Some of these
+    // only got there through hash collisions, and are filtered out once all files are done.
+    let members = clusters
+        .is_some()
+        .then(|| Arc::new(Mutex::new(HashMap::<u64, Vec<DocumentPointer>>::new())));
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Collecting examples",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let collect_examples = {
+            let duplicates = duplicates.clone();
+            let candidates = candidates.clone();
+            let doc_counts = doc_counts.clone();
+            let members = members.clone();
+            let snippet_chars = opt.snippet_chars;
+            let examples = opt.examples;
+
+            move |data: DataInstance, path: &Path, line_num: usize| -> Result<()> {
+                if let Some(text) = data.text {
+                    let hash = xxh3_64(preprocessor.apply(&text).as_bytes());
+                    if let Some(members) = &members {
+                        if doc_counts.count(&[hash][..]) > 1 {
+                            members
+                                .lock()
+                                .map_err(|_| anyhow!("Failed to acquire lock"))?
+                                .entry(hash)
+                                .or_default()
+                                .push(DocumentPointer {
+                                    path: path.into(),
+                                    line: line_num,
+                                    id: None,
+                                });
+                        }
+                    }
+                    if !candidates.contains(&hash) {
+                        return Ok(());
+                    }
+                    let mut duplicates = duplicates
+                        .lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                    let duplicate = duplicates.entry(hash).or_default();
+                    duplicate.count += 1;
+                    if duplicate.snippet.is_empty() {
+                        duplicate.snippet = text.chars().take(snippet_chars).collect();
+                    }
+                    if duplicate.examples.len() < examples {
+                        duplicate.examples.push(DocumentPointer {
+                            path: path.into(),
+                            line: line_num,
+                            id: data.id,
+                        });
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute(path, collect_examples)?;
+    }
+
+    executor.join()?;
+
+    if let (Some(file), Some(members)) = (clusters, members) {
+        let members = std::mem::take(
+            &mut *members
+                .lock()
+                .map_err(|_| anyhow!("Failed to acquire lock"))?,
+        );
+        write_clusters(
+            file,
+            "xxh3",
+            members
+                .into_iter()
+                .filter(|(_, members)| members.len() > 1)
+                .collect(),
+        )?;
+    }
+
+    let duplicates = std::mem::take(
+        &mut *duplicates
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+    );
+    let mut duplicates: Vec<(u64, Duplicate)> = duplicates
+        .into_iter()
+        .filter(|(_, duplicate)| duplicate.count > 1)
+        .collect();
+    duplicates.sort_by_key(|(_, duplicate)| std::cmp::Reverse(duplicate.count));
+
+    Ok(duplicates)
+}
+
+/// Byte and chunk counts for '--chunks'.
+#[derive(Debug, Clone, Default, Serialize)]
+struct ChunkSummary {
+    documents: usize,
+    /// Documents with some, but not all, of their bytes in duplicated chunks.
+    partly_duplicated_documents: usize,
+    /// Documents with all of their bytes in duplicated chunks.
+    fully_duplicated_documents: usize,
+    chunks: usize,
+    duplicated_chunks: usize,
+    bytes: u64,
+    duplicated_bytes: u64,
+}
+
+impl ChunkSummary {
+    fn merge(&mut self, other: &ChunkSummary) {
+        self.documents += other.documents;
+        self.partly_duplicated_documents += other.partly_duplicated_documents;
+        self.fully_duplicated_documents += other.fully_duplicated_documents;
+        self.chunks += other.chunks;
+        self.duplicated_chunks += other.duplicated_chunks;
+        self.bytes += other.bytes;
+        self.duplicated_bytes += other.duplicated_bytes;
+    }
+
+    fn duplicated_fraction(&self) -> f64 {
+        self.duplicated_bytes as f64 / self.bytes.max(1) as f64
+    }
+
+    fn to_json(&self) -> Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        value["duplicated_fraction"] = json!(self.duplicated_fraction());
+        Ok(value)
+    }
+
+    fn print(&self) {
+        println!(
+            "{}: {}",
+            style("documents").cyan(),
+            self.documents.separate_with_commas()
+        );
+        println!(
+            "{}: {}",
+            style("partly duplicated documents").cyan(),
+            self.partly_duplicated_documents.separate_with_commas()
+        );
+        println!(
+            "{}: {}",
+            style("fully duplicated documents").cyan(),
+            self.fully_duplicated_documents.separate_with_commas()
+        );
+        println!(
+            "{}: {} of {}",
+            style("duplicated chunks").cyan(),
+            self.duplicated_chunks.separate_with_commas(),
+            self.chunks.separate_with_commas()
+        );
+        println!(
+            "{}: {} of {} ({:.2}%)",
+            style("duplicated bytes").cyan(),
+            self.duplicated_bytes.separate_with_commas(),
+            self.bytes.separate_with_commas(),
+            100.0 * self.duplicated_fraction()
+        );
+    }
+}
+
+/// Estimate how many bytes of the corpus are duplicated by counting content-defined chunks.
+///
+/// The first copy of a chunk to be counted is the original and every other copy is a duplicate.
+/// Copies of a chunk all have the same size, so the number of duplicated bytes doesn't depend on
+/// the order documents are processed in, but which documents count as duplicated does.
+fn duplicated_chunks(opt: &Opt) -> Result<ChunkSummary> {
+    log::info!("Initializing chunk counter...");
+    // Only whether a chunk has been seen matters, so a byte per count is plenty.
+    let chunk_counts = Arc::new(NgramCounter::<AtomicU8>::new(
+        opt.size as usize,
+        opt.hashes as usize,
+        opt.seed,
+        0,
+    )?);
+    let chunker = Chunker::new(opt.chunk_size);
+    let preprocessor = Preprocessor::defaults();
+    let totals = Arc::new(Mutex::new(ChunkSummary::default()));
+
+    log::info!("Counting chunks...");
+    let mut executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting chunks",
+        opt.quiet,
+    )?;
+    executor.set_shared_state();
+
+    for path in &opt.path {
+        let count_chunks = {
+            let chunk_counts = chunk_counts.clone();
+
+            move |data: DataInstance, _: &Path, _: usize, local: &mut ChunkSummary| -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    if text.is_empty() {
+                        return Ok(());
+                    }
+                    let mut duplicated_bytes: u64 = 0;
+                    for chunk in chunker.chunks(text.as_bytes()) {
+                        let hash = [xxh3_64(chunk)];
+                        local.chunks += 1;
+                        if chunk_counts.increment(&hash[..], 1) > 1 {
+                            local.duplicated_chunks += 1;
+                            duplicated_bytes += chunk.len() as u64;
+                        }
+                    }
+                    local.documents += 1;
+                    local.bytes += text.len() as u64;
+                    local.duplicated_bytes += duplicated_bytes;
+                    if duplicated_bytes == text.len() as u64 {
+                        local.fully_duplicated_documents += 1;
+                    } else if duplicated_bytes > 0 {
+                        local.partly_duplicated_documents += 1;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let sync_totals_callback = {
+            let totals = totals.clone();
+
+            move |local: ChunkSummary| -> Result<()> {
+                totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .merge(&local);
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            count_chunks,
+            || -> Result<ChunkSummary> { Ok(ChunkSummary::default()) },
+            sync_totals_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?
+        .clone();
+    Ok(totals)
+}
+
+/// Find the clusters of near-duplicate documents with the most members by their SimHash
+/// fingerprints, sorted by the number of members. If `clusters` is set, every cluster with more
+/// than one member is written to it.
+fn near_duplicates(
+    opt: &Opt,
+    clusters_file: Option<&mut OutputFile>,
+) -> Result<Vec<(u64, Duplicate)>> {
+    // First pass: fingerprint every document.
+    log::info!("Fingerprinting documents...");
+    let fingerprints: Arc<Mutex<Vec<(u64, u32, usize)>>> = Arc::new(Mutex::new(Vec::new()));
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Fingerprinting documents",
+        opt.quiet,
+    )?;
+
+    for (file_index, path) in opt.path.iter().enumerate() {
+        let file_index = file_index as u32;
+        let shingle_size = opt.shingle_size;
+        let seed = opt.seed.unwrap_or_default();
+        let preprocessor = Preprocessor::defaults();
+        let fingerprint_documents = move |data: DataInstance,
+                                          _: &Path,
+                                          line_num: usize,
+                                          local: &mut Vec<(u64, u32, usize)>|
+              -> Result<()> {
+            if let Some(fingerprint) = data.text.and_then(|text| {
+                simhash::fingerprint(&preprocessor.apply(&text), shingle_size, seed)
+            }) {
+                local.push((fingerprint, file_index, line_num));
+            }
+            Ok(())
+        };
+
+        let collect_fingerprints = {
+            let fingerprints = fingerprints.clone();
+            move |mut local: Vec<(u64, u32, usize)>| -> Result<()> {
+                fingerprints
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .append(&mut local);
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            fingerprint_documents,
+            || -> Result<Vec<(u64, u32, usize)>> { Ok(Vec::new()) },
+            collect_fingerprints,
+        )?;
+    }
+
+    executor.join()?;
+
+    let mut fingerprints = std::mem::take(
+        &mut *fingerprints
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+    );
+    // Files finish in any order, so sort to make clusters and examples deterministic.
+    fingerprints.sort_unstable_by_key(|&(_, file_index, line)| (file_index, line));
+
+    log::info!(
+        "Clustering {} fingerprints...",
+        fingerprints.len().separate_with_commas()
+    );
+    let clusters = simhash::cluster(
+        &fingerprints.iter().map(|f| f.0).collect::<Vec<_>>(),
+        opt.max_distance,
+    );
+    let mut sizes: HashMap<usize, usize> = HashMap::new();
+    for &cluster in &clusters {
+        *sizes.entry(cluster).or_default() += 1;
+    }
+    let mut largest: Vec<(usize, usize)> = sizes
+        .iter()
+        .filter(|(_, &n)| n > 1)
+        .map(|(&cluster, &n)| (cluster, n))
+        .collect();
+    largest.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    largest.truncate(opt.topk);
+    let ranks: HashMap<usize, usize> = largest
+        .iter()
+        .enumerate()
+        .map(|(rank, &(cluster, _))| (cluster, rank))
+        .collect();
+
+    // The cluster's representative is always its first member, so it's also the first example.
+    let mut duplicates: Vec<(u64, Duplicate)> = largest
+        .iter()
+        .map(|&(cluster, count)| {
+            (
+                fingerprints[cluster].0,
+                Duplicate {
+                    count,
+                    ..Default::default()
+                },
+            )
+        })
+        .collect();
+    // The rank and example index of each example, by its file index and line number.
+    let mut wanted: HashMap<(u32, usize), (usize, usize)> = HashMap::new();
+    for (&(_, file_index, line), cluster) in fingerprints.iter().zip(&clusters) {
+        if let Some(&rank) = ranks.get(cluster) {
+            let duplicate = &mut duplicates[rank].1;
+            if duplicate.examples.len() < opt.examples {
+                wanted.insert((file_index, line), (rank, duplicate.examples.len()));
+                duplicate.examples.push(DocumentPointer {
+                    path: opt.path[file_index as usize].clone(),
+                    line,
+                    id: None,
+                });
+            }
+        }
+    }
+    if let Some(file) = clusters_file {
+        let mut members: HashMap<usize, Vec<DocumentPointer>> = HashMap::new();
+        for (&(_, file_index, line), cluster) in fingerprints.iter().zip(&clusters) {
+            if sizes[cluster] > 1 {
+                members.entry(*cluster).or_default().push(DocumentPointer {
+                    path: opt.path[file_index as usize].clone(),
+                    line,
+                    id: None,
+                });
+            }
+        }
+        write_clusters(
+            file,
+            "simhash",
+            members
+                .into_iter()
+                .map(|(cluster, members)| (fingerprints[cluster].0, members))
+                .collect(),
+        )?;
+    }
+    drop(fingerprints);
+    drop(clusters);
+
+    // Second pass: get snippets and IDs for the examples, only reading the files they're in.
+    log::info!("Collecting examples...");
+    let mut file_indices: Vec<u32> = wanted.keys().map(|&(file_index, _)| file_index).collect();
+    file_indices.sort_unstable();
+    file_indices.dedup();
+    let paths: Vec<PathBuf> = file_indices
+        .iter()
+        .map(|&i| opt.path[i as usize].clone())
+        .collect();
+    let wanted = Arc::new(wanted);
+    let duplicates = Arc::new(Mutex::new(duplicates));
+    let executor = DataExecutor::new(
+        &paths,
+        opt.workers,
+        opt.limit,
+        "Collecting examples",
+        opt.quiet,
+    )?;
+
+    for (&file_index, path) in file_indices.iter().zip(&paths) {
+        let collect_examples = {
+            let wanted = wanted.clone();
+            let duplicates = duplicates.clone();
+            let snippet_chars = opt.snippet_chars;
+
+            move |data: DataInstance, _: &Path, line_num: usize| -> Result<()> {
+                let (rank, example) = match wanted.get(&(file_index, line_num)) {
+                    Some(&found) => found,
+                    None => return Ok(()),
+                };
+                let mut duplicates = duplicates
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                let duplicate = &mut duplicates[rank].1;
+                duplicate.examples[example].id = data.id;
+                if example == 0 {
+                    if let Some(text) = data.text {
+                        duplicate.snippet = text.chars().take(snippet_chars).collect();
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute(path, collect_examples)?;
+    }
+
+    executor.join()?;
+
+    let duplicates = std::mem::take(
+        &mut *duplicates
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+    );
+    Ok(duplicates)
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use console::style;
+use serde_json::json;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::io;
+use crate::ngrams::{CountHistogram, SortedRuns};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::load_tokenizer;
+use crate::util;
+
+/// The max number of distinct ngrams each worker aggregates in memory before writing them out
+/// as a sorted run.
+const MAX_LOCAL_NGRAMS: usize = 1_000_000;
+
+/// The numbers of most frequent ngrams to report the coverage of.
+const TOP_COVERAGE: &[usize] = &[100, 1_000, 10_000];
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Ngram size.
+    #[structopt(short = "n", long = "ngram", default_value = "1")]
+    ngram: usize,
+
+    /// Only include ngrams that occur at least this many times in the output.
+    #[structopt(long = "min-count", default_value = "1")]
+    min_count: u64,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the frequency table to. Output will be written as JSON lines, i.e. each
+    /// line will be a JSON object with the keys "tokens", "string", and "count". Lines are in no
+    /// particular order. The file is compressed if its name ends in ".gz" or ".zst".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: PathBuf,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the concentration metrics as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    // Validate arguments.
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    if opt.out.is_dir() {
+        bail!("-o/--out must be a valid file name, not a directory");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut writer, out_path) = util::get_output_file(&opt.out, opt.force)?;
+
+    // Map: each job counts ngrams locally and writes them out as a sorted run whenever the local
+    // counts get too big, and once more at the end of the file.
+    let runs = Arc::new(SortedRuns::new(&io::tmp_dir())?);
+
+    log::info!("Counting ngrams...");
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting ngrams",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let collect_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let runs = runs.clone();
+
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_counts: &mut HashMap<Vec<String>, u64>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let tokens = tokenizer.tokenize(&preprocessor.apply(&text))?;
+                    for ngram in tokens.windows(opt.ngram) {
+                        match local_counts.get_mut(ngram) {
+                            Some(count) => *count += 1,
+                            None => {
+                                local_counts.insert(ngram.to_vec(), 1);
+                            }
+                        }
+                    }
+                    if local_counts.len() >= MAX_LOCAL_NGRAMS {
+                        runs.write_run(std::mem::take(local_counts))?;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let write_run_callback = {
+            let runs = runs.clone();
+
+            move |local_counts: HashMap<Vec<String>, u64>| -> Result<()> {
+                Ok(runs.write_run(local_counts)?)
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            collect_ngrams,
+            || -> Result<HashMap<Vec<String>, u64>> { Ok(HashMap::new()) },
+            write_run_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    // Reduce: merge the sorted runs, which brings all partial counts of an ngram together.
+    log::info!("Merging {} sorted runs...", runs.num_runs());
+    let mut num_distinct: usize = 0;
+    let mut num_written: usize = 0;
+    let mut histogram = CountHistogram::new();
+    runs.merge(|ngram, count| {
+        num_distinct += 1;
+        histogram.add(count);
+        if count < opt.min_count {
+            return Ok(());
+        }
+        let ngram_str = tokenizer.decode(&ngram)?;
+        let json_out = json!({
+            "tokens": ngram,
+            "string": ngram_str,
+            "count": count,
+        });
+        writeln!(writer, "{json_out}")?;
+        num_written += 1;
+        Ok(())
+    })?;
+    writer.finish()?;
+
+    log::info!(
+        "Found {} distinct ngrams, wrote {} with a count of at least {}",
+        num_distinct.separate_with_commas(),
+        num_written.separate_with_commas(),
+        opt.min_count,
+    );
+
+    let concentration = histogram.concentration(TOP_COVERAGE);
+    if opt.json {
+        println!("{}", serde_json::to_string(&concentration)?);
+    } else if !opt.quiet {
+        println!(
+            "{}: {}",
+            style("distinct ngrams").cyan(),
+            concentration.types.separate_with_commas()
+        );
+        println!(
+            "{}: {}",
+            style("total ngrams").cyan(),
+            concentration.tokens.separate_with_commas()
+        );
+        println!(
+            "{}: {:.4}",
+            style("gini coefficient").cyan(),
+            concentration.gini
+        );
+        println!(
+            "{}: {:.4} bits",
+            style("entropy").cyan(),
+            concentration.entropy
+        );
+        for (k, coverage) in &concentration.top_coverage {
+            println!(
+                "{}: {:.2}%",
+                style(format!("top {} coverage", k.separate_with_commas())).cyan(),
+                100.0 * coverage
+            );
+        }
+    }
+    log::info!("Output written to {:?}", out_path);
+
+    Ok(())
+}
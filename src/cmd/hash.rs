@@ -0,0 +1,202 @@
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::sync_channel;
+use std::time::Duration;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use sha1::{Digest, Sha1};
+use structopt::StructOpt;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::table::{ColumnType, OutFormat, TableWriter};
+use crate::tokens::load_tokenizer;
+
+/// The columns of CSV, Parquet, and SQLite output files.
+const COLUMNS: &[(&str, ColumnType)] = &[
+    ("path", ColumnType::String),
+    ("line", ColumnType::Int),
+    ("xxh3", ColumnType::String),
+    ("sha1", ColumnType::String),
+    ("num_tokens", ColumnType::Int),
+];
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e.
+    /// each line will be a JSON object with the keys "path", "line", "id" (if the document
+    /// has one), "xxh3", "sha1", and "num_tokens".
+    ///
+    /// If not given, the digests are written to stdout. See '--out-format' for other formats.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// The format of the '-o/--out' file: "jsonl", "csv", "parquet", or "sqlite". CSV and
+    /// Parquet files have the columns "path", "line", "xxh3", "sha1", and "num_tokens". Document
+    /// IDs are only included in JSON. Parquet files are written all at once at the end, so every
+    /// digest is kept in memory until then. SQLite databases get the rows appended to the
+    /// "digests" table, so they're never overwritten.
+    #[structopt(long = "out-format", default_value = "jsonl", possible_values = &["jsonl", "csv", "parquet", "sqlite"])]
+    out_format: OutFormat,
+
+    /// Don't show progress bars.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+/// The digest record written for each document.
+#[derive(Debug, Clone, Serialize)]
+struct DocumentDigest {
+    path: PathBuf,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    xxh3: String,
+    sha1: String,
+    num_tokens: usize,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Hashing", opt.quiet)?;
+    let (tx, rx) = sync_channel::<DocumentDigest>(512_000);
+
+    for path in &opt.path {
+        let tokenizer = tokenizer.clone();
+        let tx = tx.clone();
+
+        executor.execute(
+            path,
+            move |data: DataInstance, path: &Path, line_num: usize| -> Result<()> {
+                if let Some(text) = data.text {
+                    // Digests are always of the original text, only the token counts are
+                    // affected by preprocessing.
+                    let preprocessed = preprocessor.apply(&text);
+                    let num_tokens = tokenizer.count_tokens(&preprocessed)?;
+
+                    let mut sha1 = Sha1::new();
+                    sha1.update(text.as_bytes());
+
+                    tx.send(DocumentDigest {
+                        path: path.into(),
+                        line: line_num,
+                        id: data.id,
+                        xxh3: format!("{:016x}", xxh3_64(text.as_bytes())),
+                        sha1: format!("{:x}", sha1.finalize()),
+                        num_tokens,
+                    })?;
+                }
+                Ok(())
+            },
+        )?;
+    }
+
+    drop(tx);
+
+    let mut write_digest = |digest: DocumentDigest| -> Result<()> {
+        if let Some(ref mut writer) = out_file {
+            writer.write_row(&serde_json::to_value(&digest)?)?;
+        } else {
+            println!("{}", serde_json::to_string(&digest)?);
+        }
+        Ok(())
+    };
+
+    // Collect digests from channel until all jobs are done.
+    while !executor.done() {
+        while let Ok(digest) = rx.recv_timeout(Duration::from_secs(1)) {
+            write_digest(digest)?;
+            if executor.has_errors() {
+                break;
+            }
+        }
+    }
+
+    executor.join()?;
+
+    // Flush anything that was sent after the last receive.
+    for digest in rx.try_iter() {
+        write_digest(digest)?;
+    }
+
+    if let Some(writer) = out_file {
+        writer.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(TableWriter, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(TableWriter::create(
+                path,
+                opt.force,
+                opt.out_format,
+                "digests",
+                COLUMNS,
+            )?))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use serde::Deserialize;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::expand_paths;
+use crate::io::LineReader;
+use crate::lookup::{DocumentReader, LineIndex, DEFAULT_INTERVAL};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Pointers to documents as "PATH:LINE", where the line number starts at 1. With
+    /// '--build-index' these are paths to index instead.
+    pointer: Vec<String>,
+
+    /// Read pointers from a JSON lines file (optionally compressed) with "path" and
+    /// "line" keys, like the output of 'wimbd topk-docs' or 'wimbd tag'.
+    #[structopt(long = "pointers", parse(from_os_str))]
+    pointers: Option<PathBuf>,
+
+    /// Build a line index for every path, so that later lookups only decompress the part of
+    /// the file around a document. The index is written next to the file with ".lines" added
+    /// to its name. Lookups are fastest for zstd files in the seekable format.
+    #[structopt(long = "build-index")]
+    build_index: bool,
+
+    /// The number of lines between the entries of a line index. Smaller intervals make
+    /// lookups faster but the index bigger.
+    #[structopt(long = "interval", default_value = "1000")]
+    interval: u32,
+
+    /// Limit the number of files to index.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use when building indexes. Defaults to
+    /// min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show the summary of built indexes.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct Pointer {
+    path: PathBuf,
+    line: u64,
+}
+
+impl Pointer {
+    fn parse(s: &str) -> Result<Self> {
+        let (path, line) = match s.rsplit_once(':') {
+            Some((path, line)) if !path.is_empty() => (path, line),
+            _ => bail!("invalid pointer {:?}, expected \"PATH:LINE\"", s),
+        };
+        let line = line
+            .parse()
+            .with_context(|| format!("invalid line number in pointer {:?}", s))?;
+        Ok(Self {
+            path: path.into(),
+            line,
+        })
+    }
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    if opt.build_index {
+        return build_indexes(opt);
+    }
+    if opt.pointer.is_empty() && opt.pointers.is_none() {
+        bail!("at least one pointer or --pointers is required");
+    }
+
+    let mut pointers = opt
+        .pointer
+        .iter()
+        .map(|pointer| Pointer::parse(pointer))
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(path) = &opt.pointers {
+        for (i, line) in read_lines(path)?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            pointers.push(
+                serde_json::from_str(&line)
+                    .with_context(|| format!("failed to parse line {} of {:?}", i + 1, path))?,
+            );
+        }
+    }
+
+    // Readers are kept open since pointers often point into the same files.
+    let mut readers: HashMap<PathBuf, DocumentReader> = HashMap::new();
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for pointer in pointers {
+        if !readers.contains_key(&pointer.path) {
+            let reader = DocumentReader::open(&pointer.path)
+                .with_context(|| format!("failed to open {:?}", pointer.path))?;
+            if !reader.is_seekable() {
+                log::debug!(
+                    "{:?} isn't seekable, documents are read by decompressing it from the start",
+                    pointer.path
+                );
+            }
+            readers.insert(pointer.path.clone(), reader);
+        }
+        match readers[&pointer.path].read_line(pointer.line)? {
+            Some(document) => writeln!(stdout, "{}", document)?,
+            None => bail!(
+                "{:?} has no line {}",
+                pointer.path,
+                pointer.line.separate_with_commas()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+fn build_indexes(opt: Opt) -> Result<()> {
+    if opt.pointers.is_some() {
+        bail!("--pointers can't be used with --build-index");
+    }
+    if opt.pointer.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.interval == 0 {
+        bail!("--interval must be greater than 0");
+    }
+    let paths = expand_paths(&opt.pointer.iter().map(PathBuf::from).collect::<Vec<_>>())?;
+    let paths = match opt.file_limit {
+        Some(file_limit) => paths.into_iter().take(file_limit).collect(),
+        None => paths,
+    };
+    if opt.interval != DEFAULT_INTERVAL {
+        log::info!(
+            "Indexing every {} lines",
+            opt.interval.separate_with_commas()
+        );
+    }
+
+    let workers = opt
+        .workers
+        .unwrap_or_else(|| std::cmp::min(64, num_cpus::get()))
+        .clamp(1, paths.len().max(1));
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(paths.len()));
+    std::thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some(path) = paths.get(next.fetch_add(1, Ordering::Relaxed)) {
+                    let result = build_index(path, opt.interval);
+                    if let Ok(mut results) = results.lock() {
+                        results.push((path, result));
+                    }
+                }
+            });
+        }
+    });
+
+    let mut results = results
+        .into_inner()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    results.sort_by_key(|(path, _)| *path);
+    for (path, result) in results {
+        let (num_lines, seekable) =
+            result.with_context(|| format!("failed to index {:?}", path))?;
+        if !opt.quiet {
+            println!(
+                "{}: {} lines{}",
+                style(path.display()).cyan(),
+                num_lines.separate_with_commas(),
+                if seekable { "" } else { " (not seekable)" }
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Build and write the line index of a file, returning the number of lines and whether
+/// documents can now be read without decompressing the file up to them.
+fn build_index(path: &Path, interval: u32) -> Result<(u64, bool)> {
+    let index = LineIndex::build(path, interval)?;
+    let index_path = LineIndex::path_for(path);
+    index.write(&index_path)?;
+    log::info!("Line index written to {:?}", index_path);
+    Ok((index.num_lines(), DocumentReader::open(path)?.is_seekable()))
+}
+
+fn read_lines(path: &Path) -> Result<impl Iterator<Item = Result<String>>> {
+    Ok(LineReader::open(path)?.map(|line| -> Result<String> { Ok(line?) }))
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use serde_json::value::RawValue;
+use serde_json::{json, Map, Value};
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, mirrored_output_paths, raw_field, DataExecutor};
+use crate::io::LineReader;
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to the sidecar file with the fields to add to the documents. This can be a JSON
+    /// lines file (optionally compressed) or, if its name ends in ".parquet", a Parquet
+    /// file. Every record needs the '--key' field. The whole file is loaded into memory.
+    #[structopt(long = "sidecar", parse(from_os_str))]
+    sidecar: PathBuf,
+
+    /// The field that identifies a document, in both the documents and the sidecar records.
+    /// Nested fields are separated by dots, like "metadata.id".
+    #[structopt(long = "key", default_value = "id")]
+    key: String,
+
+    /// A field of the sidecar records to add to the documents. Give this option once for
+    /// every field. By default all fields except the key are added.
+    #[structopt(long = "field", number_of_values = 1)]
+    field: Vec<String>,
+
+    /// Add the fields to this object field of the documents, like "metadata" or "attributes",
+    /// instead of at the top level. The object is created if a document doesn't have it.
+    #[structopt(long = "into")]
+    into: Option<String>,
+
+    /// Only keep documents with a sidecar record. By default documents without one are
+    /// written unchanged.
+    #[structopt(long = "inner")]
+    inner: bool,
+
+    /// The directory to write the output to. Every input file is written to a file with the
+    /// same name in this directory. Fields of the sidecar records replace fields of the
+    /// documents with the same name.
+    #[structopt(short = "o", long = "out")]
+    out: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the summary as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output files if they already exist.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// The sidecar records by key, with the fields to add.
+struct Sidecar {
+    records: HashMap<String, Map<String, Value>>,
+    num_duplicates: usize,
+}
+
+impl Sidecar {
+    fn read(opt: &Opt) -> Result<Self> {
+        let mut sidecar = Self {
+            records: HashMap::new(),
+            num_duplicates: 0,
+        };
+        let is_parquet = opt
+            .sidecar
+            .extension()
+            .map(|ext| ext == "parquet")
+            .unwrap_or(false);
+        if is_parquet {
+            let reader = SerializedFileReader::new(File::open(&opt.sidecar)?)?;
+            for (i, row) in reader.get_row_iter(None)?.enumerate() {
+                sidecar
+                    .insert(row?.to_json_value(), opt)
+                    .with_context(|| format!("failed to read row {} of the sidecar", i + 1))?;
+            }
+        } else {
+            for (i, line) in read_lines(&opt.sidecar)?.enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let record: Value = serde_json::from_str(&line)
+                    .with_context(|| format!("failed to parse line {} of the sidecar", i + 1))?;
+                sidecar
+                    .insert(record, opt)
+                    .with_context(|| format!("failed to read line {} of the sidecar", i + 1))?;
+            }
+        }
+        Ok(sidecar)
+    }
+
+    fn insert(&mut self, record: Value, opt: &Opt) -> Result<()> {
+        let key = match lookup(&record, &opt.key) {
+            Some(Value::Null) | None => bail!("record has no {:?} field", opt.key),
+            Some(key) => key.to_string(),
+        };
+        let mut record = match record {
+            Value::Object(record) => record,
+            _ => bail!("record isn't a JSON object"),
+        };
+        let fields = if opt.field.is_empty() {
+            if !opt.key.contains('.') {
+                record.remove(&opt.key);
+            }
+            record
+        } else {
+            opt.field
+                .iter()
+                .filter_map(|field| record.remove(field).map(|value| (field.clone(), value)))
+                .collect()
+        };
+        if self.records.insert(key, fields).is_some() {
+            self.num_duplicates += 1;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct JoinCounts {
+    documents: usize,
+    matched: usize,
+}
+
+/// The output file of a worker for one input file.
+struct LocalOutput {
+    file: OutputFile,
+    counts: JoinCounts,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if !opt.sidecar.is_file() {
+        bail!("Sidecar file {:?} does not exist", opt.sidecar);
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let out_paths = mirrored_output_paths(&opt.path, &opt.out, opt.force)?;
+
+    log::info!("Reading sidecar {:?}...", opt.sidecar);
+    let sidecar = Sidecar::read(&opt)?;
+    if sidecar.num_duplicates > 0 {
+        log::warn!(
+            "{} sidecar records have the same key as an earlier record, which they replace",
+            sidecar.num_duplicates.separate_with_commas()
+        );
+    }
+    log::info!(
+        "Joining {} sidecar records",
+        sidecar.records.len().separate_with_commas()
+    );
+    let sidecar = Arc::new(sidecar);
+    let totals = Arc::new(Mutex::new(JoinCounts::default()));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Joining", opt.quiet)?;
+
+    for (path, out_path) in opt.path.iter().zip(out_paths) {
+        let join_document = {
+            let sidecar = sidecar.clone();
+            let key = opt.key.clone();
+            let into = opt.into.clone();
+            let inner = opt.inner;
+
+            move |document: Box<RawValue>,
+                  _: &Path,
+                  _: usize,
+                  local: &mut LocalOutput|
+                  -> Result<()> {
+                local.counts.documents += 1;
+                let raw = document.get();
+                let fields = match raw_field(raw, &key)? {
+                    Some(raw_key) => sidecar
+                        .records
+                        .get(&serde_json::from_str::<Value>(raw_key)?.to_string()),
+                    None => None,
+                };
+                let fields = match fields {
+                    Some(fields) => fields,
+                    None if inner => return Ok(()),
+                    None => {
+                        writeln!(local.file, "{raw}")?;
+                        return Ok(());
+                    }
+                };
+                local.counts.matched += 1;
+
+                let mut document: Map<String, Value> = serde_json::from_str(raw)?;
+                let target = match &into {
+                    Some(into) => match document.entry(into.clone()).or_insert_with(|| json!({})) {
+                        Value::Object(target) => target,
+                        _ => bail!("--into field {:?} isn't an object", into),
+                    },
+                    None => &mut document,
+                };
+                for (name, value) in fields {
+                    target.insert(name.clone(), value.clone());
+                }
+                serde_json::to_writer(&mut local.file, &document)?;
+                local.file.write_all(b"\n")?;
+                Ok(())
+            }
+        };
+
+        // A retry starts the file over, so the output file is created again.
+        let local_output_factory = move || -> Result<LocalOutput> {
+            Ok(LocalOutput {
+                file: util::get_output_file(&out_path, true)?.0,
+                counts: JoinCounts::default(),
+            })
+        };
+
+        let finish_output_callback = {
+            let totals = totals.clone();
+            move |local: LocalOutput| -> Result<()> {
+                local.file.finish()?;
+                let mut totals = totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                totals.documents += local.counts.documents;
+                totals.matched += local.counts.matched;
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            join_document,
+            local_output_factory,
+            finish_output_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let unmatched = totals.documents - totals.matched;
+    if opt.json {
+        println!(
+            "{}",
+            json!({
+                "documents": totals.documents,
+                "matched": totals.matched,
+                "unmatched": unmatched,
+                "sidecar_records": sidecar.records.len(),
+            })
+        );
+    } else if !opt.quiet {
+        println!(
+            "{}: {}/{} ({:.2}%)",
+            style("matched documents").cyan(),
+            totals.matched.separate_with_commas(),
+            totals.documents.separate_with_commas(),
+            100.0 * totals.matched as f64 / totals.documents.max(1) as f64
+        );
+        println!(
+            "{}: {}{}",
+            style("unmatched documents").cyan(),
+            unmatched.separate_with_commas(),
+            if opt.inner { " (dropped)" } else { "" }
+        );
+    }
+
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
+
+/// Look up a field of a record, where nested fields are separated by dots.
+fn lookup<'a>(record: &'a Value, field: &str) -> Option<&'a Value> {
+    field
+        .split('.')
+        .try_fold(record, |value, key| value.get(key))
+}
+
+fn read_lines(path: &Path) -> Result<impl Iterator<Item = Result<String>>> {
+    Ok(LineReader::open(path)?.map(|line| -> Result<String> { Ok(line?) }))
+}
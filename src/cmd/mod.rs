@@ -1,6 +1,36 @@
+pub(crate) mod bench;
+pub(crate) mod bloom;
 pub(crate) mod botk;
+pub(crate) mod compare_tokenizers;
+pub(crate) mod composition;
+pub(crate) mod contains;
+pub(crate) mod contamination;
 pub(crate) mod count;
+pub(crate) mod count_lines;
+pub(crate) mod coverage;
+pub(crate) mod detokenize;
+pub(crate) mod dupes;
+pub(crate) mod freq;
+pub(crate) mod hash;
+pub(crate) mod inspect;
+pub(crate) mod join;
+pub(crate) mod pipeline;
+pub(crate) mod recount;
+pub(crate) mod repack;
+pub(crate) mod report;
+pub(crate) mod sample;
+pub(crate) mod select;
+pub(crate) mod serve;
+pub(crate) mod shuffle;
+pub(crate) mod spans;
+pub(crate) mod split_by;
 pub(crate) mod stats;
+pub(crate) mod stats_diff;
+pub(crate) mod tag;
+pub(crate) mod taxonomy;
+pub(crate) mod tokenize;
 pub(crate) mod topk;
+pub(crate) mod topk_docs;
 pub(crate) mod unique;
-mod util;
+pub(crate) mod util;
+pub(crate) mod watch;
@@ -0,0 +1,631 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use memchr::memmem::Finder;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::stats::LanguageFields;
+use super::util::{expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance};
+use crate::ngrams::{NgramCounter, NgramWindows, TopKNgrams};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::load_tokenizer;
+use crate::util::{self, OutputFile};
+
+/// What documents are counted under by the "langs" analysis if they don't have a language.
+const UNKNOWN_LANGUAGE: &str = "unknown";
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to the YAML file with the analyses to run, like
+    ///
+    ///     tokenizer: gpt2
+    ///     analyses:
+    ///       - type: stats
+    ///       - type: topk
+    ///         ngram: 3
+    ///         k: 20
+    ///         size: 8GiB
+    ///       - type: search
+    ///         name: emails
+    ///         terms: ["@gmail.com", "@yahoo.com"]
+    ///       - type: langs
+    ///
+    /// The top level takes "tokenizer" (default "unicode"), "strip_html", and
+    /// "strip_markdown", which apply to all analyses. A "topk" analysis also takes "hashes"
+    /// (default 5) and "seed", and a "search" analysis takes "case_insensitive". Every
+    /// analysis can be given a "name" for the output.
+    #[structopt(long = "spec", parse(from_os_str))]
+    spec: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the JSON results to.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// The analyses to run, read from '--spec'.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    #[serde(default = "default_tokenizer")]
+    tokenizer: String,
+    #[serde(default)]
+    strip_html: bool,
+    #[serde(default)]
+    strip_markdown: bool,
+    analyses: Vec<Analysis>,
+}
+
+fn default_tokenizer() -> String {
+    "unicode".into()
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+enum Analysis {
+    /// The number of documents, tokens, and bytes, like 'wimbd stats'.
+    Stats(NamedSpec),
+    /// The top-k ngrams, like 'wimbd topk'.
+    Topk(TopkSpec),
+    /// The documents and occurrences of search terms.
+    Search(SearchSpec),
+    /// The documents and tokens for every language, like 'wimbd stats --by-lang'.
+    Langs(NamedSpec),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NamedSpec {
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TopkSpec {
+    name: Option<String>,
+    #[serde(default = "default_ngram")]
+    ngram: usize,
+    #[serde(default = "default_k")]
+    k: usize,
+    #[serde(default = "default_size")]
+    size: String,
+    #[serde(default = "default_hashes")]
+    hashes: u8,
+    seed: Option<u64>,
+}
+
+fn default_ngram() -> usize {
+    3
+}
+
+fn default_k() -> usize {
+    20
+}
+
+fn default_size() -> String {
+    "4GiB".into()
+}
+
+fn default_hashes() -> u8 {
+    5
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SearchSpec {
+    name: Option<String>,
+    terms: Vec<String>,
+    #[serde(default)]
+    case_insensitive: bool,
+}
+
+impl Analysis {
+    fn name(&self) -> String {
+        let (name, default) = match self {
+            Self::Stats(spec) => (&spec.name, "stats"),
+            Self::Topk(spec) => (&spec.name, "topk"),
+            Self::Search(spec) => (&spec.name, "search"),
+            Self::Langs(spec) => (&spec.name, "langs"),
+        };
+        name.clone().unwrap_or_else(|| default.into())
+    }
+
+    fn needs_tokens(&self) -> bool {
+        !matches!(self, Self::Search(_))
+    }
+}
+
+impl Spec {
+    fn read(path: &Path) -> Result<Self> {
+        let spec: Self = serde_yaml::from_reader(File::open(path)?)
+            .with_context(|| format!("failed to parse spec {:?}", path))?;
+        if spec.analyses.is_empty() {
+            bail!("spec {:?} has no analyses", path);
+        }
+        let mut names = HashSet::new();
+        for analysis in &spec.analyses {
+            let name = analysis.name();
+            if !names.insert(name.clone()) {
+                bail!(
+                    "more than one analysis is named {:?}, give them different \"name\"s",
+                    name
+                );
+            }
+            match analysis {
+                Analysis::Topk(spec) => {
+                    if spec.k == 0 || spec.ngram == 0 || spec.hashes == 0 {
+                        bail!(
+                            "\"k\", \"ngram\", and \"hashes\" of {:?} must be greater than 0",
+                            name
+                        );
+                    }
+                }
+                Analysis::Search(spec) => {
+                    if spec.terms.is_empty() || spec.terms.iter().any(|term| term.is_empty()) {
+                        bail!(
+                            "{:?} needs at least one term, and terms can't be empty",
+                            name
+                        );
+                    }
+                }
+                Analysis::Stats(_) | Analysis::Langs(_) => {}
+            }
+        }
+        Ok(spec)
+    }
+}
+
+/// A "search" analysis with its terms compiled.
+struct Search {
+    finders: Vec<Finder<'static>>,
+    case_insensitive: bool,
+}
+
+/// The documents a term was found in and its total occurrences.
+#[derive(Debug, Clone, Copy, Default, Serialize)]
+struct TermCounts {
+    documents: usize,
+    occurrences: usize,
+}
+
+/// The totals of the stats and the langs analyses.
+#[derive(Debug, Clone, Default, Serialize)]
+struct Totals {
+    documents: usize,
+    tokens: usize,
+    bytes: usize,
+}
+
+impl Totals {
+    fn add(&mut self, other: &Totals) {
+        self.documents += other.documents;
+        self.tokens += other.tokens;
+        self.bytes += other.bytes;
+    }
+}
+
+/// The results of the analyses for a single file, except for the top-k which is sent to the
+/// main thread.
+#[derive(Default)]
+struct LocalResults {
+    totals: Totals,
+    searches: Vec<Vec<TermCounts>>,
+    languages: HashMap<String, Totals>,
+    topk: Vec<TopKNgrams<String, AtomicU32>>,
+}
+
+/// The results of all files.
+#[derive(Default)]
+struct Results {
+    totals: Totals,
+    searches: Vec<Vec<TermCounts>>,
+    languages: HashMap<String, Totals>,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if !opt.spec.is_file() {
+        bail!("Spec file {:?} does not exist", opt.spec);
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let mut spec = Spec::read(&opt.spec)?;
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    provenance::record_tokenizer(&spec.tokenizer);
+    let tokenizer = load_tokenizer(&spec.tokenizer)?;
+    let preprocessor = Preprocessor::new(spec.strip_html, spec.strip_markdown);
+    let needs_tokens = spec.analyses.iter().any(Analysis::needs_tokens);
+    let needs_languages = spec
+        .analyses
+        .iter()
+        .any(|analysis| matches!(analysis, Analysis::Langs(_)));
+
+    let searches: Arc<Vec<Search>> = Arc::new(
+        spec.analyses
+            .iter()
+            .filter_map(|analysis| match analysis {
+                Analysis::Search(spec) => Some(Search {
+                    finders: spec
+                        .terms
+                        .iter()
+                        .map(|term| {
+                            let term = if spec.case_insensitive {
+                                term.to_lowercase()
+                            } else {
+                                term.clone()
+                            };
+                            Finder::new(term.as_bytes()).into_owned()
+                        })
+                        .collect(),
+                    case_insensitive: spec.case_insensitive,
+                }),
+                _ => None,
+            })
+            .collect(),
+    );
+
+    // Every top-k analysis has its own counter, since they can have different ngram sizes.
+    let mut topk_specs: Vec<&mut TopkSpec> = spec
+        .analyses
+        .iter_mut()
+        .filter_map(|analysis| match analysis {
+            Analysis::Topk(spec) => Some(spec),
+            _ => None,
+        })
+        .collect();
+    let mut topks: Vec<TopKNgrams<String, AtomicU32>> = Vec::new();
+    let mut counters = Vec::new();
+    for topk_spec in topk_specs.iter_mut() {
+        let size = parse_size_default_to_gb(&topk_spec.size)
+            .map_err(|err| anyhow!("invalid topk size {:?} - {}", topk_spec.size, err))?;
+        log::info!(
+            "Initializing ngram counter for {}-grams...",
+            topk_spec.ngram
+        );
+        let seed = *topk_spec.seed.get_or_insert_with(rand::random);
+        provenance::record_seed(seed);
+        counters.push(Arc::new(NgramCounter::<AtomicU32>::new(
+            (size / 4) as usize,
+            topk_spec.hashes as usize,
+            Some(seed),
+            0,
+        )?));
+        topks.push(TopKNgrams::new(topk_spec.k));
+    }
+    let ngram_sizes: Arc<Vec<usize>> = Arc::new(topk_specs.iter().map(|spec| spec.ngram).collect());
+    let topk_sizes: Vec<usize> = topk_specs.iter().map(|spec| spec.k).collect();
+    let counters = Arc::new(counters);
+    let (tx, rx) = sync_channel::<(usize, Vec<String>, u32)>(512_000);
+
+    let results = Arc::new(Mutex::new(Results {
+        searches: searches
+            .iter()
+            .map(|search| vec![TermCounts::default(); search.finders.len()])
+            .collect(),
+        ..Default::default()
+    }));
+
+    let mut executor =
+        DataExecutor::new(&opt.path, opt.workers, opt.limit, "Analyzing", opt.quiet)?;
+    executor.enable_threads_per_file();
+    executor.set_shared_state();
+
+    for path in &opt.path {
+        let analyze_document = {
+            let tokenizer = tokenizer.clone();
+            let searches = searches.clone();
+            let counters = counters.clone();
+            let ngram_sizes = ngram_sizes.clone();
+            let min_counts: Vec<Arc<AtomicU32>> =
+                topks.iter().map(|topk| topk.min_count()).collect();
+
+            move |document: Box<RawValue>,
+                  _: &Path,
+                  _: usize,
+                  local: &mut LocalResults|
+                  -> Result<()> {
+                let raw = document.get();
+                let data: DataInstance = serde_json::from_str(raw)?;
+                let text = match data.text {
+                    Some(text) => preprocessor.apply(&text).into_owned(),
+                    None => return Ok(()),
+                };
+                let tokens = if needs_tokens {
+                    tokenizer.tokenize(&text)?
+                } else {
+                    Vec::new()
+                };
+
+                local.totals.documents += 1;
+                local.totals.tokens += tokens.len();
+                local.totals.bytes += text.len();
+
+                if needs_languages {
+                    let language = serde_json::from_str::<LanguageFields>(raw)?
+                        .language()
+                        .unwrap_or_else(|| UNKNOWN_LANGUAGE.into());
+                    let totals = local.languages.entry(language).or_default();
+                    totals.documents += 1;
+                    totals.tokens += tokens.len();
+                    totals.bytes += text.len();
+                }
+
+                let mut lowercase = None;
+                for (search, counts) in searches.iter().zip(local.searches.iter_mut()) {
+                    let haystack: &str = if search.case_insensitive {
+                        lowercase.get_or_insert_with(|| text.to_lowercase())
+                    } else {
+                        &text
+                    };
+                    for (finder, counts) in search.finders.iter().zip(counts.iter_mut()) {
+                        let occurrences = finder.find_iter(haystack.as_bytes()).count();
+                        if occurrences > 0 {
+                            counts.documents += 1;
+                            counts.occurrences += occurrences;
+                        }
+                    }
+                }
+
+                for (i, local_topk) in local.topk.iter_mut().enumerate() {
+                    let min_count = min_counts[i].load(Ordering::Relaxed);
+                    for ngram in
+                        NgramWindows::new(tokens.iter().map(|s| s.as_str()), ngram_sizes[i])
+                    {
+                        let count = counters[i].increment(&ngram[..], 1);
+                        if count >= local_topk.min_count && count >= min_count {
+                            local_topk.insert(ngram.iter().map(|s| s.to_string()).collect(), count);
+                        }
+                    }
+                }
+
+                Ok(())
+            }
+        };
+
+        let local_results_factory = {
+            let searches = searches.clone();
+            let topk_sizes = topk_sizes.clone();
+            move || -> Result<LocalResults> {
+                Ok(LocalResults {
+                    searches: searches
+                        .iter()
+                        .map(|search| vec![TermCounts::default(); search.finders.len()])
+                        .collect(),
+                    topk: topk_sizes.iter().map(|&k| TopKNgrams::new(k)).collect(),
+                    ..Default::default()
+                })
+            }
+        };
+
+        let sync_results_callback = {
+            let results = results.clone();
+            let min_counts: Vec<Arc<AtomicU32>> =
+                topks.iter().map(|topk| topk.min_count()).collect();
+            let tx = tx.clone();
+            move |mut local: LocalResults| -> Result<()> {
+                for (i, local_topk) in local.topk.iter_mut().enumerate() {
+                    for (ngram, count) in local_topk.drain() {
+                        if count >= min_counts[i].load(Ordering::Relaxed) {
+                            tx.send((i, ngram.to_vec(), count))?;
+                        }
+                    }
+                }
+
+                let mut results = results
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                results.totals.add(&local.totals);
+                for (counts, local_counts) in results.searches.iter_mut().zip(&local.searches) {
+                    for (counts, local_counts) in counts.iter_mut().zip(local_counts) {
+                        counts.documents += local_counts.documents;
+                        counts.occurrences += local_counts.occurrences;
+                    }
+                }
+                for (language, totals) in &local.languages {
+                    results
+                        .languages
+                        .entry(language.clone())
+                        .or_default()
+                        .add(totals);
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            analyze_document,
+            local_results_factory,
+            sync_results_callback,
+        )?;
+    }
+
+    drop(tx);
+
+    // Collect the top-k candidates from the channel until all jobs are done.
+    while !executor.done() {
+        while let Ok((i, ngram, count)) = rx.recv_timeout(Duration::from_secs(1)) {
+            topks[i].insert(ngram, count);
+            if executor.has_errors() {
+                break;
+            }
+        }
+    }
+
+    executor.join()?;
+
+    let results = results
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let mut topks = topks.into_iter();
+    let mut search_counts = results.searches.iter();
+    let mut output = serde_json::Map::new();
+    for analysis in &spec.analyses {
+        let result = match analysis {
+            Analysis::Stats(_) => json!({
+                "documents": results.totals.documents,
+                "tokens": results.totals.tokens,
+                "bytes": results.totals.bytes,
+                "mean_tokens_per_document":
+                    results.totals.tokens as f64 / results.totals.documents.max(1) as f64,
+            }),
+            Analysis::Topk(_) => {
+                let mut topk = topks.next().unwrap();
+                let mut rows = Vec::new();
+                for (i, (ngram, count)) in topk.drain().iter().enumerate() {
+                    let string = tokenizer.decode(ngram)?;
+                    rows.push(json!({
+                        "tokens": **ngram,
+                        "string": string,
+                        "count": count,
+                        "rank": i + 1,
+                    }));
+                }
+                Value::Array(rows)
+            }
+            Analysis::Search(spec) => {
+                let counts = search_counts.next().unwrap();
+                Value::Object(
+                    spec.terms
+                        .iter()
+                        .zip(counts)
+                        .map(|(term, counts)| Ok((term.clone(), serde_json::to_value(counts)?)))
+                        .collect::<Result<_>>()?,
+                )
+            }
+            Analysis::Langs(_) => {
+                let languages: BTreeMap<&String, &Totals> = results.languages.iter().collect();
+                serde_json::to_value(languages)?
+            }
+        };
+        output.insert(analysis.name(), result);
+    }
+
+    let json_out = Value::Object(output.clone()).to_string();
+    if opt.json {
+        println!("{json_out}");
+    } else if !opt.quiet {
+        print_results(&spec, &output);
+    }
+
+    if let Some(ref mut file) = out_file {
+        writeln!(file, "{json_out}")?;
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn print_results(spec: &Spec, output: &serde_json::Map<String, Value>) {
+    for analysis in &spec.analyses {
+        let name = analysis.name();
+        let result = &output[&name];
+        println!("{}", style(&name).bold());
+        match analysis {
+            Analysis::Stats(_) => {
+                for (key, value) in result.as_object().into_iter().flatten() {
+                    println!("  {}: {}", style(key).cyan(), value);
+                }
+            }
+            Analysis::Topk(_) => {
+                let rows = result.as_array().map(Vec::as_slice).unwrap_or_default();
+                for row in rows {
+                    println!(
+                        "  [{}/{}] {:?} (count ≤ {})",
+                        row["rank"],
+                        rows.len(),
+                        style(row["string"].as_str().unwrap_or_default()).cyan(),
+                        row["count"]
+                    );
+                }
+            }
+            Analysis::Search(_) | Analysis::Langs(_) => {
+                for (key, counts) in result.as_object().into_iter().flatten() {
+                    let counts = counts
+                        .as_object()
+                        .into_iter()
+                        .flatten()
+                        .map(|(name, count)| {
+                            format!(
+                                "{} {}",
+                                count.as_u64().unwrap_or_default().separate_with_commas(),
+                                name
+                            )
+                        })
+                        .collect::<Vec<_>>();
+                    println!("  {}: {}", style(key).cyan(), counts.join(", "));
+                }
+            }
+        }
+    }
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::io::LineReader;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to the ngrams to count, a JSON lines file (optionally compressed), such as the
+    /// output of 'wimbd topk' or 'wimbd botk'. Each line is either an object with a "tokens"
+    /// list or a "string" to tokenize, a JSON list of tokens, or a JSON string to tokenize.
+    /// If an object also has a "count", it's reported next to the exact count.
+    #[structopt(long = "ngrams", parse(from_os_str))]
+    ngrams: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e. each line
+    /// will be a JSON object with the keys "tokens", "string", and "count", and
+    /// "previous_count" if the ngram had one. Ngrams are in the same order as the input.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace. It should be the same tokenizer the ngrams were counted with.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+/// An ngram to count.
+#[derive(Debug, Clone)]
+struct Target {
+    tokens: Vec<String>,
+    string: String,
+    previous_count: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct RecountedNgram<'a> {
+    tokens: &'a [String],
+    string: &'a str,
+    count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    previous_count: Option<u64>,
+}
+
+/// The ngrams to count, indexed by their tokens.
+struct Targets {
+    targets: Vec<Target>,
+    /// The index of every distinct ngram, since the input may have duplicates.
+    index: HashMap<Vec<String>, usize>,
+    /// The distinct ngram lengths in tokens.
+    lengths: Vec<usize>,
+}
+
+impl Targets {
+    fn read(
+        path: &Path,
+        tokenizer: &Arc<dyn Tokenizer>,
+        preprocessor: Preprocessor,
+    ) -> Result<Self> {
+        let mut targets = Vec::new();
+        let mut index = HashMap::new();
+        let mut lengths = BTreeSet::new();
+        for (i, line) in read_lines(path)?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let target = parse_target(&line, tokenizer, preprocessor)
+                .with_context(|| format!("failed to parse ngram on line {}", i + 1))?;
+            if target.tokens.is_empty() {
+                bail!("ngram on line {} has no tokens", i + 1);
+            }
+            lengths.insert(target.tokens.len());
+            let next_index = index.len();
+            index.entry(target.tokens.clone()).or_insert(next_index);
+            targets.push(target);
+        }
+        Ok(Self {
+            targets,
+            index,
+            lengths: lengths.into_iter().collect(),
+        })
+    }
+
+    /// Add the occurrences of every ngram in the tokens to the counts, which are by
+    /// distinct ngram. Overlapping occurrences all count.
+    fn count(&self, tokens: &[String], counts: &mut [usize]) {
+        for &length in &self.lengths {
+            for window in tokens.windows(length) {
+                if let Some(&i) = self.index.get(window) {
+                    counts[i] += 1;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if !opt.ngrams.is_file() {
+        bail!("Ngrams file {:?} does not exist", opt.ngrams);
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let targets = Arc::new(Targets::read(&opt.ngrams, &tokenizer, preprocessor)?);
+    if targets.targets.is_empty() {
+        bail!("No ngrams found in {:?}", opt.ngrams);
+    }
+    log::info!(
+        "Counting {} distinct ngrams...",
+        targets.index.len().separate_with_commas()
+    );
+
+    let num_distinct = targets.index.len();
+    let totals = Arc::new(Mutex::new(vec![0usize; num_distinct]));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Counting", opt.quiet)?;
+
+    for path in &opt.path {
+        let count_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let targets = targets.clone();
+
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_counts: &mut Vec<usize>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    let tokens = tokenizer.tokenize(&text)?;
+                    targets.count(&tokens, local_counts);
+                }
+                Ok(())
+            }
+        };
+
+        // Counts are only added once the whole file is done so that retries don't count any
+        // document twice.
+        let sync_counts_callback = {
+            let totals = totals.clone();
+
+            move |local_counts: Vec<usize>| -> Result<()> {
+                let mut totals = totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                for (total, count) in totals.iter_mut().zip(local_counts) {
+                    *total += count;
+                }
+                Ok(())
+            }
+        };
+
+        let local_counts_factory = move || -> Result<Vec<usize>> { Ok(vec![0; num_distinct]) };
+
+        executor.execute_with_callback(
+            path,
+            count_ngrams,
+            local_counts_factory,
+            sync_counts_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+
+    let mut num_with_previous: usize = 0;
+    let mut num_matching: usize = 0;
+    let mut max_overestimate: u64 = 0;
+    let mut max_underestimate: u64 = 0;
+    for (i, target) in targets.targets.iter().enumerate() {
+        let count = totals[targets.index[&target.tokens]];
+        if let Some(previous_count) = target.previous_count {
+            num_with_previous += 1;
+            let count = count as u64;
+            if previous_count == count {
+                num_matching += 1;
+            } else if previous_count > count {
+                max_overestimate = max_overestimate.max(previous_count - count);
+            } else {
+                max_underestimate = max_underestimate.max(count - previous_count);
+            }
+        }
+
+        let row = RecountedNgram {
+            tokens: &target.tokens,
+            string: &target.string,
+            count,
+            previous_count: target.previous_count,
+        };
+        if let Some(ref mut file) = out_file {
+            serde_json::to_writer(&mut *file, &row)?;
+            file.write_all(b"\n")?;
+        } else if opt.json {
+            println!("{}", serde_json::to_string(&row)?);
+        } else if !opt.quiet {
+            let previous = match target.previous_count {
+                Some(previous_count) => format!(" (previously {previous_count})"),
+                None => String::new(),
+            };
+            println!(
+                "[{}/{}] {:?} count = {}{}",
+                i + 1,
+                targets.targets.len(),
+                style(&target.string).cyan(),
+                count,
+                previous
+            );
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+
+    if num_with_previous > 0 {
+        if opt.json && opt.out.is_some() {
+            println!(
+                "{}",
+                json!({
+                    "num_ngrams": targets.targets.len(),
+                    "num_with_previous_count": num_with_previous,
+                    "num_matching": num_matching,
+                    "max_overestimate": max_overestimate,
+                    "max_underestimate": max_underestimate,
+                })
+            );
+        } else if !opt.quiet && !opt.json {
+            println!(
+                "{}: {}/{}",
+                style("previous counts that match").cyan(),
+                num_matching.separate_with_commas(),
+                num_with_previous.separate_with_commas()
+            );
+            println!(
+                "{}: {}",
+                style("max overestimate").cyan(),
+                max_overestimate.separate_with_commas()
+            );
+            if max_underestimate > 0 {
+                println!(
+                    "{}: {}",
+                    style("max underestimate").cyan(),
+                    max_underestimate.separate_with_commas()
+                );
+            }
+        }
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn parse_target(
+    line: &str,
+    tokenizer: &Arc<dyn Tokenizer>,
+    preprocessor: Preprocessor,
+) -> Result<Target> {
+    let value: Value = serde_json::from_str(line)?;
+    let (tokens, string, previous_count) = match value {
+        Value::Object(mut object) => {
+            let previous_count = match object.get("count") {
+                Some(count) => Some(
+                    count
+                        .as_u64()
+                        .ok_or_else(|| anyhow!("\"count\" must be a non-negative integer"))?,
+                ),
+                None => None,
+            };
+            match (object.remove("tokens"), object.remove("string")) {
+                (Some(tokens), string) => (
+                    parse_tokens(tokens)?,
+                    string.and_then(|s| s.as_str().map(String::from)),
+                    previous_count,
+                ),
+                (None, Some(Value::String(string))) => (
+                    tokenizer.tokenize(&preprocessor.apply(&string))?,
+                    Some(string),
+                    previous_count,
+                ),
+                _ => bail!("expected a \"tokens\" list or a \"string\""),
+            }
+        }
+        Value::Array(_) => (parse_tokens(value)?, None, None),
+        Value::String(string) => (
+            tokenizer.tokenize(&preprocessor.apply(&string))?,
+            Some(string),
+            None,
+        ),
+        _ => bail!("expected an object, a list of tokens, or a string"),
+    };
+    let string = match string {
+        Some(string) => string,
+        None => tokenizer.decode(&tokens)?,
+    };
+    Ok(Target {
+        tokens,
+        string,
+        previous_count,
+    })
+}
+
+fn parse_tokens(value: Value) -> Result<Vec<String>> {
+    match value {
+        Value::Array(tokens) => tokens
+            .into_iter()
+            .map(|token| match token {
+                Value::String(token) => Ok(token),
+                _ => bail!("tokens must be strings"),
+            })
+            .collect(),
+        _ => bail!("\"tokens\" must be a list"),
+    }
+}
+
+fn read_lines(path: &Path) -> Result<impl Iterator<Item = Result<String>>> {
+    Ok(LineReader::open(path)?.map(|line| -> Result<String> { Ok(line?) }))
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
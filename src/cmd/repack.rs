@@ -0,0 +1,211 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::channel;
+
+use anyhow::{anyhow, bail, Result};
+use humantime::format_duration;
+use structopt::StructOpt;
+use thousands::Separable;
+use threadpool::ThreadPool;
+
+use super::util::{expand_paths, parse_size_default_to_gb};
+use crate::io::{LineReader, ShardWriter};
+use crate::progress::get_file_progress_bar;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The number of output shards to write.
+    #[structopt(short = "n", long = "num-shards")]
+    num_shards: Option<usize>,
+
+    /// The target (compressed) size of each output shard, e.g. "1GiB".
+    /// This is an alternative to '-n/--num-shards'.
+    #[structopt(long = "shard-size", parse(try_from_str = parse_size_default_to_gb))]
+    shard_size: Option<u64>,
+
+    /// Keep documents in the same order as the input files. By default input files are
+    /// assigned to shards to balance the shard sizes as much as possible, which doesn't
+    /// preserve the order across shards.
+    #[structopt(long = "preserve-order")]
+    preserve_order: bool,
+
+    /// The directory to write the output shards to.
+    #[structopt(short = "o", long = "out")]
+    out: PathBuf,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output shards if they already exist.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    opt.path = expand_paths(&opt.path)?;
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+
+    let mut sizes = Vec::with_capacity(opt.path.len());
+    for path in &opt.path {
+        if !path.is_file() {
+            bail!("File {:?} does not exist", path);
+        }
+        sizes.push(fs::metadata(path)?.len());
+    }
+    let total_size: u64 = sizes.iter().sum();
+
+    let num_shards = match (opt.num_shards, opt.shard_size) {
+        (Some(_), Some(_)) => bail!("only one of -n/--num-shards or --shard-size can be given"),
+        (None, None) => bail!("one of -n/--num-shards or --shard-size is required"),
+        (Some(0), None) => bail!("-n/--num-shards must be greater than 0"),
+        (None, Some(0)) => bail!("--shard-size must be greater than 0"),
+        (Some(num_shards), None) => num_shards,
+        (None, Some(shard_size)) => std::cmp::max(1, total_size.div_ceil(shard_size) as usize),
+    };
+    let num_shards = std::cmp::min(num_shards, opt.path.len());
+
+    let assignments = if opt.preserve_order {
+        assign_contiguous(&sizes, num_shards)
+    } else {
+        assign_balanced(&sizes, num_shards)
+    };
+
+    fs::create_dir_all(&opt.out)?;
+    let shard_paths: Vec<PathBuf> = (0..assignments.len())
+        .map(|i| opt.out.join(format!("part-{i:05}.json.gz")))
+        .collect();
+    for path in &shard_paths {
+        if path.is_file() {
+            if opt.force {
+                log::warn!("Overwriting output file {:?}", path);
+            } else {
+                bail!(
+                    "Output file {:?} already exists, use --force to overwrite",
+                    path
+                );
+            }
+        }
+    }
+
+    log::info!(
+        "Repacking {} files ({} bytes) into {} shards...",
+        opt.path.len().separate_with_commas(),
+        total_size.separate_with_commas(),
+        assignments.len()
+    );
+
+    let start = std::time::Instant::now();
+    let workers = std::cmp::max(
+        1,
+        std::cmp::min(
+            opt.workers
+                .unwrap_or_else(|| std::cmp::min(64, num_cpus::get())),
+            assignments.len(),
+        ),
+    );
+    let pool = ThreadPool::with_name("wimbd-worker".to_string(), workers);
+    let progress = get_file_progress_bar("Repacking", assignments.len(), opt.quiet)?;
+    let (tx, rx) = channel::<Result<usize>>();
+
+    for (index, (shard_path, files)) in shard_paths.into_iter().zip(assignments).enumerate() {
+        let inputs: Vec<PathBuf> = files.iter().map(|&i| opt.path[i].clone()).collect();
+        let out = opt.out.clone();
+        let tx = tx.clone();
+        pool.execute(move || {
+            let result = write_shard(&out, index, &inputs)
+                .map_err(|err| anyhow!("{err:?} encounted while writing {shard_path:?}"));
+            tx.send(result).ok();
+        });
+    }
+    drop(tx);
+
+    let mut total_lines: usize = 0;
+    for result in rx {
+        total_lines += result?;
+        progress.inc(1);
+    }
+    pool.join();
+    progress.finish();
+
+    log::info!(
+        "Repacked {} JSON lines in {}",
+        total_lines.separate_with_commas(),
+        format_duration(std::time::Duration::from_secs(start.elapsed().as_secs()))
+    );
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
+
+/// Assign files to shards in order, starting a new shard once the current one reaches the
+/// target size.
+fn assign_contiguous(sizes: &[u64], num_shards: usize) -> Vec<Vec<usize>> {
+    let total: u64 = sizes.iter().sum();
+    let mut shards: Vec<Vec<usize>> = vec![Vec::new()];
+    let mut cumulative: u64 = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        // The boundary of shard `j` is at `total * (j + 1) / num_shards`.
+        let boundary = total * shards.len() as u64 / num_shards as u64;
+        if cumulative >= boundary && !shards.last().unwrap().is_empty() && shards.len() < num_shards
+        {
+            shards.push(Vec::new());
+        }
+        shards.last_mut().unwrap().push(i);
+        cumulative += size;
+    }
+    shards
+}
+
+/// Assign files to shards greedily, largest files first, always adding to the smallest
+/// shard so far. Files within a shard keep their relative input order.
+fn assign_balanced(sizes: &[u64], num_shards: usize) -> Vec<Vec<usize>> {
+    let mut order: Vec<usize> = (0..sizes.len()).collect();
+    order.sort_by_key(|&i| Reverse(sizes[i]));
+
+    let mut heap: BinaryHeap<Reverse<(u64, usize)>> =
+        (0..num_shards).map(|j| Reverse((0, j))).collect();
+    let mut shards: Vec<Vec<usize>> = vec![Vec::new(); num_shards];
+    for i in order {
+        let Reverse((size, j)) = heap.pop().unwrap();
+        shards[j].push(i);
+        heap.push(Reverse((size + sizes[i], j)));
+    }
+
+    for shard in shards.iter_mut() {
+        shard.sort_unstable();
+    }
+    shards.retain(|shard| !shard.is_empty());
+    shards
+}
+
+/// Concatenate the lines of the input files into the gzip-compressed shard with the given index.
+fn write_shard(out: &Path, index: usize, inputs: &[PathBuf]) -> Result<usize> {
+    let mut writer = ShardWriter::new(out, "part", "json.gz")?.starting_at(index);
+    for input in inputs {
+        let mut reader = LineReader::open(input)?;
+        let mut line = String::new();
+        while reader.read_line_into(&mut line)? {
+            writer.write_line(&line)?;
+        }
+    }
+    let num_lines = writer.lines_written();
+    writer.finish()?;
+    Ok(num_lines)
+}
@@ -0,0 +1,510 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use crate::io::open_decompressed;
+use crate::util;
+
+/// Max number of rows to render for any table.
+const MAX_ROWS: usize = 50;
+
+/// Width (in characters) of the text bar charts in Markdown output.
+const BAR_WIDTH: usize = 40;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// The name of the dataset, used as the title of the report.
+    #[structopt(long = "name", default_value = "Dataset")]
+    name: String,
+
+    /// Path to a JSON output file from 'wimbd stats'.
+    #[structopt(long = "stats", parse(from_os_str))]
+    stats: Option<PathBuf>,
+
+    /// Path to a JSON lines output file from 'wimbd topk' or 'wimbd botk'.
+    /// Can be given multiple times.
+    #[structopt(long = "topk", number_of_values = 1, parse(from_os_str))]
+    topk: Vec<PathBuf>,
+
+    /// Any other JSON or JSON lines output to include as a table, given as 'TITLE=PATH',
+    /// e.g. '--section "Languages=lang.jsonl"'. Can be given multiple times.
+    #[structopt(long = "section", number_of_values = 1)]
+    section: Vec<String>,
+
+    /// The output format, either "markdown" or "html". By default this is inferred from the
+    /// extension of the output file, falling back to "markdown".
+    #[structopt(long = "format")]
+    format: Option<String>,
+
+    /// A path to write the report to. If not given the report is written to stdout.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// A renderable piece of a report section.
+enum Block {
+    Heading(String),
+    Table {
+        headers: Vec<String>,
+        rows: Vec<Vec<String>>,
+    },
+    Bars {
+        labels: Vec<String>,
+        values: Vec<f64>,
+    },
+}
+
+struct Section {
+    title: String,
+    blocks: Vec<Block>,
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    if opt.stats.is_none() && opt.topk.is_empty() && opt.section.is_empty() {
+        bail!("at least one of --stats, --topk, or --section is required");
+    }
+
+    let format = match &opt.format {
+        Some(format) => format.to_lowercase(),
+        None => match opt.out.as_ref().and_then(|p| p.extension()) {
+            Some(ext) if ext == "html" || ext == "htm" => "html".to_string(),
+            _ => "markdown".to_string(),
+        },
+    };
+    if format != "markdown" && format != "html" {
+        bail!("--format must be one of 'markdown' or 'html'");
+    }
+
+    let mut sections = Vec::new();
+    if let Some(ref path) = opt.stats {
+        sections.push(stats_section(path)?);
+    }
+    for path in &opt.topk {
+        sections.push(topk_section(path)?);
+    }
+    for spec in &opt.section {
+        let (title, path) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("--section must be given as 'TITLE=PATH', got '{}'", spec))?;
+        sections.push(generic_section(title, Path::new(path))?);
+    }
+
+    let report = if format == "html" {
+        render_html(&opt.name, &sections)
+    } else {
+        render_markdown(&opt.name, &sections)
+    };
+
+    if let Some(ref path) = opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        }
+        let (mut file, path) = util::get_output_file(path, opt.force)?;
+        file.write_all(report.as_bytes())?;
+        file.finish()?;
+        log::info!("Report written to {:?}", path);
+    } else {
+        print!("{report}");
+    }
+
+    Ok(())
+}
+
+fn read_json(path: &Path) -> Result<Value> {
+    let mut contents = String::new();
+    open_decompressed(path)
+        .and_then(|mut reader| Ok(reader.read_to_string(&mut contents)?))
+        .with_context(|| format!("failed to read {path:?}"))?;
+    serde_json::from_str(&contents).with_context(|| format!("failed to parse {path:?}"))
+}
+
+fn read_json_lines(path: &Path) -> Result<Vec<Value>> {
+    let reader = BufReader::new(
+        open_decompressed(path).with_context(|| format!("failed to read {path:?}"))?,
+    );
+    let mut values = Vec::new();
+    for (i, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        values.push(
+            serde_json::from_str(&line)
+                .with_context(|| format!("failed to parse line {} of {path:?}", i + 1))?,
+        );
+    }
+    Ok(values)
+}
+
+fn file_stem(path: &Path) -> String {
+    path.file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string_lossy().to_string())
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Null => "".to_string(),
+        Value::String(s) => s.clone(),
+        Value::Number(n) => {
+            if let Some(n) = n.as_u64() {
+                n.separate_with_commas()
+            } else if let Some(n) = n.as_i64() {
+                n.separate_with_commas()
+            } else {
+                format!("{:.4}", n.as_f64().unwrap_or(f64::NAN))
+            }
+        }
+        other => other.to_string(),
+    }
+}
+
+fn stats_section(path: &Path) -> Result<Section> {
+    // Runs with '--append' add a line to the report, so the last one is the latest.
+    let stats = read_json_lines(path)?
+        .pop()
+        .ok_or_else(|| anyhow!("{path:?} is empty"))?;
+    let stats = stats
+        .as_object()
+        .ok_or_else(|| anyhow!("expected a JSON object in {path:?}"))?;
+
+    let mut blocks = Vec::new();
+    let mut summary_rows = Vec::new();
+    for (key, value) in stats {
+        match value {
+            Value::Array(items) => {
+                // Lists of document pointers, e.g. 'max_token_documents'.
+                if let Some(block) = records_table(items) {
+                    blocks.push(Block::Heading(key.replace('_', " ")));
+                    blocks.push(block);
+                }
+            }
+            // Histograms and breakdowns, e.g. 'tokens_per_document' or 'languages'.
+            Value::Object(fields) => object_blocks(&key.replace('_', " "), fields, &mut blocks),
+            _ => summary_rows.push(vec![key.replace('_', " "), format_value(value)]),
+        }
+    }
+    blocks.insert(
+        0,
+        Block::Table {
+            headers: vec!["metric".to_string(), "value".to_string()],
+            rows: summary_rows,
+        },
+    );
+
+    Ok(Section {
+        title: "Summary statistics".to_string(),
+        blocks,
+    })
+}
+
+/// Render a nested object of a stats report under the heading `title`. Histograms are rendered
+/// as a table and a bar chart, objects of objects as one table with a row per key, and any other
+/// object as a table of its scalar fields followed by its nested fields.
+fn object_blocks(title: &str, fields: &serde_json::Map<String, Value>, blocks: &mut Vec<Block>) {
+    if let (Some(Value::Array(edges)), Some(Value::Array(counts))) =
+        (fields.get("edges"), fields.get("counts"))
+    {
+        let labels: Vec<String> = edges
+            .iter()
+            .enumerate()
+            .map(|(i, edge)| match edges.get(i + 1) {
+                Some(next) => format!("[{}, {})", format_value(edge), format_value(next)),
+                None => format!(">={}", format_value(edge)),
+            })
+            .collect();
+        let values: Vec<f64> = counts.iter().map(|c| c.as_f64().unwrap_or(0.0)).collect();
+        // Most buckets are empty, so only the range between the first and last non-empty
+        // bucket is shown.
+        let start = values.iter().position(|&v| v > 0.0).unwrap_or(0);
+        let end = values.iter().rposition(|&v| v > 0.0).map_or(0, |i| i + 1);
+        let labels = labels[start..end.max(start)].to_vec();
+        let values = values[start..end.max(start)].to_vec();
+        blocks.push(Block::Heading(title.to_string()));
+        blocks.push(Block::Table {
+            headers: vec!["bucket".to_string(), "documents".to_string()],
+            rows: labels
+                .iter()
+                .zip(&counts[start..])
+                .map(|(label, count)| vec![label.clone(), format_value(count)])
+                .collect(),
+        });
+        blocks.push(Block::Bars { labels, values });
+        return;
+    }
+
+    if !fields.is_empty() && fields.values().all(Value::is_object) {
+        let mut headers = vec!["name".to_string()];
+        for value in fields.values().take(MAX_ROWS) {
+            for key in value.as_object().into_iter().flat_map(|o| o.keys()) {
+                if !headers.contains(key) {
+                    headers.push(key.clone());
+                }
+            }
+        }
+        let rows = fields
+            .iter()
+            .take(MAX_ROWS)
+            .map(|(name, value)| {
+                let mut row = vec![name.clone()];
+                row.extend(
+                    headers[1..]
+                        .iter()
+                        .map(|h| value.get(h).map(format_value).unwrap_or_default()),
+                );
+                row
+            })
+            .collect();
+        blocks.push(Block::Heading(title.to_string()));
+        blocks.push(Block::Table { headers, rows });
+        return;
+    }
+
+    let rows: Vec<Vec<String>> = fields
+        .iter()
+        .filter(|(_, value)| !value.is_object() && !value.is_array())
+        .map(|(key, value)| vec![key.replace('_', " "), format_value(value)])
+        .collect();
+    if !rows.is_empty() {
+        blocks.push(Block::Heading(title.to_string()));
+        blocks.push(Block::Table {
+            headers: vec!["metric".to_string(), "value".to_string()],
+            rows,
+        });
+    }
+    for (key, value) in fields {
+        let title = format!("{}: {}", title, key.replace('_', " "));
+        match value {
+            Value::Object(fields) => object_blocks(&title, fields, blocks),
+            Value::Array(items) => {
+                if let Some(block) = records_table(items) {
+                    blocks.push(Block::Heading(title));
+                    blocks.push(block);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn topk_section(path: &Path) -> Result<Section> {
+    let entries = read_json_lines(path)?;
+    let mut rows = Vec::new();
+    let mut labels = Vec::new();
+    let mut values = Vec::new();
+    for (i, entry) in entries.iter().take(MAX_ROWS).enumerate() {
+        let rank = entry
+            .get("rank")
+            .map(format_value)
+            .unwrap_or_else(|| (i + 1).to_string());
+        let ngram = entry.get("string").map(format_value).unwrap_or_default();
+        let count = entry.get("count").cloned().unwrap_or(Value::Null);
+        rows.push(vec![rank, ngram.clone(), format_value(&count)]);
+        labels.push(ngram);
+        values.push(count.as_f64().unwrap_or(0.0));
+    }
+
+    Ok(Section {
+        title: format!("Ngrams: {}", file_stem(path)),
+        blocks: vec![
+            Block::Table {
+                headers: vec!["rank".to_string(), "ngram".to_string(), "count".to_string()],
+                rows,
+            },
+            Block::Bars { labels, values },
+        ],
+    })
+}
+
+fn generic_section(title: &str, path: &Path) -> Result<Section> {
+    // Try parsing as a single JSON document first, then fall back to JSON lines.
+    let records = match read_json(path) {
+        Ok(Value::Array(items)) => items,
+        Ok(Value::Object(map)) => {
+            let rows = map
+                .iter()
+                .map(|(k, v)| vec![k.replace('_', " "), format_value(v)])
+                .collect();
+            return Ok(Section {
+                title: title.to_string(),
+                blocks: vec![Block::Table {
+                    headers: vec!["key".to_string(), "value".to_string()],
+                    rows,
+                }],
+            });
+        }
+        _ => read_json_lines(path)?,
+    };
+
+    Ok(Section {
+        title: title.to_string(),
+        blocks: records_table(&records).into_iter().collect(),
+    })
+}
+
+/// Build a table from a list of JSON objects, using the union of keys as the columns.
+fn records_table(records: &[Value]) -> Option<Block> {
+    let mut headers: Vec<String> = Vec::new();
+    for record in records.iter().take(MAX_ROWS) {
+        for key in record.as_object()?.keys() {
+            if !headers.contains(key) {
+                headers.push(key.clone());
+            }
+        }
+    }
+    if headers.is_empty() {
+        return None;
+    }
+
+    let rows = records
+        .iter()
+        .take(MAX_ROWS)
+        .map(|record| {
+            headers
+                .iter()
+                .map(|h| record.get(h).map(format_value).unwrap_or_default())
+                .collect()
+        })
+        .collect();
+
+    Some(Block::Table { headers, rows })
+}
+
+fn escape_markdown(s: &str) -> String {
+    s.replace('|', "\\|").replace('\n', " ")
+}
+
+fn render_markdown(name: &str, sections: &[Section]) -> String {
+    let mut out = format!("# Data card: {name}\n");
+    for section in sections {
+        out.push_str(&format!("\n## {}\n", section.title));
+        for block in &section.blocks {
+            match block {
+                Block::Heading(heading) => out.push_str(&format!("\n### {heading}\n")),
+                Block::Table { headers, rows } => {
+                    out.push_str(&format!("\n| {} |\n", headers.join(" | ")));
+                    out.push_str(&format!("|{}\n", " --- |".repeat(headers.len())));
+                    for row in rows {
+                        let cells: Vec<String> = row.iter().map(|c| escape_markdown(c)).collect();
+                        out.push_str(&format!("| {} |\n", cells.join(" | ")));
+                    }
+                }
+                Block::Bars { labels, values } => {
+                    let max = values.iter().cloned().fold(0.0, f64::max);
+                    if labels.is_empty() || max <= 0.0 {
+                        continue;
+                    }
+                    let label_width = labels
+                        .iter()
+                        .map(|l| l.chars().count())
+                        .max()
+                        .unwrap_or(0)
+                        .min(30);
+                    out.push_str("\n```text\n");
+                    for (label, value) in labels.iter().zip(values) {
+                        let label: String = label.chars().take(label_width).collect();
+                        let width = ((value / max) * BAR_WIDTH as f64).round() as usize;
+                        out.push_str(&format!(
+                            "{:<label_width$} {} {}\n",
+                            label.replace('\n', " "),
+                            "█".repeat(width),
+                            value
+                        ));
+                    }
+                    out.push_str("```\n");
+                }
+            }
+        }
+    }
+    out
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn render_html(name: &str, sections: &[Section]) -> String {
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Data card: {}</title>\n\
+         <style>body {{ font-family: sans-serif; max-width: 960px; margin: auto; }} \
+         table {{ border-collapse: collapse; margin: 1em 0; }} \
+         td, th {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}</style>\n\
+         </head>\n<body>\n<h1>Data card: {}</h1>\n",
+        escape_html(name),
+        escape_html(name)
+    );
+    for section in sections {
+        out.push_str(&format!("<h2>{}</h2>\n", escape_html(&section.title)));
+        for block in &section.blocks {
+            match block {
+                Block::Heading(heading) => {
+                    out.push_str(&format!("<h3>{}</h3>\n", escape_html(heading)))
+                }
+                Block::Table { headers, rows } => {
+                    out.push_str("<table>\n<tr>");
+                    for header in headers {
+                        out.push_str(&format!("<th>{}</th>", escape_html(header)));
+                    }
+                    out.push_str("</tr>\n");
+                    for row in rows {
+                        out.push_str("<tr>");
+                        for cell in row {
+                            out.push_str(&format!("<td>{}</td>", escape_html(cell)));
+                        }
+                        out.push_str("</tr>\n");
+                    }
+                    out.push_str("</table>\n");
+                }
+                Block::Bars { labels, values } => {
+                    let max = values.iter().cloned().fold(0.0, f64::max);
+                    if labels.is_empty() || max <= 0.0 {
+                        continue;
+                    }
+                    // A simple horizontal bar chart as inline SVG.
+                    let row_height = 20;
+                    let label_width = 240;
+                    let bar_width = 480.0;
+                    out.push_str(&format!(
+                        "<svg width=\"{}\" height=\"{}\" xmlns=\"http://www.w3.org/2000/svg\">\n",
+                        label_width + bar_width as usize + 120,
+                        row_height * labels.len()
+                    ));
+                    for (i, (label, value)) in labels.iter().zip(values).enumerate() {
+                        let y = i * row_height;
+                        let width = (value / max) * bar_width;
+                        out.push_str(&format!(
+                            "<text x=\"0\" y=\"{}\" font-size=\"12\">{}</text>\
+                             <rect x=\"{}\" y=\"{}\" width=\"{:.1}\" height=\"{}\" fill=\"#4a90d9\"/>\
+                             <text x=\"{:.1}\" y=\"{}\" font-size=\"12\">{}</text>\n",
+                            y + 14,
+                            escape_html(&label.chars().take(36).collect::<String>()),
+                            label_width,
+                            y + 2,
+                            width,
+                            row_height - 4,
+                            label_width as f64 + width + 4.0,
+                            y + 14,
+                            value
+                        ));
+                    }
+                    out.push_str("</svg>\n");
+                }
+            }
+        }
+    }
+    out.push_str("</body>\n</html>\n");
+    out
+}
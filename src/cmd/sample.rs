@@ -0,0 +1,271 @@
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use rand::{random, rngs::StdRng, Rng};
+use serde_json::Value;
+use structopt::StructOpt;
+
+use super::util::{derive_rng, expand_paths, DataExecutor};
+use crate::provenance;
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The number of documents to sample. Ignored when '--stratify-by' is given.
+    #[structopt(short = "n", long = "num", default_value = "100")]
+    num: usize,
+
+    /// A top-level field to stratify the sample by, e.g. "lang". Documents missing the field
+    /// are grouped into a "null" stratum. Requires '--per-stratum'.
+    #[structopt(long = "stratify-by")]
+    stratify_by: Option<String>,
+
+    /// The number of documents to sample from each stratum when using '--stratify-by'.
+    #[structopt(long = "per-stratum")]
+    per_stratum: Option<usize>,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Set the seed for sampling. By default the seed is chosen at random.
+    /// Given the same seed and the same list of paths the sample is deterministic.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// A path to write the sampled documents to as JSON lines.
+    /// If not given, the documents are written to stdout.
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// A sampled document. Each document is assigned a uniformly random key and we keep the
+/// documents with the smallest keys, which gives a uniform sample that can be merged across
+/// workers.
+#[derive(Debug)]
+struct Sampled {
+    key: u64,
+    document: Value,
+}
+
+impl PartialEq for Sampled {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for Sampled {}
+
+impl PartialOrd for Sampled {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Sampled {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        self.key.cmp(&other.key)
+    }
+}
+
+/// A bounded sample per stratum.
+struct StratifiedSample {
+    size: usize,
+    strata: HashMap<String, BinaryHeap<Sampled>>,
+}
+
+impl StratifiedSample {
+    fn new(size: usize) -> Self {
+        Self {
+            size,
+            strata: HashMap::new(),
+        }
+    }
+
+    fn would_keep(&self, stratum: &str, key: u64) -> bool {
+        match self.strata.get(stratum) {
+            Some(heap) if heap.len() >= self.size => {
+                heap.peek().map(|max| key < max.key).unwrap_or(true)
+            }
+            _ => true,
+        }
+    }
+
+    fn insert(&mut self, stratum: String, sampled: Sampled) {
+        let heap = self.strata.entry(stratum).or_default();
+        heap.push(sampled);
+        if heap.len() > self.size {
+            heap.pop();
+        }
+    }
+
+    fn merge(&mut self, other: StratifiedSample) {
+        for (stratum, heap) in other.strata {
+            for sampled in heap {
+                self.insert(stratum.clone(), sampled);
+            }
+        }
+    }
+}
+
+/// The local state of a worker: its own RNG and sample.
+struct LocalSample {
+    rng: StdRng,
+    sample: StratifiedSample,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+    let size = match (&opt.stratify_by, opt.per_stratum) {
+        (Some(_), Some(per_stratum)) => per_stratum,
+        (Some(_), None) => bail!("--per-stratum is required with --stratify-by"),
+        (None, Some(_)) => bail!("--per-stratum can only be used with --stratify-by"),
+        (None, None) => opt.num,
+    };
+    if size == 0 {
+        bail!("sample size must be greater than 0");
+    }
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let seed = opt.seed.unwrap_or_else(random);
+    provenance::record_seed(seed);
+    let sample = Arc::new(Mutex::new(StratifiedSample::new(size)));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Sampling", opt.quiet)?;
+
+    for (i, path) in opt.path.iter().enumerate() {
+        let local_sample_factory = move || -> Result<LocalSample> {
+            Ok(LocalSample {
+                // Each file gets its own RNG stream so results don't depend on scheduling.
+                rng: derive_rng(seed, "sample", i),
+                sample: StratifiedSample::new(size),
+            })
+        };
+
+        let stratify_by = opt.stratify_by.clone();
+        let collect_sample =
+            move |document: Value, _: &Path, _: usize, local: &mut LocalSample| -> Result<()> {
+                let stratum = match &stratify_by {
+                    Some(field) => match document.get(field) {
+                        Some(Value::String(s)) => s.clone(),
+                        Some(other) => other.to_string(),
+                        None => "null".to_string(),
+                    },
+                    None => String::new(),
+                };
+                let key: u64 = local.rng.gen();
+                if local.sample.would_keep(&stratum, key) {
+                    local.sample.insert(stratum, Sampled { key, document });
+                }
+                Ok(())
+            };
+
+        let sync_sample_callback = {
+            let sample = sample.clone();
+            move |local: LocalSample| -> Result<()> {
+                sample
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .merge(local.sample);
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            collect_sample,
+            local_sample_factory,
+            sync_sample_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let mut sample = sample
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let strata: BTreeMap<String, BinaryHeap<Sampled>> = sample.strata.drain().collect();
+    for (stratum, heap) in strata {
+        if opt.stratify_by.is_some() {
+            if heap.len() < size {
+                log::warn!(
+                    "Stratum {:?} only has {} documents, fewer than the {} requested",
+                    stratum,
+                    heap.len(),
+                    size
+                );
+            }
+            if !opt.quiet && out_file.is_some() {
+                println!("{}: {}", style(&stratum).cyan(), heap.len());
+            }
+        }
+
+        for sampled in heap.into_sorted_vec() {
+            let json_out = serde_json::to_string(&sampled.document)?;
+            if let Some(ref mut file) = out_file {
+                writeln!(file, "{json_out}")?;
+            } else {
+                println!("{json_out}");
+            }
+        }
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
@@ -0,0 +1,256 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde_json::json;
+use serde_json::value::RawValue;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, mirrored_output_paths, raw_field, DataExecutor};
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// A field to keep, optionally renamed with "field:name", e.g. "--field text" or
+    /// "--field metadata.url:url". Nested fields are separated by dots and are kept at the top
+    /// level under their last name, e.g. "metadata.url" as "url". Give this option once for
+    /// every field. Fields are written in the order they're given.
+    #[structopt(long = "field", number_of_values = 1)]
+    field: Vec<String>,
+
+    /// Skip documents that don't have all of the fields. By default missing fields are left
+    /// out of the document.
+    #[structopt(long = "skip-missing")]
+    skip_missing: bool,
+
+    /// The directory to write the output to. Every input file is written to a file with the
+    /// same name in this directory.
+    #[structopt(short = "o", long = "out")]
+    out: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the summary as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output files if they already exist.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// A field to keep and the name to write it as, already JSON-encoded.
+#[derive(Debug, Clone)]
+struct Selection {
+    field: String,
+    name: String,
+}
+
+impl Selection {
+    fn parse(s: &str) -> Result<Self> {
+        let (field, name) = match s.rsplit_once(':') {
+            Some((field, name)) => (field, name),
+            None => (s, s.rsplit('.').next().unwrap_or(s)),
+        };
+        if field.is_empty() || name.is_empty() {
+            bail!(
+                "invalid --field {:?}, expected \"field\" or \"field:name\"",
+                s
+            );
+        }
+        Ok(Self {
+            field: field.into(),
+            name: serde_json::to_string(name)?,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SelectCounts {
+    documents: usize,
+    skipped: usize,
+    bytes_in: usize,
+    bytes_out: usize,
+}
+
+/// The output file of a worker for one input file.
+struct LocalOutput {
+    file: OutputFile,
+    counts: SelectCounts,
+    line: String,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.field.is_empty() {
+        bail!("at least one --field is required");
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let selections: Vec<Selection> = opt
+        .field
+        .iter()
+        .map(|field| Selection::parse(field))
+        .collect::<Result<_>>()?;
+    let mut names = HashSet::new();
+    for selection in &selections {
+        if !names.insert(&selection.name) {
+            bail!(
+                "more than one --field is written as {}, rename one with \"field:name\"",
+                selection.name
+            );
+        }
+    }
+    let selections = Arc::new(selections);
+
+    let out_paths = mirrored_output_paths(&opt.path, &opt.out, opt.force)?;
+    let totals = Arc::new(Mutex::new(SelectCounts::default()));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Selecting", opt.quiet)?;
+
+    for (path, out_path) in opt.path.iter().zip(out_paths) {
+        let select_fields = {
+            let selections = selections.clone();
+            let skip_missing = opt.skip_missing;
+
+            move |document: Box<RawValue>,
+                  _: &Path,
+                  _: usize,
+                  local: &mut LocalOutput|
+                  -> Result<()> {
+                let raw = document.get();
+                local.counts.documents += 1;
+                local.counts.bytes_in += raw.len() + 1;
+
+                // Fields are copied as raw JSON, so they're written exactly as they were.
+                local.line.clear();
+                local.line.push('{');
+                for selection in selections.iter() {
+                    match raw_field(raw, &selection.field)? {
+                        Some(value) => {
+                            if local.line.len() > 1 {
+                                local.line.push(',');
+                            }
+                            local.line.push_str(&selection.name);
+                            local.line.push(':');
+                            local.line.push_str(value);
+                        }
+                        None if skip_missing => {
+                            local.counts.skipped += 1;
+                            return Ok(());
+                        }
+                        None => {}
+                    }
+                }
+                local.line.push_str("}\n");
+                local.counts.bytes_out += local.line.len();
+                local.file.write_all(local.line.as_bytes())?;
+                Ok(())
+            }
+        };
+
+        // A retry starts the file over, so the output file is created again.
+        let local_output_factory = move || -> Result<LocalOutput> {
+            Ok(LocalOutput {
+                file: util::get_output_file(&out_path, true)?.0,
+                counts: SelectCounts::default(),
+                line: String::new(),
+            })
+        };
+
+        let finish_output_callback = {
+            let totals = totals.clone();
+            move |local: LocalOutput| -> Result<()> {
+                local.file.finish()?;
+                let mut totals = totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                totals.documents += local.counts.documents;
+                totals.skipped += local.counts.skipped;
+                totals.bytes_in += local.counts.bytes_in;
+                totals.bytes_out += local.counts.bytes_out;
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            select_fields,
+            local_output_factory,
+            finish_output_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    if opt.json {
+        println!(
+            "{}",
+            json!({
+                "documents": totals.documents,
+                "written": totals.documents - totals.skipped,
+                "skipped": totals.skipped,
+                "bytes_in": totals.bytes_in,
+                "bytes_out": totals.bytes_out,
+            })
+        );
+    } else if !opt.quiet {
+        println!(
+            "{}: {}",
+            style("documents").cyan(),
+            (totals.documents - totals.skipped).separate_with_commas()
+        );
+        if opt.skip_missing {
+            println!(
+                "{}: {}",
+                style("skipped documents").cyan(),
+                totals.skipped.separate_with_commas()
+            );
+        }
+        println!(
+            "{}: {} of {} ({:.2}%)",
+            style("uncompressed bytes").cyan(),
+            totals.bytes_out.separate_with_commas(),
+            totals.bytes_in.separate_with_commas(),
+            100.0 * totals.bytes_out as f64 / totals.bytes_in.max(1) as f64
+        );
+    }
+
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Result};
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use tiny_http::{Header, Request, Response, Server};
+
+use crate::index::NgramIndex;
+use crate::tokens::{load_tokenizer, Tokenizer};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to an index directory written by 'wimbd topk --save-index'.
+    #[structopt(long = "index", parse(from_os_str))]
+    index: PathBuf,
+
+    /// The address to listen on, e.g. "127.0.0.1:8080", or ":8080" to listen on all interfaces.
+    #[structopt(long = "addr", default_value = "127.0.0.1:8080")]
+    addr: String,
+
+    /// Set the number of threads/workers handling requests.
+    #[structopt(short = "j", long = "workers", default_value = "4")]
+    workers: usize,
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    if opt.workers == 0 {
+        bail!("-j/--workers must be greater than 0");
+    }
+
+    log::info!("Loading index from {:?}...", opt.index);
+    let index = Arc::new(NgramIndex::load(&opt.index)?);
+    let tokenizer = load_tokenizer(&index.metadata.tokenizer)?;
+
+    let addr = if opt.addr.starts_with(':') {
+        format!("0.0.0.0{}", opt.addr)
+    } else {
+        opt.addr.clone()
+    };
+    let server =
+        Arc::new(Server::http(&addr).map_err(|err| anyhow!("Failed to listen on {addr}: {err}"))?);
+    log::info!("Listening on http://{addr}");
+
+    let handles: Vec<_> = (0..opt.workers)
+        .map(|_| {
+            let server = server.clone();
+            let index = index.clone();
+            let tokenizer = tokenizer.clone();
+            std::thread::spawn(move || -> Result<()> {
+                loop {
+                    let request = server.recv()?;
+                    if let Err(err) = handle(request, &index, &tokenizer) {
+                        log::warn!("Failed to respond to request: {}", err);
+                    }
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| anyhow!("Worker thread panicked"))??;
+    }
+
+    Ok(())
+}
+
+fn handle(request: Request, index: &NgramIndex, tokenizer: &Arc<dyn Tokenizer>) -> Result<()> {
+    let url = request.url().to_string();
+    let (route, query) = url.split_once('?').unwrap_or((&url, ""));
+    let params = parse_query(query);
+    log::debug!("{} {}", request.method(), url);
+
+    let result = match route {
+        "/info" => Ok(json!(index.metadata)),
+        "/count" | "/phrase" | "/contains" => match params.get("q") {
+            Some(q) => query_index(route, q, index, tokenizer),
+            None => Err((400, "missing query parameter 'q'".to_string())),
+        },
+        _ => Err((404, format!("unknown endpoint '{route}'"))),
+    };
+
+    let (status, body) = match result {
+        Ok(body) => (200, body),
+        Err((status, error)) => (status, json!({ "error": error })),
+    };
+    let header = Header::from_bytes("Content-Type", "application/json").unwrap();
+    request.respond(
+        Response::from_string(body.to_string())
+            .with_status_code(status)
+            .with_header(header),
+    )?;
+    Ok(())
+}
+
+/// Look up a query against the index. Counts are upper bounds since the index is a counting
+/// Bloom filter.
+fn query_index(
+    route: &str,
+    q: &str,
+    index: &NgramIndex,
+    tokenizer: &Arc<dyn Tokenizer>,
+) -> std::result::Result<Value, (u16, String)> {
+    let n = index.metadata.ngram;
+    let tokens = tokenizer
+        .tokenize(q)
+        .map_err(|err| (500, err.to_string()))?;
+    if route == "/count" && tokens.len() != n {
+        return Err((
+            400,
+            format!("query must have exactly {n} tokens, got {}", tokens.len()),
+        ));
+    }
+    if tokens.len() < n {
+        return Err((
+            400,
+            format!("query must have at least {n} tokens, got {}", tokens.len()),
+        ));
+    }
+
+    let counts: Vec<u32> = tokens
+        .windows(n)
+        .map(|ngram| index.counter.count(ngram))
+        .collect();
+
+    Ok(match route {
+        "/count" => json!({
+            "query": q,
+            "tokens": tokens,
+            "count": counts[0],
+        }),
+        "/phrase" => json!({
+            "query": q,
+            "tokens": tokens,
+            // A phrase can't occur more often than its rarest ngram.
+            "count": counts.iter().min(),
+            "num_ngrams": counts.len(),
+        }),
+        _ => {
+            let num_found = counts.iter().filter(|&&count| count > 0).count();
+            json!({
+                "query": q,
+                "num_ngrams": counts.len(),
+                "num_found": num_found,
+                "coverage": num_found as f64 / counts.len() as f64,
+            })
+        }
+    })
+}
+
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            (decode_component(key), decode_component(value))
+        })
+        .collect()
+}
+
+/// Decode a percent-encoded URL query component, where '+' also stands for a space.
+fn decode_component(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                match (hex_value(bytes[i + 1]), hex_value(bytes[i + 2])) {
+                    (Some(hi), Some(lo)) => {
+                        decoded.push(hi * 16 + lo);
+                        i += 2;
+                    }
+                    _ => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn hex_value(byte: u8) -> Option<u8> {
+    (byte as char).to_digit(16).map(|value| value as u8)
+}
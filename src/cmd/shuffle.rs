@@ -0,0 +1,218 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use rand::{random, rngs::StdRng, seq::SliceRandom, Rng};
+use serde_json::value::RawValue;
+use structopt::StructOpt;
+
+use super::util::{derive_rng, expand_paths, DataExecutor};
+use crate::io::{configured_tmp_dir, LineReader, ShardWriter, SpillDir, SpillWriter};
+use crate::progress::get_file_progress_bar;
+use crate::provenance;
+
+/// The number of lines a worker buffers for a bucket before writing them out.
+const BUCKET_BUFFER_SIZE: usize = 1024;
+
+type BucketWriter = Mutex<Option<SpillWriter>>;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The number of output shards to write. Each shard is shuffled in memory, so this
+    /// should be chosen such that a single shard comfortably fits in memory.
+    #[structopt(short = "n", long = "num-shards")]
+    num_shards: usize,
+
+    /// The directory to write the output shards to.
+    #[structopt(short = "o", long = "out")]
+    out: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Set the seed for shuffling. By default the seed is chosen at random.
+    /// Given the same seed and the same list of paths the output is deterministic.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Don't show progress bars.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output shards if they already exist.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// The local state of a worker: its own RNG and a buffer of lines for each bucket.
+struct LocalBuckets {
+    rng: StdRng,
+    buffers: Vec<Vec<String>>,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.num_shards == 0 {
+        bail!("-n/--num-shards must be greater than 0");
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let shard_paths: Vec<PathBuf> = (0..opt.num_shards)
+        .map(|i| opt.out.join(format!("shard-{i:05}.json.gz")))
+        .collect();
+    for path in &shard_paths {
+        if path.is_file() {
+            if opt.force {
+                log::warn!("Overwriting output file {:?}", path);
+            } else {
+                bail!(
+                    "Output file {:?} already exists, use --force to overwrite",
+                    path
+                );
+            }
+        }
+    }
+
+    fs::create_dir_all(&opt.out)?;
+    // The buckets add up to the size of the whole input, so unless '--tmp-dir' is given they go
+    // next to the output instead of the system's temporary directory, which is often small.
+    let spill_dir = SpillDir::new(configured_tmp_dir().unwrap_or(&opt.out))?;
+
+    let seed = opt.seed.unwrap_or_else(random);
+    provenance::record_seed(seed);
+    let num_buckets = opt.num_shards;
+
+    // First pass: scatter every document into a random bucket.
+    log::info!("Scattering documents into {} buckets...", num_buckets);
+    let bucket_paths: Vec<PathBuf> = (0..num_buckets).map(|i| spill_dir.file(i)).collect();
+    let mut writers = Vec::with_capacity(num_buckets);
+    for i in 0..num_buckets {
+        writers.push(Mutex::new(Some(spill_dir.create(i)?)));
+    }
+    let writers: Arc<Vec<BucketWriter>> = Arc::new(writers);
+
+    let mut executor =
+        DataExecutor::new(&opt.path, opt.workers, opt.limit, "Scattering", opt.quiet)?;
+    // Full buffers are written to the shared buckets in the middle of a file, so a file can't
+    // simply be started over.
+    executor.set_shared_state();
+
+    for (i, path) in opt.path.iter().enumerate() {
+        let local_buckets_factory = move || -> Result<LocalBuckets> {
+            Ok(LocalBuckets {
+                // Each file gets its own RNG stream so results don't depend on scheduling.
+                rng: derive_rng(seed, "scatter", i),
+                buffers: vec![Vec::new(); num_buckets],
+            })
+        };
+
+        let scatter = {
+            let writers = writers.clone();
+            move |document: Box<RawValue>,
+                  _: &Path,
+                  _: usize,
+                  local: &mut LocalBuckets|
+                  -> Result<()> {
+                let bucket = local.rng.gen_range(0..num_buckets);
+                local.buffers[bucket].push(document.get().to_string());
+                if local.buffers[bucket].len() >= BUCKET_BUFFER_SIZE {
+                    flush_bucket(&writers[bucket], &mut local.buffers[bucket])?;
+                }
+                Ok(())
+            }
+        };
+
+        let flush_buckets = {
+            let writers = writers.clone();
+            move |mut local: LocalBuckets| -> Result<()> {
+                for (writer, buffer) in writers.iter().zip(local.buffers.iter_mut()) {
+                    flush_bucket(writer, buffer)?;
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(path, scatter, local_buckets_factory, flush_buckets)?;
+    }
+
+    executor.join()?;
+
+    for writer in writers.iter() {
+        if let Some(encoder) = writer
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .take()
+        {
+            encoder.finish()?.flush()?;
+        }
+    }
+
+    // Second pass: shuffle each bucket in memory and write it out as a shard.
+    log::info!("Shuffling buckets into {} shards...", opt.num_shards);
+    let progress = get_file_progress_bar("Shuffling", num_buckets, opt.quiet)?;
+    for (i, bucket_path) in bucket_paths.iter().enumerate() {
+        let mut lines: Vec<String> = LineReader::open(bucket_path)?.collect::<io::Result<_>>()?;
+
+        // Lines arrive in whatever order the workers happened to flush their buffers, so put
+        // them in a fixed order first to keep the shuffle deterministic for a given seed.
+        lines.sort_unstable();
+        let mut rng = derive_rng(seed, "shuffle", i);
+        lines.shuffle(&mut rng);
+
+        let mut writer = ShardWriter::new(&opt.out, "shard", "json.gz")?.starting_at(i);
+        for line in &lines {
+            writer.write_line(line)?;
+        }
+        writer.finish()?;
+
+        fs::remove_file(bucket_path)?;
+        progress.inc(1);
+    }
+    progress.finish();
+
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
+
+/// Write out a worker's buffered lines to a bucket.
+fn flush_bucket(writer: &BucketWriter, buffer: &mut Vec<String>) -> Result<()> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    let mut writer = writer
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let writer = writer
+        .as_mut()
+        .ok_or_else(|| anyhow!("Bucket writer already closed"))?;
+    for line in buffer.drain(..) {
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,390 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::AtomicU32;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::json;
+use structopt::StructOpt;
+use xxhash_rust::xxh3::Xxh3;
+
+use super::util::{expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance};
+use crate::ngrams::NgramCounter;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::load_tokenizer;
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The size of the base ngrams that spans are grown from.
+    #[structopt(short = "n", long = "ngram", default_value = "13")]
+    ngram: usize,
+
+    /// The minimum number of times a span has to occur to be reported. This is also the
+    /// minimum count for base ngrams while growing spans.
+    #[structopt(long = "threshold", default_value = "10")]
+    threshold: u32,
+
+    /// The minimum length of a span, in tokens, to be reported.
+    #[structopt(long = "min-length", default_value = "50")]
+    min_length: usize,
+
+    /// The number of spans to return, longest first.
+    #[structopt(short = "k", long = "topk", default_value = "20")]
+    topk: usize,
+
+    /// The max number of example documents to point to for each span.
+    #[structopt(long = "examples", default_value = "5")]
+    examples: usize,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Specify the size budget for the internal ngram counter hash table, e.g. "8GiB".
+    /// In general it's best to choose the largest size that will fit in memory
+    /// on your machine.
+    #[structopt(long = "size", default_value = "4GiB", parse(try_from_str = parse_size_default_to_gb))]
+    size: u64,
+
+    /// Specify the number of hash functions to use.
+    #[structopt(short = "h", long = "hashes", default_value = "5")]
+    hashes: u8,
+
+    /// Set the seed for the hashing functions. By default the seed is chosen at random.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e.
+    /// each line will be a JSON object with the keys "length", "count", "string", and "examples".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct SpanPointer {
+    path: PathBuf,
+    line: usize,
+    /// The token offset of the span within the document.
+    start: usize,
+}
+
+#[derive(Debug, Clone)]
+struct Span {
+    tokens: Vec<String>,
+    count: usize,
+    examples: Vec<SpanPointer>,
+}
+
+impl Span {
+    fn merge(&mut self, other: Span, max_examples: usize) {
+        self.count += other.count;
+        let room = max_examples.saturating_sub(self.examples.len());
+        self.examples.extend(other.examples.into_iter().take(room));
+    }
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    // Validate arguments.
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.size == 0 {
+        bail!("--size must be greater than 0");
+    }
+    if opt.hashes == 0 {
+        bail!("-h/--hashes must be greater than 0");
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    if opt.threshold < 2 {
+        bail!("--threshold must be at least 2");
+    }
+    if opt.min_length < opt.ngram {
+        bail!("--min-length must be at least -n/--ngram");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+    // Pick a seed up front so that it's recorded in the run metadata.
+    provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    log::info!("Initializing ngram counter...");
+    // We're storing an array of u32s, each of which is 4 bytes.
+    let ngram_counts = Arc::new(NgramCounter::<AtomicU32>::new(
+        (opt.size / 4) as usize,
+        opt.hashes as usize,
+        opt.seed,
+        0,
+    )?);
+
+    // First pass: count all base ngrams.
+    log::info!("Counting base ngrams...");
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting ngrams",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let count_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let ngram_counts = ngram_counts.clone();
+
+            move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
+                if let Some(text) = data.text {
+                    let tokens = tokenizer.tokenize(&preprocessor.apply(&text))?;
+                    for ngram in tokens.windows(opt.ngram) {
+                        ngram_counts.increment(ngram, 1);
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute(path, count_ngrams)?;
+    }
+
+    executor.join()?;
+
+    // Second pass: grow spans from frequent base ngrams for as long as the next base ngram is
+    // also frequent, then count how often each of these maximal spans occurs.
+    log::info!("Growing spans...");
+    let spans: Arc<Mutex<HashMap<u64, Span>>> = Arc::new(Mutex::new(HashMap::new()));
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Growing spans",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let grow_spans = {
+            let tokenizer = tokenizer.clone();
+            let ngram_counts = ngram_counts.clone();
+
+            move |data: DataInstance,
+                  path: &Path,
+                  line_num: usize,
+                  local_spans: &mut HashMap<u64, Span>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let tokens = tokenizer.tokenize(&preprocessor.apply(&text))?;
+                    let frequent: Vec<bool> = tokens
+                        .windows(opt.ngram)
+                        .map(|ngram| ngram_counts.count(ngram) >= opt.threshold)
+                        .collect();
+
+                    let mut start = 0;
+                    while start < frequent.len() {
+                        if !frequent[start] {
+                            start += 1;
+                            continue;
+                        }
+                        let mut end = start;
+                        while end + 1 < frequent.len() && frequent[end + 1] {
+                            end += 1;
+                        }
+
+                        let span = &tokens[start..end + opt.ngram];
+                        if span.len() >= opt.min_length {
+                            let pointer = SpanPointer {
+                                path: path.into(),
+                                line: line_num,
+                                start,
+                            };
+                            let entry =
+                                local_spans.entry(hash_span(span)).or_insert_with(|| Span {
+                                    tokens: span.to_vec(),
+                                    count: 0,
+                                    examples: Vec::new(),
+                                });
+                            entry.count += 1;
+                            if entry.examples.len() < opt.examples {
+                                entry.examples.push(pointer);
+                            }
+                        }
+                        start = end + 1;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let sync_spans_callback = {
+            let spans = spans.clone();
+            let max_examples = opt.examples;
+
+            move |local_spans: HashMap<u64, Span>| -> Result<()> {
+                let mut spans = spans
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                for (hash, span) in local_spans {
+                    match spans.get_mut(&hash) {
+                        Some(existing) => existing.merge(span, max_examples),
+                        None => {
+                            spans.insert(hash, span);
+                        }
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            grow_spans,
+            || -> Result<HashMap<u64, Span>> { Ok(HashMap::new()) },
+            sync_spans_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let spans = std::mem::take(
+        &mut *spans
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+    );
+    let mut spans: Vec<Span> = spans
+        .into_values()
+        .filter(|span| span.count >= opt.threshold as usize)
+        .collect();
+    spans.sort_by(|a, b| {
+        b.tokens
+            .len()
+            .cmp(&a.tokens.len())
+            .then(b.count.cmp(&a.count))
+    });
+    spans.truncate(opt.topk);
+
+    for (i, span) in spans.iter().enumerate() {
+        let span_str = tokenizer.decode(&span.tokens)?;
+        let json_out = &json!({
+            "length": span.tokens.len(),
+            "count": span.count,
+            "string": span_str,
+            "examples": span.examples,
+            "rank": i + 1,
+        })
+        .to_string();
+
+        // Display output.
+        if opt.json {
+            println!("{json_out}");
+        } else if opt.out.is_none() {
+            let example = &span.examples[0];
+            println!(
+                "[{}/{}] {} tokens (count {}, e.g. {:?} line {}): {:?}",
+                i + 1,
+                spans.len(),
+                span.tokens.len(),
+                span.count,
+                example.path,
+                example.line,
+                style(span_str).cyan(),
+            );
+        }
+
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+        }
+    }
+
+    if spans.is_empty() {
+        log::warn!(
+            "No spans of at least {} tokens occurred {} or more times",
+            opt.min_length,
+            opt.threshold
+        );
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn hash_span(tokens: &[String]) -> u64 {
+    let mut hasher = Xxh3::new();
+    for token in tokens {
+        hasher.update(token.as_bytes());
+        // Separate tokens so that e.g. ["ab", "c"] and ["a", "bc"] hash differently.
+        hasher.update(&[0]);
+    }
+    hasher.digest()
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
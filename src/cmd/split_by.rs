@@ -0,0 +1,274 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde_json::json;
+use serde_json::value::RawValue;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, parse_size_default_to_gb, raw_field, DataExecutor};
+use crate::io::ShardWriter;
+
+/// The number of lines a worker buffers for a value before writing them out.
+const VALUE_BUFFER_SIZE: usize = 1024;
+
+/// The directory for documents without the field, or where it's null.
+const NULL_VALUE: &str = "null";
+
+type ValueWriter = Arc<Mutex<ShardWriter>>;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The field to split by, e.g. "lang". Nested fields are separated by dots, like
+    /// "metadata.source". Documents without the field, or where it's null, go to "null".
+    #[structopt(long = "field")]
+    field: String,
+
+    /// The directory to write the output to. The shards for each value of the field are
+    /// written to a subdirectory named after the value, with characters that aren't safe in
+    /// file names percent-encoded.
+    #[structopt(short = "o", long = "out")]
+    out: PathBuf,
+
+    /// The target (compressed) size of each output shard, e.g. "1GiB". By default all
+    /// documents with the same value go into a single shard.
+    #[structopt(long = "shard-size", parse(try_from_str = parse_size_default_to_gb))]
+    shard_size: Option<u64>,
+
+    /// The max number of distinct values of the field. Splitting stops with an error if
+    /// there are more, which usually means the field isn't categorical, like an ID or a URL.
+    #[structopt(long = "max-values", default_value = "1000")]
+    max_values: usize,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Write into the output directory even if it isn't empty, overwriting existing shards.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// The writers and document counts for every value seen so far, by directory name.
+struct Values {
+    out: PathBuf,
+    shard_size: Option<u64>,
+    max_values: usize,
+    writers: Mutex<HashMap<String, (ValueWriter, usize)>>,
+}
+
+impl Values {
+    /// Get the writer for a value, creating it if it's new, and count the lines that are
+    /// about to be written.
+    fn writer(&self, dir_name: &str, num_lines: usize) -> Result<ValueWriter> {
+        let mut writers = self
+            .writers
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        if !writers.contains_key(dir_name) {
+            if writers.len() >= self.max_values {
+                bail!(
+                    "found more than {} distinct values, use --max-values to allow more",
+                    self.max_values
+                );
+            }
+            let mut writer = ShardWriter::new(self.out.join(dir_name), "part", "json.gz")?;
+            if let Some(shard_size) = self.shard_size {
+                writer = writer.with_max_bytes(shard_size);
+            }
+            writers.insert(dir_name.into(), (Arc::new(Mutex::new(writer)), 0));
+        }
+        let (writer, count) = writers.get_mut(dir_name).unwrap();
+        *count += num_lines;
+        Ok(writer.clone())
+    }
+
+    /// Write out a worker's buffered lines for a value.
+    fn flush(&self, dir_name: &str, buffer: &mut Vec<String>) -> Result<()> {
+        if buffer.is_empty() {
+            return Ok(());
+        }
+        let writer = self.writer(dir_name, buffer.len())?;
+        let mut writer = writer
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        for line in buffer.drain(..) {
+            writer.write_line(&line)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.field.is_empty() {
+        bail!("--field can't be empty");
+    }
+    if opt.max_values == 0 {
+        bail!("--max-values must be greater than 0");
+    }
+    if opt.shard_size == Some(0) {
+        bail!("--shard-size must be greater than 0");
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+    if opt.out.is_dir() && fs::read_dir(&opt.out)?.next().is_some() {
+        if opt.force {
+            log::warn!("Writing into non-empty output directory {:?}", opt.out);
+        } else {
+            bail!(
+                "Output directory {:?} isn't empty, use --force to write into it anyway",
+                opt.out
+            );
+        }
+    }
+    fs::create_dir_all(&opt.out)?;
+
+    let values = Arc::new(Values {
+        out: opt.out.clone(),
+        shard_size: opt.shard_size,
+        max_values: opt.max_values,
+        writers: Mutex::new(HashMap::new()),
+    });
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Splitting", opt.quiet)?;
+
+    for path in &opt.path {
+        let split = {
+            let values = values.clone();
+            let field = opt.field.clone();
+
+            move |document: Box<RawValue>,
+                  _: &Path,
+                  _: usize,
+                  buffers: &mut HashMap<String, Vec<String>>|
+                  -> Result<()> {
+                let raw = document.get();
+                let value_dir = dir_name(raw_field(raw, &field)?)?;
+                let buffer = buffers.entry(value_dir.clone()).or_default();
+                buffer.push(raw.to_string());
+                if buffer.len() >= VALUE_BUFFER_SIZE {
+                    values.flush(&value_dir, buffer)?;
+                }
+                Ok(())
+            }
+        };
+
+        let flush_buffers = {
+            let values = values.clone();
+            move |mut buffers: HashMap<String, Vec<String>>| -> Result<()> {
+                for (dir_name, buffer) in buffers.iter_mut() {
+                    values.flush(dir_name, buffer)?;
+                }
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            split,
+            || -> Result<HashMap<String, Vec<String>>> { Ok(HashMap::new()) },
+            flush_buffers,
+        )?;
+    }
+
+    executor.join()?;
+
+    let writers = std::mem::take(
+        &mut *values
+            .writers
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+    );
+    let mut counts: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    for (dir_name, (writer, count)) in writers {
+        let writer = Arc::try_unwrap(writer)
+            .map_err(|_| anyhow!("Writer for {:?} is still in use", dir_name))?
+            .into_inner()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?;
+        let num_shards = writer.finish()?.len();
+        counts.insert(dir_name, (count, num_shards));
+    }
+
+    if opt.json {
+        let json_out: BTreeMap<&String, _> = counts
+            .iter()
+            .map(|(dir_name, (count, num_shards))| {
+                (dir_name, json!({"documents": count, "shards": num_shards}))
+            })
+            .collect();
+        println!("{}", json!(json_out));
+    } else if !opt.quiet {
+        let mut counts: Vec<_> = counts.iter().collect();
+        counts.sort_by_key(|(_, (count, _))| std::cmp::Reverse(*count));
+        for (dir_name, (count, num_shards)) in counts {
+            println!(
+                "{}: {} documents in {} shard{}",
+                style(dir_name).cyan(),
+                count.separate_with_commas(),
+                num_shards,
+                if *num_shards == 1 { "" } else { "s" }
+            );
+        }
+    }
+
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
+
+/// The name of the directory for the raw JSON of a field value. Strings are used without their
+/// quotes, other values as JSON. Characters other than ASCII letters, digits, '-', and '_', as
+/// well as a leading '.', are percent-encoded so that every value gets a distinct, safe name.
+fn dir_name(value: Option<&str>) -> Result<String> {
+    let value = match value.map(str::trim) {
+        None | Some("null") => return Ok(NULL_VALUE.into()),
+        Some(value) if value.starts_with('"') => serde_json::from_str::<String>(value)?,
+        Some(value) => value.to_string(),
+    };
+    if value.is_empty() {
+        // Percent-encoding never produces a lone '%'.
+        return Ok("%".into());
+    }
+    let mut name = String::with_capacity(value.len());
+    for (i, byte) in value.bytes().enumerate() {
+        if byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_' || (byte == b'.' && i > 0) {
+            name.push(byte as char);
+        } else {
+            name.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    Ok(name)
+}
@@ -1,23 +1,36 @@
-use std::collections::VecDeque;
-use std::fs::File;
-use std::io::Write;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{BufRead, BufReader, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use console::style;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
+use serde_json::Value;
 use structopt::StructOpt;
 use thousands::Separable;
 
-use super::util::{DataExecutor, DataInstance};
-use crate::tokens::{tokenize, PretrainedTokenizer};
-use crate::util;
+use super::util::{
+    document_weight, expand_paths, uniform_hash, BoundedHeap, DataExecutor, DataInstance,
+    LengthBand,
+};
+use crate::encoding::{
+    count_invalid_surrogate_escapes, count_mojibake, count_replacement_chars,
+    replace_invalid_surrogate_escapes,
+};
+use crate::io::open_decompressed;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::table::{ColumnType, OutFormat, TableWriter};
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util::{self, OutputFile};
 
 #[derive(Debug, StructOpt, Clone)]
 pub(crate) struct Opt {
-    /// Path to a gzip-compressed JSON lines file.
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
     #[structopt(parse(from_os_str))]
     path: Vec<PathBuf>,
 
@@ -39,6 +52,13 @@ pub(crate) struct Opt {
     #[structopt(short = "o", long = "out")]
     out: Option<PathBuf>,
 
+    /// The format of the '-o/--out' file: "jsonl", "csv", "parquet", or "sqlite". CSV and
+    /// Parquet files have a single row with the totals and the min and max tokens per document.
+    /// The other stats are only included in JSON. SQLite databases get the row appended to the
+    /// "stats" table, so they're never overwritten.
+    #[structopt(long = "out-format", default_value = "jsonl", possible_values = &["jsonl", "csv", "parquet", "sqlite"])]
+    out_format: OutFormat,
+
     /// Don't show progress bars. Additionally, if an output file is specified nothing will be written to stdout.
     /// This doesn't affect logging.
     #[structopt(short = "q", long = "quiet")]
@@ -56,37 +76,323 @@ pub(crate) struct Opt {
     /// from HuggingFace.
     #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
     tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+
+    /// Only include documents with at least this many tokens.
+    #[structopt(long = "min-doc-tokens")]
+    min_doc_tokens: Option<usize>,
+
+    /// Only include documents with at most this many tokens.
+    ///
+    /// Note that the total number of bytes and the encoding report always cover all documents.
+    #[structopt(long = "max-doc-tokens")]
+    max_doc_tokens: Option<usize>,
+
+    /// Stop tokenizing documents after this many tokens, so that huge documents, like
+    /// minified code, don't stall a worker. Truncated documents only count this many tokens,
+    /// and how many were truncated is reported as "truncated_documents". '--min-doc-tokens'
+    /// and '--max-doc-tokens' apply to the truncated documents.
+    #[structopt(long = "truncate-doc-tokens")]
+    truncate_doc_tokens: Option<usize>,
+
+    /// Also check for encoding damage: Unicode replacement characters, unpaired surrogate
+    /// escapes in the raw JSON, and mojibake (UTF-8 text decoded as Latin-1 or Windows-1252,
+    /// like "Ã©"). This reports per-file rates and the most damaged documents.
+    ///
+    /// Lines with unpaired surrogate escapes normally fail to parse. In this mode they are
+    /// repaired with replacement characters instead.
+    #[structopt(long = "check-encoding")]
+    check_encoding: bool,
+
+    /// Also write stats for every document to this file as JSON lines, i.e.
+    /// each line will be a JSON object with the keys "path", "line", "id", "tokens", and "bytes".
+    /// Documents are grouped by file but files are in no particular order. The file is
+    /// compressed if its name ends in ".gz" or ".zst".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(long = "per-doc", parse(from_os_str))]
+    per_doc: Option<PathBuf>,
+
+    /// The bucket edges for the tokens-per-document and bytes-per-document histograms in the
+    /// JSON output, e.g. "0,128,512,2048". Bucket i counts the documents with at least edges[i]
+    /// and less than edges[i + 1] tokens (or bytes), and the last bucket is open-ended.
+    /// Defaults to 0 and the powers of two.
+    #[structopt(long = "bucket-edges", use_delimiter = true)]
+    bucket_edges: Vec<usize>,
+
+    /// Fold the stats of the given files into a previously saved JSON report from this command,
+    /// e.g. when a dataset grows, instead of recomputing everything. Files that are already
+    /// in the report are skipped. The bucket edges are taken from the report, and the other
+    /// options should be the same as the ones the report was created with. Reports written
+    /// before file names were recorded can't be checked for files that are already in them.
+    ///
+    /// The report has to be in JSON. It's read before any output is written, so it's fine to
+    /// overwrite it with '-o/--out' and '-f/--force'.
+    #[structopt(long = "update", parse(from_os_str))]
+    update: Option<PathBuf>,
+
+    /// Only process a random sample of this fraction of the lines in each file, e.g. "0.01",
+    /// and extrapolate the total tokens and documents from it. The JSON output then has a
+    /// "sample" object with the standard errors of those estimates. Every other stat, like the
+    /// histograms and the min and max tokens per document, only covers the sampled documents.
+    ///
+    /// Files still have to be read in full, but lines that aren't sampled are neither parsed
+    /// nor tokenized.
+    #[structopt(long = "sample-rate")]
+    sample_rate: Option<f64>,
+
+    /// Set the seed for '--sample-rate'. By default the seed is chosen at random, or taken from
+    /// the report with '--update'. Given the same seed and the same files the sample is
+    /// deterministic.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// Also report the N documents with the most and the fewest tokens, and the N documents
+    /// with the most and the fewest bytes. Unlike the max and min token documents, which are
+    /// only the documents tied with the max and min, these are always N documents each.
+    #[structopt(long = "extremes")]
+    extremes: Option<usize>,
+
+    /// Also report the tokens, documents, and bytes for every language, as given by the
+    /// "lang" or "language" field of the documents or of their "metadata" object. Documents
+    /// without one are counted under "unknown".
+    #[structopt(long = "by-lang")]
+    by_lang: bool,
+
+    /// Also report the tokens, documents, and bytes weighted by this numeric field of the
+    /// documents, e.g. a sampling weight or the number of epochs, so that they reflect the
+    /// effective training mixture rather than the raw data. Nested fields are separated by
+    /// dots, like "metadata.weight". Documents without the field have a weight of 1.
+    #[structopt(long = "weight-field")]
+    weight_field: Option<String>,
 }
 
+impl Opt {
+    /// Options for collecting stats over `path` with [`collect()`] from other commands. Everything
+    /// else is left at the defaults.
+    pub(crate) fn new(
+        path: Vec<PathBuf>,
+        workers: Option<usize>,
+        quiet: bool,
+        tokenizer: &str,
+        preprocessor: Preprocessor,
+    ) -> Self {
+        Self {
+            path,
+            limit: None,
+            file_limit: None,
+            workers,
+            out: None,
+            out_format: OutFormat::Jsonl,
+            quiet,
+            json: false,
+            force: false,
+            tokenizer: tokenizer.into(),
+            strip_html: preprocessor.strip_html,
+            strip_markdown: preprocessor.strip_markdown,
+            min_doc_tokens: None,
+            max_doc_tokens: None,
+            truncate_doc_tokens: None,
+            check_encoding: false,
+            per_doc: None,
+            bucket_edges: Vec::new(),
+            update: None,
+            sample_rate: None,
+            seed: None,
+            extremes: None,
+            by_lang: false,
+            weight_field: None,
+        }
+    }
+}
+
+/// The columns of CSV and Parquet output files.
+const COLUMNS: &[(&str, ColumnType)] = &[
+    ("total_tokens", ColumnType::Int),
+    ("total_documents", ColumnType::Int),
+    ("total_bytes", ColumnType::Int),
+    ("document_max_tokens", ColumnType::Int),
+    ("document_min_tokens", ColumnType::Int),
+];
+
+/// What documents are counted under with '--by-lang' if they don't have a language.
+const UNKNOWN_LANGUAGE: &str = "unknown";
+
+/// Max number of most damaged documents to report with '--check-encoding'.
+const NUM_DAMAGED_DOCUMENTS: usize = 20;
+
+/// The largest power of two in the default histogram bucket edges.
+const MAX_BUCKET_EDGE_POWER: u32 = 32;
+
+type PerDocWriter = Arc<Mutex<Option<OutputFile>>>;
+
 pub(crate) fn main(mut opt: Opt) -> Result<()> {
     if opt.path.is_empty() {
         bail!("at least one path is required");
     }
+
+    let previous_report: Option<StatsReport> = match &opt.update {
+        Some(path) => {
+            if !opt.bucket_edges.is_empty() {
+                bail!("--bucket-edges can't be used with --update, the report's edges are used");
+            }
+            let report = StatsReport::read(path)?;
+            opt.bucket_edges = report.tokens_per_document.edges.clone();
+            if let (Some(sample), None) = (&report.sample, opt.seed) {
+                opt.seed = Some(sample.seed);
+            }
+            // Reports list the expanded paths, so brace patterns have to be expanded
+            // before they can be compared.
+            opt.path = expand_paths(&opt.path)?;
+            let num_paths = opt.path.len();
+            opt.path.retain(|path| !report.files.contains(path));
+            if opt.path.len() < num_paths {
+                log::warn!(
+                    "Skipping {} file(s) that are already in {:?}",
+                    num_paths - opt.path.len(),
+                    path
+                );
+            }
+            Some(report)
+        }
+        None => None,
+    };
+
+    let (out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let report = match previous_report {
+        Some(mut report) => {
+            if !opt.path.is_empty() {
+                report.merge(collect(&opt)?)?;
+            }
+            report
+        }
+        None => collect(&opt)?,
+    };
+    let row = serde_json::to_value(&report)?;
+    let json_out = row.to_string();
+
+    if opt.json {
+        println!("{json_out}");
+    } else if !opt.quiet {
+        report.display();
+    }
+
+    if let Some(mut writer) = out_file {
+        writer.write_row(&row)?;
+        writer.finish()?;
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    if let Some(path) = &opt.per_doc {
+        log::info!("Per-document stats written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Collect stats over the files in `opt.path`. This only writes the '--per-doc' output, if any.
+pub(crate) fn collect(opt: &Opt) -> Result<StatsReport> {
+    let mut opt = opt.clone();
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    opt.path = expand_paths(&opt.path)?;
     if let Some(file_limit) = opt.file_limit {
         opt.path.truncate(file_limit);
     }
+    if opt.bucket_edges.is_empty() {
+        opt.bucket_edges.push(0);
+        opt.bucket_edges
+            .extend((0..=MAX_BUCKET_EDGE_POWER).map(|power| 1 << power));
+    } else if opt
+        .bucket_edges
+        .windows(2)
+        .any(|edges| edges[0] >= edges[1])
+    {
+        bail!("--bucket-edges must be in strictly increasing order");
+    } else if opt.bucket_edges[0] != 0 {
+        opt.bucket_edges.insert(0, 0);
+    }
 
-    let tokenizer: Option<PretrainedTokenizer> = if &opt.tokenizer == "unicode" {
-        None
-    } else {
-        Some(PretrainedTokenizer::new(&opt.tokenizer)?)
+    let sampler = match opt.sample_rate {
+        Some(rate) => {
+            let seed = *opt.seed.get_or_insert_with(rand::random);
+            provenance::record_seed(seed);
+            Some(Sampler::new(rate, seed)?)
+        }
+        None => None,
     };
 
-    let (mut out_file, out_path) = match get_output_file(&opt)? {
-        Some(out) => (Some(out.0), Some(out.1)),
-        None => (None, None),
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+    let length_band = LengthBand::new(opt.min_doc_tokens, opt.max_doc_tokens)?;
+
+    let per_doc_writer: Option<PerDocWriter> = match &opt.per_doc {
+        Some(path) => {
+            let (file, _) = util::get_output_file(path, opt.force)?;
+            Some(Arc::new(Mutex::new(Some(file))))
+        }
+        None => None,
     };
 
-    let stats: Stats<Arc<AtomicUsize>> = Stats::default();
+    let mut stats: Stats<Arc<AtomicUsize>> = Stats {
+        tokens_per_document: Arc::new(Mutex::new(Histogram::new(&opt.bucket_edges))),
+        bytes_per_document: Arc::new(Mutex::new(Histogram::new(&opt.bucket_edges))),
+        ..Default::default()
+    };
+    if opt.check_encoding {
+        stats.encoding = Some(Arc::new(Mutex::new(EncodingReport::default())));
+    }
+    if let Some(n) = opt.extremes {
+        stats.extremes = Some(Arc::new(Mutex::new(ExtremeHeaps::new(n))));
+    }
+    if opt.by_lang {
+        stats.languages = Some(Arc::new(Mutex::new(BTreeMap::new())));
+    }
+    if opt.weight_field.is_some() {
+        stats.weighted = Some(Arc::new(Mutex::new(WeightedTotals::default())));
+    }
 
     let mut executor =
         DataExecutor::new(&opt.path, opt.workers, opt.limit, "Collecting", opt.quiet)?;
-    executor.max_retries = 2;
+    executor.set_default_max_retries(2);
+    executor.enable_threads_per_file();
 
     for path in &opt.path {
         let sync_stats_callback = {
             let stats = stats.clone();
+            let per_doc_writer = per_doc_writer.clone();
             move |mut local_stats: LocalStats| -> Result<()> {
+                // Write per-document stats. These are only written once the whole file is done
+                // so that retries don't write any document twice.
+                if let (Some(writer), Some(per_doc)) = (&per_doc_writer, &local_stats.per_doc) {
+                    let mut writer = writer
+                        .lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                    let writer = writer
+                        .as_mut()
+                        .ok_or_else(|| anyhow!("Per-document output is already closed"))?;
+                    for doc_stats in per_doc {
+                        serde_json::to_writer(&mut *writer, doc_stats)?;
+                        writer.write_all(b"\n")?;
+                    }
+                }
+
                 // Update counts.
                 stats
                     .total_tokens
@@ -94,12 +400,31 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
                 stats
                     .total_documents
                     .fetch_add(local_stats.total_documents, Ordering::Relaxed);
+                stats
+                    .truncated_documents
+                    .fetch_add(local_stats.truncated_documents, Ordering::Relaxed);
                 stats
                     .document_max_tokens
                     .fetch_max(local_stats.document_max_tokens, Ordering::Relaxed);
                 stats
                     .document_min_tokens
                     .fetch_min(local_stats.document_min_tokens, Ordering::Relaxed);
+                *stats
+                    .tokens_squared
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))? += local_stats.tokens_squared;
+
+                // Merge histograms.
+                stats
+                    .tokens_per_document
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .merge(&local_stats.tokens_per_document);
+                stats
+                    .bytes_per_document
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .merge(&local_stats.bytes_per_document);
 
                 // Prune max/min token document pointers.
                 stats.prune_documents()?;
@@ -128,188 +453,1060 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
                     }
                 }
 
+                // Sync extreme documents.
+                if let (Some(extremes), Some(local_extremes)) =
+                    (&stats.extremes, local_stats.extremes.take())
+                {
+                    extremes
+                        .lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock"))?
+                        .merge(local_extremes);
+                }
+
+                // Sync per-language stats.
+                if let (Some(languages), Some(local_languages)) =
+                    (&stats.languages, local_stats.languages.take())
+                {
+                    let mut languages = languages
+                        .lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                    merge_languages(&mut languages, local_languages);
+                }
+
+                // Sync weighted totals.
+                if let (Some(weighted), Some(local_weighted)) =
+                    (&stats.weighted, &local_stats.weighted)
+                {
+                    weighted
+                        .lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock"))?
+                        .merge(local_weighted);
+                }
+
+                // Sync encoding damage.
+                if let Some(ref encoding) = stats.encoding {
+                    let mut encoding = encoding
+                        .lock()
+                        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                    encoding.merge(&mut local_stats);
+                }
+
                 Ok(())
             }
         };
 
         let local_stats_factory = {
             let stats = stats.clone();
+            let per_doc = per_doc_writer.is_some();
+            let extremes = opt.extremes;
+            let by_lang = opt.by_lang;
+            let weighted = opt.weight_field.is_some();
+            let bucket_edges = opt.bucket_edges.clone();
             move || -> Result<LocalStats> {
                 Ok(LocalStats {
+                    tokens_per_document: Histogram::new(&bucket_edges),
+                    bytes_per_document: Histogram::new(&bucket_edges),
                     document_max_tokens: stats.document_max_tokens.load(Ordering::Relaxed),
                     document_min_tokens: stats.document_min_tokens.load(Ordering::Relaxed),
+                    per_doc: per_doc.then(Vec::new),
+                    extremes: extremes.map(ExtremeHeaps::new),
+                    languages: by_lang.then(BTreeMap::new),
+                    weighted: weighted.then(WeightedTotals::default),
                     ..Default::default()
                 })
             }
         };
 
         let tokenizer = tokenizer.clone();
-        executor.execute_with_callback(
-            path,
-            move |data: DataInstance,
-                  path: &Path,
-                  line_num: usize,
-                  local_stats: &mut LocalStats|
-                  -> Result<()> {
-                local_stats.total_documents += 1;
-
-                if let Some(text) = data.text {
-                    let mut num_tokens = 0;
-
-                    if let Some(ref tokenizer) = tokenizer {
-                        let tokens = tokenizer.tokenize(&text)?;
-                        num_tokens += tokens.len();
-                    } else {
-                        for _ in tokenize(&text) {
-                            num_tokens += 1;
+        let check_encoding = opt.check_encoding;
+        let truncate_doc_tokens = opt.truncate_doc_tokens;
+        let by_lang = opt.by_lang;
+        let weight_field = opt.weight_field.clone();
+        if check_encoding || sampler.is_some() || by_lang || weight_field.is_some() {
+            let sampler = sampler.clone();
+            executor.execute_with_callback(
+                path,
+                move |raw: Box<RawValue>,
+                      path: &Path,
+                      line_num: usize,
+                      local_stats: &mut LocalStats|
+                      -> Result<()> {
+                    if let Some(ref sampler) = sampler {
+                        if !sampler.keep(path, line_num) {
+                            return Ok(());
                         }
                     }
-
-                    local_stats.total_tokens += num_tokens;
-                    local_stats.document_max_tokens =
-                        std::cmp::max(num_tokens, local_stats.document_max_tokens);
-                    local_stats.document_min_tokens =
-                        std::cmp::min(num_tokens, local_stats.document_min_tokens);
-                    if num_tokens == local_stats.document_max_tokens {
-                        local_stats.max_token_documents.push(DocumentPointer {
-                            path: path.into(),
-                            line: line_num,
-                            num_tokens,
-                        });
-                    }
-                    if num_tokens == local_stats.document_min_tokens {
-                        local_stats.min_token_documents.push(DocumentPointer {
-                            path: path.into(),
-                            line: line_num,
-                            num_tokens,
-                        });
-                    }
-                }
-
-                Ok(())
-            },
-            local_stats_factory,
-            sync_stats_callback,
-        )?;
+                    let raw = raw.get();
+                    let data: DataInstance = if check_encoding {
+                        let invalid_surrogates = count_invalid_surrogate_escapes(raw);
+                        let data: DataInstance =
+                            serde_json::from_str(&replace_invalid_surrogate_escapes(raw))?;
+                        local_stats.record_encoding(
+                            path,
+                            line_num,
+                            data.text.as_deref().unwrap_or_default(),
+                            invalid_surrogates,
+                        );
+                        data
+                    } else {
+                        serde_json::from_str(raw)?
+                    };
+                    let lang = if by_lang {
+                        serde_json::from_str::<LanguageFields>(raw)?.language()
+                    } else {
+                        None
+                    };
+                    let weight = match &weight_field {
+                        Some(field) => Some(document_weight(raw, field)?),
+                        None => None,
+                    };
+                    collect_stats(
+                        data,
+                        lang.as_deref(),
+                        weight,
+                        path,
+                        line_num,
+                        local_stats,
+                        &tokenizer,
+                        &preprocessor,
+                        &length_band,
+                        truncate_doc_tokens,
+                    )
+                },
+                local_stats_factory,
+                sync_stats_callback,
+            )?;
+        } else {
+            executor.execute_with_callback(
+                path,
+                move |data: DataInstance,
+                      path: &Path,
+                      line_num: usize,
+                      local_stats: &mut LocalStats|
+                      -> Result<()> {
+                    collect_stats(
+                        data,
+                        None,
+                        None,
+                        path,
+                        line_num,
+                        local_stats,
+                        &tokenizer,
+                        &preprocessor,
+                        &length_band,
+                        truncate_doc_tokens,
+                    )
+                },
+                local_stats_factory,
+                sync_stats_callback,
+            )?;
+        }
     }
 
     executor.join()?;
+    if let Some(writer) = per_doc_writer {
+        let writer = writer
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .take();
+        if let Some(writer) = writer {
+            writer.finish()?;
+        }
+    }
     stats.total_bytes.store(
         executor.total_bytes.load(Ordering::Relaxed),
         Ordering::Relaxed,
     );
     stats.prune_documents()?;
+    let tokens_squared = *stats
+        .tokens_squared
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
 
-    let json_out = serde_json::to_string(&stats)?;
+    let mut report = stats.into_report()?;
+    report.files = opt.path;
+    if let Some(sampler) = sampler {
+        sampler.extrapolate(&mut report, tokens_squared);
+    }
+    Ok(report)
+}
 
-    if opt.json {
-        println!("{json_out}");
-    } else if !opt.quiet {
-        for (name, value) in stats.get_display_values() {
-            println!("{}: {}", style(name).cyan(), value);
+/// Decides which lines are sampled with '--sample-rate'. Every line is sampled independently
+/// based on a hash of its path and line number, so retries sample the same lines.
+#[derive(Debug, Clone)]
+struct Sampler {
+    rate: f64,
+    seed: u64,
+}
+
+impl Sampler {
+    fn new(rate: f64, seed: u64) -> Result<Self> {
+        if !(rate > 0.0 && rate <= 1.0) {
+            bail!("--sample-rate must be greater than 0 and at most 1");
         }
+        Ok(Self { rate, seed })
+    }
 
-        // Show max token documents.
-        println!("{}:", style("max token documents").cyan());
-        let max_token_documents = stats
-            .max_token_documents
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire lock"))?;
-        for doc_pointer in (*max_token_documents).iter() {
-            println!("  - {}: {:?}", style("path").cyan(), doc_pointer.path);
-            println!("    {}: {}", style("line").cyan(), doc_pointer.line);
-            println!("    {}: {}", style("tokens").cyan(), doc_pointer.num_tokens);
+    fn keep(&self, path: &Path, line_num: usize) -> bool {
+        uniform_hash(self.seed, path, line_num, 0) < self.rate
+    }
+
+    /// Scale the totals of a report over the sampled documents up to estimates for all
+    /// documents. With every line sampled independently with probability p, the sum of the
+    /// sampled values divided by p is an unbiased estimate of the total, with an estimated
+    /// variance of (1 - p) / p^2 times the sum of the squared sampled values.
+    fn extrapolate(&self, report: &mut StatsReport, tokens_squared: f64) {
+        let p = self.rate;
+        let variance_factor = (1.0 - p) / (p * p);
+        report.sample = Some(SampleInfo {
+            rate: self.rate,
+            seed: self.seed,
+            sampled_documents: report.total_documents,
+            sampled_tokens: report.total_tokens,
+            total_documents_std_error: (variance_factor * report.total_documents as f64).sqrt(),
+            total_tokens_std_error: (variance_factor * tokens_squared).sqrt(),
+        });
+        report.total_documents = (report.total_documents as f64 / p).round() as usize;
+        report.total_tokens = (report.total_tokens as f64 / p).round() as usize;
+        if let Some(ref mut weighted) = report.weighted {
+            weighted.tokens /= p;
+            weighted.documents /= p;
+            weighted.bytes /= p;
         }
+    }
+}
 
-        // Show min token documents.
-        println!("{}:", style("min token documents").cyan());
-        let min_token_documents = stats
-            .min_token_documents
-            .lock()
-            .map_err(|_| anyhow!("Failed to acquire lock"))?;
-        for doc_pointer in (*min_token_documents).iter() {
-            println!("  - {}: {:?}", style("path").cyan(), doc_pointer.path);
-            println!("    {}: {}", style("line").cyan(), doc_pointer.line);
-            println!("    {}: {}", style("tokens").cyan(), doc_pointer.num_tokens);
+/// How the stats were sampled with '--sample-rate', and the standard errors of the
+/// extrapolated totals.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SampleInfo {
+    rate: f64,
+    seed: u64,
+    sampled_documents: usize,
+    sampled_tokens: usize,
+    total_documents_std_error: f64,
+    total_tokens_std_error: f64,
+}
+
+/// The fields that a document's language is taken from with '--by-lang'. Values that aren't
+/// strings, like the scores of some language ID tools, are ignored.
+#[derive(Debug, Deserialize)]
+pub(crate) struct LanguageFields {
+    lang: Option<Value>,
+    language: Option<Value>,
+    metadata: Option<LanguageMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LanguageMetadata {
+    lang: Option<Value>,
+    language: Option<Value>,
+}
+
+impl LanguageFields {
+    pub(crate) fn language(self) -> Option<String> {
+        let metadata = self.metadata.map(|m| [m.lang, m.language]);
+        [self.lang, self.language]
+            .into_iter()
+            .chain(metadata.into_iter().flatten())
+            .flatten()
+            .find_map(|value| match value {
+                Value::String(lang) if !lang.is_empty() => Some(lang),
+                _ => None,
+            })
+    }
+}
+
+/// The totals for a single language with '--by-lang'.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct LanguageStats {
+    tokens: usize,
+    documents: usize,
+    bytes: usize,
+}
+
+/// The totals weighted by '--weight-field'.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct WeightedTotals {
+    tokens: f64,
+    documents: f64,
+    bytes: f64,
+}
+
+impl WeightedTotals {
+    fn merge(&mut self, other: &WeightedTotals) {
+        self.tokens += other.tokens;
+        self.documents += other.documents;
+        self.bytes += other.bytes;
+    }
+}
+
+fn merge_languages(
+    languages: &mut BTreeMap<String, LanguageStats>,
+    other: BTreeMap<String, LanguageStats>,
+) {
+    for (lang, other_stats) in other {
+        let lang_stats = languages.entry(lang).or_default();
+        lang_stats.tokens += other_stats.tokens;
+        lang_stats.documents += other_stats.documents;
+        lang_stats.bytes += other_stats.bytes;
+    }
+}
+
+/// Add a document to the stats. `lang` is its language with '--by-lang' and `weight` is its
+/// weight with '--weight-field'.
+#[allow(clippy::too_many_arguments)]
+fn collect_stats(
+    data: DataInstance,
+    lang: Option<&str>,
+    weight: Option<f64>,
+    path: &Path,
+    line_num: usize,
+    local_stats: &mut LocalStats,
+    tokenizer: &Arc<dyn Tokenizer>,
+    preprocessor: &Preprocessor,
+    length_band: &LengthBand,
+    truncate_doc_tokens: Option<usize>,
+) -> Result<()> {
+    let num_bytes = data.text.as_ref().map_or(0, |text| text.len());
+    let num_tokens = if let Some(text) = data.text {
+        let text = preprocessor.apply(&text);
+        if let Some(max_tokens) = truncate_doc_tokens {
+            let (tokens, truncated) = tokenizer.tokenize_truncated(&text, max_tokens)?;
+            if truncated {
+                local_stats.truncated_documents += 1;
+            }
+            Some(tokens.len())
+        } else {
+            Some(tokenizer.count_tokens(&text)?)
         }
+    } else {
+        None
+    };
+
+    if !length_band.contains(num_tokens.unwrap_or(0)) {
+        return Ok(());
     }
 
-    if let Some(ref mut file) = out_file {
-        writeln!(file, "{json_out}")?;
+    local_stats.total_documents += 1;
+    local_stats.bytes_per_document.add(num_bytes);
+
+    if let Some(ref mut languages) = local_stats.languages {
+        let lang_stats = languages
+            .entry(lang.unwrap_or(UNKNOWN_LANGUAGE).to_string())
+            .or_default();
+        lang_stats.tokens += num_tokens.unwrap_or(0);
+        lang_stats.documents += 1;
+        lang_stats.bytes += num_bytes;
     }
 
-    if let Some(path) = out_path {
-        log::info!("Output written to {:?}", path);
+    if let (Some(weighted), Some(weight)) = (&mut local_stats.weighted, weight) {
+        weighted.tokens += weight * num_tokens.unwrap_or(0) as f64;
+        weighted.documents += weight;
+        weighted.bytes += weight * num_bytes as f64;
+    }
+
+    if let Some(ref mut per_doc) = local_stats.per_doc {
+        per_doc.push(DocumentStats {
+            path: path.into(),
+            line: line_num,
+            id: data.id,
+            tokens: num_tokens,
+            bytes: num_bytes,
+        });
+    }
+
+    if let Some(num_tokens) = num_tokens {
+        local_stats.total_tokens += num_tokens;
+        local_stats.tokens_squared += (num_tokens as f64).powi(2);
+        local_stats.tokens_per_document.add(num_tokens);
+        local_stats.document_max_tokens =
+            std::cmp::max(num_tokens, local_stats.document_max_tokens);
+        local_stats.document_min_tokens =
+            std::cmp::min(num_tokens, local_stats.document_min_tokens);
+        if num_tokens == local_stats.document_max_tokens {
+            local_stats.max_token_documents.push(DocumentPointer {
+                path: path.into(),
+                line: line_num,
+                num_tokens,
+            });
+        }
+        if num_tokens == local_stats.document_min_tokens {
+            local_stats.min_token_documents.push(DocumentPointer {
+                path: path.into(),
+                line: line_num,
+                num_tokens,
+            });
+        }
+        if let Some(ref mut extremes) = local_stats.extremes {
+            extremes.add(ExtremeDocument {
+                path: path.into(),
+                line: line_num,
+                tokens: num_tokens,
+                bytes: num_bytes,
+            });
+        }
     }
 
     Ok(())
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct DocumentPointer {
     path: PathBuf,
     line: usize,
     num_tokens: usize,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+struct ExtremeDocument {
+    path: PathBuf,
+    line: usize,
+    tokens: usize,
+    bytes: usize,
+}
+
+/// The documents with the most and fewest tokens and bytes for '--extremes'. Ties are broken
+/// by path and line number so that the result doesn't depend on the processing order.
+#[derive(Debug, Clone)]
+struct ExtremeHeaps {
+    most_tokens: BoundedHeap<Reverse<(usize, ExtremeDocument)>>,
+    fewest_tokens: BoundedHeap<(usize, ExtremeDocument)>,
+    most_bytes: BoundedHeap<Reverse<(usize, ExtremeDocument)>>,
+    fewest_bytes: BoundedHeap<(usize, ExtremeDocument)>,
+}
+
+impl ExtremeHeaps {
+    fn new(n: usize) -> Self {
+        Self {
+            most_tokens: BoundedHeap::new(n),
+            fewest_tokens: BoundedHeap::new(n),
+            most_bytes: BoundedHeap::new(n),
+            fewest_bytes: BoundedHeap::new(n),
+        }
+    }
+
+    fn add(&mut self, doc: ExtremeDocument) {
+        self.most_tokens.push(Reverse((doc.tokens, doc.clone())));
+        self.fewest_tokens.push((doc.tokens, doc.clone()));
+        self.most_bytes.push(Reverse((doc.bytes, doc.clone())));
+        self.fewest_bytes.push((doc.bytes, doc));
+    }
+
+    fn merge(&mut self, other: Self) {
+        self.most_tokens.merge(other.most_tokens);
+        self.fewest_tokens.merge(other.fewest_tokens);
+        self.most_bytes.merge(other.most_bytes);
+        self.fewest_bytes.merge(other.fewest_bytes);
+    }
+
+    fn into_report(self) -> ExtremeDocuments {
+        let most = |heap: BoundedHeap<Reverse<(usize, ExtremeDocument)>>| {
+            heap.into_sorted_vec()
+                .into_iter()
+                .map(|Reverse((_, doc))| doc)
+                .collect()
+        };
+        let fewest = |heap: BoundedHeap<(usize, ExtremeDocument)>| {
+            heap.into_sorted_vec()
+                .into_iter()
+                .map(|(_, doc)| doc)
+                .collect()
+        };
+        ExtremeDocuments {
+            n: self.most_tokens.n,
+            most_tokens: most(self.most_tokens),
+            fewest_tokens: fewest(self.fewest_tokens),
+            most_bytes: most(self.most_bytes),
+            fewest_bytes: fewest(self.fewest_bytes),
+        }
+    }
+}
+
+/// The documents from [`ExtremeHeaps`] as they're reported, sorted from the most extreme.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ExtremeDocuments {
+    n: usize,
+    most_tokens: Vec<ExtremeDocument>,
+    fewest_tokens: Vec<ExtremeDocument>,
+    most_bytes: Vec<ExtremeDocument>,
+    fewest_bytes: Vec<ExtremeDocument>,
+}
+
+impl ExtremeDocuments {
+    fn merge(self, other: Self) -> Self {
+        let mut heaps = ExtremeHeaps::new(self.n.max(other.n));
+        for report in [self, other] {
+            for doc in report.most_tokens {
+                heaps.most_tokens.push(Reverse((doc.tokens, doc)));
+            }
+            for doc in report.fewest_tokens {
+                heaps.fewest_tokens.push((doc.tokens, doc));
+            }
+            for doc in report.most_bytes {
+                heaps.most_bytes.push(Reverse((doc.bytes, doc)));
+            }
+            for doc in report.fewest_bytes {
+                heaps.fewest_bytes.push((doc.bytes, doc));
+            }
+        }
+        heaps.into_report()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct DocumentStats {
+    path: PathBuf,
+    line: usize,
+    id: Option<Value>,
+    tokens: Option<usize>,
+    bytes: usize,
+}
+
 #[derive(Debug, Clone)]
 struct LocalStats {
     total_tokens: usize,
+    truncated_documents: usize,
+    /// The sum of the squared tokens per document, for the standard error with '--sample-rate'.
+    tokens_squared: f64,
     total_documents: usize,
     document_max_tokens: usize,
     document_min_tokens: usize,
     max_token_documents: Vec<DocumentPointer>,
     min_token_documents: Vec<DocumentPointer>,
+    path: Option<PathBuf>,
+    encoding: FileEncodingStats,
+    damaged_documents: Vec<DamagedDocument>,
+    per_doc: Option<Vec<DocumentStats>>,
+    tokens_per_document: Histogram,
+    bytes_per_document: Histogram,
+    extremes: Option<ExtremeHeaps>,
+    languages: Option<BTreeMap<String, LanguageStats>>,
+    weighted: Option<WeightedTotals>,
 }
 
 impl Default for LocalStats {
     fn default() -> Self {
         Self {
             total_tokens: 0,
+            truncated_documents: 0,
+            tokens_squared: 0.0,
             total_documents: 0,
             document_max_tokens: 0,
             document_min_tokens: usize::MAX,
             max_token_documents: Vec::new(),
             min_token_documents: Vec::new(),
+            path: None,
+            encoding: FileEncodingStats::default(),
+            damaged_documents: Vec::new(),
+            per_doc: None,
+            tokens_per_document: Histogram::default(),
+            bytes_per_document: Histogram::default(),
+            extremes: None,
+            languages: None,
+            weighted: None,
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize)]
+impl LocalStats {
+    fn record_encoding(&mut self, path: &Path, line: usize, text: &str, invalid_surrogates: usize) {
+        if self.path.is_none() {
+            self.path = Some(path.into());
+        }
+
+        let replacement_chars = count_replacement_chars(text);
+        let mojibake = count_mojibake(text);
+        let damage = replacement_chars + invalid_surrogates + mojibake;
+
+        self.encoding.documents += 1;
+        self.encoding.replacement_chars += replacement_chars;
+        self.encoding.invalid_surrogates += invalid_surrogates;
+        self.encoding.mojibake += mojibake;
+        if damage > 0 {
+            self.encoding.damaged_documents += 1;
+            self.damaged_documents.push(DamagedDocument {
+                path: path.into(),
+                line,
+                damage,
+                replacement_chars,
+                invalid_surrogates,
+                mojibake,
+            });
+            if self.damaged_documents.len() >= 2 * NUM_DAMAGED_DOCUMENTS {
+                prune_damaged_documents(&mut self.damaged_documents);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct FileEncodingStats {
+    documents: usize,
+    damaged_documents: usize,
+    damaged_document_rate: f64,
+    replacement_chars: usize,
+    invalid_surrogates: usize,
+    mojibake: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct DamagedDocument {
+    path: PathBuf,
+    line: usize,
+    damage: usize,
+    replacement_chars: usize,
+    invalid_surrogates: usize,
+    mojibake: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct EncodingReport {
+    files: BTreeMap<PathBuf, FileEncodingStats>,
+    worst_documents: Vec<DamagedDocument>,
+}
+
+impl FileEncodingStats {
+    fn add(&mut self, other: &FileEncodingStats) {
+        self.documents += other.documents;
+        self.damaged_documents += other.damaged_documents;
+        self.replacement_chars += other.replacement_chars;
+        self.invalid_surrogates += other.invalid_surrogates;
+        self.mojibake += other.mojibake;
+    }
+}
+
+impl EncodingReport {
+    /// Add the stats of a file. With '--threads-per-file' a file is split over several local
+    /// stats, so this can be called more than once per file.
+    fn merge(&mut self, local_stats: &mut LocalStats) {
+        if let Some(path) = local_stats.path.take() {
+            self.files
+                .entry(path)
+                .or_default()
+                .add(&local_stats.encoding);
+        }
+        self.worst_documents
+            .append(&mut local_stats.damaged_documents);
+        prune_damaged_documents(&mut self.worst_documents);
+    }
+
+    /// Compute the damaged document rate of every file once all files are done.
+    fn finish(&mut self) {
+        for file_stats in self.files.values_mut() {
+            if file_stats.documents > 0 {
+                file_stats.damaged_document_rate =
+                    file_stats.damaged_documents as f64 / file_stats.documents as f64;
+            }
+        }
+    }
+}
+
+/// Keep only the most damaged documents, sorted by damage descending.
+fn prune_damaged_documents(documents: &mut Vec<DamagedDocument>) {
+    documents.sort_by_key(|document| std::cmp::Reverse(document.damage));
+    documents.truncate(NUM_DAMAGED_DOCUMENTS);
+}
+
+/// A histogram with fixed bucket edges. Bucket i counts values in [edges[i], edges[i + 1]),
+/// and the last bucket is open-ended. The first edge is always 0.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Histogram {
+    edges: Vec<usize>,
+    counts: Vec<usize>,
+}
+
+impl Histogram {
+    fn new(edges: &[usize]) -> Self {
+        Self {
+            edges: edges.to_vec(),
+            counts: vec![0; edges.len()],
+        }
+    }
+
+    fn add(&mut self, value: usize) {
+        let bucket = self.edges.partition_point(|&edge| edge <= value);
+        self.counts[bucket.saturating_sub(1)] += 1;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other_count) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other_count;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 struct Stats<T: std::fmt::Debug> {
     total_tokens: T,
     total_documents: T,
+    truncated_documents: T,
     total_bytes: T,
     document_max_tokens: T,
     document_min_tokens: T,
     max_token_documents: Arc<Mutex<VecDeque<DocumentPointer>>>,
     min_token_documents: Arc<Mutex<VecDeque<DocumentPointer>>>,
+    tokens_per_document: Arc<Mutex<Histogram>>,
+    bytes_per_document: Arc<Mutex<Histogram>>,
+    encoding: Option<Arc<Mutex<EncodingReport>>>,
+    extremes: Option<Arc<Mutex<ExtremeHeaps>>>,
+    languages: Option<Arc<Mutex<BTreeMap<String, LanguageStats>>>>,
+    weighted: Option<Arc<Mutex<WeightedTotals>>>,
+    tokens_squared: Arc<Mutex<f64>>,
 }
 
-impl<T: std::fmt::Debug> Stats<T> {
+/// The final stats, which is what gets written as JSON. Reports can be read back and merged.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct StatsReport {
+    total_tokens: usize,
+    total_documents: usize,
+    total_bytes: usize,
+    document_max_tokens: usize,
+    document_min_tokens: usize,
+    /// The number of documents that were cut off by '--truncate-doc-tokens'.
+    #[serde(default)]
+    truncated_documents: usize,
+    max_token_documents: Vec<DocumentPointer>,
+    min_token_documents: Vec<DocumentPointer>,
+    tokens_per_document: Histogram,
+    bytes_per_document: Histogram,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    encoding: Option<EncodingReport>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    extremes: Option<ExtremeDocuments>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    languages: Option<BTreeMap<String, LanguageStats>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    weighted: Option<WeightedTotals>,
+    /// The files the stats were collected over.
+    #[serde(default)]
+    files: Vec<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sample: Option<SampleInfo>,
+}
+
+impl StatsReport {
+    /// Read a report that was written with '-o/--out' as JSON lines, optionally compressed.
+    /// Runs with '--append' add a line to the file, so the last line is read.
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let mut last_line = None;
+        for line in BufReader::new(open_decompressed(path)?).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                last_line = Some(line);
+            }
+        }
+        let line = last_line.ok_or_else(|| anyhow!("{:?} is empty", path))?;
+        serde_json::from_str(&line).with_context(|| format!("{:?} isn't a stats report", path))
+    }
+
+    /// The numbers in the report by name, for comparing reports. Breakdowns are flattened into
+    /// dotted names, like "languages.en.tokens" or "files.<path>.documents", and histogram
+    /// buckets are named by their lower edge, like "tokens_per_document.>=100".
+    pub(crate) fn metrics(&self) -> BTreeMap<String, f64> {
+        let mut metrics = BTreeMap::new();
+        let mut add = |name: String, value: f64| {
+            metrics.insert(name, value);
+        };
+        add("total_tokens".into(), self.total_tokens as f64);
+        add("total_documents".into(), self.total_documents as f64);
+        add("total_bytes".into(), self.total_bytes as f64);
+        add(
+            "document_max_tokens".into(),
+            self.document_max_tokens as f64,
+        );
+        add(
+            "document_min_tokens".into(),
+            self.document_min_tokens as f64,
+        );
+        add(
+            "truncated_documents".into(),
+            self.truncated_documents as f64,
+        );
+        add("files".into(), self.files.len() as f64);
+        for (name, histogram) in [
+            ("tokens_per_document", &self.tokens_per_document),
+            ("bytes_per_document", &self.bytes_per_document),
+        ] {
+            for (edge, count) in histogram.edges.iter().zip(&histogram.counts) {
+                add(format!("{name}.>={edge}"), *count as f64);
+            }
+        }
+        if let Some(ref weighted) = self.weighted {
+            add("weighted.tokens".into(), weighted.tokens);
+            add("weighted.documents".into(), weighted.documents);
+            add("weighted.bytes".into(), weighted.bytes);
+        }
+        if let Some(ref languages) = self.languages {
+            for (lang, stats) in languages {
+                add(format!("languages.{lang}.tokens"), stats.tokens as f64);
+                add(
+                    format!("languages.{lang}.documents"),
+                    stats.documents as f64,
+                );
+                add(format!("languages.{lang}.bytes"), stats.bytes as f64);
+            }
+        }
+        if let Some(ref encoding) = self.encoding {
+            for (path, stats) in &encoding.files {
+                let path = path.display();
+                add(format!("files.{path}.documents"), stats.documents as f64);
+                add(
+                    format!("files.{path}.damaged_documents"),
+                    stats.damaged_documents as f64,
+                );
+            }
+        }
+        metrics
+    }
+
+    /// Merge another report into this one. Both need to have the same histogram bucket edges.
+    pub(crate) fn merge(&mut self, mut other: StatsReport) -> Result<()> {
+        if self.tokens_per_document.edges != other.tokens_per_document.edges
+            || self.bytes_per_document.edges != other.bytes_per_document.edges
+        {
+            bail!("Can't merge stats with different histogram bucket edges");
+        }
+        match (&mut self.sample, &other.sample) {
+            (None, None) => {}
+            (Some(sample), Some(other_sample))
+                if sample.rate == other_sample.rate && sample.seed == other_sample.seed =>
+            {
+                // The estimates are independent, so their variances add up.
+                sample.sampled_documents += other_sample.sampled_documents;
+                sample.sampled_tokens += other_sample.sampled_tokens;
+                sample.total_documents_std_error = sample
+                    .total_documents_std_error
+                    .hypot(other_sample.total_documents_std_error);
+                sample.total_tokens_std_error = sample
+                    .total_tokens_std_error
+                    .hypot(other_sample.total_tokens_std_error);
+            }
+            _ => bail!("Can't merge stats that weren't sampled with the same rate and seed"),
+        }
+
+        self.files.append(&mut other.files);
+        self.total_tokens += other.total_tokens;
+        self.total_documents += other.total_documents;
+        self.total_bytes += other.total_bytes;
+        self.truncated_documents += other.truncated_documents;
+        self.tokens_per_document.merge(&other.tokens_per_document);
+        self.bytes_per_document.merge(&other.bytes_per_document);
+
+        match other.document_max_tokens.cmp(&self.document_max_tokens) {
+            std::cmp::Ordering::Greater => {
+                self.document_max_tokens = other.document_max_tokens;
+                self.max_token_documents = other.max_token_documents;
+            }
+            std::cmp::Ordering::Equal => self
+                .max_token_documents
+                .append(&mut other.max_token_documents),
+            std::cmp::Ordering::Less => {}
+        }
+        match other.document_min_tokens.cmp(&self.document_min_tokens) {
+            std::cmp::Ordering::Less => {
+                self.document_min_tokens = other.document_min_tokens;
+                self.min_token_documents = other.min_token_documents;
+            }
+            std::cmp::Ordering::Equal => self
+                .min_token_documents
+                .append(&mut other.min_token_documents),
+            std::cmp::Ordering::Greater => {}
+        }
+
+        match (&mut self.encoding, other.encoding) {
+            (Some(encoding), Some(mut other_encoding)) => {
+                encoding.files.append(&mut other_encoding.files);
+                encoding
+                    .worst_documents
+                    .append(&mut other_encoding.worst_documents);
+                prune_damaged_documents(&mut encoding.worst_documents);
+            }
+            (None, Some(other_encoding)) => self.encoding = Some(other_encoding),
+            (_, None) => {}
+        }
+
+        self.extremes = match (self.extremes.take(), other.extremes) {
+            (Some(extremes), Some(other_extremes)) => Some(extremes.merge(other_extremes)),
+            (extremes, other_extremes) => extremes.or(other_extremes),
+        };
+
+        match (&mut self.languages, other.languages) {
+            (Some(languages), Some(other_languages)) => merge_languages(languages, other_languages),
+            (None, Some(other_languages)) => self.languages = Some(other_languages),
+            (_, None) => {}
+        }
+
+        match (&mut self.weighted, other.weighted) {
+            (Some(weighted), Some(other_weighted)) => weighted.merge(&other_weighted),
+            (None, Some(other_weighted)) => self.weighted = Some(other_weighted),
+            (_, None) => {}
+        }
+
+        Ok(())
+    }
+
     fn get_display_values(&self) -> Vec<(String, String)> {
-        vec![
+        let mut values = vec![
             (
                 "total tokens".to_string(),
-                format!("{:?}", self.total_tokens).separate_with_commas(),
+                self.total_tokens.separate_with_commas(),
             ),
             (
                 "total documents".to_string(),
-                format!("{:?}", self.total_documents).separate_with_commas(),
+                self.total_documents.separate_with_commas(),
             ),
             (
                 "total bytes".to_string(),
-                format!("{:?}", self.total_bytes).separate_with_commas(),
+                self.total_bytes.separate_with_commas(),
             ),
             (
                 "max tokens per document".to_string(),
-                format!("{:?}", self.document_max_tokens).separate_with_commas(),
+                self.document_max_tokens.separate_with_commas(),
             ),
             (
                 "min tokens per document".to_string(),
-                format!("{:?}", self.document_min_tokens).separate_with_commas(),
+                self.document_min_tokens.separate_with_commas(),
             ),
-        ]
+        ];
+        if let Some(ref weighted) = self.weighted {
+            values.push((
+                "weighted tokens".to_string(),
+                (weighted.tokens.round() as usize).separate_with_commas(),
+            ));
+            values.push((
+                "weighted documents".to_string(),
+                (weighted.documents.round() as usize).separate_with_commas(),
+            ));
+            values.push((
+                "weighted bytes".to_string(),
+                (weighted.bytes.round() as usize).separate_with_commas(),
+            ));
+        }
+        if self.truncated_documents > 0 {
+            values.push((
+                "truncated documents".to_string(),
+                self.truncated_documents.separate_with_commas(),
+            ));
+        }
+        if let Some(ref sample) = self.sample {
+            values[0].1 += &format!(
+                " (± {})",
+                (sample.total_tokens_std_error.round() as usize).separate_with_commas()
+            );
+            values[1].1 += &format!(
+                " (± {})",
+                (sample.total_documents_std_error.round() as usize).separate_with_commas()
+            );
+            values.push((
+                "sampled documents".to_string(),
+                format!(
+                    "{} ({}% of lines)",
+                    sample.sampled_documents.separate_with_commas(),
+                    100.0 * sample.rate
+                ),
+            ));
+        }
+        values
+    }
+
+    /// Print the report in a human-readable format.
+    pub(crate) fn display(&self) {
+        for (name, value) in self.get_display_values() {
+            println!("{}: {}", style(name).cyan(), value);
+        }
+
+        // Show max token documents.
+        println!("{}:", style("max token documents").cyan());
+        for doc_pointer in self.max_token_documents.iter() {
+            println!("  - {}: {:?}", style("path").cyan(), doc_pointer.path);
+            println!("    {}: {}", style("line").cyan(), doc_pointer.line);
+            println!("    {}: {}", style("tokens").cyan(), doc_pointer.num_tokens);
+        }
+
+        // Show min token documents.
+        println!("{}:", style("min token documents").cyan());
+        for doc_pointer in self.min_token_documents.iter() {
+            println!("  - {}: {:?}", style("path").cyan(), doc_pointer.path);
+            println!("    {}: {}", style("line").cyan(), doc_pointer.line);
+            println!("    {}: {}", style("tokens").cyan(), doc_pointer.num_tokens);
+        }
+
+        // Show extreme documents.
+        if let Some(ref extremes) = self.extremes {
+            for (name, docs) in [
+                ("most tokens", &extremes.most_tokens),
+                ("fewest tokens", &extremes.fewest_tokens),
+                ("most bytes", &extremes.most_bytes),
+                ("fewest bytes", &extremes.fewest_bytes),
+            ] {
+                println!("{}:", style(format!("documents with the {name}")).cyan());
+                for doc in docs {
+                    println!("  - {}: {:?}", style("path").cyan(), doc.path);
+                    println!("    {}: {}", style("line").cyan(), doc.line);
+                    println!("    {}: {}", style("tokens").cyan(), doc.tokens);
+                    println!("    {}: {}", style("bytes").cyan(), doc.bytes);
+                }
+            }
+        }
+
+        // Show per-language stats.
+        if let Some(ref languages) = self.languages {
+            println!("{}:", style("languages").cyan());
+            for (lang, lang_stats) in languages {
+                println!("  - {}: {}", style("language").cyan(), lang);
+                println!(
+                    "    {}: {}",
+                    style("tokens").cyan(),
+                    lang_stats.tokens.separate_with_commas()
+                );
+                println!(
+                    "    {}: {}",
+                    style("documents").cyan(),
+                    lang_stats.documents.separate_with_commas()
+                );
+                println!(
+                    "    {}: {}",
+                    style("bytes").cyan(),
+                    lang_stats.bytes.separate_with_commas()
+                );
+            }
+        }
+
+        // Show encoding damage.
+        if let Some(ref encoding) = self.encoding {
+            println!("{}:", style("encoding damage by file").cyan());
+            for (path, file_stats) in encoding.files.iter() {
+                println!("  - {}: {:?}", style("path").cyan(), path);
+                println!(
+                    "    {}: {} ({:.4}%)",
+                    style("damaged documents").cyan(),
+                    file_stats.damaged_documents.separate_with_commas(),
+                    100.0 * file_stats.damaged_document_rate
+                );
+                println!(
+                    "    {}: {}",
+                    style("replacement chars").cyan(),
+                    file_stats.replacement_chars.separate_with_commas()
+                );
+                println!(
+                    "    {}: {}",
+                    style("invalid surrogates").cyan(),
+                    file_stats.invalid_surrogates.separate_with_commas()
+                );
+                println!(
+                    "    {}: {}",
+                    style("mojibake").cyan(),
+                    file_stats.mojibake.separate_with_commas()
+                );
+            }
+            println!("{}:", style("most damaged documents").cyan());
+            for doc in encoding.worst_documents.iter() {
+                println!("  - {}: {:?}", style("path").cyan(), doc.path);
+                println!("    {}: {}", style("line").cyan(), doc.line);
+                println!("    {}: {}", style("damage").cyan(), doc.damage);
+            }
+        }
     }
 }
 
@@ -345,28 +1542,227 @@ impl Stats<Arc<AtomicUsize>> {
     }
 }
 
+impl Stats<Arc<AtomicUsize>> {
+    fn into_report(self) -> Result<StatsReport> {
+        let max_token_documents = self
+            .max_token_documents
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .iter()
+            .cloned()
+            .collect();
+        let min_token_documents = self
+            .min_token_documents
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .iter()
+            .cloned()
+            .collect();
+        let tokens_per_document = self
+            .tokens_per_document
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .clone();
+        let bytes_per_document = self
+            .bytes_per_document
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .clone();
+        let extremes = match &self.extremes {
+            Some(extremes) => Some(
+                extremes
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .clone()
+                    .into_report(),
+            ),
+            None => None,
+        };
+        let languages = match &self.languages {
+            Some(languages) => Some(
+                languages
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .clone(),
+            ),
+            None => None,
+        };
+        let weighted = match &self.weighted {
+            Some(weighted) => Some(
+                weighted
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .clone(),
+            ),
+            None => None,
+        };
+        let encoding = match &self.encoding {
+            Some(encoding) => {
+                let mut encoding = encoding
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?
+                    .clone();
+                encoding.finish();
+                Some(encoding)
+            }
+            None => None,
+        };
+
+        Ok(StatsReport {
+            total_tokens: self.total_tokens.load(Ordering::Relaxed),
+            total_documents: self.total_documents.load(Ordering::Relaxed),
+            total_bytes: self.total_bytes.load(Ordering::Relaxed),
+            document_max_tokens: self.document_max_tokens.load(Ordering::Relaxed),
+            document_min_tokens: self.document_min_tokens.load(Ordering::Relaxed),
+            truncated_documents: self.truncated_documents.load(Ordering::Relaxed),
+            max_token_documents,
+            min_token_documents,
+            tokens_per_document,
+            bytes_per_document,
+            encoding,
+            extremes,
+            languages,
+            weighted,
+            files: Vec::new(),
+            sample: None,
+        })
+    }
+}
+
 impl Default for Stats<Arc<AtomicUsize>> {
     fn default() -> Self {
         Self {
             total_tokens: Arc::new(AtomicUsize::new(0)),
             total_documents: Arc::new(AtomicUsize::new(0)),
+            truncated_documents: Arc::new(AtomicUsize::new(0)),
             total_bytes: Arc::new(AtomicUsize::new(0)),
             document_max_tokens: Arc::new(AtomicUsize::new(0)),
             document_min_tokens: Arc::new(AtomicUsize::new(usize::MAX)),
             max_token_documents: Arc::new(Mutex::new(VecDeque::new())),
             min_token_documents: Arc::new(Mutex::new(VecDeque::new())),
+            tokens_per_document: Arc::new(Mutex::new(Histogram::default())),
+            bytes_per_document: Arc::new(Mutex::new(Histogram::default())),
+            encoding: None,
+            extremes: None,
+            languages: None,
+            weighted: None,
+            tokens_squared: Arc::new(Mutex::new(0.0)),
         }
     }
 }
 
-fn get_output_file(opt: &Opt) -> Result<Option<(File, PathBuf)>> {
+fn get_output_file(opt: &Opt) -> Result<Option<(TableWriter, PathBuf)>> {
     if let Some(path) = &opt.out {
         if path.is_dir() {
             bail!("-o/--out must be a valid file name, not a directory");
         } else {
-            Ok(Some(util::get_output_file(path, opt.force)?))
+            Ok(Some(TableWriter::create(
+                path,
+                opt.force,
+                opt.out_format,
+                "stats",
+                COLUMNS,
+            )?))
         }
     } else {
         Ok(None)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    #[test]
+    fn test_by_lang() {
+        let path = std::env::temp_dir().join(format!("wimbd-stats-{}.jsonl", std::process::id()));
+        std::fs::write(
+            &path,
+            concat!(
+                "{\"text\": \"one two three\", \"lang\": \"en\"}\n",
+                "{\"text\": \"eins zwei\", \"metadata\": {\"language\": \"de\"}}\n",
+                "{\"text\": \"four\", \"lang\": \"en\"}\n",
+                "{\"text\": \"???\"}\n",
+            ),
+        )
+        .unwrap();
+
+        let mut opt = Opt::new(
+            vec![path.clone()],
+            Some(2),
+            true,
+            "unicode",
+            Preprocessor::default(),
+        );
+        opt.by_lang = true;
+        let report = collect(&opt).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let languages = report.languages.unwrap();
+        assert_eq!(
+            languages.keys().collect::<Vec<_>>(),
+            vec!["de", "en", UNKNOWN_LANGUAGE]
+        );
+        assert_eq!(languages["en"].documents, 2);
+        assert_eq!(languages["en"].tokens, 4);
+        assert_eq!(languages["de"].documents, 1);
+        assert_eq!(languages["de"].bytes, "eins zwei".len());
+        assert_eq!(languages[UNKNOWN_LANGUAGE].documents, 1);
+    }
+
+    #[test]
+    fn test_encoding_report_merges_threads() {
+        // With '--threads-per-file', the same file shows up in several local stats.
+        let path = PathBuf::from("a.jsonl");
+        let mut report = EncodingReport::default();
+        for (documents, damaged_documents) in [(3, 1), (5, 0)] {
+            let mut local_stats = LocalStats {
+                path: Some(path.clone()),
+                ..Default::default()
+            };
+            local_stats.encoding.documents = documents;
+            local_stats.encoding.damaged_documents = damaged_documents;
+            report.merge(&mut local_stats);
+        }
+        report.finish();
+
+        let file_stats = &report.files[&path];
+        assert_eq!(file_stats.documents, 8);
+        assert_eq!(file_stats.damaged_documents, 1);
+        assert_eq!(file_stats.damaged_document_rate, 0.125);
+    }
+
+    #[test]
+    fn test_read_appended_report() {
+        let input =
+            std::env::temp_dir().join(format!("wimbd-stats-read-{}.jsonl", std::process::id()));
+        std::fs::write(&input, "{\"text\": \"one two\"}\n").unwrap();
+        let opt = Opt::new(
+            vec![input.clone()],
+            None,
+            true,
+            "unicode",
+            Preprocessor::default(),
+        );
+        let first = collect(&opt).unwrap();
+        let mut second = collect(&opt).unwrap();
+        second.total_documents = 2;
+        std::fs::remove_file(&input).unwrap();
+
+        // Written with '--append', and compressed.
+        let path = input.with_extension("json.gz");
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        for report in [&first, &second] {
+            writeln!(encoder, "{}", serde_json::to_string(report).unwrap()).unwrap();
+        }
+        encoder.finish().unwrap();
+
+        let report = StatsReport::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(report.total_documents, 2);
+        assert_eq!(report.total_tokens, 2);
+    }
+}
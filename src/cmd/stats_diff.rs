@@ -0,0 +1,163 @@
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::json;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::stats::StatsReport;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to the old stats report, as written by 'wimbd stats -o'.
+    #[structopt(parse(from_os_str))]
+    old: PathBuf,
+
+    /// Path to the new stats report.
+    #[structopt(parse(from_os_str))]
+    new: PathBuf,
+
+    /// Fail if any of the '--check' metrics changed by more than this many percent, e.g. "1"
+    /// or "0.5". This is meant for gating dataset releases in scripts.
+    #[structopt(long = "max-change")]
+    max_change: Option<f64>,
+
+    /// A metric to check with '--max-change', like "total_documents" or "languages.en.tokens".
+    /// Give this option once for every metric.
+    #[structopt(long = "check", number_of_values = 1, default_value = "total_tokens")]
+    check: Vec<String>,
+
+    /// Also show metrics that didn't change.
+    #[structopt(long = "all")]
+    all: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+}
+
+/// The change of a single metric. Metrics that are missing from a report count as 0 there.
+#[derive(Debug, Serialize)]
+struct MetricDiff {
+    metric: String,
+    old: Option<f64>,
+    new: Option<f64>,
+    delta: f64,
+    /// The change in percent of the old value, if it isn't 0.
+    percent_change: Option<f64>,
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    if let Some(max_change) = opt.max_change {
+        if max_change.is_nan() || max_change < 0.0 {
+            bail!("--max-change must be a non-negative number");
+        }
+    }
+
+    let old = StatsReport::read(&opt.old)?.metrics();
+    let new = StatsReport::read(&opt.new)?.metrics();
+    let names: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+    let diffs: Vec<MetricDiff> = names
+        .into_iter()
+        .map(|name| {
+            let (old, new) = (old.get(name).copied(), new.get(name).copied());
+            let delta = new.unwrap_or(0.0) - old.unwrap_or(0.0);
+            let percent_change = match old {
+                Some(old) if old != 0.0 => Some(100.0 * delta / old),
+                _ => None,
+            };
+            MetricDiff {
+                metric: name.clone(),
+                old,
+                new,
+                delta,
+                percent_change,
+            }
+        })
+        .filter(|diff| opt.all || diff.delta != 0.0 || diff.old.is_none() || diff.new.is_none())
+        .collect();
+
+    let mut violations = Vec::new();
+    if let Some(max_change) = opt.max_change {
+        for metric in &opt.check {
+            let (old, new) = match (old.get(metric), new.get(metric)) {
+                (None, None) => bail!("--check metric {:?} isn't in either report", metric),
+                (old, new) => (old.copied().unwrap_or(0.0), new.copied().unwrap_or(0.0)),
+            };
+            let exceeded = if old == 0.0 {
+                new != 0.0
+            } else {
+                (100.0 * (new - old) / old).abs() > max_change
+            };
+            if exceeded {
+                violations.push(metric.clone());
+            }
+        }
+    }
+
+    if opt.json {
+        println!(
+            "{}",
+            json!({
+                "metrics": diffs,
+                "violations": violations,
+            })
+        );
+    } else {
+        if diffs.is_empty() {
+            println!("No differences");
+        }
+        let format_value = |value: Option<f64>| match value {
+            Some(value) => format_number(value),
+            None => "-".to_string(),
+        };
+        for diff in &diffs {
+            let change = match diff.percent_change {
+                Some(percent) => format!("{percent:+.2}%"),
+                None if diff.old.is_none() => "added".to_string(),
+                None if diff.new.is_none() => "removed".to_string(),
+                None => "n/a".to_string(),
+            };
+            let change = if diff.delta > 0.0 {
+                style(change).green()
+            } else if diff.delta < 0.0 {
+                style(change).red()
+            } else {
+                style(change).dim()
+            };
+            println!(
+                "{}: {} -> {} ({}{}, {})",
+                style(&diff.metric).cyan(),
+                format_value(diff.old),
+                format_value(diff.new),
+                if diff.delta > 0.0 { "+" } else { "" },
+                format_number(diff.delta),
+                change
+            );
+        }
+    }
+
+    if !violations.is_empty() {
+        bail!(
+            "{} changed by more than {}%",
+            violations.join(", "),
+            opt.max_change.unwrap_or_default()
+        );
+    }
+
+    Ok(())
+}
+
+/// Format a number with thousands separators, keeping decimals only for fractional values,
+/// like weighted totals.
+fn format_number(value: f64) -> String {
+    if value.fract() == 0.0 {
+        (value as i64).separate_with_commas()
+    } else {
+        format!("{value:.2}")
+    }
+}
@@ -0,0 +1,343 @@
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::json;
+use structopt::StructOpt;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::io::LineReader;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to a JSON lines file with the ngrams to tag, which may be gzip or zstd-compressed.
+    /// Each line should be a JSON object with either a "tokens" key, a list of tokens like in
+    /// the output of 'wimbd topk', or a "string" key, which is tokenized with the tokenizer
+    /// from '-t/--tokenizer'. Ngrams can have different sizes.
+    #[structopt(long = "ngrams", parse(from_os_str))]
+    ngrams: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write the spans to as JSON lines, i.e. each line will be a
+    /// JSON object with the keys "path", "line", "id", and "spans" for a document that
+    /// contains at least one of the ngrams. Spans are [start, end) character offsets into the
+    /// document's text, sorted, with overlapping and adjacent spans merged.
+    /// Documents are grouped by file but files are in no particular order. The file is
+    /// compressed if its name ends in ".gz" or ".zst".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: PathBuf,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the summary as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace. It should be the same tokenizer the ngrams were collected with.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+}
+
+/// The ngrams to tag.
+struct NgramList {
+    ngrams: HashSet<Vec<String>>,
+    /// The distinct ngram sizes, smallest first.
+    sizes: Vec<usize>,
+}
+
+impl NgramList {
+    fn read(path: &Path, tokenizer: &Arc<dyn Tokenizer>) -> Result<Self> {
+        let mut ngrams = HashSet::new();
+        for (i, line) in read_list_lines(path)?.enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let value: serde_json::Value = serde_json::from_str(&line)?;
+            let tokens: Vec<String> = if let Some(tokens) = value.get("tokens") {
+                serde_json::from_value(tokens.clone())?
+            } else if let Some(serde_json::Value::String(s)) = value.get("string") {
+                tokenizer.tokenize(s)?
+            } else {
+                bail!(
+                    "line {} of {:?} has neither a \"tokens\" nor a \"string\" key",
+                    i + 1,
+                    path
+                );
+            };
+            if !tokens.is_empty() {
+                ngrams.insert(tokens);
+            }
+        }
+
+        let mut sizes: Vec<usize> = ngrams
+            .iter()
+            .map(|ngram| ngram.len())
+            .collect::<HashSet<usize>>()
+            .into_iter()
+            .collect();
+        sizes.sort_unstable();
+
+        Ok(Self { ngrams, sizes })
+    }
+
+    /// Find the byte spans of all occurrences of the ngrams in a sequence of tokens, given the
+    /// byte span of each token. The spans are sorted and merged.
+    fn find_spans(&self, tokens: &[String], offsets: &[(usize, usize)]) -> Vec<(usize, usize)> {
+        let mut spans: Vec<(usize, usize)> = Vec::new();
+        for &n in &self.sizes {
+            for (start, ngram) in tokens.windows(n).enumerate() {
+                if self.ngrams.contains(ngram) {
+                    spans.push((offsets[start].0, offsets[start + n - 1].1));
+                }
+            }
+        }
+        merge_spans(spans)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TaggedDocument {
+    path: PathBuf,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    spans: Vec<(usize, usize)>,
+}
+
+#[derive(Default)]
+struct LocalTags {
+    documents: Vec<TaggedDocument>,
+    total_documents: usize,
+    tagged_chars: usize,
+    total_chars: usize,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.out.is_dir() {
+        bail!("-o/--out must be a valid file name, not a directory");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+
+    log::info!("Reading ngrams from {:?}...", opt.ngrams);
+    let ngram_list = Arc::new(NgramList::read(&opt.ngrams, &tokenizer)?);
+    if ngram_list.ngrams.is_empty() {
+        bail!("no ngrams found in {:?}", opt.ngrams);
+    }
+    log::info!(
+        "Tagging {} ngrams of size(s) {:?}",
+        ngram_list.ngrams.len(),
+        ngram_list.sizes
+    );
+
+    let (out_file, out_path) = util::get_output_file(&opt.out, opt.force)?;
+    let writer = Arc::new(Mutex::new(Some(out_file)));
+
+    let total_documents = Arc::new(AtomicUsize::new(0));
+    let tagged_documents = Arc::new(AtomicUsize::new(0));
+    let tagged_chars = Arc::new(AtomicUsize::new(0));
+    let total_chars = Arc::new(AtomicUsize::new(0));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Tagging", opt.quiet)?;
+
+    for path in &opt.path {
+        let tag_document = {
+            let ngram_list = ngram_list.clone();
+            let tokenizer = tokenizer.clone();
+
+            move |data: DataInstance,
+                  path: &Path,
+                  line_num: usize,
+                  local_tags: &mut LocalTags|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let (tokens, offsets): (Vec<String>, Vec<(usize, usize)>) =
+                        tokenizer.tokenize_with_offsets(&text)?.into_iter().unzip();
+
+                    local_tags.total_documents += 1;
+                    local_tags.total_chars += text.chars().count();
+
+                    let spans = to_char_spans(&text, &ngram_list.find_spans(&tokens, &offsets));
+                    if !spans.is_empty() {
+                        local_tags.tagged_chars +=
+                            spans.iter().map(|(start, end)| end - start).sum::<usize>();
+                        local_tags.documents.push(TaggedDocument {
+                            path: path.into(),
+                            line: line_num,
+                            id: data.id,
+                            spans,
+                        });
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        // Tagged documents are only written once the whole file is done so that retries don't
+        // write any document twice.
+        let write_tags_callback = {
+            let writer = writer.clone();
+            let total_documents = total_documents.clone();
+            let tagged_documents = tagged_documents.clone();
+            let tagged_chars = tagged_chars.clone();
+            let total_chars = total_chars.clone();
+
+            move |local_tags: LocalTags| -> Result<()> {
+                let mut writer = writer
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                let writer = writer
+                    .as_mut()
+                    .ok_or_else(|| anyhow!("Output is already closed"))?;
+                for document in &local_tags.documents {
+                    serde_json::to_writer(&mut *writer, document)?;
+                    writer.write_all(b"\n")?;
+                }
+
+                total_documents.fetch_add(local_tags.total_documents, Ordering::Relaxed);
+                tagged_documents.fetch_add(local_tags.documents.len(), Ordering::Relaxed);
+                tagged_chars.fetch_add(local_tags.tagged_chars, Ordering::Relaxed);
+                total_chars.fetch_add(local_tags.total_chars, Ordering::Relaxed);
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            tag_document,
+            || -> Result<LocalTags> { Ok(LocalTags::default()) },
+            write_tags_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    let writer = writer
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?
+        .take();
+    if let Some(writer) = writer {
+        writer.finish()?;
+    }
+
+    let total_documents = total_documents.load(Ordering::Relaxed);
+    let tagged_documents = tagged_documents.load(Ordering::Relaxed);
+    let tagged_chars = tagged_chars.load(Ordering::Relaxed);
+    let total_chars = total_chars.load(Ordering::Relaxed);
+
+    if opt.json {
+        println!(
+            "{}",
+            json!({
+                "total_documents": total_documents,
+                "tagged_documents": tagged_documents,
+                "total_chars": total_chars,
+                "tagged_chars": tagged_chars,
+            })
+        );
+    } else if !opt.quiet {
+        println!(
+            "{}: {}/{} ({:.2}%)",
+            style("tagged documents").cyan(),
+            tagged_documents,
+            total_documents,
+            100.0 * tagged_documents as f64 / total_documents.max(1) as f64
+        );
+        println!(
+            "{}: {}/{} ({:.2}%)",
+            style("tagged characters").cyan(),
+            tagged_chars,
+            total_chars,
+            100.0 * tagged_chars as f64 / total_chars.max(1) as f64
+        );
+    }
+
+    log::info!("Output written to {:?}", out_path);
+
+    Ok(())
+}
+
+/// Sort spans and merge the ones that overlap or touch.
+pub(crate) fn merge_spans(mut spans: Vec<(usize, usize)>) -> Vec<(usize, usize)> {
+    spans.sort_unstable();
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(spans.len());
+    for (start, end) in spans {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Convert sorted byte spans into character spans.
+pub(crate) fn to_char_spans(text: &str, spans: &[(usize, usize)]) -> Vec<(usize, usize)> {
+    if spans.is_empty() {
+        return Vec::new();
+    }
+    // Walk through the text once, since spans are sorted.
+    let mut boundaries = text
+        .char_indices()
+        .map(|(byte, _)| byte)
+        .chain(std::iter::once(text.len()))
+        .enumerate();
+    let mut char_offset = |byte: usize| -> usize {
+        for (char_idx, char_byte) in boundaries.by_ref() {
+            if char_byte >= byte {
+                return char_idx;
+            }
+        }
+        text.chars().count()
+    };
+    spans
+        .iter()
+        .map(|&(start, end)| (char_offset(start), char_offset(end)))
+        .collect()
+}
+
+/// Read the lines of the ngram list, which may be compressed.
+fn read_list_lines(path: &Path) -> Result<impl Iterator<Item = Result<String>>> {
+    Ok(LineReader::open(path)?.map(|line| -> Result<String> { Ok(line?) }))
+}
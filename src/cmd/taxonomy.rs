@@ -0,0 +1,469 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde::Serialize;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::tag::{merge_spans, to_char_spans};
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+use crate::util::{self, OutputFile};
+
+/// The prefix of the attribute names in '-o/--out', followed by the category.
+const ATTRIBUTE_PREFIX: &str = "wimbd__taxonomy__";
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// Path to a YAML file that maps category names to lists of phrases, e.g.
+    ///
+    ///   medical: ["blood pressure", "side effects"]
+    ///   legal boilerplate: ["all rights reserved", "terms of service"]
+    ///
+    /// Phrases are tokenized with '-t/--tokenizer' and match documents that contain the same
+    /// sequence of tokens, so they never match inside of words. A phrase can be in more than
+    /// one category.
+    #[structopt(long = "catalog", parse(from_os_str))]
+    catalog: PathBuf,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// A path to write Dolma-style attributes to as JSON lines, i.e. each line will be a JSON
+    /// object with the keys "path", "line", "id", and "attributes" for a document that matches
+    /// at least one category. The attributes map "wimbd__taxonomy__<category>" to a list of
+    /// [start, end, 1.0] character spans, sorted, with overlapping and adjacent spans merged.
+    /// Documents are grouped by file but files are in no particular order. The file is
+    /// compressed if its name ends in ".gz" or ".zst".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: Option<PathBuf>,
+
+    /// Also count how many documents match each pair of categories, e.g. to see which kinds
+    /// of personal information tend to show up together. The counts are shown for every pair
+    /// that co-occurs at least once, and as a full matrix with '--json'.
+    #[structopt(long = "cooccurrence")]
+    cooccurrence: bool,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format the summary as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+}
+
+/// The phrases of every category, tokenized.
+struct Catalog {
+    categories: Vec<String>,
+    /// The indices of the categories of every phrase.
+    phrases: HashMap<Vec<String>, Vec<usize>>,
+    /// The distinct phrase sizes in tokens, smallest first.
+    sizes: Vec<usize>,
+}
+
+impl Catalog {
+    fn read(path: &Path, tokenizer: &Arc<dyn Tokenizer>) -> Result<Self> {
+        let entries: BTreeMap<String, Vec<String>> = serde_yaml::from_reader(File::open(path)?)
+            .map_err(|err| anyhow!("failed to parse catalog {:?}: {}", path, err))?;
+
+        let mut categories = Vec::with_capacity(entries.len());
+        let mut phrases: HashMap<Vec<String>, Vec<usize>> = HashMap::new();
+        for (category, category_phrases) in entries {
+            let index = categories.len();
+            for phrase in &category_phrases {
+                let tokens: Vec<String> = tokenizer.tokenize(phrase)?;
+                if tokens.is_empty() {
+                    log::warn!(
+                        "Skipping phrase {:?} of {:?} without any tokens",
+                        phrase,
+                        category
+                    );
+                    continue;
+                }
+                let indices = phrases.entry(tokens).or_default();
+                if !indices.contains(&index) {
+                    indices.push(index);
+                }
+            }
+            categories.push(category);
+        }
+
+        let mut sizes: Vec<usize> = phrases.keys().map(|phrase| phrase.len()).collect();
+        sizes.sort_unstable();
+        sizes.dedup();
+
+        Ok(Self {
+            categories,
+            phrases,
+            sizes,
+        })
+    }
+
+    /// Find the byte spans of all phrase occurrences in a sequence of tokens, given the byte
+    /// span of each token, by category.
+    fn find_spans(
+        &self,
+        tokens: &[String],
+        offsets: &[(usize, usize)],
+    ) -> Vec<Vec<(usize, usize)>> {
+        let mut spans = vec![Vec::new(); self.categories.len()];
+        for &n in &self.sizes {
+            for (start, ngram) in tokens.windows(n).enumerate() {
+                if let Some(indices) = self.phrases.get(ngram) {
+                    for &index in indices {
+                        spans[index].push((offsets[start].0, offsets[start + n - 1].1));
+                    }
+                }
+            }
+        }
+        spans
+    }
+}
+
+/// The counts for a single category.
+#[derive(Debug, Clone, Default, Serialize)]
+struct CategoryCounts {
+    documents: usize,
+    occurrences: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct TaggedDocument {
+    path: PathBuf,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    attributes: BTreeMap<String, Vec<(usize, usize, f64)>>,
+}
+
+#[derive(Default)]
+struct LocalCounts {
+    total_documents: usize,
+    categories: Vec<CategoryCounts>,
+    /// The number of documents that match both categories of every pair, see [`pair_index()`].
+    /// This is empty without '--cooccurrence'.
+    cooccurrence: Vec<usize>,
+    documents: Vec<TaggedDocument>,
+}
+
+impl LocalCounts {
+    fn new(num_categories: usize, cooccurrence: bool) -> Self {
+        let num_pairs = if cooccurrence {
+            num_categories * num_categories.saturating_sub(1) / 2
+        } else {
+            0
+        };
+        Self {
+            categories: vec![CategoryCounts::default(); num_categories],
+            cooccurrence: vec![0; num_pairs],
+            ..Default::default()
+        }
+    }
+}
+
+/// The index of the pair of categories `i < j` out of `n` in [`LocalCounts::cooccurrence`],
+/// which holds the upper triangle of the co-occurrence matrix row by row.
+fn pair_index(i: usize, j: usize, n: usize) -> usize {
+    i * (2 * n - i - 1) / 2 + (j - i - 1)
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+
+    log::info!("Reading catalog from {:?}...", opt.catalog);
+    let catalog = Arc::new(Catalog::read(&opt.catalog, &tokenizer)?);
+    if catalog.phrases.is_empty() {
+        bail!("no phrases found in {:?}", opt.catalog);
+    }
+    log::info!(
+        "Tagging {} phrases in {} categories",
+        catalog.phrases.len(),
+        catalog.categories.len()
+    );
+
+    let (writer, out_path) = match &opt.out {
+        Some(path) if path.is_dir() => {
+            bail!("-o/--out must be a valid file name, not a directory")
+        }
+        Some(path) => {
+            let (file, path) = util::get_output_file(path, opt.force)?;
+            (Some(Arc::new(Mutex::new(Some(file)))), Some(path))
+        }
+        None => (None, None),
+    };
+    let totals = Arc::new(Mutex::new(LocalCounts::new(
+        catalog.categories.len(),
+        opt.cooccurrence,
+    )));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Tagging", opt.quiet)?;
+
+    for path in &opt.path {
+        let tag_document = {
+            let catalog = catalog.clone();
+            let tokenizer = tokenizer.clone();
+            let write_attributes = writer.is_some();
+
+            move |data: DataInstance,
+                  path: &Path,
+                  line_num: usize,
+                  local_counts: &mut LocalCounts|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let (tokens, offsets): (Vec<String>, Vec<(usize, usize)>) =
+                        tokenizer.tokenize_with_offsets(&text)?.into_iter().unzip();
+
+                    local_counts.total_documents += 1;
+                    let mut attributes = BTreeMap::new();
+                    let mut matched = Vec::new();
+                    for (index, spans) in catalog
+                        .find_spans(&tokens, &offsets)
+                        .into_iter()
+                        .enumerate()
+                    {
+                        if spans.is_empty() {
+                            continue;
+                        }
+                        let counts = &mut local_counts.categories[index];
+                        counts.documents += 1;
+                        counts.occurrences += spans.len();
+                        matched.push(index);
+                        if write_attributes {
+                            attributes.insert(
+                                format!("{ATTRIBUTE_PREFIX}{}", catalog.categories[index]),
+                                to_char_spans(&text, &merge_spans(spans))
+                                    .into_iter()
+                                    .map(|(start, end)| (start, end, 1.0))
+                                    .collect(),
+                            );
+                        }
+                    }
+                    if !local_counts.cooccurrence.is_empty() {
+                        let num_categories = catalog.categories.len();
+                        for (k, &i) in matched.iter().enumerate() {
+                            for &j in &matched[k + 1..] {
+                                local_counts.cooccurrence[pair_index(i, j, num_categories)] += 1;
+                            }
+                        }
+                    }
+                    if !attributes.is_empty() {
+                        local_counts.documents.push(TaggedDocument {
+                            path: path.into(),
+                            line: line_num,
+                            id: data.id,
+                            attributes,
+                        });
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        // Counts and attributes are only added once the whole file is done so that retries
+        // don't count or write any document twice.
+        let sync_counts_callback = {
+            let writer = writer.clone();
+            let totals = totals.clone();
+
+            move |local_counts: LocalCounts| -> Result<()> {
+                if let Some(writer) = &writer {
+                    write_documents(writer, &local_counts.documents)?;
+                }
+                let mut totals = totals
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                totals.total_documents += local_counts.total_documents;
+                for (total, counts) in totals.categories.iter_mut().zip(&local_counts.categories) {
+                    total.documents += counts.documents;
+                    total.occurrences += counts.occurrences;
+                }
+                for (total, count) in totals
+                    .cooccurrence
+                    .iter_mut()
+                    .zip(&local_counts.cooccurrence)
+                {
+                    *total += count;
+                }
+                Ok(())
+            }
+        };
+
+        let local_counts_factory = {
+            let num_categories = catalog.categories.len();
+            let cooccurrence = opt.cooccurrence;
+            move || -> Result<LocalCounts> { Ok(LocalCounts::new(num_categories, cooccurrence)) }
+        };
+
+        executor.execute_with_callback(
+            path,
+            tag_document,
+            local_counts_factory,
+            sync_counts_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    if let Some(writer) = writer {
+        let writer = writer
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .take();
+        if let Some(writer) = writer {
+            writer.finish()?;
+        }
+    }
+
+    let totals = totals
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    if opt.json {
+        let categories: BTreeMap<&str, &CategoryCounts> = catalog
+            .categories
+            .iter()
+            .map(|category| category.as_str())
+            .zip(&totals.categories)
+            .collect();
+        let mut json_out = json!({
+            "total_documents": totals.total_documents,
+            "categories": categories,
+        });
+        if opt.cooccurrence {
+            json_out["cooccurrence"] = json!(cooccurrence_matrix(&catalog, &totals));
+        }
+        println!("{json_out}");
+    } else if !opt.quiet {
+        for (category, counts) in catalog.categories.iter().zip(&totals.categories) {
+            println!("{}:", style(category).cyan());
+            println!(
+                "  {}: {}/{} ({:.2}%)",
+                style("documents").cyan(),
+                counts.documents.separate_with_commas(),
+                totals.total_documents.separate_with_commas(),
+                100.0 * counts.documents as f64 / totals.total_documents.max(1) as f64
+            );
+            println!(
+                "  {}: {}",
+                style("occurrences").cyan(),
+                counts.occurrences.separate_with_commas()
+            );
+        }
+        if opt.cooccurrence {
+            print_cooccurrence(&catalog, &totals);
+        }
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// The full, symmetric co-occurrence matrix by category, with the number of documents of each
+/// category on the diagonal.
+fn cooccurrence_matrix<'a>(
+    catalog: &'a Catalog,
+    totals: &LocalCounts,
+) -> BTreeMap<&'a str, BTreeMap<&'a str, usize>> {
+    let n = catalog.categories.len();
+    let mut matrix = BTreeMap::new();
+    for (i, category) in catalog.categories.iter().enumerate() {
+        let row = (0..n)
+            .map(|j| {
+                let count = match i.cmp(&j) {
+                    std::cmp::Ordering::Less => totals.cooccurrence[pair_index(i, j, n)],
+                    std::cmp::Ordering::Equal => totals.categories[i].documents,
+                    std::cmp::Ordering::Greater => totals.cooccurrence[pair_index(j, i, n)],
+                };
+                (catalog.categories[j].as_str(), count)
+            })
+            .collect();
+        matrix.insert(category.as_str(), row);
+    }
+    matrix
+}
+
+/// Print the pairs of categories that co-occur in at least one document, most frequent first.
+fn print_cooccurrence(catalog: &Catalog, totals: &LocalCounts) {
+    let n = catalog.categories.len();
+    let mut pairs: Vec<(usize, usize, usize)> = (0..n)
+        .flat_map(|i| (i + 1..n).map(move |j| (i, j)))
+        .map(|(i, j)| (i, j, totals.cooccurrence[pair_index(i, j, n)]))
+        .filter(|&(_, _, count)| count > 0)
+        .collect();
+    pairs.sort_by(|a, b| b.2.cmp(&a.2).then((a.0, a.1).cmp(&(b.0, b.1))));
+
+    println!("{}:", style("co-occurrence").cyan());
+    if pairs.is_empty() {
+        println!("  no documents match more than one category");
+    }
+    for (i, j, count) in pairs {
+        let (a, b) = (&totals.categories[i], &totals.categories[j]);
+        // The share of documents that match either category which match both.
+        let jaccard = count as f64 / (a.documents + b.documents - count).max(1) as f64;
+        println!(
+            "  {} + {}: {} documents (jaccard {:.3})",
+            style(&catalog.categories[i]).cyan(),
+            style(&catalog.categories[j]).cyan(),
+            count.separate_with_commas(),
+            jaccard
+        );
+    }
+}
+
+fn write_documents(writer: &Mutex<Option<OutputFile>>, documents: &[TaggedDocument]) -> Result<()> {
+    let mut writer = writer
+        .lock()
+        .map_err(|_| anyhow!("Failed to acquire lock"))?;
+    let writer = writer
+        .as_mut()
+        .ok_or_else(|| anyhow!("Output is already closed"))?;
+    for document in documents {
+        serde_json::to_writer(&mut *writer, document)?;
+        writer.write_all(b"\n")?;
+    }
+    Ok(())
+}
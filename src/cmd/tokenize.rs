@@ -0,0 +1,229 @@
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use serde::Serialize;
+use serde_json::Value;
+use structopt::StructOpt;
+use thousands::Separable;
+
+use super::util::{expand_paths, DataExecutor, DataInstance};
+use crate::io::{NpyDtype, NpyWriter};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::load_tokenizer;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The name of a pretrained tokenizer from HuggingFace. The unicode tokenizer can't be used
+    /// since it doesn't have token IDs.
+    #[structopt(short = "t", long = "tokenizer")]
+    tokenizer: String,
+
+    /// The directory to write the output shards to. Every input file gets a shard of token IDs
+    /// "part-<n>.npy", where n is the index of the input file, and an index of the documents in
+    /// it "part-<n>.index.jsonl". Every line of the index is a JSON object with the keys "path",
+    /// "line", "id", "start", and "length", where "start" and "length" are the position of the
+    /// document's tokens in the shard.
+    #[structopt(short = "o", long = "out", parse(from_os_str))]
+    out: PathBuf,
+
+    /// The type of the token IDs, "u16" or "u32". Defaults to the smallest type that can hold
+    /// every ID in the tokenizer's vocabulary.
+    #[structopt(long = "dtype")]
+    dtype: Option<NpyDtype>,
+
+    /// A token ID to append to every document, like the ID of an end-of-text token. It counts
+    /// towards the document's length in the index.
+    #[structopt(long = "eos-id")]
+    eos_id: Option<u32>,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Force overwriting output shards if they already exist.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+/// A line of a shard's index.
+#[derive(Debug, Serialize)]
+struct IndexEntry {
+    path: PathBuf,
+    line: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<Value>,
+    start: u64,
+    length: u64,
+}
+
+/// The shard of the file being processed and the index of its documents so far.
+struct LocalShard {
+    writer: NpyWriter,
+    index: Vec<IndexEntry>,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.out.is_file() {
+        bail!("-o/--out must be a directory, not a file");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    if opt.tokenizer == "unicode" {
+        bail!("-t/--tokenizer must be a pretrained tokenizer");
+    }
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+
+    let vocab_size = tokenizer.vocab_size().unwrap_or(usize::MAX);
+    let dtype = opt
+        .dtype
+        .unwrap_or_else(|| NpyDtype::for_vocab_size(vocab_size));
+    if dtype == NpyDtype::U16 && vocab_size > u16::MAX as usize + 1 {
+        log::warn!(
+            "The vocabulary has {} tokens, so some IDs may not fit in u16",
+            vocab_size.separate_with_commas()
+        );
+    }
+    if let Some(eos_id) = opt.eos_id {
+        if dtype == NpyDtype::U16 && eos_id > u16::MAX as u32 {
+            bail!("--eos-id {} doesn't fit in u16", eos_id);
+        }
+    }
+
+    fs::create_dir_all(&opt.out)?;
+    let shard_paths: Vec<(PathBuf, PathBuf)> = (0..opt.path.len())
+        .map(|i| {
+            (
+                opt.out.join(format!("part-{i:05}.npy")),
+                opt.out.join(format!("part-{i:05}.index.jsonl")),
+            )
+        })
+        .collect();
+    for path in shard_paths.iter().flat_map(|(shard, index)| [shard, index]) {
+        if path.is_file() {
+            if opt.force {
+                log::warn!("Overwriting output file {:?}", path);
+            } else {
+                bail!(
+                    "Output file {:?} already exists, use --force to overwrite",
+                    path
+                );
+            }
+        }
+    }
+
+    let total_documents = Arc::new(AtomicU64::new(0));
+    let total_tokens = Arc::new(AtomicU64::new(0));
+
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Tokenizing", opt.quiet)?;
+
+    let preprocessor = Preprocessor::defaults();
+
+    for (path, (shard_path, index_path)) in opt.path.iter().zip(shard_paths) {
+        let tokenize_document = {
+            let tokenizer = tokenizer.clone();
+            let eos_id = opt.eos_id;
+
+            move |data: DataInstance,
+                  path: &Path,
+                  line_num: usize,
+                  local_shard: &mut LocalShard|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let mut ids = tokenizer.tokenize_ids(&preprocessor.apply(&text))?;
+                    if let Some(eos_id) = eos_id {
+                        ids.push(eos_id);
+                    }
+                    let start = local_shard.writer.len();
+                    local_shard.writer.write_values(&ids)?;
+                    local_shard.index.push(IndexEntry {
+                        path: path.into(),
+                        line: line_num,
+                        id: data.id,
+                        start,
+                        length: ids.len() as u64,
+                    });
+                }
+                Ok(())
+            }
+        };
+
+        // The shard is only finished and its index written once the whole file is done, and a
+        // retry that starts over creates the shard again, so no document is written twice.
+        let finish_shard_callback = {
+            let total_documents = total_documents.clone();
+            let total_tokens = total_tokens.clone();
+
+            move |local_shard: LocalShard| -> Result<()> {
+                let tokens = local_shard.writer.finish()?;
+                let mut writer = BufWriter::new(File::create(&index_path)?);
+                for entry in &local_shard.index {
+                    serde_json::to_writer(&mut writer, entry)?;
+                    writer.write_all(b"\n")?;
+                }
+                writer.flush()?;
+                total_documents.fetch_add(local_shard.index.len() as u64, Ordering::Relaxed);
+                total_tokens.fetch_add(tokens, Ordering::Relaxed);
+                Ok(())
+            }
+        };
+
+        let local_shard_factory = move || -> Result<LocalShard> {
+            Ok(LocalShard {
+                writer: NpyWriter::create(&shard_path, dtype)?,
+                index: Vec::new(),
+            })
+        };
+
+        executor.execute_with_callback(
+            path,
+            tokenize_document,
+            local_shard_factory,
+            finish_shard_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    if !opt.quiet {
+        println!(
+            "Wrote {} tokens of {} documents to {} shards",
+            total_tokens.load(Ordering::Relaxed).separate_with_commas(),
+            total_documents
+                .load(Ordering::Relaxed)
+                .separate_with_commas(),
+            opt.path.len().separate_with_commas()
+        );
+    }
+    log::info!("Output written to {:?}", opt.out);
+
+    Ok(())
+}
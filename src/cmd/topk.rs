@@ -1,28 +1,65 @@
-use std::collections::VecDeque;
-use std::fs::File;
-use std::io::Write;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
 use std::ops::AddAssign;
 use std::path::{Path, PathBuf};
-use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::mpsc::sync_channel;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use atomic_traits::{Atomic, NumOps};
 use console::style;
 use num_traits::{Bounded, NumCast, One, SaturatingSub, Zero};
+use serde::{Deserialize, Serialize};
 use serde_json::json;
+use serde_json::value::RawValue;
 use structopt::StructOpt;
+use thousands::Separable;
 
-use super::util::{parse_size_default_to_gb, DataExecutor, DataInstance};
-use crate::ngrams::{NgramCounter, TopKNgrams};
-use crate::tokens::{tokenize, PretrainedTokenizer};
-use crate::util;
+use super::util::{
+    document_weight, expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance, LengthBand,
+};
+use crate::index::{IndexMetadata, NgramIndex};
+use crate::io;
+use crate::ngrams::{NgramCounter, NgramWindows, SpillCounter, TopKNgrams};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::table::{ColumnType, OutFormat, TableWriter};
+use crate::tokens::load_tokenizer;
+
+/// How often to update the '--tui' dashboard with the current top-k.
+const DASHBOARD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The number of top-k candidates to show in the '--tui' dashboard.
+const NUM_DASHBOARD_CANDIDATES: usize = 10;
+
+/// The number of spill files to partition counts into with '--exact'. Each partition has to fit
+/// in memory when the counts are aggregated at the end.
+const NUM_SPILL_PARTITIONS: usize = 256;
+
+/// The max number of distinct ngrams each worker aggregates in memory with '--exact' before
+/// spilling them to disk.
+const MAX_LOCAL_NGRAMS: usize = 1_000_000;
+
+/// The name of the file in '--checkpoint-dir' that points to the latest checkpoint.
+const CHECKPOINT_STATE_FILE: &str = "state.json";
+
+/// The columns of CSV and Parquet output files.
+const COLUMNS: &[(&str, ColumnType)] = &[
+    ("tokens", ColumnType::StringList),
+    ("string", ColumnType::String),
+    ("count", ColumnType::Int),
+    ("rank", ColumnType::Int),
+];
 
 #[derive(Debug, StructOpt, Clone)]
 pub(crate) struct Opt {
-    /// Path to a gzip-compressed JSON lines file.
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
     #[structopt(parse(from_os_str))]
     path: Vec<PathBuf>,
 
@@ -60,8 +97,11 @@ pub(crate) struct Opt {
     #[structopt(long = "seed")]
     seed: Option<u64>,
 
-    /// A path to write the output to. Output will be written as JSON lines, i.e.
-    /// each line will be a JSON object with the keys "ngram" and "count".
+    /// A path to write the output to. Output will be written as JSON lines by default, i.e.
+    /// each line will be a JSON object with the keys "tokens", "string", "count", "rank", and
+    /// "fraction_of_corpus", which is the count divided by the total number of ngrams in the
+    /// data, or the number of documents with '--doc-freq'. See '--out-format' for other
+    /// formats, which don't include "fraction_of_corpus".
     ///
     /// If given a valid file name, the output will be written to that file. If the file
     /// already exists and you want to overwrite it, use the '-f/--force' option.
@@ -70,6 +110,13 @@ pub(crate) struct Opt {
     #[structopt(short = "o", long = "out")]
     out: Option<PathBuf>,
 
+    /// The format of the '-o/--out' file: "jsonl", "csv", "parquet", or "sqlite". CSV and
+    /// Parquet files have the columns "tokens", "string", "count", and "rank". In CSV files the
+    /// tokens are written as a JSON array. SQLite databases get the rows appended to the
+    /// "ngrams" table, so they're never overwritten.
+    #[structopt(long = "out-format", default_value = "jsonl", possible_values = &["jsonl", "csv", "parquet", "sqlite"])]
+    out_format: OutFormat,
+
     /// Don't show progress bars and minimize other output.
     /// This doesn't affect logging.
     #[structopt(short = "q", long = "quiet")]
@@ -88,12 +135,45 @@ pub(crate) struct Opt {
     #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
     tokenizer: String,
 
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+
+    /// Only include documents with at least this many tokens.
+    #[structopt(long = "min-doc-tokens")]
+    min_doc_tokens: Option<usize>,
+
+    /// Only include documents with at most this many tokens.
+    #[structopt(long = "max-doc-tokens")]
+    max_doc_tokens: Option<usize>,
+
+    /// Stop tokenizing documents after this many tokens and only count the ngrams in those,
+    /// so that huge documents, like minified code, don't stall a worker. The number of
+    /// truncated documents is logged at the end. '--min-doc-tokens' and '--max-doc-tokens'
+    /// apply to the truncated documents.
+    #[structopt(long = "truncate-doc-tokens")]
+    truncate_doc_tokens: Option<usize>,
+
     /// Set a minimum count threshold for ngrams to be considered for the top-k.
     /// Setting a high threshold can improve speed, but be careful not to set a threshold
     /// higher than what you expect the minimum count in the top-k to be.
     #[structopt(long = "threshold", default_value = "1")]
     threshold: u32,
 
+    /// Raise the threshold automatically while files are still being processed. Normally
+    /// ngrams are only held back once their count is below the min count of the global top-k,
+    /// which is only updated as whole files finish, so early in a large run nearly every ngram
+    /// is a candidate. With this option every worker also shares the min count of its own
+    /// top-k, which is a lower bound of the final min count just the same, and the highest one
+    /// becomes the threshold for all workers. This cuts the work of merging candidates without
+    /// dropping any ngram that belongs in the top-k. This doesn't apply with '--exact'.
+    #[structopt(long = "auto-threshold")]
+    auto_threshold: bool,
+
     /// Use u64 integers instead of u32 integers in the hash table.
     /// The doubles the memory requirements for a given hash table size and therefore increases the
     /// probability of hash collisions for a given memory budget, but may be useful when the topk
@@ -101,6 +181,172 @@ pub(crate) struct Opt {
     /// Note that overflows are always guarded against by capping the counts to the data type max.
     #[structopt(long = "u64")]
     use_u64: bool,
+
+    /// Count the number of documents that contain each ngram instead of the number of times
+    /// it occurs, so that every document counts at most once. This keeps ngrams that are
+    /// repeated many times in a few documents, like boilerplate, from crowding out ngrams
+    /// that are in many documents, which is usually what matters for contamination and
+    /// corpus analyses.
+    #[structopt(long = "doc-freq")]
+    doc_freq: bool,
+
+    /// Save the ngram counter to this directory as an index that can be queried later
+    /// with 'wimbd serve'. If '--seed' isn't given, a random seed is chosen and saved
+    /// with the index.
+    #[structopt(long = "save-index", parse(from_os_str))]
+    save_index: Option<PathBuf>,
+
+    /// Save a checkpoint to this directory after every '--checkpoint-files' files, so that a
+    /// long run can be continued with '--resume' after a crash. A checkpoint has the ngram
+    /// counter, the current top-k candidates, and the list of completed files, so it takes
+    /// about as much disk space as '--size'. This doesn't apply with '--exact'.
+    #[structopt(long = "checkpoint-dir", parse(from_os_str))]
+    checkpoint_dir: Option<PathBuf>,
+
+    /// The number of files to process between checkpoints. All files of a batch have to finish
+    /// before its checkpoint is written, so some workers sit idle at the end of every batch.
+    #[structopt(long = "checkpoint-files", default_value = "256")]
+    checkpoint_files: usize,
+
+    /// Continue from the checkpoint in '--checkpoint-dir', skipping the files it completed.
+    /// The ngram size, tokenizer, '--size', '-h/--hashes', '--u64', and '--doc-freq' have to be
+    /// the same as in the original run, and the seed is taken from the checkpoint. Other
+    /// options that change the counts, like '--limit' or '--weight-field', should also be the
+    /// same. If there's no checkpoint yet, this starts from scratch.
+    #[structopt(long = "resume")]
+    resume: bool,
+
+    /// Count ngrams exactly instead of with a counting Bloom filter. This trades memory for
+    /// disk space in '--tmp-dir' and is much slower, but the counts are true counts
+    /// instead of upper bounds. The '--size', '--hashes', and '--seed' options
+    /// don't apply.
+    #[structopt(long = "exact")]
+    exact: bool,
+
+    /// The backend to use with '--exact'. Currently the only option is "sorted-spill",
+    /// which hash-partitions partial counts into compressed spill files and aggregates the
+    /// partitions one at a time at the end.
+    #[structopt(long = "backend", default_value = "sorted-spill")]
+    backend: ExactBackend,
+
+    /// Weight every document's ngrams by this numeric field of the document, e.g. a sampling
+    /// weight or the number of epochs, so that counts reflect the effective training mixture
+    /// rather than the raw data. Nested fields are separated by dots, like "metadata.weight".
+    /// Weights are rounded to whole numbers since counts are integers, so fractional weights
+    /// should be scaled up first. Documents without the field have a weight of 1.
+    #[structopt(long = "weight-field")]
+    weight_field: Option<String>,
+}
+
+/// What's saved to '--checkpoint-dir' after every batch of files.
+#[derive(Debug, Serialize, Deserialize)]
+struct CheckpointState {
+    ngram: usize,
+    tokenizer: String,
+    size: u64,
+    hashes: u8,
+    seed: u64,
+    use_u64: bool,
+    doc_freq: bool,
+    /// The file in the checkpoint directory with the raw counts of the ngram counter.
+    counts_file: String,
+    completed: BTreeSet<PathBuf>,
+    /// The ngrams and counts of the global top-k.
+    candidates: Vec<(Vec<String>, u64)>,
+    total_ngrams: u64,
+    truncated_documents: usize,
+}
+
+impl CheckpointState {
+    fn load(dir: &Path) -> Result<Option<Self>> {
+        let path = dir.join(CHECKPOINT_STATE_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        let state = serde_json::from_reader(BufReader::new(File::open(&path)?))
+            .with_context(|| format!("Failed to read checkpoint {path:?}"))?;
+        Ok(Some(state))
+    }
+
+    /// Make sure the counts of the checkpoint can be added to with these options.
+    fn check(&self, opt: &Opt) -> Result<()> {
+        if self.ngram != opt.ngram
+            || self.tokenizer != opt.tokenizer
+            || self.size != opt.size
+            || self.hashes != opt.hashes
+            || self.use_u64 != opt.use_u64
+            || self.doc_freq != opt.doc_freq
+        {
+            bail!(
+                "The checkpoint was made with different options: -n {}, -t {}, --size {}, -h {}{}{}",
+                self.ngram,
+                self.tokenizer,
+                self.size,
+                self.hashes,
+                if self.use_u64 { ", --u64" } else { "" },
+                if self.doc_freq { ", --doc-freq" } else { "" }
+            );
+        }
+        if opt.seed.is_some_and(|seed| seed != self.seed) {
+            bail!(
+                "The checkpoint was made with --seed {}, not {}",
+                self.seed,
+                opt.seed.unwrap()
+            );
+        }
+        Ok(())
+    }
+
+    /// Write the counts and then the state, which points to them. Both are written to temporary
+    /// files first so a crash never leaves a partial checkpoint behind, and the counts of the
+    /// previous checkpoint are only removed once the new state is in place.
+    fn save<A>(
+        &self,
+        dir: &Path,
+        ngram_counts: &NgramCounter<A>,
+        previous_counts_file: Option<&str>,
+    ) -> Result<()>
+    where
+        A: Atomic + NumOps,
+        <A as Atomic>::Type: Zero + One + Bounded + NumCast + Ord + SaturatingSub + Clone,
+    {
+        let counts_path = dir.join(&self.counts_file);
+        let tmp_path = dir.join(format!("{}.tmp", self.counts_file));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        ngram_counts.write_counts(&mut writer)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &counts_path)?;
+
+        let state_path = dir.join(CHECKPOINT_STATE_FILE);
+        let tmp_path = dir.join(format!("{CHECKPOINT_STATE_FILE}.tmp"));
+        let mut writer = BufWriter::new(File::create(&tmp_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp_path, &state_path)?;
+
+        if let Some(previous) = previous_counts_file {
+            if previous != self.counts_file {
+                fs::remove_file(dir.join(previous))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ExactBackend {
+    SortedSpill,
+}
+
+impl FromStr for ExactBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "sorted-spill" => Ok(ExactBackend::SortedSpill),
+            _ => bail!("unknown backend '{}', expected 'sorted-spill'", s),
+        }
+    }
 }
 
 pub(crate) fn main(mut opt: Opt) -> Result<()> {
@@ -117,18 +363,68 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
     if opt.ngram == 0 {
         bail!("-n/--ngram must be greater than 0");
     }
+    if opt.exact && opt.save_index.is_some() {
+        bail!("--save-index can't be used with --exact");
+    }
+    if opt.save_index.is_some() && opt.use_u64 {
+        bail!("--save-index can't be used with --u64");
+    }
+    if opt.exact && opt.checkpoint_dir.is_some() {
+        bail!("--checkpoint-dir can't be used with --exact");
+    }
+    if opt.resume && opt.checkpoint_dir.is_none() {
+        bail!("--resume requires --checkpoint-dir");
+    }
+    if opt.checkpoint_files == 0 {
+        bail!("--checkpoint-files must be greater than 0");
+    }
+    opt.path = expand_paths(&opt.path)?;
     if let Some(file_limit) = opt.file_limit {
         opt.path.truncate(file_limit);
     }
+    // Resolve the output file name first, since generated names include the seed if it's given.
+    opt.out = get_output_path(&opt);
+    let checkpoint = match &opt.checkpoint_dir {
+        Some(dir) => match CheckpointState::load(dir)? {
+            Some(state) if opt.resume => {
+                state.check(&opt)?;
+                // The counts only line up with the hash functions they were made with.
+                opt.seed = Some(state.seed);
+                Some(state)
+            }
+            Some(_) if !opt.force => bail!(
+                "{:?} already has a checkpoint, use --resume or -f/--force to start over",
+                dir
+            ),
+            Some(_) => None,
+            None => {
+                if opt.resume {
+                    log::warn!(
+                        "There's no checkpoint in {:?} yet, starting from scratch",
+                        dir
+                    );
+                }
+                fs::create_dir_all(dir)?;
+                None
+            }
+        },
+        None => None,
+    };
+    // Pick a seed up front so that it's recorded in the run metadata.
+    provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
 
-    if opt.use_u64 {
-        topk::<AtomicU64>(opt)
+    if opt.exact {
+        match opt.backend {
+            ExactBackend::SortedSpill => topk_exact(opt),
+        }
+    } else if opt.use_u64 {
+        topk::<AtomicU64>(opt, checkpoint)
     } else {
-        topk::<AtomicU32>(opt)
+        topk::<AtomicU32>(opt, checkpoint)
     }
 }
 
-fn topk<A>(opt: Opt) -> Result<()>
+fn topk<A>(opt: Opt, checkpoint: Option<CheckpointState>) -> Result<()>
 where
     A: Atomic + NumOps + Send + Sync + 'static,
     <A as Atomic>::Type: Zero
@@ -146,13 +442,15 @@ where
         + serde::Serialize,
 {
     let mut topk: TopKNgrams<String, A> = TopKNgrams::new(opt.topk);
-    let (tx, rx) = sync_channel::<(Vec<String>, <A as Atomic>::Type)>(512_000);
+    // The highest min count of any worker's local top-k so far, for '--auto-threshold'.
+    let auto_threshold: Arc<A> = Arc::new(<A as Atomic>::new(<A as Atomic>::Type::zero()));
 
-    let tokenizer: Option<PretrainedTokenizer> = if &opt.tokenizer == "unicode" {
-        None
-    } else {
-        Some(PretrainedTokenizer::new(&opt.tokenizer)?)
-    };
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+    let length_band = LengthBand::new(opt.min_doc_tokens, opt.max_doc_tokens)?;
+    let truncated_documents = Arc::new(AtomicUsize::new(0));
+    let total_ngrams = Arc::new(AtomicU64::new(0));
 
     let (mut out_file, out_path) = match get_output_file(&opt)? {
         Some(out) => (Some(out.0), Some(out.1)),
@@ -176,113 +474,280 @@ where
         <A as Atomic>::Type::zero(),
     )?);
 
+    // The files that are already counted, and the counts file of the last checkpoint.
+    let mut completed: BTreeSet<PathBuf> = BTreeSet::new();
+    let mut counts_file: Option<String> = None;
+    if let Some(state) = checkpoint {
+        let dir = opt.checkpoint_dir.as_ref().unwrap();
+        log::info!(
+            "Resuming from the checkpoint in {:?} with {} completed file(s)...",
+            dir,
+            state.completed.len()
+        );
+        ngram_counts.read_counts(BufReader::new(File::open(dir.join(&state.counts_file))?))?;
+        for (ngram, count) in state.candidates {
+            topk.insert(
+                ngram,
+                <<A as Atomic>::Type as NumCast>::from(count)
+                    .unwrap_or_else(<A as Atomic>::Type::max_value),
+            );
+        }
+        total_ngrams.store(state.total_ngrams, Ordering::Relaxed);
+        truncated_documents.store(state.truncated_documents, Ordering::Relaxed);
+        completed = state.completed;
+        counts_file = Some(state.counts_file);
+    }
+
     log::info!("Counting ngrams...");
+    // The executor of each batch would only record the files of that batch.
+    provenance::record_files(&opt.path);
+    let remaining: Vec<PathBuf> = opt
+        .path
+        .iter()
+        .filter(|path| !completed.contains(*path))
+        .cloned()
+        .collect();
+    // Without checkpoints all files are processed in one batch.
+    let batch_size = match opt.checkpoint_dir {
+        Some(_) => opt.checkpoint_files,
+        None => std::cmp::max(1, remaining.len()),
+    };
+    let mut last_dashboard_update = Instant::now();
 
-    let executor = DataExecutor::new(
-        &opt.path,
-        opt.workers,
-        opt.limit,
-        "Counting ngrams",
-        opt.quiet,
-    )?;
+    for batch in remaining.chunks(batch_size) {
+        let (tx, rx) = sync_channel::<(Vec<String>, <A as Atomic>::Type)>(512_000);
+        let mut executor =
+            DataExecutor::new(batch, opt.workers, opt.limit, "Counting ngrams", opt.quiet)?;
+        executor.enable_threads_per_file();
+        executor.set_shared_state();
 
-    // Send work to threads. Each job reads a file, collects ngrams, increments each ngram's global count,
-    // and then collects it's own local top-k which it will merge with the global top-k after
-    // processing the file.
-    // The fact that each worker uses the current global counts for each ngram to fill its local
-    // top-k ensures that the final top-k will be correct (ignoring hash collisions in Bloom
-    // counter).
-    for path in &opt.path {
-        // This is our function that collects/counts ngrams from a data line.
-        let collect_ngrams = {
-            let tokenizer = tokenizer.clone();
-            let ngram_counts = ngram_counts.clone();
-            let min_count = topk.min_count();
-            let threshold = <<A as Atomic>::Type as NumCast>::from(opt.threshold).unwrap();
+        // Send work to threads. Each job reads a file, collects ngrams, increments each ngram's global count,
+        // and then collects it's own local top-k which it will merge with the global top-k after
+        // processing the file.
+        // The fact that each worker uses the current global counts for each ngram to fill its local
+        // top-k ensures that the final top-k will be correct (ignoring hash collisions in Bloom
+        // counter).
+        for path in batch {
+            // This is our function that collects/counts ngrams from a data line.
+            let collect_ngrams = {
+                let tokenizer = tokenizer.clone();
+                let ngram_counts = ngram_counts.clone();
+                let min_count = topk.min_count();
+                let threshold = <<A as Atomic>::Type as NumCast>::from(opt.threshold).unwrap();
+                let auto_threshold = opt.auto_threshold.then(|| auto_threshold.clone());
+                let truncated_documents = truncated_documents.clone();
+                let total_ngrams = total_ngrams.clone();
 
-            move |data: DataInstance,
-                  _: &Path,
-                  _: usize,
-                  local_topk: &mut TopKNgrams<String, A>|
-                  -> Result<()> {
-                if let Some(text) = data.text {
-                    let tokens: Box<dyn Iterator<Item = String>> =
-                        if let Some(tokenizer) = &tokenizer {
-                            Box::new(tokenizer.tokenize(&text)?.into_iter())
+                move |data: DataInstance,
+                      weight: u64,
+                      local_topk: &mut TopKNgrams<String, A>|
+                      -> Result<()> {
+                    if weight == 0 {
+                        return Ok(());
+                    }
+                    let increment_by = <<A as Atomic>::Type as NumCast>::from(weight)
+                        .unwrap_or_else(<A as Atomic>::Type::max_value);
+                    if let Some(text) = data.text {
+                        let text = preprocessor.apply(&text);
+                        let tokens = if let Some(max_tokens) = opt.truncate_doc_tokens {
+                            let (tokens, truncated) =
+                                tokenizer.tokenize_truncated(&text, max_tokens)?;
+                            if truncated {
+                                truncated_documents.fetch_add(1, Ordering::Relaxed);
+                            }
+                            tokens.into_iter().map(Cow::Owned).collect()
                         } else {
-                            Box::new(tokenize(&text).map(|s| s.to_string()))
+                            tokenizer.tokens(&text)?
                         };
-
-                    let mut ngram_deque: VecDeque<String> = VecDeque::with_capacity(opt.ngram);
-                    for token in tokens {
-                        if ngram_deque.len() == opt.ngram {
-                            ngram_deque.pop_front();
+                        if !length_band.contains(tokens.len()) {
+                            return Ok(());
                         }
+                        let tokens = tokens.iter().map(|token| token.as_ref());
 
-                        ngram_deque.push_back(token);
-
-                        if ngram_deque.len() == opt.ngram {
+                        let mut num_ngrams: u64 = 0;
+                        let mut seen: HashSet<Vec<&str>> = HashSet::new();
+                        for ngram in NgramWindows::new(tokens, opt.ngram) {
+                            if opt.doc_freq && !seen.insert(ngram.clone()) {
+                                continue;
+                            }
+                            num_ngrams += 1;
                             let count: <A as Atomic>::Type =
-                                ngram_counts.increment(&ngram_deque, <A as Atomic>::Type::one());
+                                ngram_counts.increment(&ngram[..], increment_by);
                             if count > threshold
                                 && count >= local_topk.min_count
                                 && count >= min_count.load(Ordering::Relaxed)
+                                && auto_threshold
+                                    .as_ref()
+                                    .is_none_or(|auto| count >= auto.load(Ordering::Relaxed))
                             {
-                                let ngram: Vec<String> = ngram_deque.iter().cloned().collect();
+                                let ngram: Vec<String> =
+                                    ngram.iter().map(|s| s.to_string()).collect();
                                 local_topk.insert(ngram, count);
+                                if let Some(auto) = &auto_threshold {
+                                    // The local top-k holds k distinct ngrams with at least this
+                                    // count once it's full, so the final top-k can't have a lower
+                                    // min count. Racing stores can only lower the threshold, which
+                                    // is still safe.
+                                    if local_topk.min_count > auto.load(Ordering::Relaxed) {
+                                        auto.store(local_topk.min_count, Ordering::Relaxed);
+                                    }
+                                }
                             }
                         }
+                        // With '--doc-freq' counts are relative to the number of documents.
+                        let total = if opt.doc_freq { 1 } else { num_ngrams };
+                        total_ngrams.fetch_add(total.saturating_mul(weight), Ordering::Relaxed);
                     }
+
+                    Ok(())
                 }
+            };
 
-                Ok(())
-            }
-        };
+            // This callback will be invoked at the end of a file to merge the local top-k with the
+            // global top-k.
+            let sync_local_topk_callback = {
+                let min_count = topk.min_count();
+                let threshold = <<A as Atomic>::Type as NumCast>::from(opt.threshold).unwrap();
+                let auto_threshold = opt.auto_threshold.then(|| auto_threshold.clone());
+                let tx = tx.clone();
 
-        // This callback will be invoked at the end of a file to merge the local top-k with the
-        // global top-k.
-        let sync_local_topk_callback = {
-            let min_count = topk.min_count();
-            let threshold = <<A as Atomic>::Type as NumCast>::from(opt.threshold).unwrap();
-            let tx = tx.clone();
-
-            move |mut local_topk: TopKNgrams<String, A>| -> Result<()> {
-                for (ngram, count) in local_topk.drain() {
-                    if count > threshold && count >= min_count.load(Ordering::Relaxed) {
-                        tx.send((ngram.to_vec(), count))?;
+                move |mut local_topk: TopKNgrams<String, A>| -> Result<()> {
+                    let auto_threshold = auto_threshold
+                        .as_ref()
+                        .map_or(threshold, |auto| auto.load(Ordering::Relaxed));
+                    for (ngram, count) in local_topk.drain() {
+                        if count > threshold
+                            && count >= min_count.load(Ordering::Relaxed)
+                            && count >= auto_threshold
+                        {
+                            tx.send((ngram.to_vec(), count))?;
+                        }
                     }
+                    Ok(())
                 }
-                Ok(())
+            };
+
+            // This is just for initializing the local top-k.
+            let local_topk_factory = move || -> Result<TopKNgrams<String, A>> {
+                let topk: TopKNgrams<String, A> = TopKNgrams::new(opt.topk);
+                Ok(topk)
+            };
+
+            if let Some(weight_field) = opt.weight_field.clone() {
+                executor.execute_with_callback(
+                    path,
+                    move |raw: Box<RawValue>,
+                          _: &Path,
+                          _: usize,
+                          local_topk: &mut TopKNgrams<String, A>|
+                          -> Result<()> {
+                        let raw = raw.get();
+                        let weight = document_weight(raw, &weight_field)?.round() as u64;
+                        collect_ngrams(serde_json::from_str(raw)?, weight, local_topk)
+                    },
+                    local_topk_factory,
+                    sync_local_topk_callback,
+                )?;
+            } else {
+                executor.execute_with_callback(
+                    path,
+                    move |data: DataInstance,
+                          _: &Path,
+                          _: usize,
+                          local_topk: &mut TopKNgrams<String, A>|
+                          -> Result<()> { collect_ngrams(data, 1, local_topk) },
+                    local_topk_factory,
+                    sync_local_topk_callback,
+                )?;
             }
-        };
+        }
 
-        // This is just for initializing the local top-k.
-        let local_topk_factory = move || -> Result<TopKNgrams<String, A>> {
-            let topk: TopKNgrams<String, A> = TopKNgrams::new(opt.topk);
-            Ok(topk)
-        };
+        drop(tx);
 
-        executor.execute_with_callback(
-            path,
-            collect_ngrams,
-            local_topk_factory,
-            sync_local_topk_callback,
-        )?;
-    }
+        // Collect ngrams and counts from channel until all jobs are done.
+        while !executor.done() {
+            while let Ok((ngram, count)) = rx.recv_timeout(Duration::from_secs(1)) {
+                topk.insert(ngram, count);
+                if executor.has_errors() || last_dashboard_update.elapsed() >= DASHBOARD_INTERVAL {
+                    break;
+                }
+            }
 
-    drop(tx);
+            if let Some(dashboard) = executor.dashboard() {
+                if last_dashboard_update.elapsed() >= DASHBOARD_INTERVAL {
+                    // Counting the non-zero entries requires a full scan of the hash table, so we
+                    // don't do this very often.
+                    let occupancy = ngram_counts.nonzero() as f64 / counter_size as f64;
+                    dashboard.set_metric("counter occupancy", format!("{:.2}%", 100.0 * occupancy));
+                    dashboard.set_metric("top-k min count", topk.min_count.to_string());
+                    dashboard.set_candidates(
+                        topk.iter()
+                            .take(NUM_DASHBOARD_CANDIDATES)
+                            .map(|(ngram, count)| format!("{:?} ({count})", ngram.join(" ")))
+                            .collect(),
+                    );
+                    last_dashboard_update = Instant::now();
+                }
+            }
+        }
 
-    // Collect ngrams and counts from channel until all jobs are done.
-    while !executor.done() {
-        while let Ok((ngram, count)) = rx.recv_timeout(Duration::from_secs(1)) {
+        executor.join()?;
+
+        for (ngram, count) in rx.try_iter() {
             topk.insert(ngram, count);
-            if executor.has_errors() {
-                break;
-            }
+        }
+
+        if let Some(dir) = &opt.checkpoint_dir {
+            completed.extend(batch.iter().cloned());
+            let state = CheckpointState {
+                ngram: opt.ngram,
+                tokenizer: opt.tokenizer.clone(),
+                size: opt.size,
+                hashes: opt.hashes,
+                seed: opt.seed.unwrap(),
+                use_u64: opt.use_u64,
+                doc_freq: opt.doc_freq,
+                // Named after the number of completed files so that the counts of the previous
+                // checkpoint are never overwritten.
+                counts_file: format!("counts-{}.bin", completed.len()),
+                completed: completed.clone(),
+                candidates: topk
+                    .iter()
+                    .map(|(ngram, count)| {
+                        (
+                            ngram.clone(),
+                            <u64 as NumCast>::from(count).unwrap_or(u64::MAX),
+                        )
+                    })
+                    .collect(),
+                total_ngrams: total_ngrams.load(Ordering::Relaxed),
+                truncated_documents: truncated_documents.load(Ordering::Relaxed),
+            };
+            state.save(dir, &ngram_counts, counts_file.as_deref())?;
+            counts_file = Some(state.counts_file);
+            log::info!(
+                "Checkpoint written to {:?} ({} of {} files completed)",
+                dir,
+                completed.len(),
+                opt.path.len()
+            );
         }
     }
+    log_truncated_documents(&opt, &truncated_documents);
+    let total_ngrams = log_total_ngrams(&opt, &total_ngrams);
 
-    executor.join()?;
+    if let Some(dir) = &opt.save_index {
+        log::info!("Saving index...");
+        let metadata = IndexMetadata {
+            ngram: opt.ngram,
+            tokenizer: opt.tokenizer.clone(),
+            hashes: opt.hashes as usize,
+            seed: opt.seed.unwrap(),
+            size: counter_size as usize,
+        };
+        NgramIndex::save(&ngram_counts, &metadata, dir)?;
+        log::info!("Index written to {:?}", dir);
+    }
 
     let mut warn_about_overflows = false;
 
@@ -293,18 +758,18 @@ where
             warn_about_overflows = true;
         }
 
-        let ngram_str = if let Some(ref tokenizer) = tokenizer {
-            tokenizer.decode(ngram)?
-        } else {
-            ngram.join(" ")
-        };
-        let json_out = &json!({
+        let ngram_str = tokenizer.decode(ngram)?;
+        let row = json!({
             "tokens": **ngram,
             "string": ngram_str,
             "count": count,
             "rank": i + 1,
-        })
-        .to_string();
+            "fraction_of_corpus": fraction_of_corpus(
+                <u64 as NumCast>::from(*count).unwrap_or(u64::MAX),
+                total_ngrams
+            ),
+        });
+        let json_out = &row.to_string();
 
         // Display output.
         if opt.json {
@@ -320,8 +785,8 @@ where
         }
 
         // Write ngram and count to file.
-        if let Some(ref mut file) = out_file {
-            writeln!(file, "{json_out}")?;
+        if let Some(ref mut writer) = out_file {
+            writer.write_row(&row)?;
         }
     }
 
@@ -333,6 +798,10 @@ where
         log::warn!("u32 overflow in ngram counts");
     }
 
+    if let Some(writer) = out_file {
+        writer.finish()?;
+    }
+
     if let Some(path) = out_path {
         log::info!("Output written to {:?}", path);
     }
@@ -340,23 +809,257 @@ where
     Ok(())
 }
 
-fn get_output_file(opt: &Opt) -> Result<Option<(File, PathBuf)>> {
-    if let Some(path) = &opt.out {
-        if path.is_dir() || path.extension().is_none() {
-            let mut parts = vec![format!("n{}-k{}-h{}", opt.ngram, opt.topk, opt.hashes)];
-            if let Some(limit) = opt.limit {
-                parts.push(format!("-limit{limit}"));
+/// Like [`topk()`] but with exact counts from a [`SpillCounter`].
+fn topk_exact(opt: Opt) -> Result<()> {
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+    let length_band = LengthBand::new(opt.min_doc_tokens, opt.max_doc_tokens)?;
+    let truncated_documents = Arc::new(AtomicUsize::new(0));
+    let total_ngrams = Arc::new(AtomicU64::new(0));
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let ngram_counts = Arc::new(SpillCounter::new(&io::tmp_dir(), NUM_SPILL_PARTITIONS)?);
+
+    log::info!("Counting ngrams...");
+
+    let mut executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting ngrams",
+        opt.quiet,
+    )?;
+    executor.set_shared_state();
+
+    // Each job aggregates counts locally and spills them to disk whenever the local counts
+    // get too big, and once more at the end of the file.
+    for path in &opt.path {
+        let collect_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let ngram_counts = ngram_counts.clone();
+            let truncated_documents = truncated_documents.clone();
+            let total_ngrams = total_ngrams.clone();
+
+            move |data: DataInstance,
+                  weight: u64,
+                  local_counts: &mut HashMap<Vec<String>, u64>|
+                  -> Result<()> {
+                if weight == 0 {
+                    return Ok(());
+                }
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    let tokens = if let Some(max_tokens) = opt.truncate_doc_tokens {
+                        let (tokens, truncated) =
+                            tokenizer.tokenize_truncated(&text, max_tokens)?;
+                        if truncated {
+                            truncated_documents.fetch_add(1, Ordering::Relaxed);
+                        }
+                        tokens
+                    } else {
+                        tokenizer.tokenize(&text)?
+                    };
+                    if !length_band.contains(tokens.len()) {
+                        return Ok(());
+                    }
+                    let total = if opt.doc_freq {
+                        1
+                    } else {
+                        tokens.len().saturating_sub(opt.ngram - 1) as u64
+                    };
+                    total_ngrams.fetch_add(total.saturating_mul(weight), Ordering::Relaxed);
+                    let mut seen: HashSet<&[String]> = HashSet::new();
+                    for ngram in tokens.windows(opt.ngram) {
+                        if opt.doc_freq && !seen.insert(ngram) {
+                            continue;
+                        }
+                        match local_counts.get_mut(ngram) {
+                            Some(count) => *count += weight,
+                            None => {
+                                local_counts.insert(ngram.to_vec(), weight);
+                            }
+                        }
+                    }
+                    if local_counts.len() >= MAX_LOCAL_NGRAMS {
+                        ngram_counts.spill(std::mem::take(local_counts))?;
+                    }
+                }
+                Ok(())
             }
-            if let Some(seed) = opt.seed {
-                parts.push(format!("-seed{seed}"));
+        };
+
+        let spill_callback = {
+            let ngram_counts = ngram_counts.clone();
+
+            move |local_counts: HashMap<Vec<String>, u64>| -> Result<()> {
+                Ok(ngram_counts.spill(local_counts)?)
             }
-            Ok(Some(util::get_output_file(
-                path.join(format!("{}.jsonl", parts.join("-"))),
-                opt.force,
-            )?))
+        };
+
+        let local_counts_factory = || -> Result<HashMap<Vec<String>, u64>> { Ok(HashMap::new()) };
+
+        if let Some(weight_field) = opt.weight_field.clone() {
+            executor.execute_with_callback(
+                path,
+                move |raw: Box<RawValue>,
+                      _: &Path,
+                      _: usize,
+                      local_counts: &mut HashMap<Vec<String>, u64>|
+                      -> Result<()> {
+                    let raw = raw.get();
+                    let weight = document_weight(raw, &weight_field)?.round() as u64;
+                    collect_ngrams(serde_json::from_str(raw)?, weight, local_counts)
+                },
+                local_counts_factory,
+                spill_callback,
+            )?;
         } else {
-            Ok(Some(util::get_output_file(path, opt.force)?))
+            executor.execute_with_callback(
+                path,
+                move |data: DataInstance,
+                      _: &Path,
+                      _: usize,
+                      local_counts: &mut HashMap<Vec<String>, u64>|
+                      -> Result<()> { collect_ngrams(data, 1, local_counts) },
+                local_counts_factory,
+                spill_callback,
+            )?;
         }
+    }
+
+    executor.join()?;
+    log_truncated_documents(&opt, &truncated_documents);
+    let total_ngrams = log_total_ngrams(&opt, &total_ngrams);
+
+    log::info!("Aggregating spilled counts...");
+    let threshold = opt.threshold as u64;
+    let mut heap: BinaryHeap<Reverse<(u64, Vec<String>)>> = BinaryHeap::with_capacity(opt.topk + 1);
+    ngram_counts.for_each_count(|ngram, count| {
+        if count > threshold {
+            heap.push(Reverse((count, ngram)));
+            if heap.len() > opt.topk {
+                heap.pop();
+            }
+        }
+        Ok(())
+    })?;
+    let topk_final: Vec<(u64, Vec<String>)> = heap
+        .into_sorted_vec()
+        .into_iter()
+        .map(|Reverse(item)| item)
+        .collect();
+
+    for (i, (count, ngram)) in topk_final.iter().enumerate() {
+        let ngram_str = tokenizer.decode(ngram)?;
+        let row = json!({
+            "tokens": ngram,
+            "string": ngram_str,
+            "count": count,
+            "rank": i + 1,
+            "fraction_of_corpus": fraction_of_corpus(*count, total_ngrams),
+        });
+        let json_out = &row.to_string();
+
+        // Display output.
+        if opt.json {
+            println!("{json_out}");
+        } else if opt.out.is_none() {
+            println!(
+                "[{}/{}] {:?} (count = {})",
+                i + 1,
+                topk_final.len(),
+                style(ngram_str).cyan(),
+                count,
+            );
+        }
+
+        if let Some(ref mut writer) = out_file {
+            writer.write_row(&row)?;
+        }
+    }
+
+    if topk_final.is_empty() {
+        log::warn!("No ngrams occurred more than once, topk is empty");
+    }
+
+    if let Some(writer) = out_file {
+        writer.finish()?;
+    }
+
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+fn log_truncated_documents(opt: &Opt, truncated_documents: &AtomicUsize) {
+    let truncated_documents = truncated_documents.load(Ordering::Relaxed);
+    if let Some(max_tokens) = opt.truncate_doc_tokens {
+        log::info!(
+            "Truncated {} document(s) to {} tokens",
+            truncated_documents.separate_with_commas(),
+            max_tokens.separate_with_commas()
+        );
+    }
+}
+
+/// Log the total number of ngrams counted, or documents with '--doc-freq', and return it.
+fn log_total_ngrams(opt: &Opt, total_ngrams: &AtomicU64) -> u64 {
+    let total_ngrams = total_ngrams.load(Ordering::Relaxed);
+    log::info!(
+        "Counted {} {} in total",
+        total_ngrams.separate_with_commas(),
+        if opt.doc_freq { "documents" } else { "ngrams" }
+    );
+    total_ngrams
+}
+
+/// The share of all ngrams in the data that an ngram with the given count makes up, or the
+/// share of documents with '--doc-freq'.
+fn fraction_of_corpus(count: u64, total_ngrams: u64) -> f64 {
+    if total_ngrams == 0 {
+        0.0
+    } else {
+        count as f64 / total_ngrams as f64
+    }
+}
+
+/// Get the path of the output file, with a generated file name if '-o/--out' is a directory.
+fn get_output_path(opt: &Opt) -> Option<PathBuf> {
+    let path = opt.out.as_ref()?;
+    if path.is_dir() || path.extension().is_none() {
+        let mut parts = vec![format!("n{}-k{}-h{}", opt.ngram, opt.topk, opt.hashes)];
+        if let Some(limit) = opt.limit {
+            parts.push(format!("-limit{limit}"));
+        }
+        if let Some(seed) = opt.seed {
+            parts.push(format!("-seed{seed}"));
+        }
+        Some(path.join(format!(
+            "{}.{}",
+            parts.join("-"),
+            opt.out_format.extension()
+        )))
+    } else {
+        Some(path.clone())
+    }
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(TableWriter, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        Ok(Some(TableWriter::create(
+            path,
+            opt.force,
+            opt.out_format,
+            "ngrams",
+            COLUMNS,
+        )?))
     } else {
         Ok(None)
     }
@@ -0,0 +1,500 @@
+use std::cmp::Ordering;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Result};
+use console::style;
+use serde_json::value::RawValue;
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use thousands::Separable;
+use xxhash_rust::xxh3::xxh3_64;
+
+use super::util::{expand_paths, parse_size_default_to_gb, raw_field, BoundedHeap, DataExecutor};
+use crate::ngrams::NgramCounter;
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer, UnicodeTokenizer};
+use crate::util::{self, OutputFile};
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
+    #[structopt(parse(from_os_str))]
+    path: Vec<PathBuf>,
+
+    /// The metric to rank documents by: "tokens", "bytes", "repetition", "duplicates", or
+    /// "field:NAME".
+    ///
+    /// "repetition" is the fraction of the word ngrams of a document that repeat an earlier
+    /// ngram of the same document, see '-n/--ngram'. "duplicates" is the number of exact copies
+    /// of the document in the dataset, which takes an extra pass to count. "field:NAME" ranks
+    /// by a numeric field of the documents, like a quality score added with 'join'. Nested
+    /// fields are separated by dots, like "field:metadata.quality".
+    #[structopt(long = "by", default_value = "tokens")]
+    by: Metric,
+
+    /// The number of documents to return.
+    #[structopt(short = "k", long = "topk", default_value = "20")]
+    topk: usize,
+
+    /// Return the documents with the lowest scores instead of the highest.
+    #[structopt(long = "lowest")]
+    lowest: bool,
+
+    /// The max number of characters of each document's text to include in the output.
+    #[structopt(long = "snippet-chars", default_value = "200")]
+    snippet_chars: usize,
+
+    /// Set the tokenizer to use for "tokens" and "repetition". This can be the name of a
+    /// pretrained tokenizer from HuggingFace.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// The size of the ngrams for "repetition".
+    #[structopt(short = "n", long = "ngram", default_value = "3")]
+    ngram: usize,
+
+    /// Strip HTML tags and decode HTML entities before computing the metric.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before computing the metric.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+
+    /// Limit the number of JSON lines per file to process.
+    #[structopt(short = "l", long = "limit")]
+    limit: Option<usize>,
+
+    /// Limit the number of files to process.
+    #[structopt(long = "file-limit")]
+    file_limit: Option<usize>,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Specify the size budget for the document counter hash table for "duplicates", e.g.
+    /// "8GiB".
+    #[structopt(long = "size", default_value = "4GiB", parse(try_from_str = parse_size_default_to_gb))]
+    size: u64,
+
+    /// Specify the number of hash functions to use for "duplicates".
+    #[structopt(short = "h", long = "hashes", default_value = "5")]
+    hashes: u8,
+
+    /// Set the seed for the hashing functions for "duplicates". By default the seed is chosen
+    /// at random.
+    #[structopt(long = "seed")]
+    seed: Option<u64>,
+
+    /// A path to write the output to. Output will be written as JSON lines, i.e. each line
+    /// will be a JSON object with the keys "rank", "score", "path", "line", "id", and
+    /// "snippet".
+    ///
+    /// If the file already exists and you want to overwrite it, use the '-f/--force' option.
+    #[structopt(short = "o", long = "out")]
+    out: Option<PathBuf>,
+
+    /// Don't show progress bars and minimize other output.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Format output as JSON.
+    #[structopt(long = "json")]
+    json: bool,
+
+    /// Force overwriting output file if it already exists.
+    #[structopt(short = "f", long = "force")]
+    force: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Metric {
+    Tokens,
+    Bytes,
+    Repetition,
+    Duplicates,
+    Field(String),
+}
+
+impl std::str::FromStr for Metric {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tokens" => Ok(Self::Tokens),
+            "bytes" => Ok(Self::Bytes),
+            "repetition" => Ok(Self::Repetition),
+            "duplicates" => Ok(Self::Duplicates),
+            _ => match s.strip_prefix("field:") {
+                Some(field) if !field.is_empty() => Ok(Self::Field(field.into())),
+                _ => bail!(
+                    "invalid metric {:?}, expected tokens, bytes, repetition, duplicates, or field:NAME",
+                    s
+                ),
+            },
+        }
+    }
+}
+
+/// A document in the ranking. Documents are ordered by their key, which is the score negated
+/// when ranking by the highest scores, so that the best documents come first. Ties are broken
+/// by path and line number so that the result doesn't depend on the processing order.
+#[derive(Debug, Clone)]
+struct RankedDocument {
+    key: f64,
+    score: f64,
+    path: PathBuf,
+    line: usize,
+    id: Option<Value>,
+    snippet: String,
+    /// The hash of the text when ranking by "duplicates", so that every text is only ranked
+    /// once.
+    xxh3: Option<u64>,
+}
+
+impl Ord for RankedDocument {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key
+            .total_cmp(&other.key)
+            .then_with(|| self.path.cmp(&other.path))
+            .then_with(|| self.line.cmp(&other.line))
+    }
+}
+
+impl PartialOrd for RankedDocument {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for RankedDocument {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for RankedDocument {}
+
+/// The ranking of a worker for one file.
+struct LocalRanking {
+    documents: BoundedHeap<RankedDocument>,
+    /// Documents without the text or field the metric needs.
+    skipped: usize,
+}
+
+pub(crate) fn main(mut opt: Opt) -> Result<()> {
+    if opt.path.is_empty() {
+        bail!("at least one path is required");
+    }
+    if opt.topk == 0 {
+        bail!("-k/--topk must be greater than 0");
+    }
+    if opt.ngram == 0 {
+        bail!("-n/--ngram must be greater than 0");
+    }
+    opt.path = expand_paths(&opt.path)?;
+    if let Some(file_limit) = opt.file_limit {
+        opt.path.truncate(file_limit);
+    }
+
+    let (mut out_file, out_path) = match get_output_file(&opt)? {
+        Some(out) => (Some(out.0), Some(out.1)),
+        None => (None, None),
+    };
+
+    let tokenizer: Arc<dyn Tokenizer> = match opt.by {
+        Metric::Tokens | Metric::Repetition => {
+            provenance::record_tokenizer(&opt.tokenizer);
+            load_tokenizer(&opt.tokenizer)?
+        }
+        _ => Arc::new(UnicodeTokenizer),
+    };
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    let doc_counts = if opt.by == Metric::Duplicates {
+        // Pick a seed up front so that it's recorded in the run metadata.
+        provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
+        Some(count_documents(&opt, preprocessor)?)
+    } else {
+        None
+    };
+
+    let ranking = Arc::new(Mutex::new(BoundedHeap::new(opt.topk)));
+    let skipped = Arc::new(AtomicUsize::new(0));
+    let executor = DataExecutor::new(&opt.path, opt.workers, opt.limit, "Ranking", opt.quiet)?;
+
+    for path in &opt.path {
+        let rank_document = {
+            let metric = opt.by.clone();
+            let tokenizer = tokenizer.clone();
+            let doc_counts = doc_counts.clone();
+            let lowest = opt.lowest;
+            let ngram = opt.ngram;
+            let snippet_chars = opt.snippet_chars;
+
+            move |document: Box<RawValue>,
+                  path: &Path,
+                  line_num: usize,
+                  local: &mut LocalRanking|
+                  -> Result<()> {
+                let raw = document.get();
+                let text = match raw_field(raw, "text")? {
+                    Some(text) => serde_json::from_str::<Option<String>>(text)?,
+                    None => None,
+                };
+
+                let mut xxh3 = None;
+                let score = match (&metric, &text) {
+                    (Metric::Field(field), _) => {
+                        match raw_field(raw, field)?.map(serde_json::from_str::<f64>) {
+                            Some(Ok(score)) => score,
+                            _ => {
+                                local.skipped += 1;
+                                return Ok(());
+                            }
+                        }
+                    }
+                    (_, None) => {
+                        local.skipped += 1;
+                        return Ok(());
+                    }
+                    (Metric::Tokens, Some(text)) => {
+                        tokenizer.tokenize(&preprocessor.apply(text))?.len() as f64
+                    }
+                    (Metric::Bytes, Some(text)) => preprocessor.apply(text).len() as f64,
+                    (Metric::Repetition, Some(text)) => {
+                        repetition_score(&tokenizer.tokenize(&preprocessor.apply(text))?, ngram)
+                    }
+                    (Metric::Duplicates, Some(text)) => {
+                        let hash = [xxh3_64(preprocessor.apply(text).as_bytes())];
+                        xxh3 = Some(hash[0]);
+                        doc_counts
+                            .as_ref()
+                            .map_or(0, |doc_counts| doc_counts.count(&hash[..]))
+                            as f64
+                    }
+                };
+
+                let mut document = RankedDocument {
+                    key: if lowest { score } else { -score },
+                    score,
+                    path: path.into(),
+                    line: line_num,
+                    id: None,
+                    snippet: String::new(),
+                    xxh3,
+                };
+                // Only look up the ID and snippet for documents that make the cut.
+                if !local.documents.accepts(&document) {
+                    return Ok(());
+                }
+                document.id = raw_field(raw, "id")?
+                    .map(serde_json::from_str)
+                    .transpose()?;
+                if let Some(text) = text {
+                    document.snippet = text.chars().take(snippet_chars).collect();
+                }
+                push_ranked(&mut local.documents, document);
+                Ok(())
+            }
+        };
+
+        let num_documents = opt.topk;
+        let local_ranking_factory = move || -> Result<LocalRanking> {
+            Ok(LocalRanking {
+                documents: BoundedHeap::new(num_documents),
+                skipped: 0,
+            })
+        };
+
+        let merge_ranking = {
+            let ranking = ranking.clone();
+            let skipped = skipped.clone();
+            move |local: LocalRanking| -> Result<()> {
+                let mut ranking = ranking
+                    .lock()
+                    .map_err(|_| anyhow!("Failed to acquire lock"))?;
+                for document in local.documents.into_sorted_vec() {
+                    push_ranked(&mut ranking, document);
+                }
+                skipped.fetch_add(local.skipped, AtomicOrdering::Relaxed);
+                Ok(())
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            rank_document,
+            local_ranking_factory,
+            merge_ranking,
+        )?;
+    }
+
+    executor.join()?;
+
+    let skipped = skipped.load(AtomicOrdering::Relaxed);
+    if skipped > 0 {
+        let missing = match &opt.by {
+            Metric::Field(field) => format!("a numeric {field:?} field"),
+            _ => "text".to_string(),
+        };
+        log::warn!(
+            "Skipped {} document(s) without {}",
+            skipped.separate_with_commas(),
+            missing
+        );
+    }
+
+    let ranking = std::mem::replace(
+        &mut *ranking
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?,
+        BoundedHeap::new(0),
+    )
+    .into_sorted_vec();
+
+    for (i, document) in ranking.iter().enumerate() {
+        let json_out = json!({
+            "rank": i + 1,
+            "score": document.score,
+            "path": document.path,
+            "line": document.line,
+            "id": document.id,
+            "snippet": document.snippet,
+        })
+        .to_string();
+
+        // Display output.
+        if opt.json {
+            println!("{json_out}");
+        } else if opt.out.is_none() {
+            println!(
+                "[{}/{}] {} ({:?} line {}): {:?}",
+                i + 1,
+                ranking.len(),
+                format_score(document.score),
+                document.path,
+                document.line,
+                style(&document.snippet).cyan(),
+            );
+        }
+
+        if let Some(ref mut file) = out_file {
+            writeln!(file, "{json_out}")?;
+        }
+    }
+
+    if ranking.is_empty() {
+        log::warn!("No documents to rank");
+    }
+
+    if let Some(file) = out_file {
+        file.finish()?;
+    }
+    if let Some(path) = out_path {
+        log::info!("Output written to {:?}", path);
+    }
+
+    Ok(())
+}
+
+/// Count the hashes of the texts of all documents for ranking by "duplicates".
+fn count_documents(opt: &Opt, preprocessor: Preprocessor) -> Result<Arc<NgramCounter<AtomicU32>>> {
+    log::info!("Initializing document counter...");
+    // We're storing an array of u32s, each of which is 4 bytes.
+    let doc_counts = Arc::new(NgramCounter::<AtomicU32>::new(
+        (opt.size / 4) as usize,
+        opt.hashes as usize,
+        opt.seed,
+        0,
+    )?);
+
+    log::info!("Counting documents...");
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Counting documents",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let doc_counts = doc_counts.clone();
+        executor.execute(
+            path,
+            move |document: Box<RawValue>, _: &Path, _: usize| -> Result<()> {
+                if let Some(text) = raw_field(document.get(), "text")? {
+                    if let Some(text) = serde_json::from_str::<Option<String>>(text)? {
+                        let hash = [xxh3_64(preprocessor.apply(&text).as_bytes())];
+                        doc_counts.increment(&hash[..], 1);
+                    }
+                }
+                Ok(())
+            },
+        )?;
+    }
+
+    executor.join()?;
+
+    Ok(doc_counts)
+}
+
+/// Push a document to a ranking. When ranking by "duplicates", only the first copy of every
+/// text by path and line number is kept.
+fn push_ranked(ranking: &mut BoundedHeap<RankedDocument>, document: RankedDocument) {
+    if let Some(xxh3) = document.xxh3 {
+        let replaces_copy = ranking
+            .iter()
+            .find(|other| other.xxh3 == Some(xxh3))
+            .map(|copy| document < *copy);
+        match replaces_copy {
+            Some(false) => return,
+            Some(true) => ranking.retain(|other| other.xxh3 != Some(xxh3)),
+            None => {}
+        }
+    }
+    ranking.push(document);
+}
+
+/// The fraction of the ngrams of a document that repeat an earlier ngram of the document, from
+/// 0 for no repetition to almost 1 for a single phrase repeated over and over.
+fn repetition_score(tokens: &[String], n: usize) -> f64 {
+    if tokens.len() < n {
+        return 0.0;
+    }
+    let num_ngrams = tokens.len() - n + 1;
+    let mut seen = HashSet::with_capacity(num_ngrams);
+    let repeated = tokens
+        .windows(n)
+        .filter(|ngram| !seen.insert(*ngram))
+        .count();
+    repeated as f64 / num_ngrams as f64
+}
+
+/// Format a score, keeping decimals only for fractional scores.
+fn format_score(score: f64) -> String {
+    if score.fract() == 0.0 {
+        (score as i64).separate_with_commas()
+    } else {
+        format!("{score:.4}")
+    }
+}
+
+fn get_output_file(opt: &Opt) -> Result<Option<(OutputFile, PathBuf)>> {
+    if let Some(path) = &opt.out {
+        if path.is_dir() {
+            bail!("-o/--out must be a valid file name, not a directory");
+        } else {
+            Ok(Some(util::get_output_file(path, opt.force)?))
+        }
+    } else {
+        Ok(None)
+    }
+}
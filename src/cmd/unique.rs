@@ -1,4 +1,4 @@
-use std::collections::VecDeque;
+use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::AtomicU8;
 use std::sync::Arc;
@@ -6,14 +6,28 @@ use std::sync::Arc;
 use anyhow::{bail, Result};
 use serde_json::json;
 use structopt::StructOpt;
+use thousands::Separable;
 
-use super::util::{parse_size_default_to_gb, DataExecutor, DataInstance};
-use crate::ngrams::NgramCounter;
-use crate::tokens::{tokenize, PretrainedTokenizer};
+use super::util::{expand_paths, parse_size_default_to_gb, DataExecutor, DataInstance};
+use crate::io;
+use crate::ngrams::{
+    DistinctEstimate, NgramCounter, NgramWindows, PackedNgramCounter, SpillCounter,
+};
+use crate::preprocess::Preprocessor;
+use crate::provenance;
+use crate::tokens::{load_tokenizer, Tokenizer};
+
+/// The number of spill files to partition ngrams into with '--exact'. Each partition has to fit
+/// in memory when the ngrams are counted at the end.
+const NUM_SPILL_PARTITIONS: usize = 256;
+
+/// The max number of distinct ngrams each worker collects in memory with '--exact' before
+/// spilling them to disk.
+const MAX_LOCAL_NGRAMS: usize = 1_000_000;
 
 #[derive(Debug, StructOpt, Clone)]
 pub(crate) struct Opt {
-    /// Path to a gzip-compressed JSON lines file.
+    /// Path to a JSON lines file, optionally gzip or zstd-compressed.
     #[structopt(parse(from_os_str))]
     path: Vec<PathBuf>,
 
@@ -21,6 +35,17 @@ pub(crate) struct Opt {
     #[structopt(short = "n", long = "ngram", default_value = "3")]
     ngram: usize,
 
+    /// What to count the unique values of: "ngram" for ngrams of size '-n/--ngram', or "token"
+    /// for the vocabulary, which is the same as '-n 1'.
+    #[structopt(long = "unit", default_value = "ngram", possible_values = &["ngram", "token"])]
+    unit: String,
+
+    /// Count unique values exactly instead of estimating them with a Bloom filter. This trades
+    /// memory for disk space in '--tmp-dir' and is slower, which is usually fine
+    /// for vocabularies. The '--size', '--hashes', and '--seed' options don't apply.
+    #[structopt(long = "exact")]
+    exact: bool,
+
     /// Limit the number of JSON lines per file to process.
     #[structopt(short = "l", long = "limit")]
     limit: Option<usize>,
@@ -39,6 +64,13 @@ pub(crate) struct Opt {
     #[structopt(long = "size", default_value = "4GiB", parse(try_from_str = parse_size_default_to_gb))]
     size: u64,
 
+    /// The number of bits per cell of the ngram counter: 8, 2, or 1. Counting unique ngrams
+    /// only needs to know whether a cell is set, so with 1 or 2 bits the same '--size' holds 8
+    /// or 4 times as many cells, which gives a lower false positive rate and a tighter
+    /// estimate.
+    #[structopt(long = "cell-bits", default_value = "8", possible_values = &["8", "2", "1"])]
+    cell_bits: u32,
+
     /// Specify the number of hash functions to use.
     #[structopt(short = "h", long = "hashes", default_value = "5")]
     hashes: u8,
@@ -60,6 +92,47 @@ pub(crate) struct Opt {
     /// from HuggingFace.
     #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
     tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+/// The ngram counter with the cells from '--cell-bits'.
+enum Counter {
+    Bytes(NgramCounter<AtomicU8>),
+    Packed(PackedNgramCounter),
+}
+
+impl Counter {
+    fn increment(&self, ngram: &[&str]) {
+        match self {
+            Counter::Bytes(counter) => {
+                counter.increment(ngram, 1);
+            }
+            Counter::Packed(counter) => {
+                counter.increment(ngram);
+            }
+        }
+    }
+
+    fn nonzero(&self) -> u64 {
+        match self {
+            Counter::Bytes(counter) => counter.nonzero(),
+            Counter::Packed(counter) => counter.nonzero(),
+        }
+    }
+
+    fn estimate_distinct(&self) -> Option<DistinctEstimate> {
+        match self {
+            Counter::Bytes(counter) => counter.estimate_distinct(),
+            Counter::Packed(counter) => counter.estimate_distinct(),
+        }
+    }
 }
 
 pub(crate) fn main(mut opt: Opt) -> Result<()> {
@@ -76,25 +149,41 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
     if opt.ngram == 0 {
         bail!("-n/--ngram must be greater than 0");
     }
+    opt.path = expand_paths(&opt.path)?;
     if let Some(file_limit) = opt.file_limit {
         opt.path.truncate(file_limit);
     }
+    // Pick a seed up front so that it's recorded in the run metadata.
+    provenance::record_seed(*opt.seed.get_or_insert_with(rand::random));
+    if &opt.unit == "token" {
+        opt.ngram = 1;
+    }
 
-    let tokenizer: Option<PretrainedTokenizer> = if &opt.tokenizer == "unicode" {
-        None
-    } else {
-        Some(PretrainedTokenizer::new(&opt.tokenizer)?)
-    };
+    provenance::record_tokenizer(&opt.tokenizer);
+    let tokenizer = load_tokenizer(&opt.tokenizer)?;
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+
+    if opt.exact {
+        return unique_exact(opt, tokenizer, preprocessor);
+    }
 
     log::info!("Initializing ngram counter...");
-    // We're storing an array of u8s, so the size (in bytes) is also the length.
-    let counter_size = opt.size;
-    let ngram_counts = Arc::new(NgramCounter::<AtomicU8>::new(
-        counter_size as usize,
-        opt.hashes as usize,
-        opt.seed,
-        0,
-    )?);
+    let ngram_counts = Arc::new(if opt.cell_bits == 8 {
+        // We're storing an array of u8s, so the size (in bytes) is also the length.
+        Counter::Bytes(NgramCounter::<AtomicU8>::new(
+            opt.size as usize,
+            opt.hashes as usize,
+            opt.seed,
+            0,
+        )?)
+    } else {
+        Counter::Packed(PackedNgramCounter::new(
+            opt.size as usize * 8 / opt.cell_bits as usize,
+            opt.cell_bits,
+            opt.hashes as usize,
+            opt.seed,
+        )?)
+    });
 
     let executor = DataExecutor::new(
         &opt.path,
@@ -112,24 +201,12 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
 
             move |data: DataInstance, _: &Path, _: usize| -> Result<()> {
                 if let Some(text) = data.text {
-                    let tokens: Box<dyn Iterator<Item = String>> =
-                        if let Some(tokenizer) = &tokenizer {
-                            Box::new(tokenizer.tokenize(&text)?.into_iter())
-                        } else {
-                            Box::new(tokenize(&text).map(|s| s.to_string()))
-                        };
-
-                    let mut ngram_deque: VecDeque<String> = VecDeque::with_capacity(opt.ngram);
-                    for token in tokens {
-                        if ngram_deque.len() == opt.ngram {
-                            ngram_deque.pop_front();
-                        }
+                    let text = preprocessor.apply(&text);
+                    let tokens = tokenizer.tokens(&text)?;
+                    let tokens = tokens.iter().map(|token| token.as_ref());
 
-                        ngram_deque.push_back(token);
-
-                        if ngram_deque.len() == opt.ngram {
-                            ngram_counts.increment(&ngram_deque, 1);
-                        }
+                    for ngram in NgramWindows::new(tokens, opt.ngram) {
+                        ngram_counts.increment(&ngram[..]);
                     }
                 }
 
@@ -144,6 +221,113 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
 
     log::info!("Counting unique ngrams...");
     let unique_count = ngram_counts.nonzero();
+    // The raw count of non-zero elements is a lower bound once ngrams start sharing elements,
+    // so we correct for that.
+    let estimate = ngram_counts.estimate_distinct();
+    if estimate.is_none() {
+        log::warn!("The ngram counter is full, so the number of unique ngrams is only a lower bound. Consider increasing --size.");
+    }
+
+    if opt.json {
+        let json_out = &json!({
+            "unique_count": unique_count,
+            "estimate": estimate.map(|e| e.estimate.round() as u64),
+            "lower": estimate.map(|e| e.lower.round() as u64),
+            "upper": estimate.and_then(|e| e.upper).map(|upper| upper.round() as u64),
+        })
+        .to_string();
+        println!("{json_out}");
+    } else {
+        match estimate {
+            Some(DistinctEstimate {
+                estimate,
+                lower,
+                upper: Some(upper),
+            }) => println!(
+                "Estimated number of unique ngrams: {} (95% CI: {} - {})",
+                (estimate.round() as u64).separate_with_commas(),
+                (lower.round() as u64).separate_with_commas(),
+                (upper.round() as u64).separate_with_commas(),
+            ),
+            Some(DistinctEstimate {
+                estimate, lower, ..
+            }) => println!(
+                "Estimated number of unique ngrams: {} (95% CI: at least {})",
+                (estimate.round() as u64).separate_with_commas(),
+                (lower.round() as u64).separate_with_commas(),
+            ),
+            None => println!(
+                "Estimated number of unique ngrams: at least {}",
+                unique_count.separate_with_commas()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Like [`main()`] but with an exact count from a [`SpillCounter`].
+fn unique_exact(opt: Opt, tokenizer: Arc<dyn Tokenizer>, preprocessor: Preprocessor) -> Result<()> {
+    let ngram_counts = Arc::new(SpillCounter::new(&io::tmp_dir(), NUM_SPILL_PARTITIONS)?);
+
+    let executor = DataExecutor::new(
+        &opt.path,
+        opt.workers,
+        opt.limit,
+        "Collecting ngrams",
+        opt.quiet,
+    )?;
+
+    for path in &opt.path {
+        let collect_ngrams = {
+            let tokenizer = tokenizer.clone();
+            let ngram_counts = ngram_counts.clone();
+
+            move |data: DataInstance,
+                  _: &Path,
+                  _: usize,
+                  local_counts: &mut HashMap<Vec<String>, u64>|
+                  -> Result<()> {
+                if let Some(text) = data.text {
+                    let text = preprocessor.apply(&text);
+                    let tokens: Vec<String> = tokenizer.tokenize(&text)?;
+                    for ngram in tokens.windows(opt.ngram) {
+                        if !local_counts.contains_key(ngram) {
+                            local_counts.insert(ngram.to_vec(), 1);
+                        }
+                    }
+                    if local_counts.len() >= MAX_LOCAL_NGRAMS {
+                        ngram_counts.spill(std::mem::take(local_counts))?;
+                    }
+                }
+                Ok(())
+            }
+        };
+
+        let spill_callback = {
+            let ngram_counts = ngram_counts.clone();
+
+            move |local_counts: HashMap<Vec<String>, u64>| -> Result<()> {
+                Ok(ngram_counts.spill(local_counts)?)
+            }
+        };
+
+        executor.execute_with_callback(
+            path,
+            collect_ngrams,
+            || -> Result<HashMap<Vec<String>, u64>> { Ok(HashMap::new()) },
+            spill_callback,
+        )?;
+    }
+
+    executor.join()?;
+
+    log::info!("Counting unique ngrams...");
+    let mut unique_count: u64 = 0;
+    ngram_counts.for_each_count(|_, _| {
+        unique_count += 1;
+        Ok(())
+    })?;
 
     if opt.json {
         let json_out = &json!({
@@ -152,7 +336,7 @@ pub(crate) fn main(mut opt: Opt) -> Result<()> {
         .to_string();
         println!("{json_out}");
     } else {
-        println!("Estimated number of unique ngrams: {}", unique_count);
+        println!("Exact number of unique ngrams: {}", unique_count);
     }
 
     Ok(())
@@ -1,59 +1,167 @@
-use std::io;
+use std::borrow::Cow;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, Write};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Barrier, Mutex, OnceLock};
 use std::time::{Duration, Instant};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use humantime::format_duration;
 use parse_size::parse_size;
-use serde::Deserialize;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::value::RawValue;
 use thousands::Separable;
 use threadpool::ThreadPool;
+use xxhash_rust::xxh3::{xxh3_64_with_seed, Xxh3};
 
-use crate::io::GzBufReader;
-use crate::progress::{
-    get_file_progress_bar, get_multi_progress_bar, get_progress_bar, MultiProgress, ProgressBar,
-    ProgressIterator,
-};
+use crate::io::{expand_braces, InvalidUtf8, LineReader};
+use crate::logging;
+use crate::progress::{FileProgress, FileProgressBar, ProgressBars, ProgressSink};
+use crate::provenance;
+use crate::tui::{self, Dashboard};
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct DataInstance {
     // Unfortunately we can't just use a borrowed string here.
     // See https://github.com/serde-rs/serde/issues/1413#issuecomment-494892266
     pub(crate) text: Option<String>,
+    pub(crate) id: Option<serde_json::Value>,
 }
 
-pub(crate) fn process_file<F, C, U, G>(
+/// What [`process_file()`] returns for a file.
+pub(crate) struct FileCounts {
+    lines: usize,
+    bytes: usize,
+    /// Lines that were skipped because they're longer than '--max-doc-bytes'.
+    oversized: usize,
+    /// Lines with invalid UTF-8 that were skipped or repaired because of '--invalid-utf8'.
+    invalid_utf8: usize,
+    skipped: Vec<SkippedLine>,
+}
+
+/// Where processing a file stopped because reading it failed. All lines before that were fully
+/// processed into `context`, so a retry can continue from there instead of starting over.
+pub(crate) struct Checkpoint<U> {
+    context: U,
+    lines: usize,
+    bytes: usize,
+    oversized: usize,
+    invalid_utf8: usize,
+    skipped: Vec<SkippedLine>,
+}
+
+/// Process a single file. If `checkpoint` is set, processing continues from there. If reading
+/// the file fails, `checkpoint` is set to where it stopped.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn process_file<D, F, C, U, G>(
     mut data_func: F,
     context: C,
     mut callback: G,
-    progress: Option<ProgressBar>,
+    progress: Option<Arc<dyn FileProgress>>,
     path: impl AsRef<Path>,
     limit: Option<usize>,
     early_exit: Arc<AtomicBool>,
-) -> Result<(usize, usize)>
+    checkpoint: &mut Option<Checkpoint<U>>,
+) -> Result<FileCounts>
 where
-    F: FnMut(DataInstance, &Path, usize, &mut U) -> Result<()>,
+    D: DeserializeOwned,
+    F: FnMut(D, &Path, usize, &mut U) -> Result<()>,
     C: Fn() -> Result<U> + Send + 'static,
     G: FnMut(U) -> Result<()>,
 {
-    let mut total_lines: usize = 0;
-    let mut total_bytes: usize = 0;
-    let reader = GzBufReader::open(&path)?;
-    let mut context = context()?;
+    let mut reader = LineReader::open(&path)?;
+    let (
+        mut context,
+        mut total_lines,
+        mut total_bytes,
+        mut oversized,
+        mut invalid_utf8,
+        mut skipped,
+    ) = match checkpoint.take() {
+        Some(previous) => {
+            match reader.skip_lines(previous.lines) {
+                Ok(n) if n < previous.lines => {
+                    bail!("{:?} is shorter than when it was last read", path.as_ref())
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    // Keep the checkpoint for the next retry.
+                    *checkpoint = Some(previous);
+                    return Err(e.into());
+                }
+            }
+            let checkpoint = previous;
+            if let Some(ref progress) = progress {
+                progress.inc(checkpoint.lines as u64);
+                progress.inc_bytes(checkpoint.bytes as u64);
+            }
+            (
+                checkpoint.context,
+                checkpoint.lines,
+                checkpoint.bytes,
+                checkpoint.oversized,
+                checkpoint.invalid_utf8,
+                checkpoint.skipped,
+            )
+        }
+        None => (context()?, 0, 0, 0, 0, Vec::new()),
+    };
+    let max_doc_bytes = MAX_DOC_BYTES.get().copied();
+    let deadline = FILE_TIMEOUT.get().map(|timeout| Instant::now() + *timeout);
+    let remaining = limit.map_or(usize::MAX, |limit| limit.saturating_sub(total_lines));
+    let skip_errors = SKIP_ERRORS.load(Ordering::Relaxed);
+    let invalid_utf8_policy = INVALID_UTF8.get().copied();
+    let mut read_failed = false;
 
-    let mut process_line = |line: &str| -> Result<()> {
+    let mut process_line = |line: io::Result<&str>| -> Result<()> {
         if early_exit.load(Ordering::Relaxed) {
             return Ok(());
         }
         total_lines += 1;
+        let line = match line {
+            Ok(line) => line,
+            // Invalid UTF-8. The rest of the line has already been consumed so we can move on.
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => match invalid_utf8_policy {
+                Some(InvalidUtf8::Skip) => {
+                    invalid_utf8 += 1;
+                    return Ok(());
+                }
+                None if skip_errors => {
+                    skipped.push(SkippedLine::new(path.as_ref(), total_lines, e));
+                    return Ok(());
+                }
+                _ => {
+                    return Err(e).with_context(|| {
+                        format!("line {} in {:?} is invalid", total_lines, path.as_ref())
+                    })
+                }
+            },
+            Err(e) => {
+                read_failed = true;
+                return Err(e.into());
+            }
+        };
         total_bytes += line.len();
+        if let Some(max_doc_bytes) = max_doc_bytes {
+            if line.len() > max_doc_bytes {
+                oversized += 1;
+                return Ok(());
+            }
+        }
         match serde_json::from_str(line) {
             Ok(data) => data_func(data, path.as_ref(), total_lines, &mut context),
             Err(e) => {
                 if let Some(io_err) = e.io_error_kind() {
                     Err(io::Error::new(io_err, e).into())
+                } else if skip_errors {
+                    skipped.push(SkippedLine::new(path.as_ref(), total_lines, e));
+                    Ok(())
                 } else {
                     Err(e).with_context(|| {
                         format!(
@@ -68,34 +176,548 @@ where
         }
     };
 
-    if let Some(limit) = limit {
-        if let Some(progress) = progress {
-            for line in reader.take(limit).progress_with(progress) {
-                process_line(&line?)?;
+    let mut result = Ok(());
+    let mut buf = Vec::with_capacity(2048);
+    // Lines with invalid bytes replaced with '--invalid-utf8 replace'.
+    let mut replaced = 0;
+    for _ in 0..remaining {
+        let line = match reader.read_bytes_into(&mut buf) {
+            Ok(true) => match std::str::from_utf8(&buf) {
+                Ok(line) => Ok(Cow::Borrowed(line)),
+                Err(_) if invalid_utf8_policy == Some(InvalidUtf8::Replace) => {
+                    replaced += 1;
+                    Ok(String::from_utf8_lossy(&buf))
+                }
+                Err(e) => Err(io::Error::new(io::ErrorKind::InvalidData, e)),
+            },
+            Ok(false) => break,
+            Err(e) => Err(e),
+        };
+        let line_bytes = buf.len() as u64;
+        result = match line {
+            Ok(ref line) => process_line(Ok(line)),
+            Err(e) => process_line(Err(e)),
+        };
+        if result.is_err() {
+            break;
+        }
+        if let Some(ref progress) = progress {
+            progress.inc(1);
+            progress.inc_bytes(line_bytes);
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                result = Err(FileTimeout { lines: total_lines }.into());
+                break;
             }
-        } else {
-            for line in reader.take(limit) {
-                process_line(&line?)?;
+        }
+    }
+    invalid_utf8 += replaced;
+    if let Err(err) = result {
+        // Only read errors leave the context in a consistent state. Errors from `data_func`
+        // might have left it half updated.
+        if read_failed {
+            *checkpoint = Some(Checkpoint {
+                context,
+                lines: total_lines - 1,
+                bytes: total_bytes,
+                oversized,
+                invalid_utf8,
+                skipped,
+            });
+        }
+        return Err(err);
+    }
+
+    callback(context)?;
+
+    Ok(FileCounts {
+        lines: total_lines,
+        bytes: total_bytes,
+        oversized,
+        invalid_utf8,
+        skipped,
+    })
+}
+
+/// The number of lines that are handed to a thread at a time with '--threads-per-file'.
+const LINE_BATCH_SIZE: usize = 256;
+
+/// Like [`process_file()`], but the lines of the file are parsed and processed by `threads`
+/// threads, each with its own context, while this thread reads and decompresses the file.
+/// Every context is passed to `callback` once the whole file has been processed without
+/// errors, so the results are only correct if contexts can be merged in any order. There are no checkpoints, so a retry
+/// starts the file over.
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn process_file_parallel<D, F, C, U, G>(
+    data_func: F,
+    context: C,
+    callback: G,
+    progress: Option<Arc<dyn FileProgress>>,
+    path: impl AsRef<Path>,
+    limit: Option<usize>,
+    early_exit: Arc<AtomicBool>,
+    threads: usize,
+) -> Result<FileCounts>
+where
+    D: DeserializeOwned,
+    F: FnMut(D, &Path, usize, &mut U) -> Result<()> + Send + Clone,
+    C: Fn() -> Result<U> + Send + Clone,
+    G: FnMut(U) -> Result<()> + Send + Clone,
+{
+    let path = path.as_ref();
+    let mut reader = LineReader::open(path)?;
+    let max_doc_bytes = MAX_DOC_BYTES.get().copied();
+    let deadline = FILE_TIMEOUT.get().map(|timeout| Instant::now() + *timeout);
+    let skip_errors = SKIP_ERRORS.load(Ordering::Relaxed);
+    let invalid_utf8_policy = INVALID_UTF8.get().copied();
+
+    let (tx, rx) = sync_channel::<Vec<(usize, String)>>(threads * 4);
+    // Only the threads hold on to the receiver, so sending fails once they're all gone, e.g.
+    // because they failed, instead of blocking on a full channel forever.
+    let rx = Arc::new(Mutex::new(rx));
+    // Set when a thread fails, so that the file isn't read any further.
+    let stop = AtomicBool::new(false);
+    // The threads and the reader meet here once they're done, so that the threads only pass
+    // their contexts on if none of them failed. Otherwise part of a failed file would end up in
+    // the results, and a retry would count it twice.
+    let done = Barrier::new(threads + 1);
+
+    std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..threads)
+            .map(|_| {
+                let (mut data_func, context, mut callback) =
+                    (data_func.clone(), context.clone(), callback.clone());
+                let (rx, stop, early_exit, done) = (rx.clone(), &stop, &early_exit, &done);
+                scope.spawn(move || -> Result<Vec<SkippedLine>> {
+                    let process_lines = move || -> Result<(U, Vec<SkippedLine>)> {
+                        let mut context = context()?;
+                        let mut skipped = Vec::new();
+                        loop {
+                            let batch = match rx
+                                .lock()
+                                .map_err(|_| anyhow!("Failed to acquire lock"))?
+                                .recv()
+                            {
+                                Ok(batch) => batch,
+                                Err(_) => break,
+                            };
+                            if early_exit.load(Ordering::Relaxed) {
+                                continue;
+                            }
+                            for (line_num, line) in batch {
+                                match serde_json::from_str(&line) {
+                                    Ok(data) => data_func(data, path, line_num, &mut context)?,
+                                    Err(e) if e.io_error_kind().is_none() && skip_errors => {
+                                        skipped.push(SkippedLine::new(path, line_num, e));
+                                    }
+                                    Err(e) => {
+                                        return Err(e).with_context(|| {
+                                            format!(
+                                                "failed to deserialize line {} in {:?}:\n{}",
+                                                line_num, path, line
+                                            )
+                                        })
+                                    }
+                                }
+                            }
+                        }
+                        Ok((context, skipped))
+                    };
+                    // Panics are caught so that this thread always gets to the barrier. The
+                    // receiver is dropped along with `process_lines` before that, since the reader
+                    // might still be blocked on a full channel.
+                    let result =
+                        std::panic::catch_unwind(std::panic::AssertUnwindSafe(process_lines))
+                            .unwrap_or_else(|_| {
+                                Err(anyhow!("a thread panicked while processing the file"))
+                            });
+                    if result.is_err() {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    done.wait();
+                    let (context, skipped) = result?;
+                    if !stop.load(Ordering::Relaxed) {
+                        callback(context)?;
+                    }
+                    Ok(skipped)
+                })
+            })
+            .collect();
+        drop(rx);
+
+        let mut counts = FileCounts {
+            lines: 0,
+            bytes: 0,
+            oversized: 0,
+            invalid_utf8: 0,
+            skipped: Vec::new(),
+        };
+        let mut read_lines = || -> Result<()> {
+            let mut buf = Vec::with_capacity(2048);
+            let mut batch = Vec::with_capacity(LINE_BATCH_SIZE);
+            for _ in 0..limit.unwrap_or(usize::MAX) {
+                if stop.load(Ordering::Relaxed) || early_exit.load(Ordering::Relaxed) {
+                    break;
+                }
+                if !reader.read_bytes_into(&mut buf)? {
+                    break;
+                }
+                counts.lines += 1;
+                if let Some(ref progress) = progress {
+                    progress.inc(1);
+                    progress.inc_bytes(buf.len() as u64);
+                }
+                let line = match String::from_utf8(std::mem::take(&mut buf)) {
+                    Ok(line) => line,
+                    Err(e) => match invalid_utf8_policy {
+                        Some(InvalidUtf8::Replace) => {
+                            counts.invalid_utf8 += 1;
+                            String::from_utf8_lossy(e.as_bytes()).into_owned()
+                        }
+                        Some(InvalidUtf8::Skip) => {
+                            counts.invalid_utf8 += 1;
+                            continue;
+                        }
+                        None if skip_errors => {
+                            counts.skipped.push(SkippedLine::new(
+                                path,
+                                counts.lines,
+                                e.utf8_error(),
+                            ));
+                            continue;
+                        }
+                        _ => {
+                            return Err(e.utf8_error()).with_context(|| {
+                                format!("line {} in {:?} is invalid", counts.lines, path)
+                            })
+                        }
+                    },
+                };
+                counts.bytes += line.len();
+                if max_doc_bytes.is_some_and(|max_doc_bytes| line.len() > max_doc_bytes) {
+                    counts.oversized += 1;
+                    continue;
+                }
+                batch.push((counts.lines, line));
+                if batch.len() >= LINE_BATCH_SIZE && tx.send(std::mem::take(&mut batch)).is_err() {
+                    // All threads are gone, their errors are returned below.
+                    break;
+                }
+                if let Some(deadline) = deadline {
+                    if Instant::now() >= deadline {
+                        return Err(FileTimeout {
+                            lines: counts.lines,
+                        }
+                        .into());
+                    }
+                }
             }
+            if !batch.is_empty() {
+                tx.send(batch).ok();
+            }
+            Ok(())
+        };
+        let read_result = read_lines();
+        if read_result.is_err() {
+            stop.store(true, Ordering::Relaxed);
         }
-    } else if let Some(progress) = progress {
-        for line in reader.progress_with(progress) {
-            process_line(&line?)?;
+        drop(tx);
+        done.wait();
+
+        let mut thread_result = Ok(());
+        for handle in handles {
+            match handle.join() {
+                Ok(Ok(mut skipped)) => counts.skipped.append(&mut skipped),
+                Ok(Err(err)) => {
+                    if thread_result.is_ok() {
+                        thread_result = Err(err);
+                    }
+                }
+                Err(_) => {
+                    if thread_result.is_ok() {
+                        thread_result = Err(anyhow!("a thread panicked while processing the file"));
+                    }
+                }
+            }
         }
-    } else {
-        for line in reader {
-            process_line(&line?)?;
+        read_result?;
+        thread_result?;
+        Ok(counts)
+    })
+}
+
+static ERROR_REPORT: OnceLock<PathBuf> = OnceLock::new();
+
+static SKIP_ERRORS: AtomicBool = AtomicBool::new(false);
+
+static INVALID_UTF8: OnceLock<InvalidUtf8> = OnceLock::new();
+
+static MAX_DOC_BYTES: OnceLock<usize> = OnceLock::new();
+
+static FILE_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+
+static THREADS_PER_FILE: OnceLock<usize> = OnceLock::new();
+
+/// Whether the error report has been written to yet by this process. Later executors
+/// append to it.
+static ERROR_REPORT_CREATED: AtomicBool = AtomicBool::new(false);
+
+/// Write a report of the files that failed after all retries and the lines that were skipped
+/// to the given path once processing is done.
+pub(crate) fn set_error_report(report: PathBuf) -> Result<()> {
+    ERROR_REPORT
+        .set(report)
+        .map_err(|_| anyhow!("error report already set"))
+}
+
+/// Skip lines that fail to parse instead of failing the whole file.
+pub(crate) fn skip_errors() {
+    SKIP_ERRORS.store(true, Ordering::Relaxed);
+}
+
+/// Handle lines that aren't valid UTF-8 with this policy instead of the default, which is to
+/// fail the file unless '--skip-errors' is set.
+pub(crate) fn set_invalid_utf8(policy: InvalidUtf8) -> Result<()> {
+    INVALID_UTF8
+        .set(policy)
+        .map_err(|_| anyhow!("invalid UTF-8 policy already set"))
+}
+
+/// Skip lines longer than this many bytes before parsing them.
+pub(crate) fn set_max_doc_bytes(max_doc_bytes: usize) -> Result<()> {
+    MAX_DOC_BYTES
+        .set(max_doc_bytes)
+        .map_err(|_| anyhow!("max doc bytes already set"))
+}
+
+/// Give up on files that take longer than this to process and quarantine them instead of
+/// failing the run.
+pub(crate) fn set_file_timeout(timeout: Duration) -> Result<()> {
+    FILE_TIMEOUT
+        .set(timeout)
+        .map_err(|_| anyhow!("file timeout already set"))
+}
+
+/// Process every file with this many threads in commands that support it, see
+/// [`DataExecutor::enable_threads_per_file()`].
+pub(crate) fn set_threads_per_file(threads: usize) -> Result<()> {
+    if threads == 0 {
+        bail!("--threads-per-file must be greater than 0");
+    }
+    THREADS_PER_FILE
+        .set(threads)
+        .map_err(|_| anyhow!("threads per file already set"))
+}
+
+/// The error for a file that took longer than '--file-timeout'.
+#[derive(Debug)]
+struct FileTimeout {
+    /// The number of lines that were processed before giving up.
+    lines: usize,
+}
+
+impl std::fmt::Display for FileTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "processing took longer than --file-timeout")
+    }
+}
+
+impl std::error::Error for FileTimeout {}
+
+/// A record in the error report, tagged with its "kind".
+#[derive(Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+enum ErrorRecord<'a> {
+    Line(&'a SkippedLine),
+    File(&'a FailedFile),
+    Quarantined(&'a QuarantinedFile),
+}
+
+/// A file that failed after all retries.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct FailedFile {
+    path: PathBuf,
+    /// The number of times the file was tried, or 0 if it was never tried, e.g. because it
+    /// doesn't exist.
+    attempts: usize,
+    error: String,
+}
+
+/// A file that was given up on because it took longer than '--file-timeout'. Nothing from it
+/// is included in the results.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct QuarantinedFile {
+    path: PathBuf,
+    /// The number of lines that were processed before giving up.
+    lines: usize,
+    error: String,
+}
+
+/// A line that was skipped because of '--skip-errors'.
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SkippedLine {
+    path: PathBuf,
+    line: usize,
+    error: String,
+}
+
+impl SkippedLine {
+    fn new(path: &Path, line: usize, error: impl std::fmt::Display) -> Self {
+        Self {
+            path: path.into(),
+            line,
+            error: error.to_string(),
         }
     }
+}
 
-    callback(context)?;
+fn write_error_report(
+    report: &Path,
+    failed: &[FailedFile],
+    quarantined: &[QuarantinedFile],
+    skipped: &[SkippedLine],
+) -> Result<()> {
+    let append = ERROR_REPORT_CREATED.swap(true, Ordering::Relaxed);
+    let mut file = File::options()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(report)?;
+    for failed_file in failed {
+        writeln!(
+            file,
+            "{}",
+            serde_json::to_string(&ErrorRecord::File(failed_file))?
+        )?;
+    }
+    for quarantined_file in quarantined {
+        writeln!(
+            file,
+            "{}",
+            serde_json::to_string(&ErrorRecord::Quarantined(quarantined_file))?
+        )?;
+    }
+    for line in skipped {
+        writeln!(file, "{}", serde_json::to_string(&ErrorRecord::Line(line))?)?;
+    }
+    Ok(())
+}
+
+/// Expand brace patterns like "c4-train.{00000..01023}-of-01024.json.gz" in path arguments
+/// with [`expand_braces()`], so that subsets of shards can be given without a manifest or
+/// running into the shell's limit on the length of the command line. Paths that exist as they
+/// are aren't expanded.
+pub(crate) fn expand_paths(paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let mut expanded = Vec::with_capacity(paths.len());
+    for path in paths {
+        match path.to_str() {
+            Some(pattern) if pattern.contains('{') && !path.exists() => {
+                expanded.extend(expand_braces(pattern)?.into_iter().map(PathBuf::from));
+            }
+            _ => expanded.push(path.clone()),
+        }
+    }
+    Ok(expanded)
+}
+
+/// A heap that keeps the `n` smallest items pushed to it. Wrap items in [`Reverse`] to keep
+/// the `n` largest instead.
+#[derive(Debug, Clone)]
+pub(crate) struct BoundedHeap<T: Ord> {
+    pub(crate) n: usize,
+    heap: BinaryHeap<T>,
+}
+
+impl<T: Ord> BoundedHeap<T> {
+    pub(crate) fn new(n: usize) -> Self {
+        Self {
+            n,
+            heap: BinaryHeap::with_capacity(n + 1),
+        }
+    }
+
+    /// Whether an item would be kept if it was pushed now.
+    pub(crate) fn accepts(&self, item: &T) -> bool {
+        self.heap.len() < self.n || self.heap.peek().is_some_and(|largest| item < largest)
+    }
+
+    pub(crate) fn push(&mut self, item: T) {
+        if self.heap.len() < self.n {
+            self.heap.push(item);
+        } else if let Some(mut largest) = self.heap.peek_mut() {
+            if item < *largest {
+                *largest = item;
+            }
+        }
+    }
+
+    pub(crate) fn merge(&mut self, other: Self) {
+        for item in other.heap {
+            self.push(item);
+        }
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &T> {
+        self.heap.iter()
+    }
+
+    /// Only keep the items for which `f` returns true.
+    pub(crate) fn retain(&mut self, f: impl FnMut(&T) -> bool) {
+        self.heap.retain(f);
+    }
+
+    /// The items in ascending order.
+    pub(crate) fn into_sorted_vec(self) -> Vec<T> {
+        self.heap.into_sorted_vec()
+    }
+}
+
+static RETRY_POLICY: OnceLock<RetryPolicy> = OnceLock::new();
 
-    Ok((total_lines, total_bytes))
+/// How to retry files that fail to process, set once from the command line.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetryPolicy {
+    /// The max number of retries per file. If not set, each command uses its own default.
+    pub(crate) max_retries: Option<usize>,
+    /// The delay before the first retry. This doubles with every retry.
+    pub(crate) backoff: Duration,
+    /// The max delay between retries.
+    pub(crate) max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: None,
+            backoff: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Set the policy for all subsequent executors.
+    pub(crate) fn set(self) -> Result<()> {
+        RETRY_POLICY
+            .set(self)
+            .map_err(|_| anyhow!("retry policy already set"))
+    }
+
+    fn get() -> Self {
+        RETRY_POLICY.get().copied().unwrap_or_default()
+    }
+
+    /// The delay before the given retry, starting from 1.
+    fn delay(&self, retry: usize) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1) as u32);
+        std::cmp::min(self.backoff.saturating_mul(factor), self.max_delay)
+    }
 }
 
 pub(crate) struct DataExecutor {
-    all_progress: MultiProgress,
-    file_progress: ProgressBar,
+    progress: Arc<dyn ProgressSink>,
     pub(crate) total_lines: Arc<AtomicUsize>,
     pub(crate) total_bytes: Arc<AtomicUsize>,
     limit: Option<usize>,
@@ -103,40 +725,64 @@ pub(crate) struct DataExecutor {
     early_exit: Arc<AtomicBool>,
     start: Instant,
     error: Arc<Mutex<Option<String>>>,
-    pub(crate) max_retries: usize,
+    max_retries: usize,
+    retry_policy: RetryPolicy,
     error_count: Arc<AtomicUsize>,
-    max_workers: usize,
-    quiet: bool,
+    oversized: Arc<AtomicUsize>,
+    invalid_utf8: Arc<AtomicUsize>,
+    skipped: Arc<Mutex<Vec<SkippedLine>>>,
+    failed: Arc<Mutex<Vec<FailedFile>>>,
+    quarantined: Arc<Mutex<Vec<QuarantinedFile>>>,
+    dashboard: Option<Dashboard>,
+    threads_per_file: usize,
+    shared_state: bool,
 }
 
 impl DataExecutor {
     pub(crate) fn new(
-        paths: &Vec<PathBuf>,
+        paths: &[PathBuf],
         max_workers: Option<usize>,
         limit: Option<usize>,
         description: &'static str,
         quiet: bool,
     ) -> Result<Self> {
-        let all_progress = get_multi_progress_bar(quiet);
-        let file_progress =
-            all_progress.add(get_file_progress_bar(description, paths.len(), quiet)?);
-        file_progress.set_position(0);
+        // The dashboard replaces all progress bars.
+        let dashboard = if tui::enabled() {
+            Some(Dashboard::start(description, paths.len())?)
+        } else {
+            None
+        };
+        let progress = ProgressBars::new(
+            description,
+            paths.len(),
+            quiet || dashboard.is_some(),
+            num_workers(max_workers, paths.len()) <= 32,
+        )?;
+        let mut executor = Self::with_progress_sink(paths, max_workers, limit, Arc::new(progress))?;
+        executor.dashboard = dashboard;
+        Ok(executor)
+    }
+
+    /// Like [`DataExecutor::new()`], but progress is reported to the given sink instead of
+    /// progress bars.
+    pub(crate) fn with_progress_sink(
+        paths: &[PathBuf],
+        max_workers: Option<usize>,
+        limit: Option<usize>,
+        progress: Arc<dyn ProgressSink>,
+    ) -> Result<Self> {
+        provenance::record_files(paths);
+
         let total_lines = Arc::new(AtomicUsize::new(0));
         let total_bytes = Arc::new(AtomicUsize::new(0));
-        let workers = std::cmp::max(
-            1,
-            std::cmp::min(
-                max_workers.unwrap_or_else(|| std::cmp::min(64, num_cpus::get())),
-                paths.len(),
-            ),
-        );
+        let workers = num_workers(max_workers, paths.len());
         let pool = ThreadPool::with_name("wimbd-worker".to_string(), workers);
         let early_exit = Arc::new(AtomicBool::new(false));
         let start = Instant::now();
         let error = Arc::new(Mutex::new(None));
+        let retry_policy = RetryPolicy::get();
         Ok(Self {
-            all_progress,
-            file_progress,
+            progress,
             total_lines,
             total_bytes,
             limit,
@@ -144,30 +790,72 @@ impl DataExecutor {
             early_exit,
             start,
             error,
-            max_retries: 0,
+            max_retries: retry_policy.max_retries.unwrap_or(0),
+            retry_policy,
             error_count: Arc::new(AtomicUsize::new(0)),
-            max_workers: workers,
-            quiet,
+            oversized: Arc::new(AtomicUsize::new(0)),
+            invalid_utf8: Arc::new(AtomicUsize::new(0)),
+            skipped: Arc::new(Mutex::new(Vec::new())),
+            failed: Arc::new(Mutex::new(Vec::new())),
+            quarantined: Arc::new(Mutex::new(Vec::new())),
+            dashboard: None,
+            threads_per_file: 1,
+            shared_state: false,
         })
     }
 
-    pub(crate) fn execute<F>(&self, path: &PathBuf, mut data_func: F) -> Result<()>
+    /// Set the number of times to retry a file for this command, unless the user has set
+    /// '--retries'.
+    pub(crate) fn set_default_max_retries(&mut self, max_retries: usize) {
+        if self.retry_policy.max_retries.is_none() {
+            self.max_retries = max_retries;
+        }
+    }
+
+    /// Process every file with the number of threads set with '--threads-per-file', so that a
+    /// few huge files can use the whole machine. Commands can only enable this if the contexts
+    /// of their data functions can be merged in any order and don't write per-file outputs,
+    /// since a file is then split over several contexts.
+    pub(crate) fn enable_threads_per_file(&mut self) {
+        self.threads_per_file = THREADS_PER_FILE.get().copied().unwrap_or(1);
+    }
+
+    /// Mark the data functions of this command as updating state that's shared between files,
+    /// like a global counter, instead of only their context. A file that fails after some of its
+    /// lines were processed is then only retried if it can resume from a checkpoint, since
+    /// starting it over would count those lines twice.
+    pub(crate) fn set_shared_state(&mut self) {
+        self.shared_state = true;
+    }
+
+    /// The live dashboard, if '--tui' was set. Commands can use this to display their own
+    /// metrics.
+    pub(crate) fn dashboard(&self) -> Option<&Dashboard> {
+        self.dashboard.as_ref()
+    }
+
+    /// Like [`DataExecutor::execute_with_callback()`], but without a context. Anything the data
+    /// function keeps track of is shared between files, see [`DataExecutor::set_shared_state()`].
+    pub(crate) fn execute<D, F>(&self, path: &PathBuf, mut data_func: F) -> Result<()>
     where
-        F: FnMut(DataInstance, &Path, usize) -> Result<()> + Send + 'static + Clone,
+        D: DeserializeOwned + 'static,
+        F: FnMut(D, &Path, usize) -> Result<()> + Send + 'static + Clone,
     {
-        self.execute_with_callback(
+        self.execute_file(
             path,
-            move |data: DataInstance,
-                  path: &Path,
-                  line_num: usize,
-                  _: &mut Option<bool>|
-                  -> Result<()> { data_func(data, path, line_num) },
+            move |data: D, path: &Path, line_num: usize, _: &mut Option<bool>| -> Result<()> {
+                data_func(data, path, line_num)
+            },
             || -> Result<Option<bool>> { Ok(None) },
             |_: Option<bool>| -> Result<()> { Ok(()) },
+            true,
         )
     }
 
-    pub(crate) fn execute_with_callback<F, C, U, G>(
+    /// Process a file in a worker thread. Each JSON line is deserialized into `D`, which is
+    /// usually [`DataInstance`] but can be any type, e.g. a [`serde_json::Value`] for commands
+    /// that need access to arbitrary fields.
+    pub(crate) fn execute_with_callback<D, F, C, U, G>(
         &self,
         path: &PathBuf,
         data_func: F,
@@ -175,75 +863,215 @@ impl DataExecutor {
         callback: G,
     ) -> Result<()>
     where
-        F: FnMut(DataInstance, &Path, usize, &mut U) -> Result<()> + Send + 'static + Clone,
+        D: DeserializeOwned + 'static,
+        F: FnMut(D, &Path, usize, &mut U) -> Result<()> + Send + 'static + Clone,
+        C: Fn() -> Result<U> + Send + 'static + Clone,
+        G: FnMut(U) -> Result<()> + Send + 'static + Clone,
+    {
+        self.execute_file(path, data_func, context, callback, self.shared_state)
+    }
+
+    fn execute_file<D, F, C, U, G>(
+        &self,
+        path: &PathBuf,
+        data_func: F,
+        context: C,
+        callback: G,
+        shared_state: bool,
+    ) -> Result<()>
+    where
+        D: DeserializeOwned + 'static,
+        F: FnMut(D, &Path, usize, &mut U) -> Result<()> + Send + 'static + Clone,
         C: Fn() -> Result<U> + Send + 'static + Clone,
         G: FnMut(U) -> Result<()> + Send + 'static + Clone,
     {
         if !path.is_file() {
             self.early_exit.store(true, Ordering::Relaxed);
+            if let Ok(mut failed) = self.failed.lock() {
+                failed.push(FailedFile {
+                    path: path.clone(),
+                    attempts: 0,
+                    error: "file does not exist".into(),
+                });
+            }
             bail!("File {:?} does not exist", path);
         }
 
-        let hide_file_progress = self.quiet || self.max_workers > 32;
         let path = path.clone();
         let total_lines = self.total_lines.clone();
         let total_bytes = self.total_bytes.clone();
-        let progress = if hide_file_progress {
-            None
+        let mut dashboard_progress = None;
+        let progress: Option<Arc<dyn FileProgress>> = if self.dashboard.is_some() {
+            // Hidden progress bars still keep track of the position and rate for the dashboard.
+            let progress = FileProgressBar::new(&path, self.limit, true, None)?;
+            dashboard_progress = Some(progress.clone());
+            Some(Arc::new(progress))
         } else {
-            Some(
-                self.all_progress
-                    .add(get_progress_bar(&path, self.limit, false)?),
-            )
+            self.progress.start_file(&path, self.limit)?
         };
         let limit = self.limit;
         let early_exit = self.early_exit.clone();
-        let file_progress = self.file_progress.clone();
+        let all_progress = self.progress.clone();
         let error = self.error.clone();
         let max_retries = self.max_retries;
+        let retry_policy = self.retry_policy;
         let error_count = self.error_count.clone();
+        let oversized = self.oversized.clone();
+        let invalid_utf8 = self.invalid_utf8.clone();
+        let dashboard = self.dashboard.clone();
+        let skipped = self.skipped.clone();
+        let failed = self.failed.clone();
+        let quarantined = self.quarantined.clone();
+        let threads_per_file = self.threads_per_file;
 
         self.pool.execute(move || {
+            logging::set_current_path(Some(&path));
+            log::debug!("Processing {:?}", path);
             let mut retries = 0;
+            let mut checkpoint = None;
+            // Whether any line of the current attempt made it to `data_func`.
+            let started = Arc::new(AtomicBool::new(false));
+            if let (Some(dashboard), Some(progress)) = (&dashboard, dashboard_progress) {
+                dashboard.start_file(&path, progress);
+            }
             loop {
-                match process_file(
-                    data_func.clone(),
-                    context.clone(),
-                    callback.clone(),
-                    progress.clone(),
-                    &path,
-                    limit,
-                    early_exit.clone(),
-                ) {
-                    Ok((n_lines, n_bytes)) => {
-                        total_lines.fetch_add(n_lines, Ordering::Relaxed);
-                        total_bytes.fetch_add(n_bytes, Ordering::Relaxed);
-                        file_progress.inc(1);
+                started.store(false, Ordering::Relaxed);
+                let data_func = {
+                    let mut data_func = data_func.clone();
+                    let started = started.clone();
+                    move |data: D, path: &Path, line_num: usize, context: &mut U| -> Result<()> {
+                        if !started.load(Ordering::Relaxed) {
+                            started.store(true, Ordering::Relaxed);
+                        }
+                        data_func(data, path, line_num, context)
+                    }
+                };
+                let result = if threads_per_file > 1 {
+                    process_file_parallel(
+                        data_func,
+                        context.clone(),
+                        callback.clone(),
+                        progress.clone(),
+                        &path,
+                        limit,
+                        early_exit.clone(),
+                        threads_per_file,
+                    )
+                } else {
+                    process_file(
+                        data_func,
+                        context.clone(),
+                        callback.clone(),
+                        progress.clone(),
+                        &path,
+                        limit,
+                        early_exit.clone(),
+                        &mut checkpoint,
+                    )
+                };
+                match result {
+                    Ok(mut counts) => {
+                        log::debug!(
+                            "Finished {:?}: {} lines, {} bytes",
+                            path,
+                            counts.lines,
+                            counts.bytes
+                        );
+                        total_lines.fetch_add(counts.lines, Ordering::Relaxed);
+                        total_bytes.fetch_add(counts.bytes, Ordering::Relaxed);
+                        oversized.fetch_add(counts.oversized, Ordering::Relaxed);
+                        invalid_utf8.fetch_add(counts.invalid_utf8, Ordering::Relaxed);
+                        if !counts.skipped.is_empty() {
+                            if let Ok(mut skipped) = skipped.lock() {
+                                skipped.append(&mut counts.skipped);
+                            }
+                        }
+                        all_progress.finish_file(&path);
+                        if let Some(dashboard) = &dashboard {
+                            dashboard.finish_file(&path, counts.lines, counts.bytes);
+                        }
+                        break;
+                    }
+                    Err(err) if err.is::<FileTimeout>() => {
+                        // A stuck file isn't likely to do better on a retry, so it's set aside
+                        // and the rest of the files are still processed.
+                        let lines = err
+                            .downcast_ref::<FileTimeout>()
+                            .map_or(0, |timeout| timeout.lines);
+                        log::error!("Quarantining {:?}: {}", path, err);
+                        if let Ok(mut quarantined) = quarantined.lock() {
+                            quarantined.push(QuarantinedFile {
+                                path: path.clone(),
+                                lines,
+                                error: err.to_string(),
+                            });
+                        }
+                        all_progress.finish_file(&path);
+                        if let Some(dashboard) = &dashboard {
+                            dashboard.record_error(&path, &err.to_string());
+                            dashboard.finish_file(&path, 0, 0);
+                        }
                         break;
                     }
                     Err(err) => {
                         log::error!("Error processing {:?}: {}", path, err);
                         error_count.fetch_add(1, Ordering::Relaxed);
+                        if let Some(dashboard) = &dashboard {
+                            dashboard.record_error(&path, &err.to_string());
+                        }
                         if let Ok(ref mut error) = error.try_lock() {
                             **error = Some(format!("{err:?} encounted while processing {path:?}"));
                         }
-                        if retries >= max_retries {
+                        // Starting over would add the lines that were already processed to the
+                        // shared state a second time.
+                        let restarts = checkpoint.is_none() && started.load(Ordering::Relaxed);
+                        if retries < max_retries && shared_state && restarts {
+                            log::warn!(
+                                "Not retrying {:?} since it would have to start over and its \
+                                 lines have already been counted",
+                                path
+                            );
+                        }
+                        if retries >= max_retries || (shared_state && restarts) {
                             early_exit.store(true, Ordering::Relaxed);
+                            if let Ok(mut failed) = failed.lock() {
+                                failed.push(FailedFile {
+                                    path: path.clone(),
+                                    attempts: retries + 1,
+                                    error: format!("{err:#}"),
+                                });
+                            }
                             if let Ok(ref mut error) = error.try_lock() {
                                 **error =
                                     Some(format!("{err:?} encounted while processing {path:?}"));
                             }
                             break;
                         } else {
-                            log::warn!("Retrying {:?}", path);
+                            retries += 1;
+                            let delay = retry_policy.delay(retries);
+                            log::warn!(
+                                "Retrying {:?} in {} ({}/{})",
+                                path,
+                                format_duration(delay),
+                                retries,
+                                max_retries
+                            );
+                            std::thread::sleep(delay);
                             if let Some(progress) = &progress {
                                 progress.reset();
                             }
-                            retries += 1;
+                            if let Some(checkpoint) = &checkpoint {
+                                log::info!(
+                                    "Resuming {:?} from line {}",
+                                    path,
+                                    checkpoint.lines + 1
+                                );
+                            }
                         }
                     }
                 };
             }
+            logging::set_current_path(None);
         });
 
         Ok(())
@@ -262,8 +1090,26 @@ impl DataExecutor {
     pub(crate) fn join(&self) -> Result<()> {
         self.pool.join();
 
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.stop()?;
+        }
+
+        if let Ok(quarantined) = self.quarantined.lock() {
+            if !quarantined.is_empty() {
+                log::warn!(
+                    "Quarantined {} file(s) that took longer than --file-timeout, the results may include part of them:",
+                    quarantined.len().separate_with_commas()
+                );
+                for file in quarantined.iter() {
+                    log::warn!("  {:?}", file.path);
+                }
+            }
+        }
+
+        self.write_error_report()?;
+
         if self.early_exit.load(Ordering::Relaxed) || self.pool.panic_count() > 0 {
-            self.file_progress.finish_and_clear();
+            self.progress.finish(false);
             if let Ok(ref error) = self.error.try_lock() {
                 if let Some(ref err) = **error {
                     bail!("{err}");
@@ -271,7 +1117,7 @@ impl DataExecutor {
             }
             bail!("Thread worker(s) finished with errors");
         } else {
-            self.file_progress.finish();
+            self.progress.finish(true);
         }
 
         let error_count = self.error_count.load(Ordering::Relaxed);
@@ -287,6 +1133,27 @@ impl DataExecutor {
             }
         }
 
+        let oversized = self.oversized.load(Ordering::Relaxed);
+        if oversized > 0 {
+            log::warn!(
+                "Skipped {} document(s) longer than --max-doc-bytes",
+                oversized.separate_with_commas()
+            );
+        }
+
+        let invalid_utf8 = self.invalid_utf8.load(Ordering::Relaxed);
+        if invalid_utf8 > 0 {
+            let action = match INVALID_UTF8.get() {
+                Some(InvalidUtf8::Replace) => "Replaced invalid bytes in",
+                _ => "Skipped",
+            };
+            log::warn!(
+                "{} {} line(s) with invalid UTF-8",
+                action,
+                invalid_utf8.separate_with_commas()
+            );
+        }
+
         log::info!(
             "Processed {} JSON lines in {}",
             self.total_lines
@@ -297,6 +1164,116 @@ impl DataExecutor {
 
         Ok(())
     }
+    /// Write the failed files and skipped lines so far to the error report, if there's one and
+    /// there were any.
+    fn write_error_report(&self) -> Result<()> {
+        let report = match ERROR_REPORT.get() {
+            Some(report) => report,
+            None => return Ok(()),
+        };
+        let failed = std::mem::take(
+            &mut *self
+                .failed
+                .lock()
+                .map_err(|_| anyhow!("Failed to acquire lock"))?,
+        );
+        let quarantined = std::mem::take(
+            &mut *self
+                .quarantined
+                .lock()
+                .map_err(|_| anyhow!("Failed to acquire lock"))?,
+        );
+        let skipped = std::mem::take(
+            &mut *self
+                .skipped
+                .lock()
+                .map_err(|_| anyhow!("Failed to acquire lock"))?,
+        );
+        if failed.is_empty() && quarantined.is_empty() && skipped.is_empty() {
+            return Ok(());
+        }
+
+        write_error_report(report, &failed, &quarantined, &skipped)?;
+        if !failed.is_empty() {
+            log::error!(
+                "{} file(s) failed, see {:?}",
+                failed.len().separate_with_commas(),
+                report
+            );
+        }
+        if !quarantined.is_empty() {
+            log::warn!(
+                "{} file(s) quarantined, see {:?}",
+                quarantined.len().separate_with_commas(),
+                report
+            );
+        }
+        if !skipped.is_empty() {
+            let num_files = skipped
+                .iter()
+                .map(|line| &line.path)
+                .collect::<HashSet<_>>()
+                .len();
+            log::warn!(
+                "Skipped {} malformed line(s) in {} file(s), see {:?}",
+                skipped.len().separate_with_commas(),
+                num_files.separate_with_commas(),
+                report
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DataExecutor {
+    fn drop(&mut self) {
+        // Make sure the terminal is restored even if we never made it to `join()`.
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.stop().ok();
+        }
+        // Same for the error report, e.g. when a file doesn't exist.
+        if let Err(err) = self.write_error_report() {
+            log::error!("Failed to write error report - {}", err);
+        }
+    }
+}
+
+/// Derive the seed of a random number stream from a command's '--seed', so that every source of
+/// randomness in a command is reproducible from that one seed. `purpose` names what the stream
+/// is for, and `index` tells apart streams for the same purpose, e.g. one for each file, so
+/// that results don't depend on how files are scheduled across workers.
+pub(crate) fn derive_seed(seed: u64, purpose: &str, index: usize) -> u64 {
+    let mut input = purpose.as_bytes().to_vec();
+    input.extend_from_slice(&(index as u64).to_le_bytes());
+    xxh3_64_with_seed(&input, seed)
+}
+
+/// A random number generator for the stream from [`derive_seed()`].
+pub(crate) fn derive_rng(seed: u64, purpose: &str, index: usize) -> StdRng {
+    StdRng::seed_from_u64(derive_seed(seed, purpose, index))
+}
+
+/// A pseudo-random number in [0, 1) for an item within a line of a file, like a token, e.g. to
+/// decide whether to sample it. It only depends on the seed and where the item is, so it's the
+/// same regardless of scheduling, retries, and resumed files.
+pub(crate) fn uniform_hash(seed: u64, path: &Path, line: usize, index: usize) -> f64 {
+    let mut hasher = Xxh3::with_seed(seed);
+    hasher.update(path.to_string_lossy().as_bytes());
+    hasher.update(&(line as u64).to_le_bytes());
+    hasher.update(&(index as u64).to_le_bytes());
+    // Use the top 53 bits, which is as many as an f64 can represent exactly.
+    (hasher.digest() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The number of worker threads to use for processing `num_paths` files.
+fn num_workers(max_workers: Option<usize>, num_paths: usize) -> usize {
+    std::cmp::max(
+        1,
+        std::cmp::min(
+            max_workers.unwrap_or_else(|| std::cmp::min(64, num_cpus::get())),
+            num_paths,
+        ),
+    )
 }
 
 pub(crate) fn parse_size_default_to_gb(src: &str) -> Result<u64, parse_size::Error> {
@@ -313,3 +1290,228 @@ pub(crate) fn parse_size_default_to_gb(src: &str) -> Result<u64, parse_size::Err
         parse_size(format!("{src}GiB"))
     }
 }
+
+/// A band of document lengths, in tokens, used to restrict an analysis to only some documents.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct LengthBand {
+    min: Option<usize>,
+    max: Option<usize>,
+}
+
+impl LengthBand {
+    pub(crate) fn new(min: Option<usize>, max: Option<usize>) -> Result<Self> {
+        if let (Some(min), Some(max)) = (min, max) {
+            if min > max {
+                bail!("--min-doc-tokens can't be greater than --max-doc-tokens");
+            }
+        }
+        Ok(Self { min, max })
+    }
+
+    pub(crate) fn contains(&self, num_tokens: usize) -> bool {
+        self.min.unwrap_or(0) <= num_tokens && num_tokens <= self.max.unwrap_or(usize::MAX)
+    }
+}
+
+/// Look up the raw JSON of the field `field` in the raw JSON of a document. Nested fields are
+/// separated by dots, like "metadata.lang". Returns `None` if the document doesn't have the
+/// field.
+pub(crate) fn raw_field<'a>(raw: &'a str, field: &str) -> Result<Option<&'a str>> {
+    let mut value = raw;
+    for key in field.split('.') {
+        let fields: HashMap<String, &RawValue> = serde_json::from_str(value)
+            .map_err(|_| anyhow!("field {:?} isn't in a JSON object", field))?;
+        match fields.get(key) {
+            Some(&raw_value) => value = raw_value.get(),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(value))
+}
+
+/// Look up the weight of a document in the numeric field `field` of its raw JSON, for
+/// weighting its contributions with '--weight-field'. Nested fields are separated by dots,
+/// like "metadata.weight". Documents without the field have a weight of 1.
+pub(crate) fn document_weight(raw: &str, field: &str) -> Result<f64> {
+    let value = match raw_field(raw, field)
+        .map_err(|_| anyhow!("--weight-field {:?} isn't in a JSON object", field))?
+    {
+        Some(value) => value,
+        None => return Ok(1.0),
+    };
+    match serde_json::from_str::<Option<f64>>(value) {
+        Ok(None) => Ok(1.0),
+        Ok(Some(weight)) if weight >= 0.0 && weight.is_finite() => Ok(weight),
+        _ => bail!(
+            "--weight-field {:?} must be a non-negative number, got {}",
+            field,
+            value
+        ),
+    }
+}
+
+/// The output path in `out` for every input file, which keeps the file's name. Fails if two
+/// input files have the same name, or if an output file already exists and neither `force` nor
+/// '--append' is set.
+pub(crate) fn mirrored_output_paths(
+    paths: &[PathBuf],
+    out: &Path,
+    force: bool,
+) -> Result<Vec<PathBuf>> {
+    let mut out_paths = Vec::with_capacity(paths.len());
+    let mut file_names = HashSet::new();
+    for path in paths {
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| anyhow!("{:?} isn't a file", path))?;
+        if !file_names.insert(file_name) {
+            bail!(
+                "More than one input file is named {:?}, so they can't all be written to -o/--out",
+                file_name
+            );
+        }
+        let out_path = out.join(file_name);
+        if out_path.is_file() && !force && !crate::util::appending() {
+            bail!(
+                "Output file {:?} already exists, use --force to overwrite or --append to append to it",
+                out_path
+            );
+        }
+        out_paths.push(out_path);
+    }
+    Ok(out_paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc::channel;
+
+    use super::*;
+
+    #[test]
+    fn test_process_file_parallel_fails_on_first_batch() {
+        let path =
+            std::env::temp_dir().join(format!("wimbd-parallel-{}.jsonl", std::process::id()));
+        // Enough lines to fill the channel many times over.
+        let lines: Vec<String> = (0..100_000)
+            .map(|i| format!("{{\"text\": \"line {i}\"}}\n"))
+            .collect();
+        std::fs::write(&path, lines.concat()).unwrap();
+
+        // Run in a thread so that a deadlock fails the test instead of hanging it.
+        let (tx, rx) = channel();
+        let thread_path = path.clone();
+        std::thread::spawn(move || {
+            let result = process_file_parallel(
+                |_: DataInstance, _: &Path, _: usize, _: &mut ()| -> Result<()> {
+                    // Give the reader time to fill up the channel and block on it.
+                    std::thread::sleep(Duration::from_millis(200));
+                    bail!("failed on purpose")
+                },
+                || -> Result<()> { Ok(()) },
+                |_: ()| -> Result<()> { Ok(()) },
+                None,
+                &thread_path,
+                None,
+                Arc::new(AtomicBool::new(false)),
+                4,
+            );
+            tx.send(result.map(|counts| counts.lines)).unwrap();
+        });
+        let result = rx
+            .recv_timeout(Duration::from_secs(30))
+            .expect("processing the file deadlocked");
+        std::fs::remove_file(&path).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("failed on purpose"));
+    }
+
+    #[test]
+    fn test_process_file_parallel_fails_late() {
+        let path =
+            std::env::temp_dir().join(format!("wimbd-parallel-late-{}.jsonl", std::process::id()));
+        let lines: Vec<String> = (0..10_000)
+            .map(|i| format!("{{\"text\": \"line {i}\"}}\n"))
+            .collect();
+        std::fs::write(&path, lines.concat()).unwrap();
+
+        // Only one line fails, so the other threads finish their share of the file just fine,
+        // but none of their contexts should be passed on.
+        let callbacks = Arc::new(AtomicUsize::new(0));
+        let result = process_file_parallel(
+            |_: DataInstance, _: &Path, line_num: usize, count: &mut usize| -> Result<()> {
+                if line_num == 9_000 {
+                    bail!("failed on purpose");
+                }
+                *count += 1;
+                Ok(())
+            },
+            || -> Result<usize> { Ok(0) },
+            {
+                let callbacks = callbacks.clone();
+                move |_: usize| -> Result<()> {
+                    callbacks.fetch_add(1, Ordering::Relaxed);
+                    Ok(())
+                }
+            },
+            None,
+            &path,
+            None,
+            Arc::new(AtomicBool::new(false)),
+            4,
+        );
+        std::fs::remove_file(&path).unwrap();
+        assert!(result.is_err());
+        assert_eq!(callbacks.load(Ordering::Relaxed), 0);
+    }
+
+    /// Process a file whose second line fails the first time it's seen and return how many
+    /// lines made it to the data function and whether processing succeeded.
+    fn process_with_retry(shared_state: bool) -> (usize, bool) {
+        let path = std::env::temp_dir().join(format!(
+            "wimbd-retry-{}-{}.jsonl",
+            shared_state,
+            std::process::id()
+        ));
+        std::fs::write(&path, "{\"text\": \"a\"}\n".repeat(3)).unwrap();
+
+        let mut executor =
+            DataExecutor::new(std::slice::from_ref(&path), Some(1), None, "Testing", true).unwrap();
+        executor.set_default_max_retries(2);
+        if shared_state {
+            executor.set_shared_state();
+        }
+        let calls = Arc::new(AtomicUsize::new(0));
+        {
+            let calls = calls.clone();
+            executor
+                .execute_with_callback(
+                    &path,
+                    move |_: DataInstance, _: &Path, _: usize, _: &mut ()| -> Result<()> {
+                        if calls.fetch_add(1, Ordering::Relaxed) == 1 {
+                            bail!("failed on purpose");
+                        }
+                        Ok(())
+                    },
+                    || -> Result<()> { Ok(()) },
+                    |_: ()| -> Result<()> { Ok(()) },
+                )
+                .unwrap();
+        }
+        let result = executor.join();
+        std::fs::remove_file(&path).unwrap();
+        (calls.load(Ordering::Relaxed), result.is_ok())
+    }
+
+    #[test]
+    fn test_retry_starts_over() {
+        assert_eq!(process_with_retry(false), (5, true));
+    }
+
+    #[test]
+    fn test_retry_with_shared_state() {
+        assert_eq!(process_with_retry(true), (2, false));
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use structopt::StructOpt;
+
+use super::stats::{self, StatsReport};
+use crate::preprocess::Preprocessor;
+
+#[derive(Debug, StructOpt, Clone)]
+pub(crate) struct Opt {
+    /// Path to the directory to watch. Files ending in '--suffix' are picked up from it
+    /// and all of its subdirectories.
+    #[structopt(parse(from_os_str))]
+    dir: PathBuf,
+
+    /// Path to the state file, a JSON object with the keys "files", the files processed so far,
+    /// and "stats", the stats over those files in the same format as 'wimbd stats --json'.
+    /// If the file exists it's picked up where it left off.
+    #[structopt(long = "state", parse(from_os_str))]
+    state: PathBuf,
+
+    /// How long to wait between listings of the directory, e.g. "30s" or "5m".
+    #[structopt(long = "interval", default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+    interval: Duration,
+
+    /// Only pick up files whose names end with this.
+    #[structopt(long = "suffix", default_value = ".gz")]
+    suffix: String,
+
+    /// Process all new files once and exit instead of watching the directory.
+    /// Without this, files are only processed once their size and modification time haven't
+    /// changed between two listings, so that files that are still being written are skipped.
+    #[structopt(long = "once")]
+    once: bool,
+
+    /// Set the max number of threads/workers to use. Defaults to min(64, num CPU).
+    #[structopt(short = "j", long = "workers")]
+    workers: Option<usize>,
+
+    /// Don't show progress bars.
+    /// This doesn't affect logging.
+    #[structopt(short = "q", long = "quiet")]
+    quiet: bool,
+
+    /// Set the tokenizer to use. This can be the name of a pretrained tokenizer
+    /// from HuggingFace. It has to be the same for the whole state.
+    #[structopt(short = "t", long = "tokenizer", default_value = "unicode")]
+    tokenizer: String,
+
+    /// Strip HTML tags and decode HTML entities before tokenizing.
+    #[structopt(long = "strip-html")]
+    strip_html: bool,
+
+    /// Strip Markdown markup, like headings, emphasis, and links, before tokenizing.
+    #[structopt(long = "strip-markdown")]
+    strip_markdown: bool,
+}
+
+/// What's persisted between rounds.
+#[derive(Debug, Serialize, Deserialize)]
+struct WatchState {
+    tokenizer: String,
+    files: BTreeSet<PathBuf>,
+    stats: Option<StatsReport>,
+}
+
+impl WatchState {
+    fn load_or_new(path: &Path, tokenizer: &str) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Self {
+                tokenizer: tokenizer.into(),
+                files: BTreeSet::new(),
+                stats: None,
+            });
+        }
+        let state: WatchState = serde_json::from_reader(fs::File::open(path)?)?;
+        if state.tokenizer != tokenizer {
+            bail!(
+                "State file {:?} was created with the {:?} tokenizer, not {:?}",
+                path,
+                state.tokenizer,
+                tokenizer
+            );
+        }
+        log::info!(
+            "Resuming from {:?} with {} processed file(s)",
+            path,
+            state.files.len()
+        );
+        Ok(state)
+    }
+
+    /// Write the state to a temporary file first so a crash never leaves a partial state behind.
+    fn save(&self, path: &Path) -> Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        serde_json::to_writer(fs::File::create(&tmp_path)?, self)?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+pub(crate) fn main(opt: Opt) -> Result<()> {
+    if !opt.dir.is_dir() {
+        bail!("{:?} is not a directory", opt.dir);
+    }
+    if opt.state.is_dir() {
+        bail!("--state must be a valid file name, not a directory");
+    }
+
+    let preprocessor = Preprocessor::new(opt.strip_html, opt.strip_markdown);
+    let mut state = WatchState::load_or_new(&opt.state, &opt.tokenizer)?;
+    // The size and modification time of each unprocessed file from the previous listing.
+    let mut last_seen: HashMap<PathBuf, (u64, SystemTime)> = HashMap::new();
+
+    loop {
+        let mut seen = HashMap::new();
+        list_files(&opt.dir, &opt.suffix, &mut seen)?;
+        seen.retain(|path, _| !state.files.contains(path));
+
+        let mut new_files: Vec<PathBuf> = seen
+            .iter()
+            .filter(|(path, info)| opt.once || last_seen.get(*path) == Some(*info))
+            .map(|(path, _)| path.clone())
+            .collect();
+        new_files.sort();
+
+        if !new_files.is_empty() {
+            log::info!("Processing {} new file(s)...", new_files.len());
+            let report = stats::collect(&stats::Opt::new(
+                new_files.clone(),
+                opt.workers,
+                opt.quiet,
+                &opt.tokenizer,
+                preprocessor,
+            ))?;
+            match state.stats {
+                Some(ref mut stats) => stats.merge(report)?,
+                None => state.stats = Some(report),
+            }
+            for path in new_files {
+                seen.remove(&path);
+                state.files.insert(path);
+            }
+            state.save(&opt.state)?;
+            log::info!(
+                "Updated {:?}, {} file(s) processed so far",
+                opt.state,
+                state.files.len()
+            );
+        }
+
+        if opt.once {
+            break;
+        }
+        last_seen = seen;
+        std::thread::sleep(opt.interval);
+    }
+
+    Ok(())
+}
+
+/// Recursively list the files in `dir` ending in `suffix` with their size and modification time.
+fn list_files(
+    dir: &Path,
+    suffix: &str,
+    files: &mut HashMap<PathBuf, (u64, SystemTime)>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let metadata = entry.metadata()?;
+        if metadata.is_dir() {
+            list_files(&path, suffix, files)?;
+        } else if path.to_string_lossy().ends_with(suffix) {
+            files.insert(path, (metadata.len(), metadata.modified()?));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,149 @@
+//! Heuristics for telling source code apart from natural language.
+
+/// Words that are much more common in source code than in prose.
+const KEYWORDS: &[&str] = &[
+    "def",
+    "elif",
+    "import",
+    "self",
+    "lambda",
+    "function",
+    "const",
+    "let",
+    "var",
+    "return",
+    "public",
+    "private",
+    "protected",
+    "static",
+    "void",
+    "int",
+    "bool",
+    "struct",
+    "impl",
+    "fn",
+    "include",
+    "namespace",
+    "null",
+    "nullptr",
+    "None",
+    "True",
+    "False",
+    "true",
+    "false",
+    "println",
+    "printf",
+    "console",
+    "async",
+    "await",
+    "typedef",
+    "enum",
+    "usize",
+    "std",
+];
+
+/// Characters that are much more common in source code than in prose.
+const SYMBOLS: &str = "{}[]();=<>|&*#$\\_";
+
+/// Features used to score how code-like a piece of text is. Each feature is in `[0, 1]`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CodeFeatures {
+    /// The fraction of non-empty lines ending with a character that typically ends a line of
+    /// code, like ';', '{', '}', or ':'.
+    pub line_end_ratio: f64,
+    /// The fraction of non-empty lines that are indented.
+    pub indent_ratio: f64,
+    /// The fraction of words that are common programming language keywords.
+    pub keyword_ratio: f64,
+    /// The fraction of non-whitespace characters that are common code symbols.
+    pub symbol_ratio: f64,
+}
+
+impl CodeFeatures {
+    /// Compute the features for a piece of text.
+    pub fn new(text: &str) -> Self {
+        let mut lines: usize = 0;
+        let mut code_line_ends: usize = 0;
+        let mut indented: usize = 0;
+        for line in text.lines() {
+            let trimmed = line.trim_end();
+            if trimmed.trim_start().is_empty() {
+                continue;
+            }
+            lines += 1;
+            if trimmed.ends_with([';', '{', '}', ':', ')', '(', ',']) && !trimmed.ends_with("...") {
+                code_line_ends += 1;
+            }
+            if line.starts_with([' ', '\t']) {
+                indented += 1;
+            }
+        }
+
+        let mut words: usize = 0;
+        let mut keywords: usize = 0;
+        for word in text
+            .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+            .filter(|w| !w.is_empty())
+        {
+            words += 1;
+            if KEYWORDS.contains(&word) {
+                keywords += 1;
+            }
+        }
+
+        let mut chars: usize = 0;
+        let mut symbols: usize = 0;
+        for c in text.chars().filter(|c| !c.is_whitespace()) {
+            chars += 1;
+            if SYMBOLS.contains(c) {
+                symbols += 1;
+            }
+        }
+
+        let ratio = |n: usize, d: usize| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+        Self {
+            line_end_ratio: ratio(code_line_ends, lines),
+            indent_ratio: ratio(indented, lines),
+            keyword_ratio: ratio(keywords, words),
+            symbol_ratio: ratio(symbols, chars),
+        }
+    }
+
+    /// Combine the features into a single score in `[0, 1]`, where higher is more code-like.
+    pub fn score(&self) -> f64 {
+        // The keyword and symbol ratios are scaled since even dense code only
+        // has ~10% keywords and symbols.
+        0.3 * self.line_end_ratio
+            + 0.2 * self.indent_ratio
+            + 0.25 * f64::min(1.0, 10.0 * self.keyword_ratio)
+            + 0.25 * f64::min(1.0, 8.0 * self.symbol_ratio)
+    }
+}
+
+/// Score how code-like a piece of text is, in `[0, 1]`. See [`CodeFeatures`].
+pub fn code_score(text: &str) -> f64 {
+    CodeFeatures::new(text).score()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_code_score_for_code() {
+        let c = "#include <stdio.h>\nint main() {\n    printf(\"hi\");\n    return 0;\n}\n";
+        assert!(code_score(c) > 0.5);
+
+        let python =
+            "import os\n\ndef main():\n    for path in os.listdir('.'):\n        print(path)\n";
+        assert!(code_score(python) > 0.5);
+    }
+
+    #[test]
+    fn test_code_score_for_prose() {
+        let prose = "The woman who died after falling from a bridge has been identified.\n\
+                     Police were called to the carriageway around 6.10am and the road was closed.";
+        assert!(code_score(prose) < 0.2);
+        assert_eq!(code_score(""), 0.0);
+    }
+}
@@ -0,0 +1,179 @@
+//! Helpers for detecting text encoding damage.
+
+use std::borrow::Cow;
+
+/// The Unicode replacement character, usually the result of lossy decoding upstream.
+pub const REPLACEMENT_CHAR: char = '\u{FFFD}';
+
+/// Characters that bytes 0x80-0x9F map to in Windows-1252. Together with U+0080-U+00BF these
+/// are what UTF-8 continuation bytes look like when decoded as Latin-1 or Windows-1252.
+const CP1252_CONTINUATIONS: &str = "€‚ƒ„…†‡ˆ‰Š‹ŒŽ‘’“”•–—˜™š›œžŸ";
+
+/// Count the number of Unicode replacement characters in some text.
+pub fn count_replacement_chars(text: &str) -> usize {
+    text.chars().filter(|c| *c == REPLACEMENT_CHAR).count()
+}
+
+fn looks_like_continuation(c: char) -> bool {
+    ('\u{80}'..='\u{BF}').contains(&c) || CP1252_CONTINUATIONS.contains(c)
+}
+
+/// Count classic mojibake sequences, i.e. UTF-8 encoded text that was decoded as Latin-1 or
+/// Windows-1252, such as "Ã©" (for "é") or "â€™" (for "’").
+pub fn count_mojibake(text: &str) -> usize {
+    let mut count = 0;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let is_lead = match c {
+            // Lead bytes of 2-byte sequences for Latin-1 supplement and Latin Extended-A.
+            'Â' | 'Ã' | 'Å' => true,
+            // Lead byte of 3-byte sequences for general punctuation, e.g. quotes and dashes.
+            'â' => chars.peek() == Some(&'€'),
+            _ => false,
+        };
+        if is_lead {
+            if let Some(next) = chars.peek() {
+                if looks_like_continuation(*next) {
+                    count += 1;
+                    chars.next();
+                }
+            }
+        }
+    }
+    count
+}
+
+/// A `\uXXXX` escape found in raw JSON.
+struct UnicodeEscape {
+    start: usize,
+    value: u32,
+}
+
+/// Iterate over the `\uXXXX` escapes in a raw JSON string, skipping other escapes like `\\`.
+fn unicode_escapes(raw: &str) -> impl Iterator<Item = UnicodeEscape> + '_ {
+    let bytes = raw.as_bytes();
+    let mut i = 0;
+    std::iter::from_fn(move || {
+        while i + 1 < bytes.len() {
+            if bytes[i] != b'\\' {
+                i += 1;
+                continue;
+            }
+            if bytes[i + 1] != b'u' {
+                // Some other escape sequence, skip it entirely.
+                i += 2;
+                continue;
+            }
+            let start = i;
+            i += 2;
+            if let Some(value) = raw
+                .get(i..i + 4)
+                .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            {
+                i += 4;
+                return Some(UnicodeEscape { start, value });
+            }
+        }
+        None
+    })
+}
+
+/// Find the byte ranges of unpaired UTF-16 surrogate escapes in raw JSON, e.g. a lone
+/// `\ud83d`. These are technically invalid and are rejected by most JSON parsers.
+fn invalid_surrogate_escapes(raw: &str) -> Vec<(usize, usize)> {
+    let mut invalid = Vec::new();
+    let mut pending_high: Option<usize> = None;
+    let mut last_end = 0;
+    for escape in unicode_escapes(raw) {
+        if let Some(high_start) = pending_high.take() {
+            if escape.start == last_end && (0xDC00..=0xDFFF).contains(&escape.value) {
+                // A valid surrogate pair.
+                last_end = escape.start + 6;
+                continue;
+            }
+            invalid.push((high_start, high_start + 6));
+        }
+        match escape.value {
+            0xD800..=0xDBFF => pending_high = Some(escape.start),
+            0xDC00..=0xDFFF => invalid.push((escape.start, escape.start + 6)),
+            _ => {}
+        }
+        last_end = escape.start + 6;
+    }
+    if let Some(high_start) = pending_high {
+        invalid.push((high_start, high_start + 6));
+    }
+    invalid
+}
+
+/// Count the number of unpaired UTF-16 surrogate escapes in a raw JSON string.
+pub fn count_invalid_surrogate_escapes(raw: &str) -> usize {
+    invalid_surrogate_escapes(raw).len()
+}
+
+/// Replace unpaired UTF-16 surrogate escapes in a raw JSON string with an escaped replacement
+/// character so that it can be parsed.
+pub fn replace_invalid_surrogate_escapes(raw: &str) -> Cow<'_, str> {
+    let invalid = invalid_surrogate_escapes(raw);
+    if invalid.is_empty() {
+        return Cow::Borrowed(raw);
+    }
+    let mut out = String::with_capacity(raw.len());
+    let mut last = 0;
+    for (start, end) in invalid {
+        out.push_str(&raw[last..start]);
+        out.push_str("\\ufffd");
+        last = end;
+    }
+    out.push_str(&raw[last..]);
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count_replacement_chars() {
+        assert_eq!(count_replacement_chars("caf\u{FFFD} \u{FFFD}"), 2);
+        assert_eq!(count_replacement_chars("café"), 0);
+    }
+
+    #[test]
+    fn test_count_mojibake() {
+        assert_eq!(count_mojibake("cafÃ© and rÃ©sumÃ©"), 3);
+        assert_eq!(count_mojibake("it\u{e2}\u{20ac}\u{2122}s"), 1);
+        assert_eq!(count_mojibake("café and résumé, Ångström"), 0);
+    }
+
+    #[test]
+    fn test_invalid_surrogate_escapes() {
+        // A valid pair.
+        assert_eq!(
+            count_invalid_surrogate_escapes(r#"{"text":"\ud83d\ude00"}"#),
+            0
+        );
+        // Lone high and low surrogates.
+        assert_eq!(count_invalid_surrogate_escapes(r#"{"text":"\ud83d x"}"#), 1);
+        assert_eq!(count_invalid_surrogate_escapes(r#"{"text":"x \ude00"}"#), 1);
+        assert_eq!(
+            count_invalid_surrogate_escapes(r#"{"text":"\ud83d\ud83d\ude00"}"#),
+            1
+        );
+        // An escaped backslash is not an escape.
+        assert_eq!(count_invalid_surrogate_escapes(r#"{"text":"\\ud83d"}"#), 0);
+    }
+
+    #[test]
+    fn test_replace_invalid_surrogate_escapes() {
+        assert_eq!(
+            replace_invalid_surrogate_escapes(r#"{"text":"a\ud83d b \ud83d\ude00"}"#),
+            r#"{"text":"a\ufffd b \ud83d\ude00"}"#
+        );
+        let raw = r#"{"text":"fine"}"#;
+        assert!(matches!(
+            replace_invalid_surrogate_escapes(raw),
+            Cow::Borrowed(_)
+        ));
+    }
+}
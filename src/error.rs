@@ -0,0 +1,107 @@
+//! The error type of the library.
+
+use std::collections::TryReserveError;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// A `Result` with a [`WimbdError`] by default.
+pub type Result<T, E = WimbdError> = std::result::Result<T, E>;
+
+/// An error from reading or writing data, tokenizing text, or counting ngrams.
+///
+/// The variants separate the classes of failures so that callers can handle them differently,
+/// e.g. retry a file after a transient IO error but skip a corrupt one.
+#[derive(Debug, Error)]
+pub enum WimbdError {
+    /// Reading or writing a file failed.
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    /// A compressed file is corrupt or truncated.
+    #[error("Failed to decompress {path:?} - {source}")]
+    Decompression {
+        path: PathBuf,
+        #[source]
+        source: io::Error,
+    },
+
+    /// Data like a JSON record or a file header is malformed.
+    #[error("{0}")]
+    Parse(String),
+
+    /// A tokenizer couldn't be loaded, or failed to tokenize or decode something.
+    #[error("{0}")]
+    Tokenizer(String),
+
+    /// An argument or call was invalid, like an index past the end of a file or writing to a
+    /// counter that's already finished.
+    #[error("{0}")]
+    InvalidInput(String),
+
+    /// There wasn't enough memory for a counter.
+    #[error("Failed to allocate counts array. You may not have enough available memory.")]
+    OutOfMemory(#[from] TryReserveError),
+
+    /// An error returned by a callback, passed through as is.
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl WimbdError {
+    /// Classify an error from reading the compressed file at `path`. Errors of the decoder,
+    /// which are of kind [`io::ErrorKind::InvalidInput`] for corrupt data and
+    /// [`io::ErrorKind::UnexpectedEof`] for truncated files, are decompression errors, and
+    /// everything else is an IO error.
+    pub fn from_read(path: impl AsRef<Path>, err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::InvalidInput | io::ErrorKind::UnexpectedEof => Self::Decompression {
+                path: path.as_ref().into(),
+                source: err,
+            },
+            _ => Self::Io(err),
+        }
+    }
+
+    /// Whether retrying might help, i.e. whether this is an IO error that network file
+    /// systems return for transient failures.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Io(err) => crate::io::is_transient(err),
+            _ => false,
+        }
+    }
+}
+
+impl From<serde_json::Error> for WimbdError {
+    fn from(err: serde_json::Error) -> Self {
+        if err.is_io() {
+            Self::Io(err.into())
+        } else {
+            Self::Parse(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classify_errors() {
+        let corrupt = io::Error::new(io::ErrorKind::InvalidInput, "corrupt deflate stream");
+        assert!(matches!(
+            WimbdError::from_read("a.json.gz", corrupt),
+            WimbdError::Decompression { .. }
+        ));
+        let timeout = io::Error::new(io::ErrorKind::TimedOut, "timed out");
+        let err = WimbdError::from_read("a.json.gz", timeout);
+        assert!(matches!(err, WimbdError::Io(_)));
+        assert!(err.is_transient());
+
+        let err: WimbdError = serde_json::from_str::<u64>("nope").unwrap_err().into();
+        assert!(matches!(err, WimbdError::Parse(_)));
+        assert!(!err.is_transient());
+    }
+}
@@ -0,0 +1,116 @@
+//! Ngram counters saved to disk so that they can be queried later without recounting.
+//!
+//! An index is a directory with a `metadata.json` file describing how the counts were collected
+//! and a `counts.bin` file with the raw hash table of the counter as little-endian u32s.
+
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::AtomicU32;
+
+use anyhow::{bail, Context, Result};
+use atomic_traits::{Atomic, NumOps};
+use num_traits::{Bounded, NumCast, One, SaturatingSub, Zero};
+use serde::{Deserialize, Serialize};
+
+use crate::ngrams::NgramCounter;
+
+const METADATA_FILE: &str = "metadata.json";
+const COUNTS_FILE: &str = "counts.bin";
+
+/// Everything needed to recreate a counter's hash functions and tokenize queries the same way.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    /// The ngram size.
+    pub ngram: usize,
+    /// The tokenizer, either "unicode" or the name of a pretrained tokenizer.
+    pub tokenizer: String,
+    /// The number of hash functions.
+    pub hashes: usize,
+    /// The seed for the hash functions.
+    pub seed: u64,
+    /// The number of elements in the hash table.
+    pub size: usize,
+}
+
+/// An ngram counter loaded from disk along with its metadata.
+pub struct NgramIndex {
+    pub metadata: IndexMetadata,
+    pub counter: NgramCounter<AtomicU32>,
+}
+
+impl NgramIndex {
+    /// Save a counter to the directory `dir`, creating it if needed.
+    pub fn save<A>(counter: &NgramCounter<A>, metadata: &IndexMetadata, dir: &Path) -> Result<()>
+    where
+        A: Atomic + NumOps,
+        <A as Atomic>::Type: Zero + One + Bounded + NumCast + Ord + SaturatingSub + Clone,
+    {
+        if std::mem::size_of::<<A as Atomic>::Type>() != 4 {
+            bail!("only counters with u32 counts can be saved as an index");
+        }
+        if counter.size() != metadata.size {
+            bail!("index metadata doesn't match the size of the counter");
+        }
+
+        fs::create_dir_all(dir)?;
+        let mut writer = BufWriter::new(File::create(dir.join(COUNTS_FILE))?);
+        counter.write_counts(&mut writer)?;
+        writer.flush()?;
+        fs::write(
+            dir.join(METADATA_FILE),
+            serde_json::to_string_pretty(metadata)?,
+        )?;
+        Ok(())
+    }
+
+    /// Load an index from the directory `dir`.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let metadata_path = dir.join(METADATA_FILE);
+        let metadata: IndexMetadata = serde_json::from_str(
+            &fs::read_to_string(&metadata_path)
+                .with_context(|| format!("Failed to read {metadata_path:?}"))?,
+        )?;
+
+        let counts_path = dir.join(COUNTS_FILE);
+        let file =
+            File::open(&counts_path).with_context(|| format!("Failed to open {counts_path:?}"))?;
+        if file.metadata()?.len() != (metadata.size * 4) as u64 {
+            bail!("{counts_path:?} doesn't match the size in the index metadata");
+        }
+
+        let counter =
+            NgramCounter::<AtomicU32>::new(metadata.size, metadata.hashes, Some(metadata.seed), 0)?;
+        counter.read_counts(BufReader::new(file))?;
+
+        Ok(Self { metadata, counter })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("wimbd-index-test-{}", std::process::id()));
+        let metadata = IndexMetadata {
+            ngram: 2,
+            tokenizer: "unicode".into(),
+            hashes: 3,
+            seed: 42,
+            size: 1024,
+        };
+        let counter =
+            NgramCounter::<AtomicU32>::new(metadata.size, metadata.hashes, Some(metadata.seed), 0)
+                .unwrap();
+        counter.increment(&["hi", "there"][..], 2);
+        NgramIndex::save(&counter, &metadata, &dir).unwrap();
+
+        let index = NgramIndex::load(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(index.metadata.ngram, 2);
+        assert_eq!(index.counter.count(&["hi", "there"][..]), 2);
+        assert_eq!(index.counter.count(&["bye", "there"][..]), 0);
+    }
+}
@@ -1,59 +1,1251 @@
 //! IO helpers.
 
 use std::{
-    fs::File,
-    io::{self, prelude::*},
-    rc::Rc,
+    fs::{self, File},
+    io::{self, prelude::*, BufWriter, SeekFrom},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        OnceLock,
+    },
+    time::Duration,
 };
 
-use anyhow::Result;
-use flate2::read::MultiGzDecoder;
+use flate2::{bufread::MultiGzDecoder, write::GzEncoder, Compression};
 
-/// A buffered reader for gzip files.
-pub struct GzBufReader {
-    reader: io::BufReader<MultiGzDecoder<File>>,
-    buf: Rc<String>,
+use crate::error::{Result, WimbdError};
+
+/// The `EIO` error code, which network file systems return for transient failures.
+const EIO: i32 = 5;
+
+/// The delay before the first time a file is reopened. This doubles with every attempt.
+const REOPEN_BACKOFF: Duration = Duration::from_millis(100);
+
+static READ_RETRIES: AtomicUsize = AtomicUsize::new(0);
+
+static TMP_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+static NEXT_SPILL_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Reopen files that hit transient read errors up to `retries` times per read, for all
+/// subsequently opened [`LineReader`]s. This is meant for network file systems like NFS or
+/// Lustre, which can fail reads with stale file handles or I/O errors, or return fewer bytes
+/// than the file has.
+pub fn set_read_retries(retries: usize) {
+    READ_RETRIES.store(retries, Ordering::Relaxed);
+}
+
+/// Put spill files under `dir` instead of the system's temporary directory, for all
+/// subsequently created [`SpillDir`]s. The directory is created if it doesn't exist yet.
+pub fn set_tmp_dir(dir: PathBuf) -> Result<()> {
+    fs::create_dir_all(&dir)?;
+    TMP_DIR
+        .set(dir)
+        .map_err(|_| WimbdError::InvalidInput("temporary directory already set".into()))
+}
+
+/// The directory set with [`set_tmp_dir()`], if any.
+pub fn configured_tmp_dir() -> Option<&'static Path> {
+    TMP_DIR.get().map(|dir| dir.as_path())
+}
+
+/// The directory to put spill files under, i.e. the one set with [`set_tmp_dir()`] or else the
+/// system's temporary directory.
+pub fn tmp_dir() -> PathBuf {
+    configured_tmp_dir()
+        .map(|dir| dir.into())
+        .unwrap_or_else(std::env::temp_dir)
+}
+
+/// What to do with lines that aren't valid UTF-8, like with the '--invalid-utf8' option.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidUtf8 {
+    /// Skip the line.
+    Skip,
+    /// Replace invalid bytes with U+FFFD and process the line as usual.
+    Replace,
+    /// Fail the file, even if errors are skipped otherwise.
+    Error,
+}
+
+impl std::str::FromStr for InvalidUtf8 {
+    type Err = WimbdError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "skip" => Ok(Self::Skip),
+            "replace" => Ok(Self::Replace),
+            "error" => Ok(Self::Error),
+            _ => Err(WimbdError::InvalidInput(format!(
+                "invalid value {:?}, expected one of skip, replace, error",
+                s
+            ))),
+        }
+    }
+}
+
+/// Whether a read error is one that network file systems return for transient failures.
+pub(crate) fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::StaleNetworkFileHandle
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::UnexpectedEof
+    ) || err.raw_os_error() == Some(EIO)
+}
+
+/// A [`Read`] wrapper that keeps track of its offset and, after a transient error, reopens the
+/// underlying reader and continues from that offset.
+///
+/// When reopening is enabled, hitting the end of the data before `expected_len` is also treated
+/// as a transient error, since network file systems can return short reads.
+struct ReopeningReader<R> {
+    inner: R,
+    reopen: Box<dyn Fn() -> io::Result<R> + Send>,
+    offset: u64,
+    expected_len: u64,
+    max_reopens: usize,
+}
+
+impl<R: Read + Seek> Read for ReopeningReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut attempts = 0;
+        loop {
+            let err = match self.inner.read(buf) {
+                Ok(0)
+                    if !buf.is_empty()
+                        && self.max_reopens > 0
+                        && self.offset < self.expected_len =>
+                {
+                    io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        format!(
+                            "short read at offset {} of {} bytes",
+                            self.offset, self.expected_len
+                        ),
+                    )
+                }
+                Ok(n) => {
+                    self.offset += n as u64;
+                    return Ok(n);
+                }
+                Err(err) if is_transient(&err) => err,
+                Err(err) => return Err(err),
+            };
+            if attempts >= self.max_reopens {
+                return Err(err);
+            }
+            attempts += 1;
+            std::thread::sleep(REOPEN_BACKOFF * 2u32.saturating_pow(attempts as u32 - 1));
+            self.inner = (self.reopen)()?;
+            self.inner.seek(SeekFrom::Start(self.offset))?;
+        }
+    }
+}
+
+/// A [`Write`] wrapper that counts the bytes written through it.
+struct CountingWriter<W> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// The format of a data file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Gzip,
+    Zstd,
+    /// Uncompressed JSON lines.
+    Plain,
+}
+
+impl Format {
+    /// Detect the format of a file from its first bytes, falling back to its extension for
+    /// files that are too short to tell, like empty ones.
+    pub fn detect(path: impl AsRef<Path>) -> Result<Self> {
+        let mut file = File::open(path.as_ref())?;
+        let mut magic = [0u8; 4];
+        let mut len = 0;
+        while len < magic.len() {
+            match file.read(&mut magic[len..])? {
+                0 => break,
+                n => len += n,
+            }
+        }
+        Ok(Self::from_magic(&magic[..len]).unwrap_or_else(|| Self::from_path(path)))
+    }
+
+    /// The format given by the magic bytes at the start of a file, or `None` if there aren't
+    /// enough bytes to tell.
+    fn from_magic(magic: &[u8]) -> Option<Self> {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Some(Self::Gzip)
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd])
+            || (magic.len() >= 4 && magic[0] & 0xf0 == 0x50 && magic[1..4] == [0x2a, 0x4d, 0x18])
+        {
+            // Zstd files can also start with a skippable frame.
+            Some(Self::Zstd)
+        } else if magic.len() >= 4 {
+            Some(Self::Plain)
+        } else {
+            None
+        }
+    }
+
+    /// The format given by a file's extension.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => Self::Gzip,
+            Some("zst") | Some("zstd") => Self::Zstd,
+            _ => Self::Plain,
+        }
+    }
 }
 
-fn new_buf() -> Rc<String> {
-    Rc::new(String::with_capacity(2048))
+/// Decompresses a file in any [`Format`].
+enum Decoder<R: BufRead> {
+    Gzip(MultiGzDecoder<R>),
+    Zstd(zstd::Decoder<'static, R>),
+    Plain(R),
 }
 
-impl GzBufReader {
-    pub fn open(path: impl AsRef<std::path::Path>) -> Result<Self> {
-        let reader = io::BufReader::new(MultiGzDecoder::new(File::open(path)?));
-        let buf = new_buf();
+impl<R: BufRead> Decoder<R> {
+    fn new(format: Format, reader: R) -> io::Result<Self> {
+        Ok(match format {
+            Format::Gzip => Self::Gzip(MultiGzDecoder::new(reader)),
+            Format::Zstd => Self::Zstd(zstd::Decoder::with_buffer(reader)?),
+            Format::Plain => Self::Plain(reader),
+        })
+    }
 
-        Ok(Self { reader, buf })
+    fn get_ref(&self) -> &R {
+        match self {
+            Self::Gzip(decoder) => decoder.get_ref(),
+            Self::Zstd(decoder) => decoder.get_ref(),
+            Self::Plain(reader) => reader,
+        }
     }
 }
 
-type DataIteratorItem = io::Result<Rc<String>>;
+impl<R: BufRead> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(decoder) => decoder.read(buf),
+            Self::Zstd(decoder) => decoder.read(buf),
+            Self::Plain(reader) => reader.read(buf),
+        }
+    }
+}
+
+/// Open a file in any [`Format`] for reading its decompressed data.
+pub fn open_decompressed(path: impl AsRef<Path>) -> Result<impl Read> {
+    let format = Format::detect(path.as_ref())?;
+    Ok(Decoder::new(
+        format,
+        io::BufReader::new(File::open(path.as_ref())?),
+    )?)
+}
+
+/// A buffered line reader for gzip, zstd, and uncompressed files. The format is detected with
+/// [`Format::detect()`].
+///
+/// Lines can be read into a buffer that's reused with [`LineReader::read_line_into()`], which
+/// avoids allocating for every line, or iterated over as owned strings. Lines include their
+/// trailing newline, if any.
+pub struct LineReader {
+    reader: io::BufReader<Decoder<io::BufReader<ReopeningReader<File>>>>,
+    bytes_read: u64,
+}
+
+impl LineReader {
+    /// Open a file, reopening it after transient read errors as set by [`set_read_retries()`].
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path: PathBuf = path.as_ref().into();
+        let format = Format::detect(&path)?;
+        let inner = File::open(&path)?;
+        let file = ReopeningReader {
+            expected_len: inner.metadata()?.len(),
+            inner,
+            reopen: Box::new(move || File::open(&path)),
+            offset: 0,
+            max_reopens: READ_RETRIES.load(Ordering::Relaxed),
+        };
+        let reader = io::BufReader::new(Decoder::new(format, io::BufReader::new(file))?);
+        Ok(Self {
+            reader,
+            bytes_read: 0,
+        })
+    }
+
+    /// Read the next line into `buf`, replacing its contents. Returns `false` at the end of
+    /// the file.
+    ///
+    /// If the line isn't valid UTF-8, an error of kind [`io::ErrorKind::InvalidData`] is
+    /// returned, but the line is still consumed so reading can continue with the next one.
+    pub fn read_line_into(&mut self, buf: &mut String) -> io::Result<bool> {
+        buf.clear();
+        let n = self.reader.read_line(buf)?;
+        self.bytes_read += n as u64;
+        Ok(n > 0)
+    }
+
+    /// Read the next line into `buf` as raw bytes, replacing its contents. Unlike
+    /// [`read_line_into()`](Self::read_line_into), the line isn't checked to be valid UTF-8.
+    /// Returns `false` at the end of the file.
+    pub fn read_bytes_into(&mut self, buf: &mut Vec<u8>) -> io::Result<bool> {
+        buf.clear();
+        let n = self.reader.read_until(b'\n', buf)?;
+        self.bytes_read += n as u64;
+        Ok(n > 0)
+    }
+
+    /// Skip the next `n` lines without decoding them, returning the number of lines actually
+    /// skipped, which is less than `n` if the end of the file is reached first.
+    pub fn skip_lines(&mut self, n: usize) -> io::Result<usize> {
+        let mut scratch = Vec::new();
+        for i in 0..n {
+            scratch.clear();
+            let read = self.reader.read_until(b'\n', &mut scratch)?;
+            if read == 0 {
+                return Ok(i);
+            }
+            self.bytes_read += read as u64;
+        }
+        Ok(n)
+    }
+
+    /// The number of decompressed bytes of the lines read or skipped so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// The number of bytes read from the compressed file so far. This is ahead of the lines
+    /// that have been returned by up to the size of the internal buffers, but can be compared
+    /// to the size of the file to track progress.
+    pub fn compressed_bytes_read(&self) -> u64 {
+        self.reader.get_ref().get_ref().get_ref().offset
+    }
+}
 
-impl Iterator for GzBufReader {
-    type Item = DataIteratorItem;
+impl Iterator for LineReader {
+    type Item = io::Result<String>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let buf = match Rc::get_mut(&mut self.buf) {
-            Some(buf) => {
-                buf.clear();
-                buf
+        let mut line = String::new();
+        self.read_line_into(&mut line)
+            .map(|more| more.then_some(line))
+            .transpose()
+    }
+}
+
+type ShardFile = CountingWriter<BufWriter<File>>;
+
+enum ShardEncoder {
+    Gzip(GzEncoder<ShardFile>),
+    Zstd(zstd::Encoder<'static, ShardFile>),
+}
+
+impl ShardEncoder {
+    fn compressed_bytes(&self) -> u64 {
+        match self {
+            Self::Gzip(encoder) => encoder.get_ref().count,
+            Self::Zstd(encoder) => encoder.get_ref().count,
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        let mut file = match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        file.flush()
+    }
+}
+
+impl Write for ShardEncoder {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// A writer for JSON lines that are split over numbered, compressed shards in a directory,
+/// named like `part-00000.json.gz`, `part-00001.json.gz`, and so on.
+///
+/// The compression is picked from the extension, which has to end in `.gz` or `.zst`. With
+/// [`ShardWriter::with_max_bytes()`] a new shard is started once the current one reaches the
+/// given compressed size. Since the encoder buffers some output, shards can end up a little
+/// larger than that, and lines are never split across shards.
+pub struct ShardWriter {
+    dir: PathBuf,
+    prefix: String,
+    extension: String,
+    zstd: bool,
+    max_bytes: Option<u64>,
+    next_index: usize,
+    current: Option<ShardEncoder>,
+    paths: Vec<PathBuf>,
+    lines_written: usize,
+}
+
+impl ShardWriter {
+    /// Create a writer for shards in `dir`, which is created if it doesn't exist yet. Existing
+    /// shards with the same names are overwritten.
+    pub fn new(dir: impl AsRef<Path>, prefix: &str, extension: &str) -> Result<Self> {
+        let zstd = if extension.ends_with("gz") {
+            false
+        } else if extension.ends_with("zst") {
+            true
+        } else {
+            return Err(WimbdError::InvalidInput(format!(
+                "shard extension must end in '.gz' or '.zst', got {:?}",
+                extension
+            )));
+        };
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().into(),
+            prefix: prefix.into(),
+            extension: extension.trim_start_matches('.').into(),
+            zstd,
+            max_bytes: None,
+            next_index: 0,
+            current: None,
+            paths: Vec::new(),
+            lines_written: 0,
+        })
+    }
+
+    /// Start a new shard once the current one has at least `max_bytes` of compressed output.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Number the shards starting from `index` instead of 0.
+    pub fn starting_at(mut self, index: usize) -> Self {
+        self.next_index = index;
+        self
+    }
+
+    /// The path of the shard with the given index.
+    pub fn shard_path(&self, index: usize) -> PathBuf {
+        self.dir
+            .join(format!("{}-{index:05}.{}", self.prefix, self.extension))
+    }
+
+    /// Write a line, adding a trailing newline if it doesn't have one.
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.current.is_none() {
+            self.current = Some(self.open_next()?);
+        }
+        let encoder = self.current.as_mut().unwrap();
+        encoder.write_all(line.as_bytes())?;
+        if !line.ends_with('\n') {
+            encoder.write_all(b"\n")?;
+        }
+        self.lines_written += 1;
+
+        if let Some(max_bytes) = self.max_bytes {
+            if encoder.compressed_bytes() >= max_bytes {
+                self.current.take().unwrap().finish()?;
             }
-            None => {
-                self.buf = new_buf();
-                Rc::make_mut(&mut self.buf)
+        }
+        Ok(())
+    }
+
+    /// The number of lines written so far, over all shards.
+    pub fn lines_written(&self) -> usize {
+        self.lines_written
+    }
+
+    /// Finish the current shard and return the paths of all shards written, in order. If no
+    /// lines were written this still writes a single empty shard.
+    pub fn finish(mut self) -> Result<Vec<PathBuf>> {
+        if self.paths.is_empty() {
+            self.current = Some(self.open_next()?);
+        }
+        if let Some(encoder) = self.current.take() {
+            encoder.finish()?;
+        }
+        Ok(std::mem::take(&mut self.paths))
+    }
+
+    fn open_next(&mut self) -> Result<ShardEncoder> {
+        let path = self.shard_path(self.next_index);
+        let file = CountingWriter {
+            inner: BufWriter::new(File::create(&path)?),
+            count: 0,
+        };
+        let encoder = if self.zstd {
+            ShardEncoder::Zstd(zstd::Encoder::new(file, 0)?)
+        } else {
+            ShardEncoder::Gzip(GzEncoder::new(file, Compression::default()))
+        };
+        self.next_index += 1;
+        self.paths.push(path);
+        Ok(encoder)
+    }
+}
+
+impl Drop for ShardWriter {
+    fn drop(&mut self) {
+        if let Some(encoder) = self.current.take() {
+            encoder.finish().ok();
+        }
+    }
+}
+
+/// A compressed spill file, see [`SpillDir::create()`].
+pub type SpillWriter = GzEncoder<BufWriter<File>>;
+
+/// A temporary directory for the spill files of a memory-bound stage, which is removed with
+/// everything in it when dropped, including when the stage fails.
+pub struct SpillDir {
+    path: PathBuf,
+}
+
+impl SpillDir {
+    /// Create a new, uniquely named directory under `tmp_dir`, usually [`tmp_dir()`].
+    pub fn new(tmp_dir: &Path) -> Result<Self> {
+        let path = tmp_dir.join(format!(
+            "wimbd-spill-{}-{}",
+            std::process::id(),
+            NEXT_SPILL_ID.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&path)?;
+        Ok(Self { path })
+    }
+
+    /// The path of the directory.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The path of the spill file with the given index. It can be read back with a
+    /// [`LineReader`].
+    pub fn file(&self, i: usize) -> PathBuf {
+        self.path.join(format!("{i:05}.jsonl.gz"))
+    }
+
+    /// Create the spill file with the given index. Spill files are compressed with a fast
+    /// setting, since they're only read once.
+    pub fn create(&self, i: usize) -> Result<SpillWriter> {
+        let file = File::create(self.file(i))?;
+        Ok(GzEncoder::new(BufWriter::new(file), Compression::fast()))
+    }
+}
+
+impl Drop for SpillDir {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.path).ok();
+    }
+}
+
+/// The size of the header of the `.npy` files written by [`NpyWriter`], including the magic
+/// string. This leaves enough room for any length, so the header can be written up front and
+/// filled in at the end.
+const NPY_HEADER_LEN: usize = 128;
+
+/// The unsigned integer type of the values in an `.npy` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NpyDtype {
+    U16,
+    U32,
+}
+
+impl NpyDtype {
+    /// The smallest type that can hold every ID of a vocabulary of the given size.
+    pub fn for_vocab_size(vocab_size: usize) -> Self {
+        if vocab_size <= u16::MAX as usize + 1 {
+            Self::U16
+        } else {
+            Self::U32
+        }
+    }
+
+    /// The size of a value in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
+
+    /// The NumPy type descriptor, always little-endian.
+    fn descr(&self) -> &'static str {
+        match self {
+            Self::U16 => "<u2",
+            Self::U32 => "<u4",
+        }
+    }
+}
+
+impl std::str::FromStr for NpyDtype {
+    type Err = WimbdError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "u16" | "uint16" => Ok(Self::U16),
+            "u32" | "uint32" => Ok(Self::U32),
+            _ => Err(WimbdError::Parse(format!(
+                "invalid dtype {:?}, expected 'u16' or 'u32'",
+                s
+            ))),
+        }
+    }
+}
+
+/// A writer for a one-dimensional array of unsigned integers in NumPy's `.npy` format, which
+/// can be loaded with `numpy.load()` or memory-mapped with `numpy.load(path, mmap_mode="r")`.
+///
+/// Values are appended as they come in, so the length doesn't have to be known up front.
+/// [`NpyWriter::finish()`] has to be called to fill in the header.
+pub struct NpyWriter {
+    file: BufWriter<File>,
+    dtype: NpyDtype,
+    len: u64,
+}
+
+impl NpyWriter {
+    /// Create a new `.npy` file, overwriting it if it already exists.
+    pub fn create(path: impl AsRef<Path>, dtype: NpyDtype) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(&[0; NPY_HEADER_LEN])?;
+        Ok(Self {
+            file,
+            dtype,
+            len: 0,
+        })
+    }
+
+    /// Append values to the array. Fails if a value doesn't fit in the dtype.
+    pub fn write_values(&mut self, values: &[u32]) -> Result<()> {
+        match self.dtype {
+            NpyDtype::U16 => {
+                for &value in values {
+                    if value > u16::MAX as u32 {
+                        return Err(WimbdError::InvalidInput(format!(
+                            "value {} doesn't fit in u16",
+                            value
+                        )));
+                    }
+                    self.file.write_all(&(value as u16).to_le_bytes())?;
+                }
+            }
+            NpyDtype::U32 => {
+                for &value in values {
+                    self.file.write_all(&value.to_le_bytes())?;
+                }
+            }
+        }
+        self.len += values.len() as u64;
+        Ok(())
+    }
+
+    /// The number of values written so far.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether no values were written so far.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Write the header and flush the file. Returns the number of values written.
+    pub fn finish(mut self) -> Result<u64> {
+        let header = npy_header(self.dtype, self.len);
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.write_all(&header)?;
+        self.file.flush()?;
+        Ok(self.len)
+    }
+}
+
+/// The header of a version 1.0 `.npy` file for a one-dimensional array, padded with spaces to
+/// [`NPY_HEADER_LEN`] bytes.
+fn npy_header(dtype: NpyDtype, len: u64) -> Vec<u8> {
+    let dict = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': ({},), }}",
+        dtype.descr(),
+        len
+    );
+    let mut header = b"\x93NUMPY\x01\x00".to_vec();
+    header.extend_from_slice(&((NPY_HEADER_LEN - 10) as u16).to_le_bytes());
+    header.extend_from_slice(dict.as_bytes());
+    header.resize(NPY_HEADER_LEN - 1, b' ');
+    header.push(b'\n');
+    header
+}
+
+/// A reader for one-dimensional arrays of unsigned integers in NumPy's `.npy` format, like
+/// the ones written by [`NpyWriter`]. Values are read on demand, so the array doesn't have to
+/// fit in memory.
+pub struct NpyReader {
+    reader: io::BufReader<File>,
+    dtype: NpyDtype,
+    len: u64,
+    data_offset: u64,
+    position: u64,
+}
+
+impl NpyReader {
+    /// Open an `.npy` file and read its header.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = io::BufReader::new(File::open(path)?);
+        let mut prefix = [0; 8];
+        reader.read_exact(&mut prefix)?;
+        if &prefix[..6] != b"\x93NUMPY" {
+            return Err(WimbdError::Parse(format!("{:?} is not an .npy file", path)));
+        }
+        let header_len = match prefix[6] {
+            1 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len)?;
+                u16::from_le_bytes(len) as usize
+            }
+            2 | 3 => {
+                let mut len = [0; 4];
+                reader.read_exact(&mut len)?;
+                u32::from_le_bytes(len) as usize
+            }
+            version => {
+                return Err(WimbdError::Parse(format!(
+                    "{:?} has unsupported .npy version {}",
+                    path, version
+                )))
             }
         };
+        let mut header = vec![0; header_len];
+        reader.read_exact(&mut header)?;
+        let (dtype, len) = parse_npy_header(&String::from_utf8_lossy(&header)).map_err(|err| {
+            WimbdError::Parse(format!("failed to read the header of {:?}: {}", path, err))
+        })?;
+        let data_offset = reader.stream_position()?;
+        Ok(Self {
+            reader,
+            dtype,
+            len,
+            data_offset,
+            position: 0,
+        })
+    }
+
+    /// The type of the values.
+    pub fn dtype(&self) -> NpyDtype {
+        self.dtype
+    }
+
+    /// The number of values in the array.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the array is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
 
-        self.reader
-            .read_line(buf)
-            .map(|u| {
-                if u == 0 {
-                    None
-                } else {
-                    Some(Rc::clone(&self.buf))
+    /// Continue reading at the value with the given index.
+    pub fn seek(&mut self, index: u64) -> Result<()> {
+        if index > self.len {
+            return Err(WimbdError::InvalidInput(format!(
+                "index {} is out of bounds for length {}",
+                index, self.len
+            )));
+        }
+        if index != self.position {
+            let offset = self.data_offset + index * self.dtype.size() as u64;
+            self.reader.seek(SeekFrom::Start(offset))?;
+            self.position = index;
+        }
+        Ok(())
+    }
+
+    /// Read the next `n` values.
+    pub fn read_values(&mut self, n: u64) -> Result<Vec<u32>> {
+        if self.position + n > self.len {
+            return Err(WimbdError::InvalidInput(format!(
+                "can't read {} values at index {} of an array of length {}",
+                n, self.position, self.len
+            )));
+        }
+        let mut bytes = vec![0; n as usize * self.dtype.size()];
+        self.reader.read_exact(&mut bytes)?;
+        self.position += n;
+        Ok(match self.dtype {
+            NpyDtype::U16 => bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]) as u32)
+                .collect(),
+            NpyDtype::U32 => bytes
+                .chunks_exact(4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+        })
+    }
+}
+
+/// Get the dtype and length from the header of an `.npy` file, which is a Python dict literal.
+fn parse_npy_header(header: &str) -> Result<(NpyDtype, u64), String> {
+    let value_of = |key: &str| -> Result<&str, String> {
+        let start = header
+            .find(&format!("'{key}':"))
+            .ok_or_else(|| format!("missing {:?}", key))?;
+        Ok(header[start + key.len() + 3..].trim_start())
+    };
+
+    let descr = value_of("descr")?;
+    let dtype = if descr.starts_with("'<u2'") {
+        NpyDtype::U16
+    } else if descr.starts_with("'<u4'") {
+        NpyDtype::U32
+    } else {
+        return Err("unsupported dtype, expected '<u2' or '<u4'".into());
+    };
+
+    let shape = value_of("shape")?;
+    let end = shape.find(')').ok_or("invalid shape")?;
+    let dims: Vec<&str> = shape[..end]
+        .trim_start_matches('(')
+        .split(',')
+        .map(|dim| dim.trim())
+        .filter(|dim| !dim.is_empty())
+        .collect();
+    if dims.len() != 1 {
+        return Err(format!(
+            "expected a one-dimensional array, got shape {}",
+            &shape[..=end]
+        ));
+    }
+    let len = dims[0]
+        .parse()
+        .map_err(|err| format!("invalid length {:?}: {}", dims[0], err))?;
+    Ok((dtype, len))
+}
+
+/// The max number of paths a single pattern can expand to, to catch typos like a range with a
+/// digit too many.
+const MAX_EXPANDED_PATHS: usize = 10_000_000;
+
+/// Expand the brace patterns in a path like a shell does, e.g. numeric ranges like
+/// "c4-train.{00000..01023}-of-01024.json.gz", which keep the zero-padding of their bounds, and
+/// lists like "{train,validation}". A path can have several patterns, which are expanded from
+/// left to right. Braces that aren't a valid pattern are kept as they are.
+pub fn expand_braces(pattern: &str) -> Result<Vec<String>> {
+    let mut expanded = vec![String::new()];
+    let mut rest = pattern;
+    while let Some(open) = rest.find('{') {
+        let close = match rest[open..].find('}') {
+            Some(i) => open + i,
+            None => break,
+        };
+        let alternatives = match brace_alternatives(&rest[open + 1..close])? {
+            Some(alternatives) => alternatives,
+            None => {
+                for path in expanded.iter_mut() {
+                    path.push_str(&rest[..=open]);
                 }
+                rest = &rest[open + 1..];
+                continue;
+            }
+        };
+        if expanded.len().saturating_mul(alternatives.len()) > MAX_EXPANDED_PATHS {
+            return Err(WimbdError::InvalidInput(format!(
+                "{:?} expands to more than {} paths",
+                pattern, MAX_EXPANDED_PATHS
+            )));
+        }
+        let prefix = &rest[..open];
+        expanded = expanded
+            .iter()
+            .flat_map(|path| {
+                alternatives
+                    .iter()
+                    .map(move |alternative| format!("{path}{prefix}{alternative}"))
             })
-            .transpose()
+            .collect();
+        rest = &rest[close + 1..];
+    }
+    for path in expanded.iter_mut() {
+        path.push_str(rest);
+    }
+    Ok(expanded)
+}
+
+/// The alternatives of the body of a brace pattern, or `None` if it isn't a pattern.
+fn brace_alternatives(body: &str) -> Result<Option<Vec<String>>> {
+    if let Some((start, end)) = body.split_once("..") {
+        let is_number = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+        if !is_number(start) || !is_number(end) {
+            return Ok(None);
+        }
+        let parse = |s: &str| {
+            s.parse::<u64>()
+                .map_err(|err| WimbdError::InvalidInput(format!("range {{{}}}: {}", body, err)))
+        };
+        let (first, last) = (parse(start)?, parse(end)?);
+        if first.abs_diff(last) >= MAX_EXPANDED_PATHS as u64 {
+            return Err(WimbdError::InvalidInput(format!(
+                "range {{{}}} has more than {} values",
+                body, MAX_EXPANDED_PATHS
+            )));
+        }
+        let is_padded = |s: &str| s.len() > 1 && s.starts_with('0');
+        let width = if is_padded(start) || is_padded(end) {
+            std::cmp::max(start.len(), end.len())
+        } else {
+            0
+        };
+        let values: Vec<u64> = if first <= last {
+            (first..=last).collect()
+        } else {
+            (last..=first).rev().collect()
+        };
+        Ok(Some(
+            values
+                .into_iter()
+                .map(|value| format!("{value:0width$}"))
+                .collect(),
+        ))
+    } else if body.contains(',') {
+        Ok(Some(body.split(',').map(String::from).collect()))
+    } else {
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Read, Write};
+
+    use super::{
+        expand_braces, parse_npy_header, LineReader, NpyDtype, NpyReader, NpyWriter,
+        ReopeningReader, ShardWriter, SpillDir, NPY_HEADER_LEN,
+    };
+
+    const FIXTURE: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/test_fixtures/c4-sample.00000-of-00001.json.gz"
+    );
+
+    #[test]
+    fn test_read_lines() {
+        let mut reader = LineReader::open(FIXTURE).unwrap();
+        let mut buf = String::new();
+        let mut num_lines = 0;
+        while reader.read_line_into(&mut buf).unwrap() {
+            assert!(buf.ends_with('\n'));
+            num_lines += 1;
+        }
+        assert_eq!(num_lines, 1000);
+        assert_eq!(reader.bytes_read(), 2304249);
+        assert_eq!(
+            reader.compressed_bytes_read(),
+            std::fs::metadata(FIXTURE).unwrap().len()
+        );
+    }
+
+    #[test]
+    fn test_skip_lines() {
+        let mut reader = LineReader::open(FIXTURE).unwrap();
+        assert_eq!(reader.skip_lines(998).unwrap(), 998);
+        assert_eq!(reader.count(), 2);
+
+        let mut reader = LineReader::open(FIXTURE).unwrap();
+        assert_eq!(reader.skip_lines(2000).unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_detect_format() {
+        use super::Format;
+
+        assert_eq!(
+            Format::from_magic(&[0x1f, 0x8b, 0x08, 0x00]),
+            Some(Format::Gzip)
+        );
+        assert_eq!(
+            Format::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]),
+            Some(Format::Zstd)
+        );
+        assert_eq!(
+            Format::from_magic(&[0x5e, 0x2a, 0x4d, 0x18]),
+            Some(Format::Zstd)
+        );
+        assert_eq!(Format::from_magic(b"{\"te"), Some(Format::Plain));
+        assert_eq!(Format::from_magic(b"{}"), None);
+
+        // Formats are detected by content, regardless of the extension.
+        let data: Vec<u8> = LineReader::open(FIXTURE)
+            .unwrap()
+            .flat_map(|line| line.unwrap().into_bytes())
+            .collect();
+        let dir = std::env::temp_dir().join(format!("wimbd-formats-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let plain = dir.join("plain.jsonl");
+        std::fs::write(&plain, &data).unwrap();
+        let zstd = dir.join("zstd");
+        std::fs::write(&zstd, zstd::encode_all(&data[..], 0).unwrap()).unwrap();
+        for path in [&plain, &zstd] {
+            let mut reader = LineReader::open(path).unwrap();
+            assert_eq!(reader.by_ref().count(), 1000);
+            assert_eq!(reader.bytes_read(), data.len() as u64);
+        }
+        assert_eq!(Format::detect(&zstd).unwrap(), Format::Zstd);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_send() {
+        fn assert_send<T: Send>() {}
+        assert_send::<LineReader>();
+    }
+
+    #[test]
+    fn test_shard_writer() {
+        let dir = std::env::temp_dir().join(format!("wimbd-shards-{}", std::process::id()));
+        let lines: Vec<String> = LineReader::open(FIXTURE)
+            .unwrap()
+            .collect::<std::io::Result<_>>()
+            .unwrap();
+
+        let mut writer = ShardWriter::new(&dir, "part", "json.gz")
+            .unwrap()
+            .with_max_bytes(200_000);
+        for line in &lines {
+            writer.write_line(line).unwrap();
+        }
+        assert_eq!(writer.lines_written(), 1000);
+        let paths = writer.finish().unwrap();
+        assert!(paths.len() > 1);
+        assert_eq!(paths[1], dir.join("part-00001.json.gz"));
+
+        let mut read_back = Vec::new();
+        for path in &paths {
+            for line in LineReader::open(path).unwrap() {
+                read_back.push(line.unwrap());
+            }
+        }
+        assert_eq!(read_back, lines);
+
+        let mut writer = ShardWriter::new(&dir, "shard", "json.zst")
+            .unwrap()
+            .starting_at(3);
+        writer.write_line("{\"text\": \"a\"}").unwrap();
+        writer.write_line("{\"text\": \"b\"}\n").unwrap();
+        let paths = writer.finish().unwrap();
+        assert_eq!(paths, vec![dir.join("shard-00003.json.zst")]);
+        let data = zstd::decode_all(std::fs::File::open(&paths[0]).unwrap()).unwrap();
+        assert_eq!(data, b"{\"text\": \"a\"}\n{\"text\": \"b\"}\n");
+
+        let paths = ShardWriter::new(&dir, "empty", "json.gz")
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(LineReader::open(&paths[0]).unwrap().count(), 0);
+
+        assert!(ShardWriter::new(&dir, "part", "json").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A reader that fails with a stale file handle after every `fail_every` bytes.
+    struct FlakyReader {
+        data: Cursor<Vec<u8>>,
+        fail_every: u64,
+        since_failure: u64,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.since_failure >= self.fail_every {
+                self.since_failure = 0;
+                return Err(io::ErrorKind::StaleNetworkFileHandle.into());
+            }
+            let len = std::cmp::min(buf.len() as u64, self.fail_every - self.since_failure);
+            let n = self.data.read(&mut buf[..len as usize])?;
+            self.since_failure += n as u64;
+            Ok(n)
+        }
+    }
+
+    impl io::Seek for FlakyReader {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            self.data.seek(pos)
+        }
+    }
+
+    fn reopening_reader(data: &[u8], max_reopens: usize) -> ReopeningReader<FlakyReader> {
+        let data = data.to_vec();
+        let open = move || FlakyReader {
+            data: Cursor::new(data.clone()),
+            fail_every: 4,
+            since_failure: 0,
+        };
+        ReopeningReader {
+            inner: open(),
+            reopen: Box::new(move || Ok(open())),
+            offset: 0,
+            expected_len: 10,
+            max_reopens,
+        }
+    }
+
+    #[test]
+    fn test_reopening_reader() {
+        let mut buf = Vec::new();
+        reopening_reader(b"0123456789", 1)
+            .read_to_end(&mut buf)
+            .unwrap();
+        assert_eq!(buf, b"0123456789");
+
+        let err = reopening_reader(b"0123456789", 0)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::StaleNetworkFileHandle);
+
+        // The data ends before the expected length.
+        let err = reopening_reader(b"012345", 1)
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn test_npy_writer() {
+        let path = std::env::temp_dir().join(format!("wimbd-{}.npy", std::process::id()));
+        let mut writer = NpyWriter::create(&path, NpyDtype::U16).unwrap();
+        writer.write_values(&[1, 2, 65535]).unwrap();
+        writer.write_values(&[]).unwrap();
+        assert!(writer.write_values(&[65536]).is_err());
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+        assert_eq!(bytes.len(), NPY_HEADER_LEN + 6);
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header = std::str::from_utf8(&bytes[10..NPY_HEADER_LEN]).unwrap();
+        assert!(header.starts_with("{'descr': '<u2', 'fortran_order': False, 'shape': (3,), }"));
+        assert!(header.ends_with(" \n"));
+        assert_eq!(&bytes[NPY_HEADER_LEN..], &[1, 0, 2, 0, 255, 255]);
+    }
+
+    #[test]
+    fn test_npy_reader() {
+        let path = std::env::temp_dir().join(format!("wimbd-reader-{}.npy", std::process::id()));
+        let values: Vec<u32> = (0..1000).map(|i| i * 100_000).collect();
+        let mut writer = NpyWriter::create(&path, NpyDtype::U32).unwrap();
+        writer.write_values(&values).unwrap();
+        writer.finish().unwrap();
+
+        let mut reader = NpyReader::open(&path).unwrap();
+        assert_eq!(reader.dtype(), NpyDtype::U32);
+        assert_eq!(reader.len(), 1000);
+        assert_eq!(reader.read_values(10).unwrap(), &values[..10]);
+        reader.seek(990).unwrap();
+        assert_eq!(reader.read_values(10).unwrap(), &values[990..]);
+        assert!(reader.read_values(1).is_err());
+        reader.seek(5).unwrap();
+        assert_eq!(reader.read_values(1).unwrap(), &[500_000]);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_parse_npy_header() {
+        assert_eq!(
+            parse_npy_header("{'descr': '<u2', 'fortran_order': False, 'shape': (42,), }  \n")
+                .unwrap(),
+            (NpyDtype::U16, 42)
+        );
+        assert_eq!(
+            parse_npy_header("{'shape': (7,),'fortran_order': False,'descr': '<u4'}").unwrap(),
+            (NpyDtype::U32, 7)
+        );
+        assert!(
+            parse_npy_header("{'descr': '<f4', 'fortran_order': False, 'shape': (1,), }").is_err()
+        );
+        assert!(
+            parse_npy_header("{'descr': '<u2', 'fortran_order': False, 'shape': (2, 3), }")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_npy_dtype() {
+        assert_eq!(NpyDtype::for_vocab_size(50_280), NpyDtype::U16);
+        assert_eq!(NpyDtype::for_vocab_size(65_536), NpyDtype::U16);
+        assert_eq!(NpyDtype::for_vocab_size(100_277), NpyDtype::U32);
+        assert_eq!("u32".parse::<NpyDtype>().unwrap(), NpyDtype::U32);
+        assert!("f32".parse::<NpyDtype>().is_err());
+    }
+
+    #[test]
+    fn test_expand_braces() {
+        assert_eq!(
+            expand_braces("c4-train.{00000..00002}-of-01024.json.gz").unwrap(),
+            vec![
+                "c4-train.00000-of-01024.json.gz",
+                "c4-train.00001-of-01024.json.gz",
+                "c4-train.00002-of-01024.json.gz",
+            ]
+        );
+        assert_eq!(
+            expand_braces("{train,val}/{9..11}.json.gz").unwrap(),
+            vec![
+                "train/9.json.gz",
+                "train/10.json.gz",
+                "train/11.json.gz",
+                "val/9.json.gz",
+                "val/10.json.gz",
+                "val/11.json.gz",
+            ]
+        );
+        assert_eq!(expand_braces("{3..1}").unwrap(), vec!["3", "2", "1"]);
+        assert_eq!(
+            expand_braces("s3://bucket/{a}/{x..y}/{1..2").unwrap(),
+            vec!["s3://bucket/{a}/{x..y}/{1..2"]
+        );
+        assert!(expand_braces("{0..99999999999}").is_err());
+    }
+
+    #[test]
+    fn test_spill_dir() {
+        let dir = SpillDir::new(&std::env::temp_dir()).unwrap();
+        let path = dir.path().to_path_buf();
+        let other = SpillDir::new(&std::env::temp_dir()).unwrap();
+        assert_ne!(other.path(), path);
+
+        let mut writer = dir.create(3).unwrap();
+        writer.write_all(b"spilled\n").unwrap();
+        writer.finish().unwrap();
+        let lines: Vec<String> = LineReader::open(dir.file(3))
+            .unwrap()
+            .collect::<io::Result<_>>()
+            .unwrap();
+        assert_eq!(lines, vec!["spilled\n"]);
+
+        drop(dir);
+        assert!(!path.exists());
     }
 }
@@ -1,5 +1,17 @@
 //! A companion toolkit for the [What's in my big data? (WIMBD)](https://github.com/allenai/wimbd) project.
 
+pub mod bloom;
+pub mod chunking;
+pub mod code;
+pub mod encoding;
+pub mod error;
+pub mod index;
 pub mod io;
+pub mod lookup;
+pub mod markup;
 pub mod ngrams;
+pub mod pipeline;
+pub mod preprocess;
+pub mod progress;
+pub mod simhash;
 pub mod tokens;
@@ -0,0 +1,111 @@
+//! Logging to the terminal and, optionally, to a file as JSON lines.
+
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::Result;
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+use simple_logger::SimpleLogger;
+
+/// The level for terminal output.
+const TERMINAL_LEVEL: LevelFilter = LevelFilter::Info;
+
+static NEXT_THREAD_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// A short, stable id for the current thread, which is easier to follow than thread names
+    /// since all workers have the same name.
+    static THREAD_ID: usize = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+
+    /// The file the current thread is processing, if any.
+    static CURRENT_PATH: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Set the file the current thread is processing, which is included in log file records.
+pub(crate) fn set_current_path(path: Option<&Path>) {
+    CURRENT_PATH.with(|current| *current.borrow_mut() = path.map(|path| path.into()));
+}
+
+struct LogFile {
+    file: Mutex<File>,
+    level: LevelFilter,
+}
+
+struct Logger {
+    terminal: SimpleLogger,
+    file: Option<LogFile>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.terminal.enabled(metadata)
+            || self
+                .file
+                .as_ref()
+                .is_some_and(|file| metadata.level() <= file.level)
+    }
+
+    fn log(&self, record: &Record) {
+        self.terminal.log(record);
+
+        if let Some(log_file) = &self.file {
+            if record.level() > log_file.level {
+                return;
+            }
+            let thread = std::thread::current();
+            let line = json!({
+                "timestamp": humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "thread": thread.name(),
+                "thread_id": THREAD_ID.with(|id| *id),
+                "path": CURRENT_PATH.with(|path| path.borrow().clone()),
+                "message": record.args().to_string(),
+            });
+            // Each record is written with a single call so records from different threads
+            // don't interleave, and without buffering so nothing is lost if the process dies.
+            if let Ok(mut file) = log_file.file.lock() {
+                file.write_all(format!("{line}\n").as_bytes()).ok();
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.terminal.flush();
+        if let Some(log_file) = &self.file {
+            if let Ok(mut file) = log_file.file.lock() {
+                file.flush().ok();
+            }
+        }
+    }
+}
+
+/// Initialize the global logger. Records at the info level and above are always written to
+/// the terminal. If a log file is given, records at `level` and above are also written to it
+/// as JSON lines.
+pub(crate) fn init(log_file: Option<&Path>, level: LevelFilter) -> Result<()> {
+    let file = match log_file {
+        Some(path) => Some(LogFile {
+            file: Mutex::new(File::options().create(true).append(true).open(path)?),
+            level,
+        }),
+        None => None,
+    };
+    let max_level = match &file {
+        Some(file) => std::cmp::max(TERMINAL_LEVEL, file.level),
+        None => TERMINAL_LEVEL,
+    };
+    let logger = Logger {
+        terminal: SimpleLogger::new().with_level(TERMINAL_LEVEL),
+        file,
+    };
+    log::set_boxed_logger(Box::new(logger))?;
+    log::set_max_level(max_level);
+    Ok(())
+}
@@ -0,0 +1,461 @@
+//! Random access to the documents of a file by line number, without decompressing the whole
+//! file.
+//!
+//! This is fastest for zstd files in the [seekable format], which are made of independently
+//! compressed frames with a seek table at the end, together with a line index. The line index
+//! maps every `interval`-th line to its offset in the decompressed data, so reading a document
+//! only decompresses the frame it's in. Other files, as well as seekable files without a line
+//! index, are decompressed from the start up to the document.
+//!
+//! # Line index format
+//!
+//! A line index is written next to the file it indexes, with ".lines" added to its name. All
+//! integers are little-endian.
+//!
+//! | Field           | Type         | Description                                          |
+//! |-----------------|--------------|------------------------------------------------------|
+//! | magic           | 8 bytes      | `b"WIMBDLIX"`                                        |
+//! | version         | u32          | Currently 1                                          |
+//! | interval        | u32          | The number of lines between entries                  |
+//! | file_len        | u64          | The size of the indexed file, to detect stale indexes |
+//! | num_lines       | u64          | The number of lines in the file                      |
+//! | num_entries     | u64          | The number of entries                                |
+//! | offsets         | u64 * n      | The decompressed offset of line `1 + i * interval`   |
+//!
+//! [seekable format]: https://github.com/facebook/zstd/blob/dev/contrib/seekable_format/zstd_seekable_compression_format.md
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use flate2::read::MultiGzDecoder;
+
+use crate::error::{Result, WimbdError};
+use crate::io::Format;
+
+const MAGIC: &[u8; 8] = b"WIMBDLIX";
+const VERSION: u32 = 1;
+
+/// The default number of lines between the entries of a line index. Reading a document
+/// decompresses up to this many lines that come before it.
+pub const DEFAULT_INTERVAL: u32 = 1000;
+
+/// The magic number of zstd skippable frames, which can have any value in the lowest 4 bits.
+const SKIPPABLE_MAGIC: u32 = 0x184D2A50;
+
+/// The magic number at the very end of a file in the seekable format.
+const SEEKABLE_MAGIC: u32 = 0x8F92EAB1;
+
+/// The size of the footer of a seek table.
+const SEEK_TABLE_FOOTER_LEN: u64 = 9;
+
+/// The frames of a zstd file in the seekable format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeekTable {
+    /// The compressed and decompressed offset of the start of every frame.
+    frames: Vec<(u64, u64)>,
+    decompressed_len: u64,
+}
+
+impl SeekTable {
+    /// Read the seek table from the end of a file, or return `None` if the file isn't in the
+    /// seekable format.
+    pub fn read<R: Read + Seek>(file: &mut R) -> Result<Option<Self>> {
+        let len = file.seek(SeekFrom::End(0))?;
+        if len < SEEK_TABLE_FOOTER_LEN + 8 {
+            return Ok(None);
+        }
+        file.seek(SeekFrom::End(-(SEEK_TABLE_FOOTER_LEN as i64)))?;
+        let mut footer = [0u8; SEEK_TABLE_FOOTER_LEN as usize];
+        file.read_exact(&mut footer)?;
+        if read_u32(&footer[5..]) != SEEKABLE_MAGIC {
+            return Ok(None);
+        }
+        let num_frames = read_u32(&footer[..4]) as u64;
+        let descriptor = footer[4];
+        if descriptor & 0x7c != 0 {
+            return Err(WimbdError::Parse(
+                "seek table descriptor has reserved bits set".into(),
+            ));
+        }
+        // Every entry has the compressed and decompressed size, and optionally a checksum.
+        let entry_len = if descriptor & 0x80 != 0 { 12 } else { 8 };
+        let table_len = num_frames * entry_len + SEEK_TABLE_FOOTER_LEN;
+        if table_len + 8 > len {
+            return Err(WimbdError::Parse(
+                "seek table is larger than the file".into(),
+            ));
+        }
+
+        let table_start = len - table_len - 8;
+        file.seek(SeekFrom::Start(table_start))?;
+        let mut header = [0u8; 8];
+        file.read_exact(&mut header)?;
+        if read_u32(&header[..4]) & 0xFFFF_FFF0 != SKIPPABLE_MAGIC
+            || read_u32(&header[4..]) as u64 != table_len
+        {
+            return Err(WimbdError::Parse(
+                "seek table isn't in a skippable frame".into(),
+            ));
+        }
+        let mut entries = vec![0u8; (num_frames * entry_len) as usize];
+        file.read_exact(&mut entries)?;
+
+        let mut frames = Vec::with_capacity(num_frames as usize);
+        let (mut compressed, mut decompressed) = (0, 0);
+        for entry in entries.chunks_exact(entry_len as usize) {
+            frames.push((compressed, decompressed));
+            compressed += read_u32(&entry[..4]) as u64;
+            decompressed += read_u32(&entry[4..8]) as u64;
+        }
+        if compressed != table_start {
+            return Err(WimbdError::Parse(format!(
+                "seek table frames add up to {} bytes, but there are {}",
+                compressed, table_start
+            )));
+        }
+
+        Ok(Some(Self {
+            frames,
+            decompressed_len: decompressed,
+        }))
+    }
+
+    /// The number of frames.
+    pub fn num_frames(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// The compressed and decompressed offset of the frame that contains the given
+    /// decompressed offset, or `None` if it's past the end.
+    pub fn frame_at(&self, offset: u64) -> Option<(u64, u64)> {
+        if offset >= self.decompressed_len {
+            return None;
+        }
+        let i = self.frames.partition_point(|&(_, start)| start <= offset);
+        Some(self.frames[i - 1])
+    }
+}
+
+/// The offsets of every `interval`-th line of a file, see the module docs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineIndex {
+    interval: u32,
+    file_len: u64,
+    num_lines: u64,
+    offsets: Vec<u64>,
+}
+
+impl LineIndex {
+    /// The path of the line index of a file.
+    pub fn path_for(path: impl AsRef<Path>) -> PathBuf {
+        let mut index_path = OsString::from(path.as_ref().as_os_str());
+        index_path.push(".lines");
+        index_path.into()
+    }
+
+    /// Index a file by decompressing it once.
+    pub fn build(path: impl AsRef<Path>, interval: u32) -> Result<Self> {
+        let path = path.as_ref();
+        if interval == 0 {
+            return Err(WimbdError::InvalidInput(
+                "line index interval must be greater than 0".into(),
+            ));
+        }
+        let file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let mut reader = BufReader::new(decompress(file, path)?);
+
+        let mut offsets = Vec::new();
+        let (mut num_lines, mut offset) = (0, 0);
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            let n = reader
+                .read_until(b'\n', &mut line)
+                .map_err(|err| WimbdError::from_read(path, err))?;
+            if n == 0 {
+                break;
+            }
+            if num_lines % interval as u64 == 0 {
+                offsets.push(offset);
+            }
+            num_lines += 1;
+            offset += n as u64;
+        }
+
+        Ok(Self {
+            interval,
+            file_len,
+            num_lines,
+            offsets,
+        })
+    }
+
+    /// The number of lines in the indexed file.
+    pub fn num_lines(&self) -> u64 {
+        self.num_lines
+    }
+
+    /// The closest line at or before the given (1-based) line that has an entry, and its
+    /// offset.
+    fn checkpoint(&self, line: u64) -> (u64, u64) {
+        let i = ((line - 1) / self.interval as u64) as usize;
+        match self.offsets.get(i) {
+            Some(&offset) => (i as u64 * self.interval as u64 + 1, offset),
+            None => (1, 0),
+        }
+    }
+
+    /// Write the index in the format described in the module docs.
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&self.interval.to_le_bytes())?;
+        writer.write_all(&self.file_len.to_le_bytes())?;
+        writer.write_all(&self.num_lines.to_le_bytes())?;
+        writer.write_all(&(self.offsets.len() as u64).to_le_bytes())?;
+        for offset in &self.offsets {
+            writer.write_all(&offset.to_le_bytes())?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Read an index written with [`LineIndex::write()`].
+    pub fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0u8; 40];
+        reader.read_exact(&mut header)?;
+        if &header[..8] != MAGIC {
+            return Err(WimbdError::Parse(format!(
+                "{:?} is not a wimbd line index",
+                path
+            )));
+        }
+        let version = read_u32(&header[8..12]);
+        if version != VERSION {
+            return Err(WimbdError::Parse(format!(
+                "Unsupported line index version {}",
+                version
+            )));
+        }
+        let interval = read_u32(&header[12..16]);
+        let file_len = read_u64(&header[16..24]);
+        let num_lines = read_u64(&header[24..32]);
+        let num_entries = read_u64(&header[32..40]);
+        if interval == 0 || num_entries != num_lines.div_ceil(interval as u64) {
+            return Err(WimbdError::Parse(format!(
+                "{:?} has an invalid number of entries",
+                path
+            )));
+        }
+        let mut offsets = Vec::with_capacity(num_entries as usize);
+        let mut bytes = [0u8; 8];
+        for _ in 0..num_entries {
+            reader.read_exact(&mut bytes)?;
+            offsets.push(u64::from_le_bytes(bytes));
+        }
+        Ok(Self {
+            interval,
+            file_len,
+            num_lines,
+            offsets,
+        })
+    }
+}
+
+/// Reads single documents of a file by line number, using its seek table and line index if
+/// it has them.
+pub struct DocumentReader {
+    path: PathBuf,
+    seek_table: Option<SeekTable>,
+    index: Option<LineIndex>,
+}
+
+impl DocumentReader {
+    /// Open a file along with its line index, if there is one. An index that's out of date
+    /// because the file changed since it was built is ignored.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let file_len = file.metadata()?.len();
+        let seek_table = if Format::detect(path)? == Format::Zstd {
+            SeekTable::read(&mut file)?
+        } else {
+            None
+        };
+
+        let index_path = LineIndex::path_for(path);
+        let index = if index_path.is_file() {
+            let index = LineIndex::read(&index_path)?;
+            if index.file_len == file_len {
+                Some(index)
+            } else {
+                log::warn!("Ignoring line index {:?}, which is out of date", index_path);
+                None
+            }
+        } else {
+            None
+        };
+
+        Ok(Self {
+            path: path.into(),
+            seek_table,
+            index,
+        })
+    }
+
+    /// Whether documents can be read without decompressing the file up to them, i.e. whether
+    /// the file is in the seekable format and has a line index.
+    pub fn is_seekable(&self) -> bool {
+        self.seek_table.is_some() && self.index.is_some()
+    }
+
+    /// Read the line with the given (1-based) number, without the trailing newline. Returns
+    /// `None` if the file has fewer lines.
+    pub fn read_line(&self, line: u64) -> Result<Option<String>> {
+        if line == 0 {
+            return Err(WimbdError::InvalidInput("line numbers start at 1".into()));
+        }
+        let (mut current, offset) = match &self.index {
+            Some(index) if line > index.num_lines => return Ok(None),
+            Some(index) => index.checkpoint(line),
+            None => (1, 0),
+        };
+
+        let mut file = File::open(&self.path)?;
+        let skip = match self.seek_table.as_ref().filter(|_| offset > 0) {
+            Some(seek_table) => match seek_table.frame_at(offset) {
+                Some((compressed, decompressed)) => {
+                    file.seek(SeekFrom::Start(compressed))?;
+                    offset - decompressed
+                }
+                None => return Ok(None),
+            },
+            None => offset,
+        };
+        let mut reader = BufReader::new(decompress(file, &self.path)?);
+        let skipped = io::copy(&mut (&mut reader).take(skip), &mut io::sink())
+            .map_err(|err| WimbdError::from_read(&self.path, err))?;
+        if skipped < skip {
+            return Ok(None);
+        }
+
+        let mut buf = Vec::new();
+        loop {
+            buf.clear();
+            let n = reader
+                .read_until(b'\n', &mut buf)
+                .map_err(|err| WimbdError::from_read(&self.path, err))?;
+            if n == 0 {
+                return Ok(None);
+            }
+            if current == line {
+                break;
+            }
+            current += 1;
+        }
+        if buf.ends_with(b"\n") {
+            buf.pop();
+        }
+        String::from_utf8(buf).map(Some).map_err(|_| {
+            WimbdError::Parse(format!(
+                "line {} of {:?} isn't valid UTF-8",
+                line, self.path
+            ))
+        })
+    }
+}
+
+/// Decompress a file from its current position, according to the format of the file.
+fn decompress(file: File, path: &Path) -> Result<Box<dyn Read>> {
+    Ok(match Format::detect(path)? {
+        Format::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(file))),
+        Format::Zstd => Box::new(zstd::Decoder::new(file)?),
+        Format::Plain => Box::new(file),
+    })
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write lines as a seekable zstd file with a frame for every `lines_per_frame` lines.
+    fn write_seekable(path: &Path, lines: &[String], lines_per_frame: usize) {
+        let mut data = Vec::new();
+        let mut entries = Vec::new();
+        for chunk in lines.chunks(lines_per_frame) {
+            let frame = zstd::encode_all(chunk.concat().as_bytes(), 0).unwrap();
+            entries.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+            entries.extend_from_slice(&(chunk.concat().len() as u32).to_le_bytes());
+            data.extend_from_slice(&frame);
+        }
+        let num_frames = lines.len().div_ceil(lines_per_frame);
+        data.extend_from_slice(&0x184D2A5Eu32.to_le_bytes());
+        data.extend_from_slice(&(entries.len() as u32 + 9).to_le_bytes());
+        data.extend_from_slice(&entries);
+        data.extend_from_slice(&(num_frames as u32).to_le_bytes());
+        data.push(0);
+        data.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        std::fs::write(path, data).unwrap();
+    }
+
+    #[test]
+    fn test_seekable_lookup() {
+        let path = std::env::temp_dir().join(format!("wimbd-lookup-{}.zst", std::process::id()));
+        let lines: Vec<String> = (1..=95).map(|i| format!("{{\"line\":{i}}}\n")).collect();
+        write_seekable(&path, &lines, 10);
+
+        let seek_table = SeekTable::read(&mut File::open(&path).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(seek_table.num_frames(), 10);
+        assert_eq!(seek_table.frame_at(0).map(|frame| frame.1), Some(0));
+
+        // Without an index the file is decompressed from the start.
+        let reader = DocumentReader::open(&path).unwrap();
+        assert!(!reader.is_seekable());
+        assert_eq!(
+            reader.read_line(42).unwrap().as_deref(),
+            Some("{\"line\":42}")
+        );
+
+        let index = LineIndex::build(&path, 7).unwrap();
+        assert_eq!(index.num_lines(), 95);
+        let index_path = LineIndex::path_for(&path);
+        index.write(&index_path).unwrap();
+        assert_eq!(LineIndex::read(&index_path).unwrap(), index);
+
+        let reader = DocumentReader::open(&path).unwrap();
+        assert!(reader.is_seekable());
+        for line in [1, 7, 8, 42, 95] {
+            assert_eq!(
+                reader.read_line(line).unwrap(),
+                Some(format!("{{\"line\":{line}}}"))
+            );
+        }
+        assert_eq!(reader.read_line(96).unwrap(), None);
+        assert!(reader.read_line(0).is_err());
+
+        std::fs::remove_file(&index_path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_not_seekable() {
+        let mut data = io::Cursor::new(zstd::encode_all(&b"a\nb\n"[..], 0).unwrap());
+        assert_eq!(SeekTable::read(&mut data).unwrap(), None);
+    }
+}
@@ -1,11 +1,28 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
 use anyhow::Result;
 use structopt::StructOpt;
 
+pub mod bloom;
+pub mod chunking;
 mod cmd;
+pub mod code;
+pub mod encoding;
+pub mod error;
+pub mod index;
 pub mod io;
+mod logging;
+pub mod lookup;
+pub mod markup;
 pub mod ngrams;
+pub mod preprocess;
 pub mod progress;
+mod provenance;
+pub mod simhash;
+mod table;
 pub mod tokens;
+pub mod tui;
 pub mod util;
 
 #[derive(Debug, StructOpt)]
@@ -16,6 +33,124 @@ pub mod util;
     setting = structopt::clap::AppSettings::ColoredHelp,
 )]
 struct Opt {
+    /// Show a live dashboard with worker status, throughput, and errors instead of progress
+    /// bars. This is much easier to follow than progress bars when using many workers.
+    #[structopt(long = "tui", global = true)]
+    tui: bool,
+
+    /// The number of times to retry processing a file after an error, e.g. due to a flaky
+    /// network file system. Defaults to 0 for most commands. Files that fail while reading
+    /// continue from where they stopped. Commands that count across files, like count, topk and
+    /// coverage, don't retry a file that would have to start over once some of its lines were
+    /// counted.
+    #[structopt(long = "retries", global = true)]
+    retries: Option<usize>,
+
+    /// The delay before the first retry of a file, e.g. "500ms". The delay doubles with
+    /// every retry.
+    #[structopt(long = "retry-backoff", global = true, default_value = "1s", parse(try_from_str = humantime::parse_duration))]
+    retry_backoff: Duration,
+
+    /// The max delay between retries, e.g. "5m".
+    #[structopt(long = "retry-max-delay", global = true, default_value = "1m", parse(try_from_str = humantime::parse_duration))]
+    retry_max_delay: Duration,
+
+    /// The number of times to reopen a file and continue from the last good offset when a read
+    /// fails with a transient error, like a stale file handle or a short read on NFS or Lustre.
+    /// Unlike '--retries', this doesn't start the file over.
+    #[structopt(long = "read-retries", global = true, default_value = "0")]
+    read_retries: usize,
+
+    /// Skip lines that are malformed JSON or invalid UTF-8 instead of failing the whole file.
+    /// Skipped lines are recorded in the '--error-report' file, "wimbd-errors.jsonl" by default.
+    #[structopt(long = "skip-errors", global = true)]
+    skip_errors: bool,
+
+    /// What to do with lines that aren't valid UTF-8, which are common in old crawls: "skip"
+    /// them, "replace" the invalid bytes with U+FFFD and process them as usual, or fail the
+    /// file with "error". The number of affected lines is logged at the end. By default such
+    /// lines fail the file unless '--skip-errors' is set.
+    #[structopt(long = "invalid-utf8", global = true, possible_values = &["skip", "replace", "error"])]
+    invalid_utf8: Option<io::InvalidUtf8>,
+
+    /// Skip documents whose raw JSON line is longer than this, e.g. "10MB", before parsing
+    /// or tokenizing them. This protects runs from corrupt files with huge lines. The number
+    /// of skipped documents is logged at the end.
+    #[structopt(long = "max-doc-bytes", global = true, parse(try_from_str = parse_size::parse_size))]
+    max_doc_bytes: Option<u64>,
+
+    /// Normalize document text to this Unicode normalization form before tokenizing, "nfc" or
+    /// "nfkc". Like the other preprocessing options below and each command's '--strip-html'
+    /// and '--strip-markdown', this applies to every command that tokenizes or hashes text,
+    /// except for 'tag' and 'taxonomy', which report offsets into the original text.
+    #[structopt(long = "normalize", global = true)]
+    normalize: Option<preprocess::Normalization>,
+
+    /// Remove URLs, like "https://..." or "www...", from document text before tokenizing.
+    #[structopt(long = "remove-urls", global = true)]
+    remove_urls: bool,
+
+    /// Collapse runs of whitespace in document text into a single space, or a single newline
+    /// if they contain a line break, before tokenizing. This runs after all other
+    /// preprocessing steps.
+    #[structopt(long = "collapse-whitespace", global = true)]
+    collapse_whitespace: bool,
+
+    /// Give up on a file that takes longer than this to process, e.g. "30m", instead of letting
+    /// a pathological file hold up the whole run. The file is quarantined: it's listed at the
+    /// end and in the '--error-report' file, and the rest of the files are still processed.
+    /// The results may still include the lines of the file that were processed before it
+    /// timed out, since commands like 'count', 'topk', 'coverage', 'unique', and 'dupes' add
+    /// to shared counters while a file is read. Files are only checked between lines, so this
+    /// doesn't help with a single read that never returns.
+    #[structopt(long = "file-timeout", global = true, parse(try_from_str = humantime::parse_duration))]
+    file_timeout: Option<Duration>,
+
+    /// Process every file with this many threads: one reads and decompresses the file while
+    /// the others parse and process its lines. This helps when there are fewer files than
+    /// cores, like a single huge file. Every worker from '-j/--workers' gets this many threads,
+    /// so lower '-j' accordingly. Only 'topk' (without '--exact'), 'stats', 'count' (without
+    /// '--stream-results'), 'pipeline', and 'contamination' support this, and retries of a
+    /// file always start over.
+    #[structopt(long = "threads-per-file", global = true)]
+    threads_per_file: Option<usize>,
+
+    /// Write a report of every file that failed after all retries, every file that was
+    /// quarantined because of '--file-timeout', and every line that was skipped with
+    /// '--skip-errors' to this file, as JSON lines. Each line is a JSON object with the keys
+    /// "kind" ("file", "quarantined", or "line"), "path", and "error", plus "attempts" for
+    /// failed files, "lines" for the number of lines processed of quarantined files, and
+    /// "line" for lines. The report is only written if anything failed or was
+    /// skipped, even if the command fails.
+    #[structopt(long = "error-report", global = true, parse(from_os_str))]
+    error_report: Option<PathBuf>,
+
+    /// A directory for the temporary files of stages that spill to disk instead of running out
+    /// of memory, like exact counting and shuffling. Spill files are compressed and removed
+    /// when the stage is done or fails. Defaults to the system's temporary directory, except
+    /// for 'shuffle', which defaults to the output directory.
+    #[structopt(long = "tmp-dir", global = true, parse(from_os_str))]
+    tmp_dir: Option<PathBuf>,
+
+    /// Append results to output files that already exist instead of refusing to overwrite
+    /// them, e.g. to accumulate the results of several runs in one JSON lines file. Output is
+    /// always written to a temporary file first and only moved into place, or appended, once
+    /// the command succeeds. Compressed outputs get another gzip member or zstd frame.
+    /// Per-file outputs like those of 'select' and 'join' are appended to as well, but
+    /// sharded outputs and Parquet files can't be appended to.
+    #[structopt(long = "append", global = true)]
+    append: bool,
+
+    /// Also write log records to this file as JSON lines, with timestamps, thread ids, and the
+    /// file being processed. Records are appended if the file already exists.
+    #[structopt(long = "log-file", global = true, parse(from_os_str))]
+    log_file: Option<PathBuf>,
+
+    /// The minimum level of log records to write to '--log-file', e.g. "debug".
+    /// This doesn't affect terminal output.
+    #[structopt(long = "log-level", global = true, default_value = "info")]
+    log_level: log::LevelFilter,
+
     #[structopt(subcommand)]
     cmd: WimbdCmd,
 }
@@ -37,6 +172,12 @@ enum WimbdCmd {
     ///
     /// > wimbd topk c4-train.01011-of-01024.json.gz --ngram=3 --topk=20 --seed=42 --size=50GiB
     ///
+    /// Count over all of C4 with a checkpoint every 128 files, and continue after a crash:
+    ///
+    /// > wimbd topk c4/*.json.gz --size=50GiB --checkpoint-dir=ckpt --checkpoint-files=128
+    ///
+    /// > wimbd topk c4/*.json.gz --size=50GiB --checkpoint-dir=ckpt --checkpoint-files=128 --resume
+    ///
     /// ACCURACY
     ///
     /// In general you should set '--size' to however many free gigabytes of RAM you have available, minus some buffer room.
@@ -51,9 +192,16 @@ enum WimbdCmd {
     Botk(cmd::botk::Opt),
 
     /// Get exact counts for given search strings. Note that the search strings will be tokenized
-    /// and the search will be done over tokens instead of searching for those substrings directly.
+    /// and the search will be done over tokens instead of searching for those substrings directly,
+    /// unless '--raw' is given.
     ///
     /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// Print the counts of every file as it's done, for partial results on a long run:
+    ///
+    /// > wimbd count data/*.json.gz -s "hello world" --stream-results -o counts.tsv
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
     Count(cmd::count::Opt),
 
@@ -68,11 +216,499 @@ enum WimbdCmd {
     /// Work is parallelized over files.
     #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
     Unique(cmd::unique::Opt),
+
+    /// Compute per-document digests (xxh3, sha1) and token counts.
+    ///
+    /// The output can be used for exact deduplication across datasets, diffing releases, and
+    /// joining other analyses back to individual documents.
+    ///
+    /// Work is parallelized over files.
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Hash(cmd::hash::Opt),
+
+    /// Measure how much of each evaluation example's ngrams appear in a corpus.
+    ///
+    /// The corpus ngrams are first collected into a Bloom filter, then for every example in
+    /// the eval set the fraction of its ngrams found in the corpus is computed. This is the
+    /// benchmark contamination metric from the WIMBD paper.
+    ///
+    /// With '--exact', it instead finds the corpus documents that contain whole eval examples
+    /// verbatim, a cheap check with high precision.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd coverage c4-train.*.json.gz --eval piqa.jsonl --eval-field goal -n 13 --size 32GiB
+    ///
+    /// Find the documents that contain a whole example, prompt and answer:
+    ///
+    /// > wimbd coverage c4-train.*.json.gz --eval piqa.jsonl --eval-field goal --eval-field sol1 --exact -o contaminated.jsonl
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Coverage(cmd::coverage::Opt),
+
+    /// Render a data card from previously produced outputs.
+    ///
+    /// The report is a single Markdown or HTML document with tables and plots summarizing the
+    /// outputs of other commands, like 'stats' and 'topk'.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd report --name C4 --stats stats.json --topk top-3grams.jsonl -o c4-card.html
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Report(cmd::report::Opt),
+
+    /// Draw a uniform random sample of documents, optionally stratified by a field.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// Sample 100 documents for each language:
+    ///
+    /// > wimbd sample data/*.json.gz --stratify-by lang --per-stratum 100 --seed 42 -o sample.jsonl
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Sample(cmd::sample::Opt),
+
+    /// Globally shuffle all documents into a new set of shards.
+    ///
+    /// This is a two-pass, external-memory shuffle: documents are first scattered into random
+    /// buckets on disk, then each bucket is shuffled in memory and written out as a shard.
+    ///
+    /// The first pass is parallelized over files.
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Shuffle(cmd::shuffle::Opt),
+
+    /// Concatenate many small files into a smaller number of larger shards.
+    ///
+    /// Shards can be sized by count ('-n') or by target size ('--shard-size').
+    ///
+    /// Work is parallelized over output shards.
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Repack(cmd::repack::Opt),
+
+    /// Estimate how much of a dataset is source code vs natural language.
+    ///
+    /// Documents are classified with simple heuristics (line endings, indentation, keyword and
+    /// symbol density) and the results are broken down per file or per metadata field.
+    ///
+    /// Work is parallelized over files.
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Composition(cmd::composition::Opt),
+
+    /// Find long spans of text that are duplicated many times, like license blocks and
+    /// boilerplate.
+    ///
+    /// The first pass counts base ngrams with a counting Bloom filter. The second pass grows
+    /// each frequent base ngram for as long as the following base ngrams are also frequent,
+    /// and counts how often each of these maximal spans occurs.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd spans data/*.json.gz -n 13 --threshold 100 --min-length 200 --size 32GiB
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Spans(cmd::spans::Opt),
+
+    /// Measure the throughput of each processing stage on a sample file.
+    ///
+    /// Decompression, JSON parsing, tokenization, and ngram counting are timed separately
+    /// with different numbers of workers, which helps with choosing '--workers' and a
+    /// tokenizer for other commands.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd bench c4-train.01011-of-01024.json.gz -j 1,8,32 -t gpt2 --limit 100000
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Bench(cmd::bench::Opt),
+
+    /// Serve an index saved with 'wimbd topk --save-index' over HTTP.
+    ///
+    /// All endpoints take the query text in the 'q' parameter and respond with JSON:
+    ///
+    /// /count?q=...     the count of a single ngram
+    ///
+    /// /phrase?q=...    an upper bound on the count of a longer phrase, i.e. the min count over its ngrams
+    ///
+    /// /contains?q=...  how many of the text's ngrams occur in the index at all
+    ///
+    /// /info            the index metadata
+    ///
+    /// Like with 'topk', counts are upper bounds.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd topk data/*.json.gz -n 3 --size 32GiB --save-index idx/
+    ///
+    /// > wimbd serve --index idx/ --addr :8080
+    ///
+    /// > curl 'localhost:8080/count?q=the+quick+fox'
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Serve(cmd::serve::Opt),
+
+    /// Compute an exact frequency table of all ngrams in a dataset.
+    ///
+    /// This is an external sort: workers write partial counts to sorted, compressed runs in the
+    /// '--tmp-dir' directory, which are then merged into exact totals. It needs disk space
+    /// on the order of the number of distinct ngrams, but little memory.
+    ///
+    /// It also reports how concentrated the distribution is, to compare the diversity of
+    /// datasets: the Gini coefficient of the counts, the Shannon entropy, and the fraction of
+    /// all ngrams covered by the 100, 1,000, and 10,000 most frequent ones.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd freq data/*.json.gz -n 2 --min-count 10 -o bigrams.jsonl.gz
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Freq(cmd::freq::Opt),
+
+    /// Find the documents with the most exact duplicates in a dataset.
+    ///
+    /// Candidates are found by counting document hashes with a counting Bloom filter, like
+    /// 'topk' does for ngrams. A second pass then gets exact counts, text snippets, and example
+    /// pointers for the candidates.
+    ///
+    /// With '--simhash', near-duplicates are found instead by clustering SimHash fingerprints
+    /// of the documents. With '--chunks', the fraction of duplicated bytes is estimated instead
+    /// by counting content-defined chunks of the documents, which also catches partly
+    /// duplicated documents.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd dupes data/*.json.gz -k 50 --examples 3 --size 16GiB
+    ///
+    /// > wimbd dupes data/*.json.gz --simhash --max-distance 3
+    ///
+    /// > wimbd dupes data/*.json.gz --chunks --chunk-size 256
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Dupes(cmd::dupes::Opt),
+
+    /// Measure how much of a dataset is contained in a reference corpus, using a prebuilt
+    /// sketch of the reference corpus.
+    ///
+    /// Reports the fraction of the dataset's ngrams found in the sketch, and the fraction of
+    /// documents and paragraphs whose ngrams are mostly found. Since the sketch only needs to be
+    /// built once, many datasets can be compared against the same reference corpus cheaply.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd topk data-a/*.json.gz -n 13 --size 32GiB --save-index corpus-a/
+    ///
+    /// > wimbd contains --sketch corpus-a/ data-b/*.json.gz
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Contains(cmd::contains::Opt),
+
+    /// Build and work with plain membership Bloom filters.
+    ///
+    /// Filters are saved with their parameters in a simple binary format that's documented in
+    /// the 'wimbd::bloom' module and can be read from other languages, e.g. to share dedup or
+    /// decontamination filters. They can also be used as a sketch for 'wimbd contains'.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd bloom build data/*.json.gz --unit paragraph --size 8GiB -o corpus.bloom
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Bloom(cmd::bloom::Opt),
+
+    /// Tag the character spans in each document where any ngram from a list occurs.
+    ///
+    /// This is meant for masking, e.g. eval-set ngrams during training, instead of dropping
+    /// whole documents. Documents are tokenized the same way as the ngrams and every occurrence
+    /// of a listed ngram is mapped back to character offsets in the original text.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd tag data/*.json.gz --ngrams eval-13grams.jsonl -o spans.jsonl.gz
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Tag(cmd::tag::Opt),
+
+    /// Watch a directory and keep stats up to date as new files appear in it.
+    ///
+    /// The directory is listed periodically and only files that weren't processed before are
+    /// read. The stats are folded into a state file after every round, so watching can be
+    /// stopped and resumed at any time. This is useful while a crawl or conversion pipeline is
+    /// still writing data.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd watch data/ --state data-stats.json --interval 5m
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Watch(cmd::watch::Opt),
+
+    /// Count the lines, documents, and compressed and uncompressed bytes of each file.
+    ///
+    /// Lines are counted without parsing them, so this is much faster than 'stats' for a
+    /// basic census of a dataset. Documents are lines that aren't blank.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd count-lines data/*.json.gz -o line-counts.jsonl
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    CountLines(cmd::count_lines::Opt),
+
+    /// Count the documents and occurrences of every category in a catalog of phrases.
+    ///
+    /// The catalog is a YAML file that maps category names, like "medical" or "legal
+    /// boilerplate", to lists of phrases. Documents and phrases are tokenized the same way and
+    /// every occurrence of a phrase counts towards its categories. The spans of the matches can
+    /// be written out as Dolma-style attributes.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// Tag documents and write the matches as attributes:
+    ///
+    /// > wimbd taxonomy data/*.json.gz --catalog categories.yaml -o attributes.jsonl.gz
+    ///
+    /// Count how many documents match each pair of categories:
+    ///
+    /// > wimbd taxonomy data/*.json.gz --catalog pii.yaml --cooccurrence --json
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Taxonomy(cmd::taxonomy::Opt),
+
+    /// Tokenize documents into shards of token IDs for training.
+    ///
+    /// Every input file is written to a ".npy" array of the token IDs of its documents, one
+    /// after another, which NumPy can load or memory-map directly. Next to it goes an index with
+    /// the position and length of every document in the array.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd tokenize data/*.json.gz -t EleutherAI/gpt-neox-20b --eos-id 0 -o tokens/
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Tokenize(cmd::tokenize::Opt),
+
+    /// Turn shards of token IDs back into JSON lines of text.
+    ///
+    /// This is the inverse of the 'tokenize' command, for auditing datasets that are only
+    /// available tokenized with the rest of the commands. Documents are found with the index
+    /// next to each shard or, without one, by splitting at an end-of-text token.
+    ///
+    /// Work is parallelized over shards.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd detokenize tokens/*.npy -t EleutherAI/gpt-neox-20b -o text/
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Detokenize(cmd::detokenize::Opt),
+
+    /// Compare the fertility of tokenizers on a sample of documents.
+    ///
+    /// For every tokenizer this reports the number of tokens per word (unicode words) and per
+    /// byte, and the fraction of tokens that are unknown tokens or byte-fallback tokens like
+    /// "<0x0A>", side by side. Every document is tokenized by every tokenizer, so use
+    /// '-l/--limit' and '--file-limit' to compare on a sample.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd compare-tokenizers data/*.json.gz -l 1000 -t unicode -t EleutherAI/gpt-neox-20b -t meta-llama/Llama-2-7b-hf
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    CompareTokenizers(cmd::compare_tokenizers::Opt),
+
+    /// Count a fixed list of ngrams exactly.
+    ///
+    /// The ngrams are read from a JSON lines file, like the output of 'wimbd topk' or
+    /// 'wimbd botk', and counted with an exact hash map instead of a Bloom filter. This
+    /// verifies approximate counts, which are reported next to the exact ones, or tracks
+    /// specific ngrams across versions of a dataset.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd recount data/*.json.gz --ngrams top-3grams.jsonl -o recounted.jsonl
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Recount(cmd::recount::Opt),
+
+    /// Split a dataset into one set of shards for every value of a field.
+    ///
+    /// Every document is written, as is, to a subdirectory of the output directory named after
+    /// its value of the field, like the language or the source. Documents for the same value
+    /// are not in any particular order.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd split-by data/*.json.gz --field metadata.lang -o by-lang/ --shard-size 1GiB
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    SplitBy(cmd::split_by::Opt),
+
+    /// Add the fields of a sidecar file to the documents with the same ID.
+    ///
+    /// The sidecar is a JSON lines or Parquet file of records keyed by document ID, like
+    /// quality scores or language tags from an earlier run. Every input file is written to a
+    /// file of the same name in the output directory, with the sidecar's fields added to the
+    /// documents that have a record.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd join data/*.json.gz --sidecar scores.parquet --field quality --into metadata -o joined/
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Join(cmd::join::Opt),
+
+    /// Keep only some fields of every document, optionally renaming them.
+    ///
+    /// Every input file is written to a file of the same name in the output directory. The
+    /// fields are copied exactly as they are, so this is a cheap way to shrink bloated records
+    /// before heavier analyses.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd select data/*.json.gz --field id --field text --field metadata.url:url -o minimal/
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Select(cmd::select::Opt),
+
+    /// Compare two stats reports.
+    ///
+    /// Prints the change of every total and breakdown, like the tokens per language or the
+    /// documents per file, between two reports from 'wimbd stats -o'. With '--max-change' the
+    /// command fails if the checked metrics changed by more than the given percentage.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd stats-diff stats-v1.jsonl stats-v2.jsonl
+    ///
+    /// > wimbd stats-diff stats-v1.jsonl stats-v2.jsonl --max-change 1 --check total_tokens --check total_documents
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    StatsDiff(cmd::stats_diff::Opt),
+
+    /// Find the top documents by a metric, like the number of tokens, how repetitive they
+    /// are, how many exact copies of them there are, or a numeric field like a quality score.
+    /// Documents are returned with pointers and text snippets.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// Find the 20 most repetitive documents:
+    ///
+    /// > wimbd topk-docs data/*.json.gz --by repetition -k 20
+    ///
+    /// Find the 50 documents with the lowest quality scores:
+    ///
+    /// > wimbd topk-docs data/*.json.gz --by field:metadata.quality --lowest -k 50 -o worst.jsonl
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    TopkDocs(cmd::topk_docs::Opt),
+
+    /// Print documents by pointer, like the ones from 'wimbd topk-docs' or 'wimbd tag'.
+    ///
+    /// Documents are read without decompressing the whole file when it has a line index,
+    /// which '--build-index' writes next to it. With zstd files in the seekable format only the
+    /// frame with the document is decompressed, which makes lookups in big shards instant.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd inspect data/shard-00.json.zst:1042 data/shard-07.json.zst:17
+    ///
+    /// Index some shards and print the documents found by 'wimbd topk-docs':
+    ///
+    /// > wimbd inspect --build-index data/*.json.zst
+    ///
+    /// > wimbd inspect --pointers worst.jsonl
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Inspect(cmd::inspect::Opt),
+
+    /// Run several analyses, like stats, top-k ngrams, searches, and language breakdowns,
+    /// in a single pass over the data.
+    ///
+    /// The analyses are read from a YAML spec. Every document is read, parsed, and tokenized
+    /// once for all of them, which saves most of the cost of separate runs over large corpora.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd pipeline data/*.json.gz --spec pipeline.yaml -o results.json
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Pipeline(cmd::pipeline::Opt),
+
+    /// Find benchmark instances that overlap with a corpus.
+    ///
+    /// The benchmark and the corpus are tokenized the same way, and an instance counts as
+    /// contaminated if enough of its ngrams occur anywhere in the corpus. The ngrams of the
+    /// benchmark are kept in memory, so the corpus is only read once.
+    ///
+    /// Work is parallelized over files.
+    ///
+    /// EXAMPLES
+    ///
+    /// > wimbd contamination data/*.json.gz --benchmark squad.jsonl --field question --field answer -o contamination.jsonl
+    ///
+    /// Count any 8-gram overlap, as in some decontamination pipelines:
+    ///
+    /// > wimbd contamination data/*.json.gz --benchmark lambada.jsonl --field text -n 8 --threshold 0
+    #[structopt(setting = structopt::clap::AppSettings::ColoredHelp)]
+    Contamination(cmd::contamination::Opt),
 }
 
 fn main() -> Result<()> {
     let opt = Opt::from_args();
-    simple_logger::init_with_level(log::Level::Info)?;
+    logging::init(opt.log_file.as_deref(), opt.log_level)?;
+    provenance::start(option_env!("BUILD_VERSION").unwrap_or(env!("CARGO_PKG_VERSION")));
+    if opt.tui {
+        tui::enable();
+    }
+    cmd::util::RetryPolicy {
+        max_retries: opt.retries,
+        backoff: opt.retry_backoff,
+        max_delay: opt.retry_max_delay,
+    }
+    .set()?;
+    io::set_read_retries(opt.read_retries);
+    if let Some(tmp_dir) = opt.tmp_dir {
+        io::set_tmp_dir(tmp_dir)?;
+    }
+    if opt.append {
+        util::set_append();
+    }
+    if let Some(report) = opt
+        .error_report
+        .or_else(|| opt.skip_errors.then(|| "wimbd-errors.jsonl".into()))
+    {
+        cmd::util::set_error_report(report)?;
+    }
+    if opt.skip_errors {
+        cmd::util::skip_errors();
+    }
+    if let Some(invalid_utf8) = opt.invalid_utf8 {
+        cmd::util::set_invalid_utf8(invalid_utf8)?;
+    }
+    if let Some(max_doc_bytes) = opt.max_doc_bytes {
+        cmd::util::set_max_doc_bytes(max_doc_bytes.try_into()?)?;
+    }
+    if let Some(file_timeout) = opt.file_timeout {
+        cmd::util::set_file_timeout(file_timeout)?;
+    }
+    if let Some(threads_per_file) = opt.threads_per_file {
+        cmd::util::set_threads_per_file(threads_per_file)?;
+    }
+    preprocess::set_defaults(preprocess::Preprocessor {
+        normalization: opt.normalize,
+        remove_urls: opt.remove_urls,
+        collapse_whitespace: opt.collapse_whitespace,
+        ..Default::default()
+    })?;
 
     let result = match opt.cmd {
         WimbdCmd::Topk(opt) => cmd::topk::main(opt),
@@ -80,6 +716,36 @@ fn main() -> Result<()> {
         WimbdCmd::Stats(opt) => cmd::stats::main(opt),
         WimbdCmd::Botk(opt) => cmd::botk::main(opt),
         WimbdCmd::Unique(opt) => cmd::unique::main(opt),
+        WimbdCmd::Hash(opt) => cmd::hash::main(opt),
+        WimbdCmd::Coverage(opt) => cmd::coverage::main(opt),
+        WimbdCmd::Report(opt) => cmd::report::main(opt),
+        WimbdCmd::Sample(opt) => cmd::sample::main(opt),
+        WimbdCmd::Shuffle(opt) => cmd::shuffle::main(opt),
+        WimbdCmd::Repack(opt) => cmd::repack::main(opt),
+        WimbdCmd::Composition(opt) => cmd::composition::main(opt),
+        WimbdCmd::Spans(opt) => cmd::spans::main(opt),
+        WimbdCmd::Bench(opt) => cmd::bench::main(opt),
+        WimbdCmd::Serve(opt) => cmd::serve::main(opt),
+        WimbdCmd::Freq(opt) => cmd::freq::main(opt),
+        WimbdCmd::Dupes(opt) => cmd::dupes::main(opt),
+        WimbdCmd::Contains(opt) => cmd::contains::main(opt),
+        WimbdCmd::Bloom(opt) => cmd::bloom::main(opt),
+        WimbdCmd::Tag(opt) => cmd::tag::main(opt),
+        WimbdCmd::Watch(opt) => cmd::watch::main(opt),
+        WimbdCmd::CountLines(opt) => cmd::count_lines::main(opt),
+        WimbdCmd::Taxonomy(opt) => cmd::taxonomy::main(opt),
+        WimbdCmd::Tokenize(opt) => cmd::tokenize::main(opt),
+        WimbdCmd::Detokenize(opt) => cmd::detokenize::main(opt),
+        WimbdCmd::CompareTokenizers(opt) => cmd::compare_tokenizers::main(opt),
+        WimbdCmd::Recount(opt) => cmd::recount::main(opt),
+        WimbdCmd::SplitBy(opt) => cmd::split_by::main(opt),
+        WimbdCmd::Join(opt) => cmd::join::main(opt),
+        WimbdCmd::Select(opt) => cmd::select::main(opt),
+        WimbdCmd::StatsDiff(opt) => cmd::stats_diff::main(opt),
+        WimbdCmd::TopkDocs(opt) => cmd::topk_docs::main(opt),
+        WimbdCmd::Inspect(opt) => cmd::inspect::main(opt),
+        WimbdCmd::Pipeline(opt) => cmd::pipeline::main(opt),
+        WimbdCmd::Contamination(opt) => cmd::contamination::main(opt),
     };
 
     if let Err(err) = result {
@@ -87,5 +753,9 @@ fn main() -> Result<()> {
         std::process::exit(1);
     }
 
+    if let Err(err) = provenance::finish() {
+        log::warn!("Failed to write run metadata: {}", err);
+    }
+
     Ok(())
 }
@@ -0,0 +1,260 @@
+//! Helpers for stripping HTML and Markdown markup from text.
+
+use std::borrow::Cow;
+
+/// Elements whose contents aren't text and should be dropped entirely.
+const SKIPPED_ELEMENTS: &[&str] = &["script", "style"];
+
+/// Strip HTML tags, comments, and the contents of `<script>` and `<style>` elements from
+/// some text, and decode common HTML entities.
+pub fn strip_html(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    // Whether the next text should be separated from the previous text, since a tag
+    // or comment was removed between them.
+    let mut separate = false;
+    while let Some(start) = rest.find('<') {
+        push_text(&mut out, &decode_entities(&rest[..start]), &mut separate);
+        rest = &rest[start..];
+
+        if let Some(comment) = rest.strip_prefix("<!--") {
+            rest = match comment.find("-->") {
+                Some(end) => &comment[end + 3..],
+                None => "",
+            };
+            separate = true;
+            continue;
+        }
+
+        // Only treat this as a tag if it looks like one, otherwise it's just a '<'.
+        let is_tag = rest[1..]
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '/' || c == '!');
+        let end = match rest.find('>') {
+            Some(end) if is_tag => end,
+            _ => {
+                push_text(&mut out, "<", &mut separate);
+                rest = &rest[1..];
+                continue;
+            }
+        };
+
+        let name = tag_name(&rest[1..end]);
+        rest = &rest[end + 1..];
+        if let Some(skipped) = SKIPPED_ELEMENTS
+            .iter()
+            .find(|e| name.eq_ignore_ascii_case(e))
+        {
+            let closing = format!("</{skipped}");
+            rest = match rest.to_ascii_lowercase().find(&closing) {
+                Some(close) => match rest[close..].find('>') {
+                    Some(close_end) => &rest[close + close_end + 1..],
+                    None => "",
+                },
+                None => "",
+            };
+        }
+        separate = true;
+    }
+    push_text(&mut out, &decode_entities(rest), &mut separate);
+    out
+}
+
+fn push_text(out: &mut String, text: &str, separate: &mut bool) {
+    if text.is_empty() {
+        return;
+    }
+    if *separate
+        && !out.ends_with(char::is_whitespace)
+        && !text.starts_with(char::is_whitespace)
+        && !out.is_empty()
+    {
+        out.push(' ');
+    }
+    *separate = false;
+    out.push_str(text);
+}
+
+fn tag_name(tag: &str) -> &str {
+    let tag = tag.trim_start_matches('/');
+    let end = tag
+        .find(|c: char| c.is_whitespace() || c == '/')
+        .unwrap_or(tag.len());
+    &tag[..end]
+}
+
+/// Decode named and numeric HTML entities. Unknown entities are left as-is.
+fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let decoded = rest[1..]
+            .find(';')
+            .filter(|&end| end <= 10)
+            .and_then(|end| decode_entity(&rest[1..end + 1]).map(|c| (c, end + 2)));
+        match decoded {
+            Some((c, len)) => {
+                out.push(c);
+                rest = &rest[len..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    Cow::Owned(out)
+}
+
+fn decode_entity(entity: &str) -> Option<char> {
+    if let Some(num) = entity.strip_prefix('#') {
+        let code = match num.strip_prefix(['x', 'X']) {
+            Some(hex) => u32::from_str_radix(hex, 16).ok()?,
+            None => num.parse().ok()?,
+        };
+        return char::from_u32(code);
+    }
+    match entity {
+        "amp" => Some('&'),
+        "lt" => Some('<'),
+        "gt" => Some('>'),
+        "quot" => Some('"'),
+        "apos" => Some('\''),
+        "nbsp" => Some(' '),
+        "ndash" => Some('–'),
+        "mdash" => Some('—'),
+        "hellip" => Some('…'),
+        "copy" => Some('©'),
+        "reg" => Some('®'),
+        _ => None,
+    }
+}
+
+/// Strip common Markdown markup from some text: headings, block quotes, list markers,
+/// code fences, horizontal rules, emphasis, inline code, and links and images (keeping
+/// their text).
+pub fn strip_markdown(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for (i, line) in text.lines().enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") || is_horizontal_rule(trimmed) {
+            continue;
+        }
+        let mut content = trimmed;
+        while let Some(quoted) = content.strip_prefix('>') {
+            content = quoted.trim_start();
+        }
+        content = content.trim_start_matches('#').trim_start();
+        content = strip_list_marker(content);
+        strip_inline_markdown(content, &mut out);
+    }
+    out
+}
+
+fn is_horizontal_rule(line: &str) -> bool {
+    let mut chars = line.chars().filter(|c| !c.is_whitespace());
+    match chars.next() {
+        Some(first @ ('-' | '*' | '_')) => {
+            let rest: Vec<char> = chars.collect();
+            rest.len() >= 2 && rest.iter().all(|c| *c == first)
+        }
+        _ => false,
+    }
+}
+
+fn strip_list_marker(line: &str) -> &str {
+    if let Some(rest) = line
+        .strip_prefix(['-', '*', '+'])
+        .filter(|rest| rest.starts_with(' '))
+    {
+        return rest.trim_start();
+    }
+    let digits = line.chars().take_while(|c| c.is_ascii_digit()).count();
+    if digits > 0 {
+        if let Some(rest) = line[digits..]
+            .strip_prefix(['.', ')'])
+            .filter(|rest| rest.starts_with(' '))
+        {
+            return rest.trim_start();
+        }
+    }
+    line
+}
+
+fn strip_inline_markdown(line: &str, out: &mut String) {
+    let mut rest = line;
+    let mut prev: Option<char> = None;
+    while let Some(c) = rest.chars().next() {
+        let next = rest[c.len_utf8()..].chars().next();
+        match c {
+            '*' | '`' | '~' => {}
+            // Underscores within words, like in "snake_case", aren't emphasis.
+            '_' if !(prev.is_some_and(char::is_alphanumeric)
+                && next.is_some_and(char::is_alphanumeric)) => {}
+            '!' if next == Some('[') => {}
+            '[' => {
+                // Links: "[text](url)" becomes "text".
+                if let Some((text, len)) = parse_link(rest) {
+                    strip_inline_markdown(text, out);
+                    rest = &rest[len..];
+                    prev = text.chars().last();
+                    continue;
+                }
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+        prev = Some(c);
+        rest = &rest[c.len_utf8()..];
+    }
+}
+
+/// Parse a Markdown link at the start of `s`, returning the link text and the length of the
+/// whole link.
+fn parse_link(s: &str) -> Option<(&str, usize)> {
+    let text_end = s.find(']')?;
+    let after = &s[text_end + 1..];
+    if !after.starts_with('(') {
+        return None;
+    }
+    let url_end = after.find(')')?;
+    Some((&s[1..text_end], text_end + 1 + url_end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_html() {
+        assert_eq!(
+            strip_html("<p>Hello <b>world</b> &amp; friends</p>"),
+            "Hello world & friends"
+        );
+        assert_eq!(
+            strip_html("a<script>var x = '<p>';</script>b<!-- hidden -->c"),
+            "a b c"
+        );
+        assert_eq!(strip_html("1 < 2 &#x3E; 0 &bogus;"), "1 < 2 > 0 &bogus;");
+        assert_eq!(strip_markdown("a snake_case _word_"), "a snake_case word");
+    }
+
+    #[test]
+    fn test_strip_markdown() {
+        let md = "# Title\n\n> Some *quoted* text\n\n- a [link](https://example.com)\n1. `code`\n\n---\n```rust\nfn main() {}\n```";
+        assert_eq!(
+            strip_markdown(md),
+            "Title\n\nSome quoted text\n\na link\ncode\n\n\n\nfn main() {}\n"
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+/// The number of types (distinct ngrams) with each count, i.e. the frequencies of frequencies,
+/// for measuring how concentrated a distribution is.
+///
+/// Counts of natural language roughly follow Zipf's law, so there are far fewer distinct
+/// counts than types and this stays small even for huge vocabularies.
+#[derive(Debug, Clone, Default)]
+pub struct CountHistogram {
+    types_by_count: BTreeMap<u64, u64>,
+}
+
+impl CountHistogram {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a type with the given count. Types with a count of 0 are ignored.
+    pub fn add(&mut self, count: u64) {
+        if count > 0 {
+            *self.types_by_count.entry(count).or_insert(0) += 1;
+        }
+    }
+
+    /// Compute the concentration metrics, including the fraction of tokens that the most
+    /// frequent `top[i]` types cover.
+    pub fn concentration(&self, top: &[usize]) -> Concentration {
+        let mut types: u64 = 0;
+        let mut tokens: u64 = 0;
+        let mut sum_count_log_count = 0.0;
+        for (&count, &num_types) in &self.types_by_count {
+            types += num_types;
+            tokens += count * num_types;
+            sum_count_log_count += num_types as f64 * count as f64 * (count as f64).log2();
+        }
+        if tokens == 0 {
+            return Concentration {
+                types,
+                tokens,
+                gini: 0.0,
+                entropy: 0.0,
+                top_coverage: top.iter().map(|&k| (k, 0.0)).collect(),
+            };
+        }
+
+        // With types sorted by count in ascending order and ranked from 1, the Gini coefficient
+        // is 2 * sum(rank * count) / (types * tokens) - (types + 1) / types. Types with the same
+        // count take up a run of consecutive ranks.
+        let mut weighted_rank_sum = 0.0;
+        let mut rank: u64 = 0;
+        for (&count, &num_types) in &self.types_by_count {
+            let rank_sum =
+                num_types as f64 * rank as f64 + (num_types * (num_types + 1)) as f64 / 2.0;
+            weighted_rank_sum += count as f64 * rank_sum;
+            rank += num_types;
+        }
+        let gini = 2.0 * weighted_rank_sum / (types as f64 * tokens as f64)
+            - (types as f64 + 1.0) / types as f64;
+
+        // -sum(p * log(p)) with p = count / tokens, rewritten as
+        // log(tokens) - sum(count * log(count)) / tokens.
+        let entropy = (tokens as f64).log2() - sum_count_log_count / tokens as f64;
+
+        let top_coverage = top
+            .iter()
+            .map(|&k| {
+                let mut remaining = k as u64;
+                let mut covered: u64 = 0;
+                for (&count, &num_types) in self.types_by_count.iter().rev() {
+                    if remaining == 0 {
+                        break;
+                    }
+                    let taken = std::cmp::min(remaining, num_types);
+                    covered += taken * count;
+                    remaining -= taken;
+                }
+                (k, covered as f64 / tokens as f64)
+            })
+            .collect();
+
+        Concentration {
+            types,
+            tokens,
+            gini: gini.max(0.0),
+            entropy: entropy.max(0.0),
+            top_coverage,
+        }
+    }
+}
+
+/// How concentrated a distribution of tokens (or ngrams) over types is.
+#[derive(Debug, Clone, Serialize)]
+pub struct Concentration {
+    /// The number of distinct types.
+    pub types: u64,
+    /// The total count over all types.
+    pub tokens: u64,
+    /// The Gini coefficient of the counts, from 0 when every type is equally frequent to
+    /// almost 1 when a single type makes up nearly everything.
+    pub gini: f64,
+    /// The Shannon entropy of the distribution in bits.
+    pub entropy: f64,
+    /// The fraction of tokens covered by the top k types, for each k.
+    pub top_coverage: Vec<(usize, f64)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CountHistogram;
+
+    fn histogram(counts: &[u64]) -> CountHistogram {
+        let mut histogram = CountHistogram::new();
+        for &count in counts {
+            histogram.add(count);
+        }
+        histogram
+    }
+
+    #[test]
+    fn test_uniform() {
+        let concentration = histogram(&[5; 8]).concentration(&[1, 4, 100]);
+        assert_eq!(concentration.types, 8);
+        assert_eq!(concentration.tokens, 40);
+        assert!(concentration.gini.abs() < 1e-9);
+        assert!((concentration.entropy - 3.0).abs() < 1e-9);
+        assert_eq!(
+            concentration.top_coverage,
+            vec![(1, 0.125), (4, 0.5), (100, 1.0)]
+        );
+    }
+
+    #[test]
+    fn test_skewed() {
+        let counts = [1, 1, 2, 4, 8, 8, 100];
+        let concentration = histogram(&counts).concentration(&[1, 2]);
+        assert_eq!(concentration.types, 7);
+        assert_eq!(concentration.tokens, 124);
+
+        // Compare against the definition with every pair of types.
+        let mean = 124.0 / 7.0;
+        let mut abs_diffs = 0.0;
+        for a in counts {
+            for b in counts {
+                abs_diffs += (a as f64 - b as f64).abs();
+            }
+        }
+        let gini = abs_diffs / (2.0 * 7.0 * 7.0 * mean);
+        assert!((concentration.gini - gini).abs() < 1e-9);
+
+        let entropy: f64 = counts
+            .iter()
+            .map(|&c| {
+                let p = c as f64 / 124.0;
+                -p * p.log2()
+            })
+            .sum();
+        assert!((concentration.entropy - entropy).abs() < 1e-9);
+        assert_eq!(
+            concentration.top_coverage,
+            vec![(1, 100.0 / 124.0), (2, 108.0 / 124.0)]
+        );
+    }
+
+    #[test]
+    fn test_empty() {
+        let concentration = histogram(&[0]).concentration(&[10]);
+        assert_eq!(concentration.types, 0);
+        assert_eq!(concentration.gini, 0.0);
+        assert_eq!(concentration.top_coverage, vec![(10, 0.0)]);
+    }
+}
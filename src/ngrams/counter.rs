@@ -1,11 +1,13 @@
 use std::hash::{BuildHasher, Hash, Hasher};
-use std::sync::atomic::Ordering;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use ahash::RandomState;
-use anyhow::{Context, Result};
 use atomic_traits::{Atomic, NumOps};
 use num_traits::{Bounded, NumCast, One, SaturatingSub, Zero};
 
+use crate::error::{Result, WimbdError};
+
 pub trait AsIterator<'a, T: 'a> {
     type Iterator: Iterator<Item = &'a T>;
 
@@ -40,6 +42,47 @@ impl<'a, T: 'a> AsIterator<'a, T> for std::collections::VecDeque<T> {
     }
 }
 
+/// The z-score of the confidence intervals of [`DistinctEstimate`]s, for 95% confidence.
+const CONFIDENCE_Z: f64 = 1.96;
+
+/// An estimate of the number of distinct ngrams in an [`NgramCounter`], with a 95% confidence
+/// interval.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistinctEstimate {
+    pub estimate: f64,
+    pub lower: f64,
+    /// This is `None` when the counter is so full that there's no upper bound.
+    pub upper: Option<f64>,
+}
+
+impl DistinctEstimate {
+    /// Estimate the number of distinct items added to a Bloom filter of `size` elements with
+    /// `num_hash_functions` from the number of non-zero elements, using the estimator of
+    /// Swamidass and Baldi: `-(size / num_hash_functions) * ln(1 - nonzero / size)`.
+    ///
+    /// The confidence interval comes from the binomial variance of the number of non-zero
+    /// elements. Returns `None` if every element is non-zero, since then there's no telling
+    /// how many items were added.
+    pub fn new(nonzero: u64, size: usize, num_hash_functions: usize) -> Option<Self> {
+        let size = size as f64;
+        let nonzero = nonzero as f64;
+        if nonzero >= size {
+            return None;
+        }
+        let invert =
+            |nonzero: f64| -(size / num_hash_functions as f64) * (1.0 - nonzero / size).ln();
+
+        let fill = nonzero / size;
+        let margin = CONFIDENCE_Z * (size * fill * (1.0 - fill)).sqrt();
+        let upper = nonzero + margin;
+        Some(Self {
+            estimate: invert(nonzero),
+            lower: invert((nonzero - margin).max(0.0)),
+            upper: (upper < size).then(|| invert(upper)),
+        })
+    }
+}
+
 /// A thread-safe counting Bloom filter for ngrams.
 pub struct NgramCounter<A>
 where
@@ -66,31 +109,55 @@ where
     ) -> Result<Self> {
         // Initialize count table
         let mut count_array = Vec::new();
-        count_array.try_reserve_exact(size).with_context(|| {
-            "Failed to allocate counts array. You may not have enough available memory.".to_string()
-        })?;
+        count_array.try_reserve_exact(size)?;
         for _ in 0..size {
             count_array.push(A::new(initial_value.clone()));
         }
 
-        // Initialize hash builders
-        let mut hash_builders = Vec::with_capacity(num_hash_functions);
-        for i in 0..num_hash_functions {
-            let hash_builder = match seed {
-                Some(seed) => RandomState::with_seed((seed as usize) + i),
-                None => RandomState::new(),
-            };
-            hash_builders.push(hash_builder);
-        }
-
         Ok(Self {
             size,
             num_hash_functions,
-            hash_builders,
+            hash_builders: hash_builders(num_hash_functions, seed),
             count_array,
         })
     }
 
+    /// Returns the number of elements in the hash table.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Write the raw counts out as little-endian integers.
+    pub fn write_counts(&self, mut writer: impl Write) -> Result<()> {
+        let width = std::mem::size_of::<<A as Atomic>::Type>();
+        for item in &self.count_array {
+            let count: u64 = NumCast::from(item.load(Ordering::Relaxed)).unwrap();
+            writer.write_all(&count.to_le_bytes()[..width])?;
+        }
+        Ok(())
+    }
+
+    /// Read raw counts written by [`NgramCounter::write_counts()`] into this counter, which
+    /// must have the same size.
+    pub fn read_counts(&self, mut reader: impl Read) -> Result<()> {
+        let width = std::mem::size_of::<<A as Atomic>::Type>();
+        let mut bytes = [0u8; 8];
+        for item in &self.count_array {
+            reader.read_exact(&mut bytes[..width])?;
+            let count = <<A as Atomic>::Type as NumCast>::from(u64::from_le_bytes(bytes))
+                .ok_or_else(|| WimbdError::Parse("count out of range".into()))?;
+            item.store(count, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// Estimate the number of distinct ngrams added to the counter. This corrects for ngrams
+    /// that share elements, which makes [`NgramCounter::nonzero()`] an underestimate once the
+    /// counter fills up.
+    pub fn estimate_distinct(&self) -> Option<DistinctEstimate> {
+        DistinctEstimate::new(self.nonzero(), self.size, self.num_hash_functions)
+    }
+
     /// Returns the number of non-zero elements in the hash table.
     pub fn nonzero(&self) -> u64 {
         let mut nonzero_count: u64 = 0;
@@ -176,16 +243,30 @@ where
         max_count
     }
 
+    /// Get the count for an ngram, i.e. the min count across all hash functions.
+    /// Like any counting Bloom filter this may over-estimate, but never under-estimates.
+    pub fn count<'a, N, I, T>(&self, ngram: &'a N) -> <A as Atomic>::Type
+    where
+        N: AsIterator<'a, T, Iterator = I> + ?Sized,
+        I: Iterator<Item = &'a T>,
+        T: 'a + Hash,
+    {
+        let mut min_count = <A as Atomic>::Type::max_value();
+        for i in 0..self.num_hash_functions {
+            let hash = self.hash(&mut ngram.as_iter(), i);
+            let index = self.index_for_hash(hash);
+            let count = self.count_array[index].load(Ordering::Relaxed);
+            min_count = std::cmp::min(min_count, count);
+        }
+        min_count
+    }
+
     fn hash<I, T>(&self, ngram: &mut I, hasher: usize) -> usize
     where
         I: Iterator<Item = T> + ?Sized,
         T: Hash,
     {
-        let mut hasher = self.hash_builders[hasher].build_hasher();
-        for token in ngram {
-            token.hash(&mut hasher);
-        }
-        hasher.finish().try_into().unwrap()
+        hash_ngram(&self.hash_builders[hasher], ngram)
     }
 
     fn index_for_hash(&self, hash: usize) -> usize {
@@ -193,6 +274,160 @@ where
     }
 }
 
+/// A thread-safe Bloom filter for ngrams with cells of only 1 or 2 bits, packed into words.
+///
+/// This is for when only presence matters, or whether an ngram was seen more than once, but
+/// not the full count. Then the same memory holds 4-8 times as many cells as an
+/// [`NgramCounter`] of `u8`s, which means far fewer false positives. Counts saturate at 1
+/// with 1-bit cells and at 3 with 2-bit cells.
+pub struct PackedNgramCounter {
+    size: usize,
+    bits: u32,
+    num_hash_functions: usize,
+    hash_builders: Vec<RandomState>,
+    words: Vec<AtomicU64>,
+}
+
+impl PackedNgramCounter {
+    /// Create a new counter with `size` cells of `bits` bits each, which must be 1 or 2.
+    pub fn new(
+        size: usize,
+        bits: u32,
+        num_hash_functions: usize,
+        seed: Option<u64>,
+    ) -> Result<Self> {
+        if bits != 1 && bits != 2 {
+            return Err(WimbdError::InvalidInput(format!(
+                "cells must have 1 or 2 bits, not {}",
+                bits
+            )));
+        }
+        let num_words = (size * bits as usize).div_ceil(64);
+        let mut words = Vec::new();
+        words.try_reserve_exact(num_words)?;
+        for _ in 0..num_words {
+            words.push(AtomicU64::new(0));
+        }
+
+        Ok(Self {
+            size,
+            bits,
+            num_hash_functions,
+            hash_builders: hash_builders(num_hash_functions, seed),
+            words,
+        })
+    }
+
+    /// Returns the number of cells.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// The count that cells saturate at.
+    pub fn max_count(&self) -> u64 {
+        (1 << self.bits) - 1
+    }
+
+    /// Estimate the number of distinct ngrams added to the counter, see
+    /// [`NgramCounter::estimate_distinct()`].
+    pub fn estimate_distinct(&self) -> Option<DistinctEstimate> {
+        DistinctEstimate::new(self.nonzero(), self.size, self.num_hash_functions)
+    }
+
+    /// Returns the number of non-zero cells.
+    pub fn nonzero(&self) -> u64 {
+        // Cells past `size` in the last word are never set, so whole words can be counted.
+        self.words
+            .iter()
+            .map(|word| {
+                let word = word.load(Ordering::Relaxed);
+                match self.bits {
+                    1 => word.count_ones() as u64,
+                    // A 2-bit cell is non-zero if either of its bits is set.
+                    _ => ((word | (word >> 1)) & 0x5555_5555_5555_5555).count_ones() as u64,
+                }
+            })
+            .sum()
+    }
+
+    /// Increment the count for an ngram by one. Returns the new count, i.e. the min count
+    /// across all hash functions.
+    pub fn increment<'a, N, I, T>(&self, ngram: &'a N) -> u64
+    where
+        N: AsIterator<'a, T, Iterator = I> + ?Sized,
+        I: Iterator<Item = &'a T>,
+        T: 'a + Hash,
+    {
+        let max_count = self.max_count();
+        let mut min_count = max_count;
+        for i in 0..self.num_hash_functions {
+            let (word, shift) =
+                self.position(hash_ngram(&self.hash_builders[i], &mut ngram.as_iter()));
+            let result =
+                self.words[word].fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                    ((bits >> shift) & max_count < max_count).then(|| bits + (1 << shift))
+                });
+            let count = match result {
+                Ok(bits) => ((bits >> shift) & max_count) + 1,
+                // The cell is already saturated.
+                Err(_) => max_count,
+            };
+            min_count = std::cmp::min(min_count, count);
+        }
+        min_count
+    }
+
+    /// Get the count for an ngram, i.e. the min count across all hash functions.
+    pub fn count<'a, N, I, T>(&self, ngram: &'a N) -> u64
+    where
+        N: AsIterator<'a, T, Iterator = I> + ?Sized,
+        I: Iterator<Item = &'a T>,
+        T: 'a + Hash,
+    {
+        let max_count = self.max_count();
+        let mut min_count = max_count;
+        for i in 0..self.num_hash_functions {
+            let (word, shift) =
+                self.position(hash_ngram(&self.hash_builders[i], &mut ngram.as_iter()));
+            let count = (self.words[word].load(Ordering::Relaxed) >> shift) & max_count;
+            min_count = std::cmp::min(min_count, count);
+        }
+        min_count
+    }
+
+    /// The index of the word of the cell for a hash and the offset of the cell in it.
+    fn position(&self, hash: usize) -> (usize, u32) {
+        let bit = (hash % self.size) * self.bits as usize;
+        (bit / 64, (bit % 64) as u32)
+    }
+}
+
+fn hash_builders(num_hash_functions: usize, seed: Option<u64>) -> Vec<RandomState> {
+    let mut hash_builders = Vec::with_capacity(num_hash_functions);
+    for i in 0..num_hash_functions {
+        let hash_builder = match seed {
+            // Unlike `RandomState::with_seed()`, this gives the same hashes across processes,
+            // which is needed for counters that are saved and loaded later.
+            Some(seed) => RandomState::with_seeds(seed, i as u64, 0, 0),
+            None => RandomState::new(),
+        };
+        hash_builders.push(hash_builder);
+    }
+    hash_builders
+}
+
+fn hash_ngram<I, T>(hash_builder: &RandomState, ngram: &mut I) -> usize
+where
+    I: Iterator<Item = T> + ?Sized,
+    T: Hash,
+{
+    let mut hasher = hash_builder.build_hasher();
+    for token in ngram {
+        token.hash(&mut hasher);
+    }
+    hasher.finish().try_into().unwrap()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -207,4 +442,87 @@ mod tests {
         let deque = VecDeque::from(["hello", "world"]);
         counter.increment(&deque, 1);
     }
+
+    #[test]
+    fn test_count() {
+        let counter = NgramCounter::<AtomicU32>::new(1024, 4, Some(1), 0).unwrap();
+        counter.increment(&["hi", "there"][..], 1);
+        counter.increment(&["hi", "there"][..], 1);
+
+        assert_eq!(counter.count(&["hi", "there"][..]), 2);
+        assert_eq!(counter.count(&VecDeque::from(["hi", "there"])), 2);
+    }
+
+    #[test]
+    fn test_write_and_read_counts() {
+        let counter = NgramCounter::<AtomicU32>::new(1024, 4, Some(1), 0).unwrap();
+        counter.increment(&["hi", "there"][..], 3);
+
+        let mut buffer = Vec::new();
+        counter.write_counts(&mut buffer).unwrap();
+        assert_eq!(buffer.len(), 1024 * 4);
+
+        let loaded = NgramCounter::<AtomicU32>::new(1024, 4, Some(1), 0).unwrap();
+        loaded.read_counts(&buffer[..]).unwrap();
+        assert_eq!(loaded.count(&["hi", "there"][..]), 3);
+    }
+
+    #[test]
+    fn test_estimate_distinct() {
+        let counter = NgramCounter::<AtomicU32>::new(10_000, 3, Some(1), 0).unwrap();
+        let words: Vec<String> = (0..5_000).map(|i| i.to_string()).collect();
+        for word in &words {
+            counter.increment(&[word.as_str()][..], 1);
+        }
+        // About 78% full, so a lot of ngrams share elements.
+        assert!(counter.nonzero() < 9_000);
+
+        let estimate = counter.estimate_distinct().unwrap();
+        assert!((estimate.estimate - 5_000.0).abs() < 250.0);
+        let upper = estimate.upper.unwrap();
+        assert!(estimate.lower < estimate.estimate && estimate.estimate < upper);
+        assert!(upper - estimate.lower < 500.0);
+
+        assert_eq!(DistinctEstimate::new(0, 100, 3).unwrap().estimate, 0.0);
+        assert!(DistinctEstimate::new(100, 100, 3).is_none());
+        assert_eq!(DistinctEstimate::new(99, 100, 3).unwrap().upper, None);
+    }
+
+    #[test]
+    fn test_packed_counter() {
+        for bits in [1, 2] {
+            // An odd size, so cells don't line up with words.
+            let counter = PackedNgramCounter::new(1_001, bits, 4, Some(1)).unwrap();
+            assert_eq!(counter.count(&["hi", "there"][..]), 0);
+            assert_eq!(counter.increment(&["hi", "there"][..]), 1);
+            assert_eq!(counter.count(&["hi", "there"][..]), 1);
+            for _ in 0..5 {
+                counter.increment(&VecDeque::from(["hi", "there"]));
+            }
+            assert_eq!(counter.count(&["hi", "there"][..]), counter.max_count());
+            assert_eq!(counter.count(&["bye"][..]), 0);
+            assert!(counter.nonzero() > 0 && counter.nonzero() <= 4);
+        }
+        assert_eq!(
+            PackedNgramCounter::new(10, 1, 1, None).unwrap().max_count(),
+            1
+        );
+        assert_eq!(
+            PackedNgramCounter::new(10, 2, 1, None).unwrap().max_count(),
+            3
+        );
+        assert!(PackedNgramCounter::new(10, 3, 1, None).is_err());
+    }
+
+    #[test]
+    fn test_packed_estimate_distinct() {
+        // 10,000 cells of 2 bits in the memory of 2,500 u8 cells.
+        let counter = PackedNgramCounter::new(10_000, 2, 3, Some(1)).unwrap();
+        let words: Vec<String> = (0..5_000).map(|i| i.to_string()).collect();
+        for word in &words {
+            counter.increment(&[word.as_str()][..]);
+        }
+        let estimate = counter.estimate_distinct().unwrap();
+        assert!((estimate.estimate - 5_000.0).abs() < 250.0);
+    }
 }
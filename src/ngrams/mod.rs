@@ -3,27 +3,28 @@
 use std::collections::VecDeque;
 use std::fmt;
 
-use anyhow::Result;
-
+mod concentration;
 mod counter;
+mod spill;
 mod topk;
+mod windows;
 
-pub use counter::NgramCounter;
+pub use concentration::{Concentration, CountHistogram};
+pub use counter::{DistinctEstimate, NgramCounter, PackedNgramCounter};
+pub use spill::{SortedRuns, SpillCounter};
 pub use topk::TopKNgrams;
+pub use windows::NgramWindows;
 
-use crate::tokens::{tokenize, PretrainedTokenizer};
+use crate::error::Result;
+use crate::tokens::Tokenizer;
 
 /// A helper function to quickly create an [`Ngram`] iterator given some text and a tokenizer.
 pub fn ngrams<'a>(
     text: &'a str,
     num: usize,
-    tokenizer: &Option<PretrainedTokenizer>,
+    tokenizer: &dyn Tokenizer,
 ) -> Result<Ngrams<'a, String>> {
-    if let Some(tokenizer) = tokenizer {
-        Ok(tokenizer.tokenize(text)?.into_iter().ngrams(num))
-    } else {
-        Ok(tokenize(text).map(|s| s.to_string()).ngrams(num))
-    }
+    Ok(tokenizer.tokenize(text)?.into_iter().ngrams(num))
 }
 
 // Ngram code here adapted from https://docs.rs/ngrams/latest/ngrams/index.html, which has a bug.
@@ -0,0 +1,287 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use xxhash_rust::xxh3::Xxh3;
+
+use crate::error::{Result, WimbdError};
+use crate::io::{LineReader, SpillDir, SpillWriter};
+
+/// An exact ngram counter that keeps its counts on disk instead of in memory.
+///
+/// Counts are hash-partitioned into compressed spill files. Each partition only holds a
+/// fraction of the distinct ngrams, so the partitions can be aggregated one at a time in memory
+/// when counting is done. The spill directory is removed when the counter is dropped.
+pub struct SpillCounter {
+    dir: SpillDir,
+    partitions: Vec<Mutex<Option<SpillWriter>>>,
+}
+
+impl SpillCounter {
+    /// Create a new counter with `num_partitions` spill files in a new directory under `tmp_dir`.
+    pub fn new(tmp_dir: &Path, num_partitions: usize) -> Result<Self> {
+        let dir = SpillDir::new(tmp_dir)?;
+
+        let mut partitions = Vec::with_capacity(num_partitions);
+        for i in 0..num_partitions {
+            partitions.push(Mutex::new(Some(dir.create(i)?)));
+        }
+
+        Ok(Self { dir, partitions })
+    }
+
+    /// Write out a batch of (partial) counts. Batches should be pre-aggregated and reasonably
+    /// large since each partition is locked once per batch.
+    pub fn spill(&self, counts: HashMap<Vec<String>, u64>) -> Result<()> {
+        let mut batches: Vec<Vec<(Vec<String>, u64)>> = vec![Vec::new(); self.partitions.len()];
+        for (ngram, count) in counts {
+            let i = (hash_ngram(&ngram) % self.partitions.len() as u64) as usize;
+            batches[i].push((ngram, count));
+        }
+
+        for (i, batch) in batches.into_iter().enumerate() {
+            if batch.is_empty() {
+                continue;
+            }
+            let mut partition = self.partitions[i]
+                .lock()
+                .map_err(|_| WimbdError::InvalidInput("Failed to acquire lock".into()))?;
+            let writer = partition.as_mut().ok_or_else(|| {
+                WimbdError::InvalidInput("Spill counter is already finished".into())
+            })?;
+            for (ngram, count) in batch {
+                serde_json::to_writer(&mut *writer, &(count, ngram))?;
+                writer.write_all(b"\n")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Aggregate the spilled counts one partition at a time and call `func` with the exact
+    /// count of every distinct ngram, in no particular order. Errors of `func` are returned as
+    /// [`WimbdError::Other`].
+    pub fn for_each_count<F>(&self, mut func: F) -> Result<()>
+    where
+        F: FnMut(Vec<String>, u64) -> anyhow::Result<()>,
+    {
+        for partition in &self.partitions {
+            if let Some(writer) = partition
+                .lock()
+                .map_err(|_| WimbdError::InvalidInput("Failed to acquire lock".into()))?
+                .take()
+            {
+                writer.finish()?.flush()?;
+            }
+        }
+
+        for i in 0..self.partitions.len() {
+            let mut counts: HashMap<Vec<String>, u64> = HashMap::new();
+            let path = self.dir.file(i);
+            let mut reader = LineReader::open(&path)?;
+            let mut line = String::new();
+            while reader
+                .read_line_into(&mut line)
+                .map_err(|err| WimbdError::from_read(&path, err))?
+            {
+                let (count, ngram): (u64, Vec<String>) = serde_json::from_str(&line)?;
+                *counts.entry(ngram).or_insert(0) += count;
+            }
+            for (ngram, count) in counts {
+                func(ngram, count)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Exact ngram counts on disk as sorted runs, i.e. an external merge sort.
+///
+/// Each batch of (partial) counts is sorted by ngram hash and written to its own compressed run
+/// file. The runs are then merged, which yields every distinct ngram exactly once with its total
+/// count, in hash order, without ever holding more than one record per run in memory. The spill
+/// directory is removed when this is dropped.
+pub struct SortedRuns {
+    dir: SpillDir,
+    num_runs: AtomicUsize,
+}
+
+impl SortedRuns {
+    /// Create a new set of runs in a new directory under `tmp_dir`.
+    pub fn new(tmp_dir: &Path) -> Result<Self> {
+        Ok(Self {
+            dir: SpillDir::new(tmp_dir)?,
+            num_runs: AtomicUsize::new(0),
+        })
+    }
+
+    /// Sort a batch of (partial) counts and write it out as a new run.
+    pub fn write_run(&self, counts: HashMap<Vec<String>, u64>) -> Result<()> {
+        if counts.is_empty() {
+            return Ok(());
+        }
+        let mut records: Vec<(u64, Vec<String>, u64)> = counts
+            .into_iter()
+            .map(|(ngram, count)| (hash_ngram(&ngram), ngram, count))
+            .collect();
+        records.sort_unstable();
+
+        let run = self.num_runs.fetch_add(1, Ordering::Relaxed);
+        let mut writer = self.dir.create(run)?;
+        for record in records {
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.finish()?.flush()?;
+
+        Ok(())
+    }
+
+    /// Returns the number of runs written so far.
+    pub fn num_runs(&self) -> usize {
+        self.num_runs.load(Ordering::Relaxed)
+    }
+
+    /// Merge all runs and call `func` with the exact count of every distinct ngram. Errors of
+    /// `func` are returned as [`WimbdError::Other`].
+    pub fn merge<F>(&self, mut func: F) -> Result<()>
+    where
+        F: FnMut(Vec<String>, u64) -> anyhow::Result<()>,
+    {
+        let mut readers = (0..self.num_runs())
+            .map(|run| {
+                let path = self.dir.file(run);
+                Ok((LineReader::open(&path)?, path))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut heap: BinaryHeap<MergeEntry> = BinaryHeap::new();
+        for (run, (reader, path)) in readers.iter_mut().enumerate() {
+            if let Some((hash, ngram, count)) = next_record(reader, path)? {
+                heap.push(Reverse((hash, ngram, run, count)));
+            }
+        }
+
+        let mut current: Option<(u64, Vec<String>, u64)> = None;
+        while let Some(Reverse((hash, ngram, run, count))) = heap.pop() {
+            match &mut current {
+                Some((current_hash, current_ngram, current_count))
+                    if *current_hash == hash && *current_ngram == ngram =>
+                {
+                    *current_count += count;
+                }
+                _ => {
+                    if let Some((_, ngram, count)) = current.take() {
+                        func(ngram, count)?;
+                    }
+                    current = Some((hash, ngram, count));
+                }
+            }
+
+            let (reader, path) = &mut readers[run];
+            if let Some((hash, ngram, count)) = next_record(reader, path)? {
+                heap.push(Reverse((hash, ngram, run, count)));
+            }
+        }
+        if let Some((_, ngram, count)) = current {
+            func(ngram, count)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The next record of a run while merging: its hash, ngram, run, and count. Entries are ordered
+/// by hash and then ngram, so all counts of an ngram come out of the heap one after the other.
+type MergeEntry = Reverse<(u64, Vec<String>, usize, u64)>;
+
+fn next_record(reader: &mut LineReader, path: &Path) -> Result<Option<(u64, Vec<String>, u64)>> {
+    match reader.next() {
+        Some(line) => {
+            let line = line.map_err(|err| WimbdError::from_read(path, err))?;
+            Ok(Some(serde_json::from_str(&line)?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn hash_ngram(ngram: &[String]) -> u64 {
+    let mut hasher = Xxh3::new();
+    for token in ngram {
+        hasher.update(token.as_bytes());
+        hasher.update(&[0]);
+    }
+    hasher.digest()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ngram(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_spill_counter() {
+        let counter = SpillCounter::new(&std::env::temp_dir(), 4).unwrap();
+        let dir = counter.dir.path().to_path_buf();
+
+        for _ in 0..3 {
+            let mut batch = HashMap::new();
+            batch.insert(ngram(&["hi", "there"]), 2);
+            batch.insert(ngram(&["bye", "now"]), 1);
+            counter.spill(batch).unwrap();
+        }
+
+        let mut counts = HashMap::new();
+        counter
+            .for_each_count(|ngram, count| {
+                counts.insert(ngram, count);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts[&ngram(&["hi", "there"])], 6);
+        assert_eq!(counts[&ngram(&["bye", "now"])], 3);
+
+        drop(counter);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn test_sorted_runs() {
+        let runs = SortedRuns::new(&std::env::temp_dir()).unwrap();
+        let dir = runs.dir.path().to_path_buf();
+
+        for i in 0..3 {
+            let mut batch = HashMap::new();
+            batch.insert(ngram(&["hi", "there"]), 2);
+            batch.insert(ngram(&["run", &i.to_string()]), 1);
+            runs.write_run(batch).unwrap();
+        }
+        runs.write_run(HashMap::new()).unwrap();
+        assert_eq!(runs.num_runs(), 3);
+
+        let mut counts = Vec::new();
+        runs.merge(|ngram, count| {
+            counts.push((ngram, count));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(counts.len(), 4);
+        let hi_there: Vec<u64> = counts
+            .iter()
+            .filter(|(n, _)| *n == ngram(&["hi", "there"]))
+            .map(|(_, count)| *count)
+            .collect();
+        assert_eq!(hi_there, vec![6]);
+
+        drop(runs);
+        assert!(!dir.exists());
+    }
+}
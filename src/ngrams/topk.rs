@@ -80,6 +80,14 @@ where
         }
     }
 
+    /// Iterate over the current top-k ngrams and their counts, highest count first.
+    pub fn iter(&self) -> impl Iterator<Item = (&Vec<T>, <A as Atomic>::Type)> {
+        self.topk
+            .iter()
+            .rev()
+            .map(|(count, ngram)| (ngram.as_ref(), *count))
+    }
+
     pub fn drain(&mut self) -> Vec<(Rc<Vec<T>>, <A as Atomic>::Type)> {
         let mut out: Vec<(Rc<Vec<T>>, <A as Atomic>::Type)> = Vec::with_capacity(self.k);
         while let Some((count, ngram)) = self.topk.pop_last() {
@@ -0,0 +1,174 @@
+use std::collections::VecDeque;
+
+/// An iterator adapter that yields the ngrams of a stream of tokens, in the order of their
+/// last token.
+///
+/// By default these are the contiguous ngrams of a single size `n`. With
+/// [`NgramWindows::with_max_n()`] ngrams of every size from `n` up to `max_n` are yielded,
+/// shortest first, and with [`NgramWindows::with_skips()`] the tokens of an ngram can be
+/// spread out with up to `k` tokens skipped in total, i.e. "k-skip-n-grams".
+///
+/// Tokens are cloned into every ngram they're part of, so this is best used with cheap tokens
+/// like `&str`.
+#[derive(Debug, Clone)]
+pub struct NgramWindows<I: Iterator> {
+    source: I,
+    min_n: usize,
+    max_n: usize,
+    skips: usize,
+    /// The most recent tokens, as many as the longest ngram can span.
+    window: VecDeque<I::Item>,
+    /// Ngrams ending at the last token that haven't been yielded yet.
+    pending: VecDeque<Vec<I::Item>>,
+}
+
+impl<I> NgramWindows<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    /// Yield the ngrams of size `n`, which has to be at least 1.
+    pub fn new(source: impl IntoIterator<IntoIter = I>, n: usize) -> Self {
+        assert!(n > 0, "ngram size must be at least 1");
+        Self {
+            source: source.into_iter(),
+            min_n: n,
+            max_n: n,
+            skips: 0,
+            window: VecDeque::with_capacity(n),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Also yield ngrams of every size up to `max_n`.
+    pub fn with_max_n(mut self, max_n: usize) -> Self {
+        self.max_n = std::cmp::max(self.min_n, max_n);
+        self
+    }
+
+    /// Also yield ngrams with up to `skips` tokens left out between their first and last token.
+    pub fn with_skips(mut self, skips: usize) -> Self {
+        self.skips = skips;
+        self
+    }
+
+    /// Queue up every ngram that ends at the last token in the window.
+    fn fill_pending(&mut self) {
+        let last = self.window.len() - 1;
+        for n in self.min_n..=std::cmp::min(self.max_n, self.window.len()) {
+            // The other n - 1 tokens are picked from the tokens before the last one that are
+            // close enough to it.
+            let span = std::cmp::min(last, n - 1 + self.skips);
+            let first = last - span;
+            let mut picks: Vec<usize> = (first..first + n - 1).collect();
+            loop {
+                let mut ngram: Vec<I::Item> =
+                    picks.iter().map(|&i| self.window[i].clone()).collect();
+                ngram.push(self.window[last].clone());
+                self.pending.push_back(ngram);
+
+                // Move on to the next combination of picks, in lexicographic order.
+                match (0..picks.len())
+                    .rev()
+                    .find(|&j| picks[j] < last - (picks.len() - j))
+                {
+                    Some(j) => {
+                        let start = picks[j] + 1;
+                        for (offset, pick) in picks[j..].iter_mut().enumerate() {
+                            *pick = start + offset;
+                        }
+                    }
+                    None => break,
+                }
+            }
+        }
+    }
+}
+
+impl<I> Iterator for NgramWindows<I>
+where
+    I: Iterator,
+    I::Item: Clone,
+{
+    type Item = Vec<I::Item>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(ngram) = self.pending.pop_front() {
+                return Some(ngram);
+            }
+            let token = self.source.next()?;
+            if self.window.len() == self.max_n + self.skips {
+                self.window.pop_front();
+            }
+            self.window.push_back(token);
+            if self.window.len() >= self.min_n {
+                self.fill_pending();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NgramWindows;
+
+    #[test]
+    fn test_contiguous() {
+        let ngrams: Vec<_> = NgramWindows::new("a b c d".split(' '), 3).collect();
+        assert_eq!(ngrams, vec![vec!["a", "b", "c"], vec!["b", "c", "d"]]);
+
+        let ngrams: Vec<_> = NgramWindows::new("a b".split(' '), 3).collect();
+        assert!(ngrams.is_empty());
+
+        let ngrams: Vec<_> = NgramWindows::new("a b".split(' '), 1).collect();
+        assert_eq!(ngrams, vec![vec!["a"], vec!["b"]]);
+    }
+
+    #[test]
+    fn test_multi_n() {
+        let ngrams: Vec<_> = NgramWindows::new("a b c".split(' '), 1)
+            .with_max_n(2)
+            .collect();
+        assert_eq!(
+            ngrams,
+            vec![
+                vec!["a"],
+                vec!["b"],
+                vec!["a", "b"],
+                vec!["c"],
+                vec!["b", "c"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_skips() {
+        let ngrams: Vec<_> = NgramWindows::new("a b c d".split(' '), 2)
+            .with_skips(1)
+            .collect();
+        assert_eq!(
+            ngrams,
+            vec![
+                vec!["a", "b"],
+                vec!["a", "c"],
+                vec!["b", "c"],
+                vec!["b", "d"],
+                vec!["c", "d"],
+            ]
+        );
+
+        let ngrams: Vec<_> = NgramWindows::new("a b c d".split(' '), 3)
+            .with_skips(1)
+            .collect();
+        assert_eq!(
+            ngrams,
+            vec![
+                vec!["a", "b", "c"],
+                vec!["a", "b", "d"],
+                vec!["a", "c", "d"],
+                vec!["b", "c", "d"],
+            ]
+        );
+    }
+}
@@ -0,0 +1,921 @@
+//! Run the main analyses of the `wimbd` CLI from Rust programs, without shelling out.
+//!
+//! Each analysis is set up with a builder that takes the paths of JSON lines files, which can be
+//! gzip or zstd-compressed, and has the same defaults as the matching command. For example,
+//! `TopKPipeline::new(&paths).ngram(3).tokenizer("gpt2").run()` returns the 20 most common
+//! trigrams of GPT-2 tokens, like `wimbd topk -n 3 -t gpt2` would.
+//!
+//! Work is parallelized over files. Unlike the CLI there are no output files; the results are
+//! returned to the caller instead. Progress can be reported to any [`ProgressSink`], like the
+//! CLI's [`ProgressBars`](crate::progress::ProgressBars), and bad lines and failing reads are
+//! handled as set with an [`IoPolicy`].
+
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fmt;
+use std::panic::AssertUnwindSafe;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Result, WimbdError};
+use crate::io::{InvalidUtf8, LineReader};
+use crate::ngrams::{NgramCounter, NgramWindows, TopKNgrams};
+use crate::preprocess::Preprocessor;
+use crate::progress::{FileProgress, ProgressSink};
+use crate::tokens::{load_tokenizer, Tokenizer};
+
+/// A tokenizer given by name, or one that the caller has set up already.
+#[derive(Clone)]
+enum TokenizerSpec {
+    Name(String),
+    Custom(Arc<dyn Tokenizer>),
+}
+
+impl TokenizerSpec {
+    fn load(&self) -> Result<Arc<dyn Tokenizer>> {
+        match self {
+            TokenizerSpec::Name(name) => load_tokenizer(name),
+            TokenizerSpec::Custom(tokenizer) => Ok(tokenizer.clone()),
+        }
+    }
+}
+
+impl fmt::Debug for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Input")
+            .field("paths", &self.paths)
+            .field("tokenizer", &self.tokenizer)
+            .field("preprocessor", &self.preprocessor)
+            .field("workers", &self.workers)
+            .field("limit", &self.limit)
+            .field("io_policy", &self.io_policy)
+            .field("progress", &self.progress.is_some())
+            .finish()
+    }
+}
+
+impl fmt::Debug for TokenizerSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenizerSpec::Name(name) => f.debug_tuple("Name").field(name).finish(),
+            TokenizerSpec::Custom(_) => f.write_str("Custom"),
+        }
+    }
+}
+
+/// How pipelines handle bad lines and files that fail to read, like the global options of the
+/// CLI with the same names. By default every bad line is an error.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct IoPolicy {
+    /// Skip lines that are malformed JSON or invalid UTF-8 instead of failing, like
+    /// '--skip-errors'.
+    pub skip_errors: bool,
+    /// What to do with lines that aren't valid UTF-8, like '--invalid-utf8'. This takes
+    /// precedence over `skip_errors`.
+    pub invalid_utf8: Option<InvalidUtf8>,
+    /// Skip lines longer than this many bytes before parsing them, like '--max-doc-bytes'.
+    pub max_doc_bytes: Option<usize>,
+    /// The max number of times to reopen a file after a transient read error, like
+    /// '--retries'. Processing continues after the lines that were already read, so no
+    /// document is counted twice.
+    pub retries: usize,
+}
+
+/// The settings that every pipeline has.
+#[derive(Clone)]
+struct Input {
+    paths: Vec<PathBuf>,
+    tokenizer: TokenizerSpec,
+    preprocessor: Preprocessor,
+    workers: Option<usize>,
+    limit: Option<usize>,
+    io_policy: IoPolicy,
+    progress: Option<Arc<dyn ProgressSink>>,
+}
+
+impl Input {
+    fn new<P: Into<PathBuf>>(paths: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            paths: paths.into_iter().map(Into::into).collect(),
+            tokenizer: TokenizerSpec::Name("unicode".into()),
+            preprocessor: Preprocessor::defaults(),
+            workers: None,
+            limit: None,
+            io_policy: IoPolicy::default(),
+            progress: None,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.paths.is_empty() {
+            return Err(WimbdError::InvalidInput(
+                "at least one path is required".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The fields of a document that pipelines use.
+#[derive(Debug, Deserialize)]
+struct Document {
+    text: Option<String>,
+}
+
+/// Call `func` on the text of every document of every file, after preprocessing.
+///
+/// Every worker gets its own context from `factory`, which is turned into a result with `finish`
+/// once there are no more files to process. The results of all workers are returned. The first
+/// error, or a panic, stops all workers and is returned instead.
+fn for_each_document<C, R, F, G, H>(input: &Input, factory: F, func: G, finish: H) -> Result<Vec<R>>
+where
+    R: Send,
+    F: Fn() -> C + Sync,
+    G: Fn(&str, &mut C) -> Result<()> + Sync,
+    H: Fn(C) -> R + Sync,
+{
+    input.validate()?;
+    let workers = input
+        .workers
+        .unwrap_or_else(|| std::cmp::min(64, num_cpus::get()))
+        .clamp(1, input.paths.len());
+    let next_file = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+
+    let process_files = |context: &mut C| -> Result<()> {
+        loop {
+            let i = next_file.fetch_add(1, Ordering::Relaxed);
+            if i >= input.paths.len() || stop.load(Ordering::Relaxed) {
+                return Ok(());
+            }
+            let path = &input.paths[i];
+            let file_progress = match &input.progress {
+                Some(progress) => progress.start_file(path, input.limit)?,
+                None => None,
+            };
+            // Lines before a read error were fully processed, so retries continue after them.
+            let mut lines_done = 0;
+            let mut retries = 0;
+            loop {
+                let mut read_failed = false;
+                let result = process_file(
+                    input,
+                    path,
+                    &mut lines_done,
+                    &mut read_failed,
+                    context,
+                    &func,
+                    &stop,
+                    file_progress.as_deref(),
+                );
+                match result {
+                    Err(err)
+                        if read_failed
+                            && err.is_transient()
+                            && retries < input.io_policy.retries =>
+                    {
+                        retries += 1;
+                        log::warn!("Retrying {:?} after error: {}", path, err);
+                    }
+                    result => break result?,
+                }
+            }
+            if let Some(progress) = &input.progress {
+                progress.finish_file(path);
+            }
+        }
+    };
+
+    let results: Result<Vec<R>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> Result<R> {
+                    let result = std::panic::catch_unwind(AssertUnwindSafe(|| -> Result<R> {
+                        let mut context = factory();
+                        process_files(&mut context)?;
+                        Ok(finish(context))
+                    }))
+                    .unwrap_or_else(|_| Err(worker_panicked()));
+                    if result.is_err() {
+                        stop.store(true, Ordering::Relaxed);
+                    }
+                    result
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or_else(|_| Err(worker_panicked())))
+            .collect()
+    });
+    if let Some(progress) = &input.progress {
+        progress.finish(results.is_ok());
+    }
+    results
+}
+
+fn worker_panicked() -> WimbdError {
+    WimbdError::Other(anyhow::anyhow!("a worker thread panicked"))
+}
+
+/// Call `func` on the text of every document of a file, after preprocessing, starting after
+/// the first `lines_done` lines. `lines_done` is kept up to date with the lines that were
+/// processed or skipped, and `read_failed` is set if an error came from reading the file.
+#[allow(clippy::too_many_arguments)]
+fn process_file<C, G>(
+    input: &Input,
+    path: &Path,
+    lines_done: &mut usize,
+    read_failed: &mut bool,
+    context: &mut C,
+    func: &G,
+    stop: &AtomicBool,
+    progress: Option<&dyn FileProgress>,
+) -> Result<()>
+where
+    G: Fn(&str, &mut C) -> Result<()>,
+{
+    let policy = &input.io_policy;
+    *read_failed = true;
+    let mut reader = LineReader::open(path)?;
+    if reader
+        .skip_lines(*lines_done)
+        .map_err(|err| WimbdError::from_read(path, err))?
+        < *lines_done
+    {
+        *read_failed = false;
+        return Err(WimbdError::InvalidInput(format!(
+            "{:?} is shorter than when it was last read",
+            path
+        )));
+    }
+    *read_failed = false;
+
+    let mut buf = Vec::with_capacity(2048);
+    while input.limit.is_none_or(|limit| *lines_done < limit) {
+        if stop.load(Ordering::Relaxed) {
+            return Ok(());
+        }
+        match reader.read_bytes_into(&mut buf) {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(err) => {
+                *read_failed = true;
+                return Err(WimbdError::from_read(path, err));
+            }
+        }
+        *lines_done += 1;
+        if let Some(progress) = progress {
+            progress.inc(1);
+            progress.inc_bytes(buf.len() as u64);
+        }
+
+        let line = match std::str::from_utf8(&buf) {
+            Ok(line) => Cow::Borrowed(line),
+            Err(err) => match policy.invalid_utf8 {
+                Some(InvalidUtf8::Replace) => String::from_utf8_lossy(&buf),
+                Some(InvalidUtf8::Skip) => continue,
+                None if policy.skip_errors => continue,
+                _ => {
+                    return Err(WimbdError::Parse(format!(
+                        "line {} of {:?} is invalid: {}",
+                        lines_done, path, err
+                    )))
+                }
+            },
+        };
+        if policy
+            .max_doc_bytes
+            .is_some_and(|max_doc_bytes| line.len() > max_doc_bytes)
+        {
+            continue;
+        }
+        let document: Document = match serde_json::from_str(&line) {
+            Ok(document) => document,
+            Err(_) if policy.skip_errors => continue,
+            Err(err) => {
+                return Err(WimbdError::Parse(format!(
+                    "failed to parse line {} of {:?}: {}",
+                    lines_done, path, err
+                )))
+            }
+        };
+        if let Some(text) = document.text {
+            func(&input.preprocessor.apply(&text), context)?;
+        }
+    }
+    Ok(())
+}
+
+/// An ngram and the number of times it occurs.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NgramCount {
+    pub ngram: Vec<String>,
+    pub count: u64,
+}
+
+/// Find the most common ngrams, like `wimbd topk`.
+///
+/// Ngrams are counted in a counting Bloom filter, so counts may be over-estimated, but never
+/// under-estimated. Use more memory with [`TopKPipeline::size()`] to make that less likely.
+#[derive(Debug, Clone)]
+pub struct TopKPipeline {
+    input: Input,
+    ngram: usize,
+    k: usize,
+    size: u64,
+    hashes: usize,
+    seed: Option<u64>,
+}
+
+impl TopKPipeline {
+    pub fn new<P: Into<PathBuf>>(paths: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            input: Input::new(paths),
+            ngram: 3,
+            k: 20,
+            size: 4 * 1024 * 1024 * 1024,
+            hashes: 5,
+            seed: None,
+        }
+    }
+
+    /// Set the ngram size. Defaults to 3.
+    pub fn ngram(mut self, ngram: usize) -> Self {
+        self.ngram = ngram;
+        self
+    }
+
+    /// Set the number of ngrams to return. Defaults to 20.
+    pub fn k(mut self, k: usize) -> Self {
+        self.k = k;
+        self
+    }
+
+    /// Set the tokenizer, "unicode" or the name of a pretrained tokenizer from HuggingFace.
+    /// Defaults to "unicode".
+    pub fn tokenizer(mut self, tokenizer: impl Into<String>) -> Self {
+        self.input.tokenizer = TokenizerSpec::Name(tokenizer.into());
+        self
+    }
+
+    /// Set a tokenizer that's already loaded, or one of your own.
+    pub fn tokenizer_impl(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.input.tokenizer = TokenizerSpec::Custom(tokenizer);
+        self
+    }
+
+    /// Set how documents are preprocessed before tokenizing. Defaults to
+    /// [`Preprocessor::defaults()`].
+    pub fn preprocessor(mut self, preprocessor: Preprocessor) -> Self {
+        self.input.preprocessor = preprocessor;
+        self
+    }
+
+    /// Set the size budget of the ngram counter in bytes. Defaults to 4GiB.
+    pub fn size(mut self, size: u64) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Set the number of hash functions of the ngram counter. Defaults to 5.
+    pub fn hashes(mut self, hashes: usize) -> Self {
+        self.hashes = hashes;
+        self
+    }
+
+    /// Set the seed of the hash functions. By default it's chosen at random.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Set the max number of worker threads. Defaults to min(64, num CPU).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.input.workers = Some(workers);
+        self
+    }
+
+    /// Only process the first `limit` lines of each file.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.input.limit = Some(limit);
+        self
+    }
+
+    /// Set how bad lines and failing reads are handled. Defaults to [`IoPolicy::default()`].
+    pub fn io_policy(mut self, io_policy: IoPolicy) -> Self {
+        self.input.io_policy = io_policy;
+        self
+    }
+
+    /// Report the progress through the files to `progress`. By default progress isn't
+    /// reported.
+    pub fn progress(mut self, progress: Arc<dyn ProgressSink>) -> Self {
+        self.input.progress = Some(progress);
+        self
+    }
+
+    /// Count the ngrams and return the top-k, most common first.
+    pub fn run(self) -> Result<Vec<NgramCount>> {
+        if self.ngram == 0 || self.k == 0 || self.hashes == 0 {
+            return Err(WimbdError::InvalidInput(
+                "the ngram size, k, and the number of hashes must be greater than 0".into(),
+            ));
+        }
+        if self.size < 4 {
+            return Err(WimbdError::InvalidInput(
+                "the size must be at least 4 bytes".into(),
+            ));
+        }
+        let tokenizer = self.input.tokenizer.load()?;
+        // We're storing an array of u32s, each of which is 4 bytes.
+        let ngram_counts =
+            NgramCounter::<AtomicU32>::new((self.size / 4) as usize, self.hashes, self.seed, 0)?;
+
+        // Every worker keeps the ngrams with the highest counts it has seen. Counts are global,
+        // so the overall top-k are among the candidates of the worker that saw them last.
+        let candidates = for_each_document(
+            &self.input,
+            || TopKNgrams::<String, AtomicU32>::new(self.k),
+            |text, local_topk| {
+                let tokens = tokenizer.tokens(text)?;
+                for ngram in NgramWindows::new(tokens.iter(), self.ngram) {
+                    let count = ngram_counts.increment(&ngram[..], 1);
+                    if count >= local_topk.min_count {
+                        local_topk
+                            .insert(ngram.iter().map(|token| token.to_string()).collect(), count);
+                    }
+                }
+                Ok(())
+            },
+            |mut local_topk| {
+                local_topk
+                    .drain()
+                    .into_iter()
+                    .map(|(ngram, _)| (*ngram).clone())
+                    .collect::<Vec<_>>()
+            },
+        )?;
+
+        let candidates: HashSet<Vec<String>> = candidates.into_iter().flatten().collect();
+        let mut topk: Vec<NgramCount> = candidates
+            .into_iter()
+            .map(|ngram| NgramCount {
+                count: ngram_counts.count(&ngram[..]) as u64,
+                ngram,
+            })
+            .collect();
+        topk.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.ngram.cmp(&b.ngram)));
+        topk.truncate(self.k);
+        Ok(topk)
+    }
+}
+
+/// Count the exact number of times that strings occur, like `wimbd count`.
+///
+/// Searches are tokenized with the same tokenizer as the documents and matched against their
+/// tokens. Overlapping occurrences all count.
+#[derive(Debug, Clone)]
+pub struct CountPipeline {
+    input: Input,
+    searches: Vec<String>,
+}
+
+impl CountPipeline {
+    pub fn new<P: Into<PathBuf>>(paths: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            input: Input::new(paths),
+            searches: Vec::new(),
+        }
+    }
+
+    /// Add a string to search for.
+    pub fn search(mut self, search: impl Into<String>) -> Self {
+        self.searches.push(search.into());
+        self
+    }
+
+    /// Set the tokenizer, "unicode" or the name of a pretrained tokenizer from HuggingFace.
+    /// Defaults to "unicode".
+    pub fn tokenizer(mut self, tokenizer: impl Into<String>) -> Self {
+        self.input.tokenizer = TokenizerSpec::Name(tokenizer.into());
+        self
+    }
+
+    /// Set a tokenizer that's already loaded, or one of your own.
+    pub fn tokenizer_impl(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.input.tokenizer = TokenizerSpec::Custom(tokenizer);
+        self
+    }
+
+    /// Set how documents are preprocessed before tokenizing. Defaults to
+    /// [`Preprocessor::defaults()`].
+    pub fn preprocessor(mut self, preprocessor: Preprocessor) -> Self {
+        self.input.preprocessor = preprocessor;
+        self
+    }
+
+    /// Set the max number of worker threads. Defaults to min(64, num CPU).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.input.workers = Some(workers);
+        self
+    }
+
+    /// Only process the first `limit` lines of each file.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.input.limit = Some(limit);
+        self
+    }
+
+    /// Set how bad lines and failing reads are handled. Defaults to [`IoPolicy::default()`].
+    pub fn io_policy(mut self, io_policy: IoPolicy) -> Self {
+        self.input.io_policy = io_policy;
+        self
+    }
+
+    /// Report the progress through the files to `progress`. By default progress isn't
+    /// reported.
+    pub fn progress(mut self, progress: Arc<dyn ProgressSink>) -> Self {
+        self.input.progress = Some(progress);
+        self
+    }
+
+    /// Count the searches and return their tokens and counts, in the order they were added.
+    /// Searches with the same tokens are only returned once.
+    pub fn run(self) -> Result<Vec<NgramCount>> {
+        let tokenizer = self.input.tokenizer.load()?;
+        let mut searches: Vec<Vec<String>> = Vec::with_capacity(self.searches.len());
+        for search in &self.searches {
+            let search_tokens = tokenizer.tokenize(search)?;
+            if search_tokens.is_empty() {
+                return Err(WimbdError::InvalidInput(format!(
+                    "search {:?} has no tokens",
+                    search
+                )));
+            }
+            if !searches.contains(&search_tokens) {
+                searches.push(search_tokens);
+            }
+        }
+        if searches.is_empty() {
+            return Err(WimbdError::InvalidInput(
+                "at least one search is required".into(),
+            ));
+        }
+
+        let counts = for_each_document(
+            &self.input,
+            || vec![0u64; searches.len()],
+            |text, local_counts| {
+                let tokens = tokenizer.tokens(text)?;
+                for (search, count) in searches.iter().zip(local_counts.iter_mut()) {
+                    *count += tokens
+                        .windows(search.len())
+                        .filter(|window| *window == search.as_slice())
+                        .count() as u64;
+                }
+                Ok(())
+            },
+            |local_counts| local_counts,
+        )?;
+
+        Ok(searches
+            .into_iter()
+            .enumerate()
+            .map(|(i, ngram)| NgramCount {
+                ngram,
+                count: counts.iter().map(|local_counts| local_counts[i]).sum(),
+            })
+            .collect())
+    }
+}
+
+/// Corpus totals from a [`StatsPipeline`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    /// The number of documents with a "text" field.
+    pub documents: u64,
+    pub tokens: u64,
+    /// The number of bytes of text, after preprocessing.
+    pub bytes: u64,
+    /// The number of tokens in the shortest document.
+    pub min_tokens: Option<u64>,
+    /// The number of tokens in the longest document.
+    pub max_tokens: Option<u64>,
+}
+
+impl Stats {
+    fn merge(&mut self, other: &Stats) {
+        self.documents += other.documents;
+        self.tokens += other.tokens;
+        self.bytes += other.bytes;
+        self.min_tokens = self.min_tokens.into_iter().chain(other.min_tokens).min();
+        self.max_tokens = self.max_tokens.into_iter().chain(other.max_tokens).max();
+    }
+}
+
+/// Count documents, tokens, and bytes, like `wimbd stats`.
+#[derive(Debug, Clone)]
+pub struct StatsPipeline {
+    input: Input,
+}
+
+impl StatsPipeline {
+    pub fn new<P: Into<PathBuf>>(paths: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            input: Input::new(paths),
+        }
+    }
+
+    /// Set the tokenizer, "unicode" or the name of a pretrained tokenizer from HuggingFace.
+    /// Defaults to "unicode".
+    pub fn tokenizer(mut self, tokenizer: impl Into<String>) -> Self {
+        self.input.tokenizer = TokenizerSpec::Name(tokenizer.into());
+        self
+    }
+
+    /// Set a tokenizer that's already loaded, or one of your own.
+    pub fn tokenizer_impl(mut self, tokenizer: Arc<dyn Tokenizer>) -> Self {
+        self.input.tokenizer = TokenizerSpec::Custom(tokenizer);
+        self
+    }
+
+    /// Set how documents are preprocessed before tokenizing. Defaults to
+    /// [`Preprocessor::defaults()`].
+    pub fn preprocessor(mut self, preprocessor: Preprocessor) -> Self {
+        self.input.preprocessor = preprocessor;
+        self
+    }
+
+    /// Set the max number of worker threads. Defaults to min(64, num CPU).
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.input.workers = Some(workers);
+        self
+    }
+
+    /// Only process the first `limit` lines of each file.
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.input.limit = Some(limit);
+        self
+    }
+
+    /// Set how bad lines and failing reads are handled. Defaults to [`IoPolicy::default()`].
+    pub fn io_policy(mut self, io_policy: IoPolicy) -> Self {
+        self.input.io_policy = io_policy;
+        self
+    }
+
+    /// Report the progress through the files to `progress`. By default progress isn't
+    /// reported.
+    pub fn progress(mut self, progress: Arc<dyn ProgressSink>) -> Self {
+        self.input.progress = Some(progress);
+        self
+    }
+
+    /// Compute the totals over all files.
+    pub fn run(self) -> Result<Stats> {
+        let tokenizer = self.input.tokenizer.load()?;
+        let stats = for_each_document(
+            &self.input,
+            Stats::default,
+            |text, local| {
+                let num_tokens = tokenizer.count_tokens(text)? as u64;
+                local.merge(&Stats {
+                    documents: 1,
+                    tokens: num_tokens,
+                    bytes: text.len() as u64,
+                    min_tokens: Some(num_tokens),
+                    max_tokens: Some(num_tokens),
+                });
+                Ok(())
+            },
+            |local| local,
+        )?;
+
+        let mut totals = Stats::default();
+        for local in &stats {
+            totals.merge(local);
+        }
+        Ok(totals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+
+    fn write_corpus(name: &str, texts: &[&str]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "wimbd-pipeline-{}-{}.jsonl",
+            name,
+            std::process::id()
+        ));
+        let lines: Vec<String> = texts
+            .iter()
+            .map(|text| serde_json::json!({ "text": text }).to_string() + "\n")
+            .collect();
+        std::fs::write(&path, lines.concat()).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_topk() {
+        let path = write_corpus("topk", &["a b c a b c", "a b c d", "e f"]);
+        let topk = TopKPipeline::new([&path])
+            .ngram(2)
+            .k(2)
+            .size(1 << 20)
+            .seed(1)
+            .workers(2)
+            .run()
+            .unwrap();
+        assert_eq!(
+            topk,
+            vec![
+                NgramCount {
+                    ngram: vec!["a".into(), "b".into()],
+                    count: 3
+                },
+                NgramCount {
+                    ngram: vec!["b".into(), "c".into()],
+                    count: 3
+                },
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_count_and_stats() {
+        let path = write_corpus("count", &["ha ha ha", "no", "ha"]);
+        let counts = CountPipeline::new([&path])
+            .search("ha ha")
+            .search("no")
+            .run()
+            .unwrap();
+        assert_eq!(counts[0].count, 2);
+        assert_eq!(counts[1].count, 1);
+
+        let stats = StatsPipeline::new([&path]).limit(2).run().unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                documents: 2,
+                tokens: 4,
+                bytes: 10,
+                min_tokens: Some(1),
+                max_tokens: Some(3),
+            }
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(StatsPipeline::new(Vec::<PathBuf>::new()).run().is_err());
+    }
+
+    #[test]
+    fn test_io_policy() {
+        let path = std::env::temp_dir().join(format!(
+            "wimbd-pipeline-io-policy-{}.jsonl",
+            std::process::id()
+        ));
+        let mut data = b"{\"text\": \"a b\"}\nnot json\n{\"text\": \"\xff\"}\n".to_vec();
+        data.extend(format!("{{\"text\": \"{}\"}}\n", "c ".repeat(100)).bytes());
+        std::fs::write(&path, data).unwrap();
+
+        assert!(matches!(
+            StatsPipeline::new([&path]).run(),
+            Err(WimbdError::Parse(_))
+        ));
+        let stats = StatsPipeline::new([&path])
+            .io_policy(IoPolicy {
+                skip_errors: true,
+                ..Default::default()
+            })
+            .run()
+            .unwrap();
+        assert_eq!((stats.documents, stats.tokens), (2, 102));
+        let stats = StatsPipeline::new([&path])
+            .io_policy(IoPolicy {
+                skip_errors: true,
+                invalid_utf8: Some(InvalidUtf8::Replace),
+                max_doc_bytes: Some(100),
+                ..Default::default()
+            })
+            .run()
+            .unwrap();
+        assert_eq!((stats.documents, stats.tokens), (2, 3));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Panics on every document.
+    struct PanickingTokenizer;
+
+    impl Tokenizer for PanickingTokenizer {
+        fn tokenize(&self, _: &str) -> Result<Vec<String>> {
+            panic!("tokenizer bug");
+        }
+
+        fn decode(&self, tokens: &[String]) -> Result<String> {
+            Ok(tokens.concat())
+        }
+
+        fn vocab_size(&self) -> Option<usize> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_worker_panic() {
+        let path = write_corpus("panic", &["a"]);
+        let result = StatsPipeline::new([&path])
+            .tokenizer_impl(Arc::new(PanickingTokenizer))
+            .run();
+        assert!(matches!(result, Err(WimbdError::Other(_))));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Counts the lines and files it's told about.
+    #[derive(Default)]
+    struct CountingProgress {
+        lines: Arc<AtomicUsize>,
+        files: AtomicUsize,
+        success: std::sync::Mutex<Option<bool>>,
+    }
+
+    struct CountingFileProgress(Arc<AtomicUsize>);
+
+    impl FileProgress for CountingFileProgress {
+        fn inc(&self, lines: u64) {
+            self.0.fetch_add(lines as usize, Ordering::Relaxed);
+        }
+
+        fn reset(&self) {}
+    }
+
+    impl ProgressSink for CountingProgress {
+        fn start_file(
+            &self,
+            _: &Path,
+            _: Option<usize>,
+        ) -> anyhow::Result<Option<Arc<dyn FileProgress>>> {
+            Ok(Some(Arc::new(CountingFileProgress(self.lines.clone()))))
+        }
+
+        fn finish_file(&self, _: &Path) {
+            self.files.fetch_add(1, Ordering::Relaxed);
+        }
+
+        fn finish(&self, success: bool) {
+            *self.success.lock().unwrap() = Some(success);
+        }
+    }
+
+    #[test]
+    fn test_progress() {
+        let path = write_corpus("progress", &["a", "b", "c"]);
+        let progress = Arc::new(CountingProgress::default());
+        StatsPipeline::new([&path, &path])
+            .progress(progress.clone())
+            .run()
+            .unwrap();
+        assert_eq!(progress.lines.load(Ordering::Relaxed), 6);
+        assert_eq!(progress.files.load(Ordering::Relaxed), 2);
+        assert_eq!(*progress.success.lock().unwrap(), Some(true));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    /// Splits text into single characters.
+    struct CharTokenizer;
+
+    impl Tokenizer for CharTokenizer {
+        fn tokenize(&self, text: &str) -> Result<Vec<String>> {
+            Ok(text.chars().map(String::from).collect())
+        }
+
+        fn decode(&self, tokens: &[String]) -> Result<String> {
+            Ok(tokens.concat())
+        }
+
+        fn vocab_size(&self) -> Option<usize> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_tokenizer_impl() {
+        let path = write_corpus("tokenizer-impl", &["abab", "ba"]);
+        let counts = CountPipeline::new([&path])
+            .tokenizer_impl(Arc::new(CharTokenizer))
+            .search("ab")
+            .run()
+            .unwrap();
+        assert_eq!(counts[0].ngram, vec!["a".to_string(), "b".to_string()]);
+        assert_eq!(counts[0].count, 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+//! The text preprocessing pipeline that's applied to documents before they're tokenized.
+//!
+//! The steps run in a fixed order: markup is stripped first, then the text is normalized, URLs
+//! are removed, and finally whitespace is collapsed, since each of the earlier steps can leave
+//! extra whitespace behind. Steps that don't change the text don't allocate.
+
+use std::borrow::Cow;
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, bail, Result};
+use unicode_normalization::{is_nfc, is_nfkc, UnicodeNormalization};
+
+use crate::markup::{strip_html, strip_markdown};
+
+static DEFAULTS: OnceLock<Preprocessor> = OnceLock::new();
+
+/// URL schemes and prefixes that start a URL for [`remove_urls()`].
+const URL_PREFIXES: &[&str] = &["https://", "http://", "ftp://", "www."];
+
+/// A Unicode normalization form.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Normalization {
+    /// Canonical composition, which only merges equivalent sequences like "e" + U+0301.
+    Nfc,
+    /// Compatibility composition, which also folds variants like full-width letters and
+    /// ligatures.
+    Nfkc,
+}
+
+impl std::str::FromStr for Normalization {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "nfc" => Ok(Self::Nfc),
+            "nfkc" => Ok(Self::Nfkc),
+            _ => bail!("invalid normalization form {:?}, expected nfc or nfkc", s),
+        }
+    }
+}
+
+/// Optional preprocessing applied to document text before it's tokenized.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Preprocessor {
+    pub strip_html: bool,
+    pub strip_markdown: bool,
+    pub normalization: Option<Normalization>,
+    pub remove_urls: bool,
+    pub collapse_whitespace: bool,
+}
+
+impl Preprocessor {
+    /// The steps set with [`set_defaults()`], plus stripping HTML or Markdown if requested.
+    pub fn new(strip_html: bool, strip_markdown: bool) -> Self {
+        let defaults = Self::defaults();
+        Self {
+            strip_html: defaults.strip_html || strip_html,
+            strip_markdown: defaults.strip_markdown || strip_markdown,
+            ..defaults
+        }
+    }
+
+    /// The steps set with [`set_defaults()`], or no steps at all if it hasn't been called.
+    pub fn defaults() -> Self {
+        DEFAULTS.get().copied().unwrap_or_default()
+    }
+
+    /// Whether any steps are enabled.
+    pub fn is_enabled(&self) -> bool {
+        self.strip_html
+            || self.strip_markdown
+            || self.normalization.is_some()
+            || self.remove_urls
+            || self.collapse_whitespace
+    }
+
+    /// Apply the preprocessing steps to some text. HTML is stripped first since Markdown
+    /// can contain inline HTML.
+    pub fn apply<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        let mut text = Cow::Borrowed(text);
+        if self.strip_html {
+            text = Cow::Owned(strip_html(&text));
+        }
+        if self.strip_markdown {
+            text = Cow::Owned(strip_markdown(&text));
+        }
+        if let Some(normalization) = self.normalization {
+            if let Cow::Owned(normalized) = normalize(&text, normalization) {
+                text = Cow::Owned(normalized);
+            }
+        }
+        if self.remove_urls {
+            if let Cow::Owned(removed) = remove_urls(&text) {
+                text = Cow::Owned(removed);
+            }
+        }
+        if self.collapse_whitespace {
+            if let Cow::Owned(collapsed) = collapse_whitespace(&text) {
+                text = Cow::Owned(collapsed);
+            }
+        }
+        text
+    }
+}
+
+/// Set the steps that every [`Preprocessor`] created afterwards starts from. This is how the
+/// global preprocessing options apply to every command.
+pub fn set_defaults(defaults: Preprocessor) -> Result<()> {
+    DEFAULTS
+        .set(defaults)
+        .map_err(|_| anyhow!("preprocessing defaults already set"))
+}
+
+/// Normalize some text to the given Unicode normalization form.
+pub fn normalize(text: &str, normalization: Normalization) -> Cow<'_, str> {
+    match normalization {
+        Normalization::Nfc if !is_nfc(text) => Cow::Owned(text.nfc().collect()),
+        Normalization::Nfkc if !is_nfkc(text) => Cow::Owned(text.nfkc().collect()),
+        _ => Cow::Borrowed(text),
+    }
+}
+
+/// Remove URLs from some text. A URL starts with a scheme like "https://" or with "www." at
+/// the start of a word and runs up to the next whitespace, minus trailing punctuation like the
+/// period at the end of a sentence.
+pub fn remove_urls(text: &str) -> Cow<'_, str> {
+    let mut out = String::new();
+    // The end of the text that has been copied to `out` or removed.
+    let mut copied = 0;
+    let mut search = 0;
+    while let Some((start, prefix_len)) = find_url_start(text, search) {
+        let end = url_end(text, start + prefix_len);
+        if end == start + prefix_len {
+            // Just the prefix, like "www." at the end of a sentence.
+            search = end;
+            continue;
+        }
+        out.push_str(&text[copied..start]);
+        copied = end;
+        search = end;
+    }
+    if copied == 0 {
+        return Cow::Borrowed(text);
+    }
+    out.push_str(&text[copied..]);
+    Cow::Owned(out)
+}
+
+/// The byte offset and prefix length of the first URL at or after `from`.
+fn find_url_start(text: &str, from: usize) -> Option<(usize, usize)> {
+    let bytes = text.as_bytes();
+    let mut i = from;
+    while i < bytes.len() {
+        let at_word_start = i == 0 || !bytes[i - 1].is_ascii_alphanumeric();
+        if at_word_start {
+            for prefix in URL_PREFIXES {
+                if bytes.len() - i >= prefix.len()
+                    && bytes[i..i + prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+                {
+                    return Some((i, prefix.len()));
+                }
+            }
+        }
+        i += 1;
+    }
+    None
+}
+
+/// The end of a URL whose body starts at `start`.
+fn url_end(text: &str, start: usize) -> usize {
+    let end = text[start..]
+        .find(char::is_whitespace)
+        .map_or(text.len(), |i| start + i);
+    start
+        + text[start..end]
+            .trim_end_matches(['.', ',', ';', ':', '!', '?', ')', ']', '"', '\''])
+            .len()
+}
+
+/// Collapse runs of whitespace into a single character and trim whitespace from both ends.
+/// Runs with a line break become a single newline so that line-based metrics still work,
+/// everything else becomes a single space.
+pub fn collapse_whitespace(text: &str) -> Cow<'_, str> {
+    let mut chars = text.chars().peekable();
+    let mut needs_collapsing = text.starts_with(char::is_whitespace);
+    while let Some(c) = chars.next() {
+        if c.is_whitespace()
+            && (c != ' ' && c != '\n' || chars.peek().is_none_or(|next| next.is_whitespace()))
+        {
+            needs_collapsing = true;
+            break;
+        }
+    }
+    if !needs_collapsing {
+        return Cow::Borrowed(text);
+    }
+
+    let mut out = String::with_capacity(text.len());
+    let mut whitespace: Option<bool> = None;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            let newline = c == '\n';
+            whitespace = Some(whitespace.unwrap_or(false) || newline);
+        } else {
+            if let Some(newline) = whitespace.take() {
+                if !out.is_empty() {
+                    out.push(if newline { '\n' } else { ' ' });
+                }
+            }
+            out.push(c);
+        }
+    }
+    Cow::Owned(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        let decomposed = "cafe\u{301}";
+        assert_eq!(normalize(decomposed, Normalization::Nfc), "caf\u{e9}");
+        assert!(matches!(
+            normalize("caf\u{e9}", Normalization::Nfc),
+            Cow::Borrowed(_)
+        ));
+        assert_eq!(
+            normalize("\u{ff21}\u{fb01}", Normalization::Nfc),
+            "\u{ff21}\u{fb01}"
+        );
+        assert_eq!(normalize("\u{ff21}\u{fb01}", Normalization::Nfkc), "Afi");
+        assert_eq!(
+            "NFKC".parse::<Normalization>().unwrap(),
+            Normalization::Nfkc
+        );
+        assert!("nfd".parse::<Normalization>().is_err());
+    }
+
+    #[test]
+    fn test_remove_urls() {
+        assert_eq!(
+            remove_urls("See https://example.com/a?b=1. Or www.example.org, or HTTP://x.y!"),
+            "See . Or , or !"
+        );
+        assert_eq!(
+            remove_urls("not-a-url www. or swww.example.com"),
+            "not-a-url www. or swww.example.com"
+        );
+        assert!(matches!(remove_urls("no links here"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        assert_eq!(collapse_whitespace("  a \t b\n\n c  "), "a b\nc");
+        assert_eq!(collapse_whitespace("a\u{a0}b"), "a b");
+        assert!(matches!(collapse_whitespace("a b\nc"), Cow::Borrowed(_)));
+    }
+
+    #[test]
+    fn test_preprocessor() {
+        let text = "<h1># Hello</h1>";
+        assert_eq!(Preprocessor::default().apply(text), text);
+        assert_eq!(Preprocessor::new(true, true).apply(text), "Hello");
+
+        let preprocessor = Preprocessor {
+            strip_html: true,
+            remove_urls: true,
+            collapse_whitespace: true,
+            ..Preprocessor::default()
+        };
+        assert!(preprocessor.is_enabled());
+        assert_eq!(
+            preprocessor.apply("<p>Visit <a href=\"x\">https://example.com</a> today</p>"),
+            "Visit today"
+        );
+    }
+}
@@ -1,7 +1,236 @@
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
 use anyhow::Result;
-use indicatif::{ProgressDrawTarget, ProgressStyle};
+use indicatif::{ProgressDrawTarget, ProgressState, ProgressStyle};
+
+pub(crate) use indicatif::{MultiProgress, ProgressBar};
+
+/// Receives progress updates while files are processed, so that applications can route
+/// progress to their own UIs, logs, or metrics. [`ProgressBars`] is the default, which draws
+/// progress bars to stderr.
+///
+/// Files are processed concurrently, so implementations need to be thread-safe.
+pub trait ProgressSink: Send + Sync {
+    /// Start tracking the progress through a single file. `limit` is the max number of lines
+    /// that will be processed from it, if any. Returning `None` skips tracking its lines.
+    fn start_file(
+        &self,
+        path: &Path,
+        limit: Option<usize>,
+    ) -> Result<Option<Arc<dyn FileProgress>>>;
+
+    /// A file is done.
+    fn finish_file(&self, path: &Path);
+
+    /// All files are done, or processing stopped early because of errors.
+    fn finish(&self, success: bool);
+}
+
+/// The progress through a single file from [`ProgressSink::start_file()`].
+pub trait FileProgress: Send + Sync {
+    /// More lines were processed.
+    fn inc(&self, lines: u64);
+
+    /// More bytes were read, for showing the throughput next to the lines.
+    fn inc_bytes(&self, _bytes: u64) {}
+
+    /// The file is retried from the start.
+    fn reset(&self);
+}
+
+impl FileProgress for ProgressBar {
+    fn inc(&self, lines: u64) {
+        ProgressBar::inc(self, lines)
+    }
+
+    fn reset(&self) {
+        ProgressBar::reset(self)
+    }
+}
+
+/// A progress bar for a single file that also shows how many MB/s are read, so that it's
+/// easy to tell whether a worker is IO-bound, CPU-bound, or stalled. It's cheap to clone.
+#[derive(Clone)]
+pub struct FileProgressBar {
+    progress: ProgressBar,
+    bytes: Arc<AtomicU64>,
+    /// The bytes read over all files, for the aggregate rate.
+    total_bytes: Option<Arc<AtomicU64>>,
+}
+
+impl FileProgressBar {
+    pub fn new(
+        path: impl AsRef<Path>,
+        limit: Option<usize>,
+        hidden: bool,
+        total_bytes: Option<Arc<AtomicU64>>,
+    ) -> Result<Self> {
+        let bytes = Arc::new(AtomicU64::new(0));
+        let progress = get_progress_bar(path, limit, hidden)?;
+        progress.set_style(file_progress_style(limit.is_some(), bytes.clone())?);
+        Ok(Self {
+            progress,
+            bytes,
+            total_bytes,
+        })
+    }
+
+    pub fn progress_bar(&self) -> &ProgressBar {
+        &self.progress
+    }
+
+    /// The number of bytes read so far.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+impl FileProgress for FileProgressBar {
+    fn inc(&self, lines: u64) {
+        self.progress.inc(lines)
+    }
+
+    fn inc_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(total_bytes) = &self.total_bytes {
+            total_bytes.fetch_add(bytes, Ordering::Relaxed);
+        }
+    }
+
+    fn reset(&self) {
+        self.progress.reset();
+        self.bytes.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The default [`ProgressSink`], with a bar for the number of files done and, unless
+/// disabled, one for each file that's being processed.
+pub struct ProgressBars {
+    all_progress: MultiProgress,
+    file_progress: ProgressBar,
+    show_files: bool,
+    /// The lines and bytes read over all files, for the aggregate rate.
+    total_lines: Arc<AtomicU64>,
+    total_bytes: Arc<AtomicU64>,
+}
+
+/// Counts the lines of a file towards the aggregate rate when there's no bar for the file.
+struct LineCounter {
+    total_lines: Arc<AtomicU64>,
+    total_bytes: Arc<AtomicU64>,
+}
+
+impl FileProgress for LineCounter {
+    fn inc(&self, lines: u64) {
+        self.total_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    fn inc_bytes(&self, bytes: u64) {
+        self.total_bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn reset(&self) {}
+}
+
+/// Counts the lines of a file towards the aggregate rate as well as its own bar.
+struct CountedFileProgressBar {
+    progress: FileProgressBar,
+    total_lines: Arc<AtomicU64>,
+}
+
+impl FileProgress for CountedFileProgressBar {
+    fn inc(&self, lines: u64) {
+        self.progress.inc(lines);
+        self.total_lines.fetch_add(lines, Ordering::Relaxed);
+    }
+
+    fn inc_bytes(&self, bytes: u64) {
+        self.progress.inc_bytes(bytes);
+    }
+
+    fn reset(&self) {
+        self.progress.reset();
+    }
+}
+
+impl ProgressBars {
+    pub fn new(
+        description: &'static str,
+        num_files: usize,
+        hidden: bool,
+        show_files: bool,
+    ) -> Result<Self> {
+        let all_progress = get_multi_progress_bar(hidden);
+        let total_lines = Arc::new(AtomicU64::new(0));
+        let total_bytes = Arc::new(AtomicU64::new(0));
+        let file_progress =
+            all_progress.add(get_file_progress_bar(description, num_files, hidden)?);
+        file_progress.set_style(
+            ProgressStyle::with_template(
+                "{msg}: files {human_pos}/{human_len} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {rate}",
+            )?
+            .progress_chars("#>-")
+            .with_key("rate", {
+                let total_lines = total_lines.clone();
+                let total_bytes = total_bytes.clone();
+                move |state: &ProgressState, w: &mut dyn Write| {
+                    let secs = state.elapsed().as_secs_f64().max(1e-3);
+                    write!(
+                        w,
+                        "{:.0} lines/s, {:.1} MB/s",
+                        total_lines.load(Ordering::Relaxed) as f64 / secs,
+                        total_bytes.load(Ordering::Relaxed) as f64 / 1_000_000.0 / secs
+                    )
+                    .ok();
+                }
+            }),
+        );
+        file_progress.set_position(0);
+        Ok(Self {
+            all_progress,
+            file_progress,
+            show_files: show_files && !hidden,
+            total_lines,
+            total_bytes,
+        })
+    }
+}
+
+impl ProgressSink for ProgressBars {
+    fn start_file(
+        &self,
+        path: &Path,
+        limit: Option<usize>,
+    ) -> Result<Option<Arc<dyn FileProgress>>> {
+        if !self.show_files {
+            return Ok(Some(Arc::new(LineCounter {
+                total_lines: self.total_lines.clone(),
+                total_bytes: self.total_bytes.clone(),
+            })));
+        }
+        let progress = FileProgressBar::new(path, limit, false, Some(self.total_bytes.clone()))?;
+        self.all_progress.add(progress.progress_bar().clone());
+        Ok(Some(Arc::new(CountedFileProgressBar {
+            progress,
+            total_lines: self.total_lines.clone(),
+        })))
+    }
+
+    fn finish_file(&self, _path: &Path) {
+        self.file_progress.inc(1);
+    }
 
-pub(crate) use indicatif::{MultiProgress, ProgressBar, ProgressIterator};
+    fn finish(&self, success: bool) {
+        if success {
+            self.file_progress.finish();
+        } else {
+            self.file_progress.finish_and_clear();
+        }
+    }
+}
 
 pub(crate) fn get_multi_progress_bar(hidden: bool) -> MultiProgress {
     if !hidden {
@@ -33,6 +262,32 @@ pub(crate) fn get_file_progress_bar(
     Ok(progress)
 }
 
+/// The style of a file's progress bar with its MB/s from the bytes read so far.
+fn file_progress_style(has_limit: bool, bytes: Arc<AtomicU64>) -> Result<ProgressStyle> {
+    let style = if has_limit {
+        ProgressStyle::with_template(
+            "{msg:<35!} {human_pos} [{wide_bar:.cyan/blue}] {per_sec:>12} {bytes_per_sec:>10}, <{eta:<3} ",
+        )?
+        .progress_chars("#>-")
+    } else {
+        ProgressStyle::with_template(
+            "{msg:<35!} {spinner:.green} {human_pos} {per_sec:12} {bytes_per_sec:>10}",
+        )?
+    };
+    Ok(style.with_key(
+        "bytes_per_sec",
+        move |state: &ProgressState, w: &mut dyn Write| {
+            let secs = state.elapsed().as_secs_f64().max(1e-3);
+            write!(
+                w,
+                "{:.1} MB/s",
+                bytes.load(Ordering::Relaxed) as f64 / 1_000_000.0 / secs
+            )
+            .ok();
+        },
+    ))
+}
+
 pub(crate) fn get_progress_bar(
     path: impl AsRef<std::path::Path>,
     limit: Option<usize>,
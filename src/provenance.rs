@@ -0,0 +1,131 @@
+//! Run metadata that's written next to every output file as '<out>.meta.json', so that results
+//! stay interpretable and reproducible long after they were produced.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use xxhash_rust::xxh3::Xxh3;
+
+static RUN: Mutex<Option<RunMetadata>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Serialize)]
+struct RunMetadata {
+    /// The full command line.
+    args: Vec<String>,
+    version: &'static str,
+    started_at: String,
+    finished_at: Option<String>,
+    seed: Option<u64>,
+    tokenizer: Option<String>,
+    /// The revision pretrained tokenizers were loaded from. This is always null for now, since
+    /// they're loaded from the default branch on HuggingFace, and the commit that resolved to
+    /// isn't reported back by the tokenizers library.
+    tokenizer_revision: Option<String>,
+    num_files: Option<usize>,
+    /// The xxh3 hash of the input file paths, each followed by a newline, in processing order.
+    files_xxh3: Option<String>,
+    outputs: Vec<PathBuf>,
+}
+
+fn now() -> String {
+    humantime::format_rfc3339_seconds(SystemTime::now()).to_string()
+}
+
+fn update(f: impl FnOnce(&mut RunMetadata)) {
+    if let Ok(mut run) = RUN.lock() {
+        if let Some(run) = run.as_mut() {
+            f(run);
+        }
+    }
+}
+
+/// Start recording metadata for this run.
+pub(crate) fn start(version: &'static str) {
+    if let Ok(mut run) = RUN.lock() {
+        *run = Some(RunMetadata {
+            args: std::env::args().collect(),
+            version,
+            started_at: now(),
+            finished_at: None,
+            seed: None,
+            tokenizer: None,
+            tokenizer_revision: None,
+            num_files: None,
+            files_xxh3: None,
+            outputs: Vec::new(),
+        });
+    }
+}
+
+/// Record the seed for hash functions or random number generators.
+pub(crate) fn record_seed(seed: u64) {
+    update(|run| run.seed = Some(seed));
+}
+
+/// Record the name of the tokenizer.
+pub(crate) fn record_tokenizer(name: &str) {
+    update(|run| run.tokenizer = Some(name.into()));
+}
+
+/// Record the input files. Only the first call counts, so commands that process their files in
+/// several batches can record all of them up front.
+pub(crate) fn record_files(paths: &[PathBuf]) {
+    let mut hasher = Xxh3::new();
+    for path in paths {
+        hasher.update(path.to_string_lossy().as_bytes());
+        hasher.update(b"\n");
+    }
+    let files_xxh3 = format!("{:016x}", hasher.digest());
+    update(|run| {
+        if run.files_xxh3.is_none() {
+            run.num_files = Some(paths.len());
+            run.files_xxh3 = Some(files_xxh3);
+        }
+    });
+}
+
+/// Record an output file to write metadata for.
+pub(crate) fn record_output(path: &Path) {
+    update(|run| {
+        if !run.outputs.iter().any(|output| output == path) {
+            run.outputs.push(path.into());
+        }
+    });
+}
+
+/// The path of the metadata file for an output file.
+fn metadata_path(path: &Path) -> PathBuf {
+    let mut metadata_path = path.as_os_str().to_owned();
+    metadata_path.push(".meta.json");
+    metadata_path.into()
+}
+
+/// Finish the run and write the metadata next to each output file.
+pub(crate) fn finish() -> Result<()> {
+    let run = {
+        let mut run = RUN.lock().map_err(|_| anyhow!("Failed to acquire lock"))?;
+        match run.as_mut() {
+            Some(run) => {
+                run.finished_at = Some(now());
+                run.clone()
+            }
+            None => return Ok(()),
+        }
+    };
+
+    for output in &run.outputs {
+        // Outputs are only written once a command succeeds.
+        if !output.exists() {
+            continue;
+        }
+        let path = metadata_path(output);
+        serde_json::to_writer_pretty(File::create(&path)?, &run)?;
+        log::info!("Run metadata written to {:?}", path);
+    }
+
+    Ok(())
+}
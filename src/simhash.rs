@@ -0,0 +1,204 @@
+//! 64-bit SimHash fingerprints for finding near-duplicate documents.
+//!
+//! A document's fingerprint is built from the hashes of its overlapping word shingles: every bit
+//! of the fingerprint is set if that bit is set in more than half of the shingle hashes. Documents
+//! that only differ in a few shingles end up with fingerprints that only differ in a few bits,
+//! so near-duplicates can be found by the Hamming distance between fingerprints.
+//!
+//! Fingerprints within a Hamming distance of `d` are found without comparing all pairs by
+//! splitting the fingerprints into `d + 1` bands. Two fingerprints that differ in at most `d`
+//! bits have to agree on at least one band, so only fingerprints that share a band are compared.
+//! Fingerprints that share a band are sorted and each one is only compared to the next
+//! [`WINDOW`] of them, so even a band shared by most fingerprints takes linear time. Pairs that
+//! are further apart in a huge bucket can be missed, unless another band or a chain of closer
+//! fingerprints joins them.
+//!
+//! All fingerprints are clustered at once in memory, which takes around 50 bytes per
+//! fingerprint on top of the fingerprints themselves.
+
+use xxhash_rust::xxh3::{xxh3_64, xxh3_64_with_seed};
+
+/// Compute the fingerprint of a text from its shingles of `shingle_size` whitespace-separated
+/// words. Texts with fewer words than that are a single shingle. Returns `None` for texts
+/// without any words.
+pub fn fingerprint(text: &str, shingle_size: usize, seed: u64) -> Option<u64> {
+    let words: Vec<u64> = text
+        .split_whitespace()
+        .map(|w| xxh3_64(w.as_bytes()))
+        .collect();
+    if words.is_empty() {
+        return None;
+    }
+
+    let mut weights = [0i64; 64];
+    let mut bytes = Vec::with_capacity(8 * shingle_size);
+    for shingle in words.windows(std::cmp::min(shingle_size.max(1), words.len())) {
+        bytes.clear();
+        for word in shingle {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+        let hash = xxh3_64_with_seed(&bytes, seed);
+        for (i, weight) in weights.iter_mut().enumerate() {
+            if hash >> i & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+
+    Some(
+        weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |fingerprint, (i, _)| fingerprint | 1 << i),
+    )
+}
+
+/// The number of bits that differ between two fingerprints.
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Split a fingerprint into `num_bands` bands of contiguous bits. Each band is returned as a key
+/// that includes the band's index, so keys from different bands never collide.
+pub fn bands(fingerprint: u64, num_bands: usize) -> impl Iterator<Item = (usize, u64)> {
+    let num_bands = num_bands.clamp(1, 64);
+    (0..num_bands).map(move |i| (i, band(fingerprint, i, num_bands)))
+}
+
+fn band(fingerprint: u64, band: usize, num_bands: usize) -> u64 {
+    let start = band * 64 / num_bands;
+    let end = (band + 1) * 64 / num_bands;
+    let mask = if end - start == 64 {
+        u64::MAX
+    } else {
+        (1 << (end - start)) - 1
+    };
+    fingerprint >> start & mask
+}
+
+/// The number of following fingerprints in the same band that each fingerprint is compared to.
+pub const WINDOW: usize = 64;
+
+/// Group fingerprints into clusters of near-duplicates, where fingerprints within
+/// `max_distance` bits of each other, directly or through other fingerprints, are in the same
+/// cluster. Returns the cluster of every fingerprint, as the index of one of its members.
+///
+/// Within a band, only fingerprints that are at most [`WINDOW`] apart in sorted order are
+/// compared, see the module docs.
+pub fn cluster(fingerprints: &[u64], max_distance: u32) -> Vec<usize> {
+    let mut parents: Vec<usize> = (0..fingerprints.len()).collect();
+
+    // Identical fingerprints are grouped up front so that they're only compared once below.
+    let mut order: Vec<usize> = (0..fingerprints.len()).collect();
+    order.sort_unstable_by_key(|&i| fingerprints[i]);
+    let mut distinct: Vec<usize> = Vec::new();
+    for &i in &order {
+        match distinct.last() {
+            Some(&last) if fingerprints[last] == fingerprints[i] => {
+                union(&mut parents, last, i);
+            }
+            _ => distinct.push(i),
+        }
+    }
+
+    let num_bands = std::cmp::min(max_distance as usize + 1, 64);
+    for i in 0..num_bands {
+        // Sorting by the whole fingerprint within a band puts the fingerprints that agree on
+        // the most bits above the band next to each other.
+        let mut keys: Vec<(u64, u64, usize)> = distinct
+            .iter()
+            .map(|&j| (band(fingerprints[j], i, num_bands), fingerprints[j], j))
+            .collect();
+        keys.sort_unstable();
+        for bucket in keys.chunk_by(|a, b| a.0 == b.0) {
+            for (j, &(_, _, a)) in bucket.iter().enumerate() {
+                let end = std::cmp::min(j + 1 + WINDOW, bucket.len());
+                for &(_, _, b) in &bucket[j + 1..end] {
+                    if hamming_distance(fingerprints[a], fingerprints[b]) <= max_distance {
+                        union(&mut parents, a, b);
+                    }
+                }
+            }
+        }
+    }
+
+    (0..fingerprints.len())
+        .map(|i| find(&mut parents, i))
+        .collect()
+}
+
+fn find(parents: &mut [usize], mut i: usize) -> usize {
+    while parents[i] != i {
+        parents[i] = parents[parents[i]];
+        i = parents[i];
+    }
+    i
+}
+
+fn union(parents: &mut [usize], a: usize, b: usize) {
+    let a = find(parents, a);
+    let b = find(parents, b);
+    if a != b {
+        parents[std::cmp::max(a, b)] = std::cmp::min(a, b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        let text = "the quick brown fox jumps over the lazy dog and keeps on running far away";
+        assert_eq!(fingerprint(text, 3, 0), fingerprint(text, 3, 0));
+        assert_eq!(fingerprint("  \n", 3, 0), None);
+        assert!(fingerprint("short", 3, 0).is_some());
+
+        // A small edit only flips a few bits, an unrelated text flips about half.
+        let edited = "the quick brown fox jumps over the lazy cat and keeps on running far away";
+        let other = "lorem ipsum dolor sit amet consectetur adipiscing elit sed do eiusmod tempor";
+        let a = fingerprint(text, 3, 0).unwrap();
+        let b = fingerprint(edited, 3, 0).unwrap();
+        let c = fingerprint(other, 3, 0).unwrap();
+        assert!(hamming_distance(a, b) < hamming_distance(a, c));
+    }
+
+    #[test]
+    fn test_bands() {
+        let fingerprint = 0x0123_4567_89ab_cdef;
+        assert_eq!(
+            bands(fingerprint, 1).collect::<Vec<_>>(),
+            vec![(0, fingerprint)]
+        );
+        assert_eq!(
+            bands(fingerprint, 4).collect::<Vec<_>>(),
+            vec![(0, 0xcdef), (1, 0x89ab), (2, 0x4567), (3, 0x0123)]
+        );
+    }
+
+    #[test]
+    fn test_cluster() {
+        let fingerprints = [0b0000, 0b1111 << 20, 0b0001, 0b0000, 0b0011, 0b1110 << 20];
+        assert_eq!(cluster(&fingerprints, 0), vec![0, 1, 2, 0, 4, 5]);
+        assert_eq!(cluster(&fingerprints, 1), vec![0, 1, 0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_cluster_large_bucket() {
+        // Unrelated fingerprints that all share the lower band, so that bucket is much larger
+        // than the window.
+        let mut fingerprints: Vec<u64> = (0..10 * WINDOW as u64)
+            .map(|i| xxh3_64(&i.to_le_bytes()) << 32)
+            .collect();
+        fingerprints.push(fingerprints[5] | 1);
+        let clusters = cluster(&fingerprints, 1);
+        assert_eq!(clusters[fingerprints.len() - 1], 5);
+        assert_eq!(
+            clusters[..10 * WINDOW],
+            (0..10 * WINDOW).collect::<Vec<_>>()
+        );
+    }
+}
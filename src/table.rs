@@ -0,0 +1,376 @@
+//! Writers for tabular results, like top-k ngrams and counts, in different file formats.
+//!
+//! SQLite databases are appended to rather than overwritten, so that the results of many runs
+//! can be queried and joined together. Every run gets a row in the "runs" table, and every row
+//! of results has the "run_id" of the run it came from.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Error, Result};
+use parquet::basic::Compression;
+use parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::parser::parse_message_type;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::Connection;
+use serde_json::Value;
+
+use crate::util::{self, OutputFile};
+
+/// The format of a results file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OutFormat {
+    Jsonl,
+    Csv,
+    Parquet,
+    Sqlite,
+}
+
+impl FromStr for OutFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(OutFormat::Jsonl),
+            "csv" => Ok(OutFormat::Csv),
+            "parquet" => Ok(OutFormat::Parquet),
+            "sqlite" => Ok(OutFormat::Sqlite),
+            _ => bail!("unknown output format '{}'", s),
+        }
+    }
+}
+
+impl OutFormat {
+    /// The file extension for generated file names.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            OutFormat::Jsonl => "jsonl",
+            OutFormat::Csv => "csv",
+            OutFormat::Parquet => "parquet",
+            OutFormat::Sqlite => "db",
+        }
+    }
+}
+
+/// The type of a column.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ColumnType {
+    Int,
+    String,
+    StringList,
+}
+
+impl ColumnType {
+    fn parquet_field(&self, name: &str) -> String {
+        match self {
+            ColumnType::Int => format!("required int64 {name};"),
+            ColumnType::String => format!("required binary {name} (UTF8);"),
+            ColumnType::StringList => format!(
+                "required group {name} (LIST) {{ repeated group list {{ required binary element (UTF8); }} }}"
+            ),
+        }
+    }
+
+    fn sqlite_type(&self) -> &'static str {
+        match self {
+            ColumnType::Int => "INTEGER",
+            // Lists are stored as JSON arrays, which SQLite's JSON functions can query.
+            ColumnType::String | ColumnType::StringList => "TEXT",
+        }
+    }
+}
+
+/// Where rows end up.
+enum Sink {
+    File(OutputFile),
+    Sqlite { conn: Connection, run_id: i64 },
+}
+
+/// Writes rows of results, given as JSON objects with a key for every column, to a file.
+pub(crate) struct TableWriter {
+    format: OutFormat,
+    table: &'static str,
+    columns: Vec<(&'static str, ColumnType)>,
+    sink: Option<Sink>,
+    /// Parquet files are written all at once when the writer is finished.
+    rows: Vec<Value>,
+}
+
+impl TableWriter {
+    /// Create the file at `path`. Rows have to have the given columns. Columns are only used
+    /// for CSV, Parquet, and SQLite, and JSON lines are written as is. `table` is the name of
+    /// the SQLite table.
+    pub(crate) fn create(
+        path: &Path,
+        force: bool,
+        format: OutFormat,
+        table: &'static str,
+        columns: &[(&'static str, ColumnType)],
+    ) -> Result<(Self, PathBuf)> {
+        let compressed = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("gz") | Some("zst")
+        );
+        let sink = match format {
+            OutFormat::Parquet if compressed => {
+                bail!("Parquet files are already compressed, use a '.parquet' extension instead")
+            }
+            OutFormat::Sqlite if compressed => {
+                bail!("SQLite databases can't be compressed, use a '.db' extension instead")
+            }
+            OutFormat::Sqlite => open_sqlite(path, table, columns)?,
+            OutFormat::Parquet if util::appending() && path.is_file() => {
+                bail!("Can't append to Parquet file {:?}", path)
+            }
+            OutFormat::Csv => {
+                // Appended rows go under the header that's already there.
+                let has_header = util::appending() && path.is_file();
+                let (mut file, _) = util::get_output_file(path, force)?;
+                if !has_header {
+                    let header: Vec<&str> = columns.iter().map(|(name, _)| *name).collect();
+                    writeln!(file, "{}", header.join(","))?;
+                }
+                Sink::File(file)
+            }
+            OutFormat::Jsonl | OutFormat::Parquet => {
+                Sink::File(util::get_output_file(path, force)?.0)
+            }
+        };
+        Ok((
+            Self {
+                format,
+                table,
+                columns: columns.to_vec(),
+                sink: Some(sink),
+                rows: Vec::new(),
+            },
+            path.into(),
+        ))
+    }
+
+    pub(crate) fn write_row(&mut self, row: &Value) -> Result<()> {
+        let sink = self
+            .sink
+            .as_mut()
+            .ok_or_else(|| anyhow!("Output file is already finished"))?;
+        match sink {
+            Sink::Sqlite { conn, run_id } => {
+                let mut values = vec![SqlValue::Integer(*run_id)];
+                for (name, column_type) in &self.columns {
+                    let value = get_column(row, name)?;
+                    values.push(match column_type {
+                        ColumnType::Int => SqlValue::Integer(
+                            value
+                                .as_i64()
+                                .ok_or_else(|| anyhow!("column '{}' is not an integer", name))?,
+                        ),
+                        ColumnType::String => SqlValue::Text(
+                            value
+                                .as_str()
+                                .ok_or_else(|| anyhow!("column '{}' is not a string", name))?
+                                .into(),
+                        ),
+                        ColumnType::StringList => SqlValue::Text(value.to_string()),
+                    });
+                }
+                let placeholders = vec!["?"; values.len()].join(", ");
+                conn.prepare_cached(&format!(
+                    "INSERT INTO {} VALUES ({})",
+                    self.table, placeholders
+                ))?
+                .execute(rusqlite::params_from_iter(values))?;
+            }
+            Sink::File(_) if self.format == OutFormat::Parquet => self.rows.push(row.clone()),
+            Sink::File(file) if self.format == OutFormat::Jsonl => writeln!(file, "{row}")?,
+            Sink::File(file) => {
+                let mut fields = Vec::with_capacity(self.columns.len());
+                for (name, column_type) in &self.columns {
+                    let value = get_column(row, name)?;
+                    fields.push(match column_type {
+                        ColumnType::String => csv_escape(
+                            value
+                                .as_str()
+                                .ok_or_else(|| anyhow!("column '{}' is not a string", name))?,
+                        ),
+                        // Lists are written as JSON arrays.
+                        ColumnType::StringList => csv_escape(&value.to_string()),
+                        ColumnType::Int => value.to_string(),
+                    });
+                }
+                writeln!(file, "{}", fields.join(","))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write any buffered rows and finish the file. Rows only show up in SQLite databases once
+    /// this is called.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        match self.sink.take() {
+            Some(Sink::Sqlite { conn, .. }) => {
+                conn.execute_batch("COMMIT")?;
+                Ok(())
+            }
+            Some(Sink::File(file)) if self.format == OutFormat::Parquet => {
+                write_parquet(file, &self.columns, &self.rows)
+            }
+            Some(Sink::File(file)) => file.finish(),
+            None => bail!("Output file is already finished"),
+        }
+    }
+}
+
+/// Open or create the database at `path`, create the table if needed, and register this run.
+/// Everything is written in a single transaction that's committed by [`TableWriter::finish()`].
+fn open_sqlite(path: &Path, table: &str, columns: &[(&'static str, ColumnType)]) -> Result<Sink> {
+    crate::provenance::record_output(path);
+    if path.is_file() {
+        log::info!("Appending to SQLite database {:?}", path);
+    } else if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let conn = Connection::open(path)?;
+    conn.execute_batch("BEGIN")?;
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS runs (
+            id INTEGER PRIMARY KEY,
+            command TEXT NOT NULL,
+            created_at TEXT NOT NULL
+        )",
+    )?;
+
+    let mut column_defs = vec!["run_id INTEGER NOT NULL REFERENCES runs(id)".to_string()];
+    for (name, column_type) in columns {
+        column_defs.push(format!("{} {} NOT NULL", name, column_type.sqlite_type()));
+    }
+    conn.execute_batch(&format!(
+        "CREATE TABLE IF NOT EXISTS {table} ({});
+        CREATE INDEX IF NOT EXISTS {table}_run_id ON {table} (run_id);",
+        column_defs.join(", ")
+    ))?;
+    for (name, column_type) in columns {
+        if *column_type == ColumnType::String {
+            conn.execute_batch(&format!(
+                "CREATE INDEX IF NOT EXISTS {table}_{name} ON {table} ({name})"
+            ))?;
+        }
+    }
+
+    let command = std::env::args().collect::<Vec<_>>().join(" ");
+    let created_at = humantime::format_rfc3339_seconds(SystemTime::now()).to_string();
+    conn.execute(
+        "INSERT INTO runs (command, created_at) VALUES (?1, ?2)",
+        (command, created_at),
+    )?;
+    let run_id = conn.last_insert_rowid();
+
+    Ok(Sink::Sqlite { conn, run_id })
+}
+
+fn get_column<'a>(row: &'a Value, name: &str) -> Result<&'a Value> {
+    row.get(name)
+        .ok_or_else(|| anyhow!("missing column '{}'", name))
+}
+
+fn csv_escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn write_parquet(
+    file: OutputFile,
+    columns: &[(&'static str, ColumnType)],
+    rows: &[Value],
+) -> Result<()> {
+    let fields: Vec<String> = columns
+        .iter()
+        .map(|(name, column_type)| column_type.parquet_field(name))
+        .collect();
+    let schema = Arc::new(parse_message_type(&format!(
+        "message results {{ {} }}",
+        fields.join(" ")
+    ))?);
+    let properties = Arc::new(
+        WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build(),
+    );
+    let mut writer = SerializedFileWriter::new(file, schema, properties)?;
+
+    let mut row_group = writer.next_row_group()?;
+    for (name, column_type) in columns {
+        let mut column = row_group
+            .next_column()?
+            .ok_or_else(|| anyhow!("missing Parquet column '{}'", name))?;
+        match column_type {
+            ColumnType::Int => {
+                let values = rows
+                    .iter()
+                    .map(|row| {
+                        get_column(row, name)?
+                            .as_i64()
+                            .ok_or_else(|| anyhow!("column '{}' is not an integer", name))
+                    })
+                    .collect::<Result<Vec<i64>>>()?;
+                column
+                    .typed::<Int64Type>()
+                    .write_batch(&values, None, None)?;
+            }
+            ColumnType::String => {
+                let values = rows
+                    .iter()
+                    .map(|row| {
+                        get_column(row, name)?
+                            .as_str()
+                            .map(ByteArray::from)
+                            .ok_or_else(|| anyhow!("column '{}' is not a string", name))
+                    })
+                    .collect::<Result<Vec<ByteArray>>>()?;
+                column
+                    .typed::<ByteArrayType>()
+                    .write_batch(&values, None, None)?;
+            }
+            ColumnType::StringList => {
+                // Every list element gets a definition level of 1 and the first element of each
+                // list a repetition level of 0. Empty lists are a single null entry.
+                let mut values = Vec::new();
+                let mut def_levels = Vec::new();
+                let mut rep_levels = Vec::new();
+                for row in rows {
+                    let list = get_column(row, name)?
+                        .as_array()
+                        .ok_or_else(|| anyhow!("column '{}' is not a list", name))?;
+                    if list.is_empty() {
+                        def_levels.push(0);
+                        rep_levels.push(0);
+                    }
+                    for (i, element) in list.iter().enumerate() {
+                        let element = element
+                            .as_str()
+                            .ok_or_else(|| anyhow!("column '{}' is not a list of strings", name))?;
+                        values.push(ByteArray::from(element));
+                        def_levels.push(1);
+                        rep_levels.push(if i == 0 { 0 } else { 1 });
+                    }
+                }
+                column.typed::<ByteArrayType>().write_batch(
+                    &values,
+                    Some(&def_levels),
+                    Some(&rep_levels),
+                )?;
+            }
+        }
+        column.close()?;
+    }
+    row_group.close()?;
+    writer.into_inner()?.finish()
+}
@@ -1,9 +1,13 @@
 //! Tokenizer classes and functions.
 
-use anyhow::{anyhow, Result};
-use tokenizers::tokenizer::Tokenizer;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use tokenizers::tokenizer::Tokenizer as HfTokenizer;
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::error::{Result, WimbdError};
+
 /// Tokenize a string using a basic unicode tokenizer.
 pub fn tokenize(s: &str) -> impl Iterator<Item = &str> {
     s.split_word_bounds().filter(|w| {
@@ -16,39 +20,273 @@ pub fn tokenize(s: &str) -> impl Iterator<Item = &str> {
     })
 }
 
+/// Like [`tokenize()`] but also yields the byte offset of each token.
+pub fn tokenize_with_offsets(s: &str) -> impl Iterator<Item = (usize, &str)> {
+    s.split_word_bound_indices()
+        .filter(|(_, w)| w.chars().any(|c| !c.is_whitespace()))
+}
+
+/// The byte offsets of the Unicode word boundaries in a string, in order. This includes the
+/// start and the end of the string.
+pub fn word_boundaries(s: &str) -> Vec<usize> {
+    let mut boundaries: Vec<usize> = s.split_word_bound_indices().map(|(i, _)| i).collect();
+    boundaries.push(s.len());
+    boundaries
+}
+
+/// An upper bound on the number of bytes per token, for cutting off text before tokenizing it
+/// with [`PretrainedTokenizer::tokenize_truncated()`].
+const MAX_BYTES_PER_TOKEN: usize = 64;
+
+/// Cut off a string after at most `max_bytes` bytes, at a char boundary.
+pub fn truncate_str(s: &str, max_bytes: usize) -> &str {
+    if s.len() <= max_bytes {
+        return s;
+    }
+    let mut end = max_bytes;
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+/// The unknown tokens of common pretrained tokenizers.
+const UNKNOWN_TOKENS: &[&str] = &["<unk>", "[UNK]", "<|unk|>", "<UNK>"];
+
+/// Whether a token is the unknown token of a common pretrained tokenizer, which stands in for
+/// text that the vocabulary can't represent.
+pub fn is_unknown_token(token: &str) -> bool {
+    UNKNOWN_TOKENS.contains(&token)
+}
+
+/// Whether a token is a byte fallback token like "<0xE2>", which SentencePiece tokenizers
+/// use for characters that aren't in their vocabulary.
+pub fn is_byte_fallback_token(token: &str) -> bool {
+    token.len() == 6
+        && token.starts_with("<0x")
+        && token.ends_with('>')
+        && token.as_bytes()[3..5].iter().all(u8::is_ascii_hexdigit)
+}
+
+/// A tokenizer that commands can use to split documents into tokens.
+///
+/// Commands load tokenizers with [`load_tokenizer()`], so a new kind of tokenizer only has to
+/// implement this trait and be added there.
+pub trait Tokenizer: Send + Sync {
+    fn tokenize(&self, text: &str) -> Result<Vec<String>>;
+
+    /// Like [`Tokenizer::tokenize()`] but tokens may borrow from the text, which saves
+    /// allocating every token for tokenizers that don't change the text.
+    fn tokens<'a>(&self, text: &'a str) -> Result<Vec<Cow<'a, str>>> {
+        Ok(self.tokenize(text)?.into_iter().map(Cow::Owned).collect())
+    }
+
+    /// The number of tokens in the text.
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(self.tokenize(text)?.len())
+    }
+
+    /// Turn tokens back into text.
+    fn decode(&self, tokens: &[String]) -> Result<String>;
+
+    /// The number of tokens in the vocabulary, or `None` if it's open-ended.
+    fn vocab_size(&self) -> Option<usize>;
+
+    /// Like [`Tokenizer::tokenize()`] but only keeps the first `max_tokens` tokens. Also
+    /// returns whether the text was truncated.
+    fn tokenize_truncated(&self, text: &str, max_tokens: usize) -> Result<(Vec<String>, bool)> {
+        let mut tokens = self.tokenize(text)?;
+        let truncated = tokens.len() > max_tokens;
+        tokens.truncate(max_tokens);
+        Ok((tokens, truncated))
+    }
+
+    /// Like [`Tokenizer::tokenize()`] but also returns the byte span of each token. Not every
+    /// tokenizer can do this.
+    fn tokenize_with_offsets(&self, _text: &str) -> Result<Vec<(String, (usize, usize))>> {
+        Err(WimbdError::Tokenizer(
+            "This tokenizer doesn't support token offsets".into(),
+        ))
+    }
+
+    /// Look up the tokens for a sequence of token IDs from the vocabulary. Not every tokenizer
+    /// has token IDs.
+    fn ids_to_tokens(&self, _ids: &[u32]) -> Result<Vec<String>> {
+        Err(WimbdError::Tokenizer(
+            "This tokenizer doesn't have token IDs".into(),
+        ))
+    }
+
+    /// Like [`Tokenizer::tokenize()`] but returns the IDs of the tokens in the vocabulary. Not
+    /// every tokenizer has token IDs.
+    fn tokenize_ids(&self, _text: &str) -> Result<Vec<u32>> {
+        Err(WimbdError::Tokenizer(
+            "This tokenizer doesn't have token IDs".into(),
+        ))
+    }
+
+    /// Turn token IDs back into text, leaving out special tokens. Not every tokenizer has token
+    /// IDs.
+    fn decode_ids(&self, _ids: &[u32]) -> Result<String> {
+        Err(WimbdError::Tokenizer(
+            "This tokenizer doesn't have token IDs".into(),
+        ))
+    }
+}
+
+/// Load a tokenizer by name. This can be "unicode" for the [`UnicodeTokenizer`] or the name of
+/// a pretrained tokenizer from HuggingFace.
+pub fn load_tokenizer(name: &str) -> Result<Arc<dyn Tokenizer>> {
+    if name == "unicode" {
+        Ok(Arc::new(UnicodeTokenizer))
+    } else {
+        Ok(Arc::new(PretrainedTokenizer::new(name)?))
+    }
+}
+
+/// The basic unicode tokenizer, see [`tokenize()`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct UnicodeTokenizer;
+
+impl Tokenizer for UnicodeTokenizer {
+    fn tokenize(&self, text: &str) -> Result<Vec<String>> {
+        Ok(tokenize(text).map(|s| s.to_string()).collect())
+    }
+
+    /// Tokens are slices of the text, so none of them are allocated.
+    fn tokens<'a>(&self, text: &'a str) -> Result<Vec<Cow<'a, str>>> {
+        Ok(tokenize(text).map(Cow::Borrowed).collect())
+    }
+
+    fn count_tokens(&self, text: &str) -> Result<usize> {
+        Ok(tokenize(text).count())
+    }
+
+    /// Tokens are joined by spaces, since the whitespace between them isn't kept.
+    fn decode(&self, tokens: &[String]) -> Result<String> {
+        Ok(tokens.join(" "))
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        None
+    }
+
+    fn tokenize_truncated(&self, text: &str, max_tokens: usize) -> Result<(Vec<String>, bool)> {
+        let mut tokens: Vec<String> = tokenize(text)
+            .take(max_tokens.saturating_add(1))
+            .map(|s| s.to_string())
+            .collect();
+        let truncated = tokens.len() > max_tokens;
+        tokens.truncate(max_tokens);
+        Ok((tokens, truncated))
+    }
+
+    fn tokenize_with_offsets(&self, text: &str) -> Result<Vec<(String, (usize, usize))>> {
+        Ok(tokenize_with_offsets(text)
+            .map(|(offset, token)| (token.to_string(), (offset, offset + token.len())))
+            .collect())
+    }
+}
+
+/// Wrap an error of a HuggingFace tokenizer.
+fn tokenizer_error(err: tokenizers::Error) -> WimbdError {
+    WimbdError::Tokenizer(err.to_string())
+}
+
 /// A wrapper class for HuggingFace tokenizers.
 #[derive(Debug, Clone)]
-pub struct PretrainedTokenizer(Tokenizer);
+pub struct PretrainedTokenizer(HfTokenizer);
 
 impl PretrainedTokenizer {
-    pub fn tokenize(&self, text: &str) -> Result<Vec<String>> {
-        Ok(self
-            .0
-            .encode(text, false)
-            .map_err(|err| anyhow!("{}", err))?
-            .into_tokens())
-    }
-
     /// Initialize a new pretrained tokenizer from a path or identifier on HuggingFace.
     pub fn new(name: &str) -> Result<Self> {
         Ok(PretrainedTokenizer(
-            Tokenizer::from_pretrained(name, None)
-                .map_err(|err| anyhow!("Failed to load pretrained tokenizer {} - {}", name, err))?,
+            HfTokenizer::from_pretrained(name, None).map_err(|err| {
+                WimbdError::Tokenizer(format!(
+                    "Failed to load pretrained tokenizer {} - {}",
+                    name, err
+                ))
+            })?,
         ))
     }
+}
+
+impl Tokenizer for PretrainedTokenizer {
+    fn tokenize(&self, text: &str) -> Result<Vec<String>> {
+        Ok(self
+            .0
+            .encode(text, false)
+            .map_err(tokenizer_error)?
+            .into_tokens())
+    }
 
-    pub fn decode(&self, tokens: &[String]) -> Result<String> {
+    fn decode(&self, tokens: &[String]) -> Result<String> {
         let ids = tokens
             .iter()
             .filter_map(|t| self.0.token_to_id(t))
             .collect();
-        self.0.decode(ids, true).map_err(|err| anyhow!("{}", err))
+        self.0.decode(ids, true).map_err(tokenizer_error)
+    }
+
+    fn vocab_size(&self) -> Option<usize> {
+        Some(self.0.get_vocab_size(true))
+    }
+
+    /// Only a prefix of the text is tokenized, so this takes bounded time however long the
+    /// text is.
+    ///
+    /// In the unlikely case that the prefix has fewer than `max_tokens` tokens, only
+    /// those are returned.
+    fn tokenize_truncated(&self, text: &str, max_tokens: usize) -> Result<(Vec<String>, bool)> {
+        let prefix = truncate_str(text, max_tokens.saturating_mul(MAX_BYTES_PER_TOKEN));
+        let mut tokens = self.tokenize(prefix)?;
+        let truncated = tokens.len() > max_tokens || prefix.len() < text.len();
+        tokens.truncate(max_tokens);
+        Ok((tokens, truncated))
+    }
+
+    fn tokenize_with_offsets(&self, text: &str) -> Result<Vec<(String, (usize, usize))>> {
+        let encoding = self.0.encode(text, false).map_err(tokenizer_error)?;
+        Ok(encoding
+            .get_tokens()
+            .iter()
+            .cloned()
+            .zip(encoding.get_offsets().iter().copied())
+            .collect())
+    }
+
+    fn ids_to_tokens(&self, ids: &[u32]) -> Result<Vec<String>> {
+        ids.iter()
+            .map(|&id| {
+                self.0.id_to_token(id).ok_or_else(|| {
+                    WimbdError::Tokenizer(format!("Token ID {} is not in the vocabulary", id))
+                })
+            })
+            .collect()
+    }
+
+    fn tokenize_ids(&self, text: &str) -> Result<Vec<u32>> {
+        Ok(self
+            .0
+            .encode(text, false)
+            .map_err(tokenizer_error)?
+            .get_ids()
+            .to_vec())
+    }
+
+    fn decode_ids(&self, ids: &[u32]) -> Result<String> {
+        self.0.decode(ids.to_vec(), true).map_err(tokenizer_error)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::tokenize;
+    use std::borrow::Cow;
+
+    use super::{
+        is_byte_fallback_token, is_unknown_token, load_tokenizer, tokenize, tokenize_with_offsets,
+        truncate_str, word_boundaries, Tokenizer, UnicodeTokenizer,
+    };
     use crate::ngrams::Ngram;
 
     #[test]
@@ -129,4 +367,84 @@ mod tests {
             ]
         );
     }
+
+    #[test]
+    fn test_tokenize_with_offsets() {
+        let s = "Héllo,  world!";
+        let tokens = tokenize_with_offsets(s).collect::<Vec<(usize, &str)>>();
+        assert_eq!(
+            tokens,
+            vec![(0, "Héllo"), (6, ","), (9, "world"), (14, "!")]
+        );
+        for (offset, token) in tokens {
+            assert_eq!(&s[offset..offset + token.len()], token);
+        }
+    }
+
+    #[test]
+    fn test_unicode_tokenizer() {
+        let tokenizer = UnicodeTokenizer;
+        assert_eq!(
+            tokenizer.tokenize("Hello, world!").unwrap(),
+            vec!["Hello", ",", "world", "!"]
+        );
+        assert_eq!(
+            tokenizer.tokenize_truncated("Hello, world!", 2).unwrap(),
+            (vec!["Hello".to_string(), ",".to_string()], true)
+        );
+        assert_eq!(
+            tokenizer.tokenize_with_offsets("Héllo world").unwrap(),
+            vec![
+                ("Héllo".to_string(), (0, 6)),
+                ("world".to_string(), (7, 12))
+            ]
+        );
+        assert_eq!(
+            tokenizer
+                .decode(&["Hello".to_string(), "world".to_string()])
+                .unwrap(),
+            "Hello world"
+        );
+        assert_eq!(tokenizer.vocab_size(), None);
+        assert_eq!(tokenizer.count_tokens("Hello, world!").unwrap(), 4);
+        assert!(tokenizer
+            .tokens("Hello, world!")
+            .unwrap()
+            .iter()
+            .all(|token| matches!(token, Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn test_load_unicode_tokenizer() {
+        let tokenizer = load_tokenizer("unicode").unwrap();
+        assert_eq!(tokenizer.tokenize("a b").unwrap(), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_word_boundaries() {
+        assert_eq!(word_boundaries("can't stop"), vec![0, 5, 6, 10]);
+        assert_eq!(word_boundaries(""), vec![0]);
+    }
+
+    #[test]
+    fn test_truncate_str() {
+        assert_eq!(truncate_str("hello", 10), "hello");
+        assert_eq!(truncate_str("hello", 3), "hel");
+        // "é" is two bytes, so it can't be cut in half.
+        assert_eq!(truncate_str("café", 4), "caf");
+        assert_eq!(truncate_str("café", 5), "café");
+        assert_eq!(truncate_str("", 0), "");
+    }
+
+    #[test]
+    fn test_special_tokens() {
+        assert!(is_unknown_token("<unk>"));
+        assert!(is_unknown_token("[UNK]"));
+        assert!(!is_unknown_token("unk"));
+        assert!(is_byte_fallback_token("<0xE2>"));
+        assert!(is_byte_fallback_token("<0x0a>"));
+        assert!(!is_byte_fallback_token("<0xZZ>"));
+        assert!(!is_byte_fallback_token("<0x100>"));
+        assert!(!is_byte_fallback_token("0xE2"));
+    }
 }
@@ -0,0 +1,397 @@
+//! A live terminal dashboard that's shown instead of progress bars when `--tui` is set.
+
+use std::collections::VecDeque;
+use std::io::{self, Stderr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Result};
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::{
+    cursor::{Hide, Show},
+    execute,
+    terminal::{EnterAlternateScreen, LeaveAlternateScreen},
+};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph, Row, Table, Wrap};
+use ratatui::{Frame, Terminal};
+use thousands::Separable;
+
+use crate::progress::FileProgressBar;
+
+/// How many finished files to keep around for the throughput table.
+const NUM_FINISHED_FILES: usize = 8;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Use the dashboard instead of progress bars for all subsequent executors.
+pub(crate) fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub(crate) fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+struct ActiveFile {
+    path: PathBuf,
+    progress: FileProgressBar,
+    started: Instant,
+}
+
+struct FinishedFile {
+    path: PathBuf,
+    lines: usize,
+    bytes: usize,
+    elapsed: Duration,
+}
+
+#[derive(Default)]
+struct State {
+    description: String,
+    total_files: usize,
+    finished_files: usize,
+    total_lines: usize,
+    total_bytes: usize,
+    active: Vec<ActiveFile>,
+    finished: VecDeque<FinishedFile>,
+    errors: usize,
+    last_error: Option<String>,
+    metrics: Vec<(String, String)>,
+    candidates: Vec<String>,
+}
+
+/// A handle to the dashboard. It's cheap to clone and can be shared across workers.
+#[derive(Clone)]
+pub(crate) struct Dashboard {
+    state: Arc<Mutex<State>>,
+    stop: Arc<AtomicBool>,
+    handle: Arc<Mutex<Option<JoinHandle<Result<()>>>>>,
+    start: Instant,
+}
+
+impl Dashboard {
+    /// Take over the terminal and start drawing the dashboard in a background thread.
+    pub(crate) fn start(description: &str, total_files: usize) -> Result<Self> {
+        let state = Arc::new(Mutex::new(State {
+            description: description.to_string(),
+            total_files,
+            ..Default::default()
+        }));
+        let stop = Arc::new(AtomicBool::new(false));
+        let start = Instant::now();
+
+        let mut stderr = io::stderr();
+        execute!(stderr, EnterAlternateScreen, Hide)?;
+        let mut terminal = Terminal::new(CrosstermBackend::new(stderr))?;
+
+        let handle = {
+            let state = state.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let result = draw_loop(&mut terminal, &state, &stop, start);
+                execute!(terminal.backend_mut(), LeaveAlternateScreen, Show)?;
+                result
+            })
+        };
+
+        Ok(Self {
+            state,
+            stop,
+            handle: Arc::new(Mutex::new(Some(handle))),
+            start,
+        })
+    }
+
+    fn update(&self, func: impl FnOnce(&mut State)) {
+        if let Ok(mut state) = self.state.lock() {
+            func(&mut state);
+        }
+    }
+
+    /// Mark a file as being processed by a worker.
+    pub(crate) fn start_file(&self, path: &Path, progress: FileProgressBar) {
+        self.update(|state| {
+            state.active.retain(|file| file.path != path);
+            state.active.push(ActiveFile {
+                path: path.into(),
+                progress,
+                started: Instant::now(),
+            });
+        });
+    }
+
+    /// Mark a file as done.
+    pub(crate) fn finish_file(&self, path: &Path, lines: usize, bytes: usize) {
+        self.update(|state| {
+            if let Some(i) = state.active.iter().position(|file| file.path == path) {
+                let file = state.active.remove(i);
+                state.finished.push_front(FinishedFile {
+                    path: file.path,
+                    lines,
+                    bytes,
+                    elapsed: file.started.elapsed(),
+                });
+                state.finished.truncate(NUM_FINISHED_FILES);
+            }
+            state.finished_files += 1;
+            state.total_lines += lines;
+            state.total_bytes += bytes;
+        });
+    }
+
+    pub(crate) fn record_error(&self, path: &Path, error: &str) {
+        self.update(|state| {
+            state.errors += 1;
+            state.last_error = Some(format!("{path:?}: {error}"));
+        });
+    }
+
+    /// Set a command-specific metric to display, like the counter occupancy.
+    pub(crate) fn set_metric(&self, name: &str, value: String) {
+        self.update(|state| {
+            match state.metrics.iter_mut().find(|(n, _)| n == name) {
+                Some((_, v)) => *v = value,
+                None => state.metrics.push((name.to_string(), value)),
+            };
+        });
+    }
+
+    /// Set the current candidates to display, like the top-k ngrams found so far.
+    pub(crate) fn set_candidates(&self, candidates: Vec<String>) {
+        self.update(|state| state.candidates = candidates);
+    }
+
+    /// Stop drawing and give the terminal back. This is safe to call more than once.
+    pub(crate) fn stop(&self) -> Result<()> {
+        self.stop.store(true, Ordering::Relaxed);
+        let handle = self
+            .handle
+            .lock()
+            .map_err(|_| anyhow!("Failed to acquire lock"))?
+            .take();
+        if let Some(handle) = handle {
+            handle
+                .join()
+                .map_err(|_| anyhow!("Dashboard thread panicked"))??;
+            log::info!(
+                "Dashboard closed after {}",
+                humantime::format_duration(Duration::from_secs(self.start.elapsed().as_secs()))
+            );
+        }
+        Ok(())
+    }
+}
+
+fn draw_loop(
+    terminal: &mut Terminal<CrosstermBackend<Stderr>>,
+    state: &Mutex<State>,
+    stop: &AtomicBool,
+    start: Instant,
+) -> Result<()> {
+    while !stop.load(Ordering::Relaxed) {
+        {
+            let state = state
+                .lock()
+                .map_err(|_| anyhow!("Failed to acquire lock"))?;
+            terminal.draw(|frame| draw(frame, &state, start.elapsed()))?;
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+    Ok(())
+}
+
+fn per_sec(n: usize, elapsed: Duration) -> usize {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        (n as f64 / secs) as usize
+    } else {
+        0
+    }
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn draw(frame: &mut Frame, state: &State, elapsed: Duration) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Length(3),
+            Constraint::Min(8),
+            Constraint::Length(NUM_FINISHED_FILES as u16 + 3),
+            Constraint::Length(4),
+        ])
+        .split(frame.size());
+
+    draw_header(frame, rows[0], state, elapsed);
+
+    let middle = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+        .split(rows[1]);
+    draw_workers(frame, middle[0], state);
+    draw_metrics(frame, middle[1], state);
+
+    draw_finished(frame, rows[2], state);
+    draw_errors(frame, rows[3], state);
+}
+
+fn draw_header(frame: &mut Frame, area: Rect, state: &State, elapsed: Duration) {
+    let ratio = if state.total_files > 0 {
+        state.finished_files as f64 / state.total_files as f64
+    } else {
+        0.0
+    };
+    let label = format!(
+        "files {}/{} | {} lines ({}/s) | {} MB ({} MB/s) | {}",
+        state.finished_files.separate_with_commas(),
+        state.total_files.separate_with_commas(),
+        state.total_lines.separate_with_commas(),
+        per_sec(state.total_lines, elapsed).separate_with_commas(),
+        (state.total_bytes / 1_000_000).separate_with_commas(),
+        per_sec(state.total_bytes, elapsed) / 1_000_000,
+        humantime::format_duration(Duration::from_secs(elapsed.as_secs())),
+    );
+    let gauge = Gauge::default()
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!(" wimbd: {} ", state.description)),
+        )
+        .gauge_style(Style::default().fg(Color::Cyan))
+        .ratio(ratio.clamp(0.0, 1.0))
+        .label(label);
+    frame.render_widget(gauge, area);
+}
+
+fn draw_workers(frame: &mut Frame, area: Rect, state: &State) {
+    let rows = state.active.iter().map(|file| {
+        Row::new(vec![
+            file_name(&file.path),
+            file.progress
+                .progress_bar()
+                .position()
+                .separate_with_commas(),
+            (file.progress.progress_bar().per_sec() as usize).separate_with_commas(),
+            format!(
+                "{:.1}",
+                file.progress.bytes() as f64
+                    / 1_000_000.0
+                    / file
+                        .progress
+                        .progress_bar()
+                        .elapsed()
+                        .as_secs_f64()
+                        .max(1e-3)
+            ),
+            humantime::format_duration(Duration::from_secs(file.started.elapsed().as_secs()))
+                .to_string(),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Percentage(44),
+            Constraint::Percentage(16),
+            Constraint::Percentage(12),
+            Constraint::Percentage(12),
+            Constraint::Percentage(16),
+        ],
+    )
+    .header(
+        Row::new(vec!["file", "lines", "lines/s", "MB/s", "elapsed"])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+    )
+    .block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" workers ({} active) ", state.active.len())),
+    );
+    frame.render_widget(table, area);
+}
+
+fn draw_metrics(frame: &mut Frame, area: Rect, state: &State) {
+    let mut lines: Vec<Line> = state
+        .metrics
+        .iter()
+        .map(|(name, value)| Line::from(format!("{name}: {value}")))
+        .collect();
+    if !state.candidates.is_empty() {
+        if !lines.is_empty() {
+            lines.push(Line::from(""));
+        }
+        lines.push(Line::styled(
+            "current candidates:",
+            Style::default().add_modifier(Modifier::BOLD),
+        ));
+        for (i, candidate) in state.candidates.iter().enumerate() {
+            lines.push(Line::from(format!("{:>3}. {candidate}", i + 1)));
+        }
+    }
+    let paragraph =
+        Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title(" metrics "));
+    frame.render_widget(paragraph, area);
+}
+
+fn draw_finished(frame: &mut Frame, area: Rect, state: &State) {
+    let rows = state.finished.iter().map(|file| {
+        Row::new(vec![
+            file_name(&file.path),
+            file.lines.separate_with_commas(),
+            per_sec(file.lines, file.elapsed).separate_with_commas(),
+            format!(
+                "{:.1}",
+                file.bytes as f64 / 1_000_000.0 / file.elapsed.as_secs_f64().max(1e-3)
+            ),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Percentage(50),
+            Constraint::Percentage(18),
+            Constraint::Percentage(14),
+            Constraint::Percentage(18),
+        ],
+    )
+    .header(
+        Row::new(vec!["file", "lines", "lines/s", "MB/s"])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+    )
+    .block(
+        Block::default()
+            .borders(Borders::ALL)
+            .title(" recently finished "),
+    );
+    frame.render_widget(table, area);
+}
+
+fn draw_errors(frame: &mut Frame, area: Rect, state: &State) {
+    let text = match &state.last_error {
+        Some(error) => error.clone(),
+        None => "none".to_string(),
+    };
+    let style = if state.errors > 0 {
+        Style::default().fg(Color::Red)
+    } else {
+        Style::default()
+    };
+    let paragraph = Paragraph::new(text)
+        .style(style)
+        .wrap(Wrap { trim: true })
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!(" errors ({}) ", state.errors)),
+        );
+    frame.render_widget(paragraph, area);
+}
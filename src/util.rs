@@ -1,25 +1,171 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 
 use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
-pub(crate) fn get_output_file(path: impl AsRef<Path>, force: bool) -> Result<(File, PathBuf)> {
+static APPEND: AtomicBool = AtomicBool::new(false);
+
+/// Used to give every temporary output file of this process a unique name.
+static NUM_TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// Append to output files that already exist instead of refusing to overwrite them.
+pub(crate) fn set_append() {
+    APPEND.store(true, Ordering::Relaxed);
+}
+
+/// Whether output files are appended to, see [`set_append()`].
+pub(crate) fn appending() -> bool {
+    APPEND.load(Ordering::Relaxed)
+}
+
+/// An output file that's compressed on the fly if its name ends in ".gz" or ".zst".
+///
+/// Everything is written to a temporary file next to the output file first, which only
+/// replaces the output file, or is appended to it with '--append', once [`OutputFile::finish()`]
+/// succeeds. This way a command that fails or crashes never leaves a half-written output file
+/// behind. An output file that's dropped without being finished is discarded.
+pub(crate) struct OutputFile {
+    writer: Option<OutputWriter>,
+    path: PathBuf,
+    temp_path: PathBuf,
+    append: bool,
+}
+
+enum OutputWriter {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputWriter {
+    fn finish(self) -> io::Result<File> {
+        let writer = match self {
+            OutputWriter::Plain(writer) => writer,
+            OutputWriter::Gzip(writer) => writer.finish()?,
+            OutputWriter::Zstd(writer) => writer.finish()?,
+        };
+        writer.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+impl OutputFile {
+    fn create(path: &Path, append: bool) -> Result<Self> {
+        let file_name = path
+            .file_name()
+            .with_context(|| format!("{:?} isn't a file", path))?;
+        let mut temp_name = std::ffi::OsString::from(".");
+        temp_name.push(file_name);
+        temp_name.push(format!(
+            ".tmp-{}-{}",
+            std::process::id(),
+            NUM_TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        let temp_path = path.with_file_name(temp_name);
+
+        let writer = BufWriter::new(File::create(&temp_path)?);
+        // Appending compressed output adds another gzip member or zstd frame to the file,
+        // which readers decode as if it was a single stream.
+        let writer = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("gz") => OutputWriter::Gzip(GzEncoder::new(writer, Compression::default())),
+            Some("zst") => OutputWriter::Zstd(zstd::Encoder::new(writer, 0)?),
+            _ => OutputWriter::Plain(writer),
+        };
+        Ok(Self {
+            writer: Some(writer),
+            path: path.into(),
+            temp_path,
+            append,
+        })
+    }
+
+    /// Finish the compressed stream, if any, and move everything to the output file.
+    pub(crate) fn finish(mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            let result = self.persist(writer);
+            if result.is_err() {
+                let _ = fs::remove_file(&self.temp_path);
+            }
+            result.with_context(|| format!("Failed to write output file {:?}", self.path))?;
+        }
+        Ok(())
+    }
+
+    fn persist(&self, writer: OutputWriter) -> io::Result<()> {
+        writer.finish()?.sync_all()?;
+        if self.append && self.path.is_file() {
+            let mut file = File::options().append(true).open(&self.path)?;
+            io::copy(&mut File::open(&self.temp_path)?, &mut file)?;
+            file.sync_all()?;
+            fs::remove_file(&self.temp_path)
+        } else {
+            fs::rename(&self.temp_path, &self.path)
+        }
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.writer.as_mut() {
+            Some(OutputWriter::Plain(writer)) => writer.write(buf),
+            Some(OutputWriter::Gzip(writer)) => writer.write(buf),
+            Some(OutputWriter::Zstd(writer)) => writer.write(buf),
+            None => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "output file is already finished",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.writer.as_mut() {
+            Some(OutputWriter::Plain(writer)) => writer.flush(),
+            Some(OutputWriter::Gzip(writer)) => writer.flush(),
+            Some(OutputWriter::Zstd(writer)) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for OutputFile {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.take() {
+            drop(writer);
+            log::warn!("Discarding unfinished output file {:?}", self.path);
+            if let Err(err) = fs::remove_file(&self.temp_path) {
+                log::error!("Failed to remove {:?} - {}", self.temp_path, err);
+            }
+        }
+    }
+}
+
+/// Open an output file for writing, compressed according to its extension (see [`OutputFile`]).
+/// Nothing is written to the path until the file is finished. Run metadata is written next to
+/// it once the command is done.
+pub(crate) fn get_output_file(
+    path: impl AsRef<Path>,
+    force: bool,
+) -> Result<(OutputFile, PathBuf)> {
     let path = path.as_ref();
+    crate::provenance::record_output(path);
 
+    let append = appending();
     if path.is_file() {
-        if force {
+        if append {
+            log::info!("Appending to output file {:?}", path);
+        } else if force {
             log::warn!("Overwriting output file {:?}", path);
         } else {
             bail!(
-                "Output file {:?} already exists, use --force to overwrite",
+                "Output file {:?} already exists, use --force to overwrite or --append to append to it",
                 path
             );
         }
-        Ok((File::options().write(true).open(path)?, path.into()))
-    } else {
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        Ok((File::create(path)?, path.into()))
+    } else if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
     }
+    Ok((OutputFile::create(path, append)?, path.into()))
 }